@@ -0,0 +1,84 @@
+use anyhow::{anyhow, Ok, Result};
+
+use crate::{
+    block::BlockEngine,
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// advise 的结果
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Advice {
+    pub way: usize,
+    // 采样到的平均每条 (key, value) 的大小
+    pub avg_entry_size: f64,
+    pub sampled: usize,
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 随机走 samples 条 root 到叶子的路径, 用 entry_size 量出叶子里每条数据的大小,
+    // 算出一个让 page_size 大小的页面达到 utilization 填充率的 way
+    // 还没有 codec, 数据的编码大小由调用方给出
+    pub fn advise<F>(&self, page_size: usize, utilization: f64, samples: usize, entry_size: F) -> Result<Advice>
+    where
+        F: Fn(&K, &V) -> usize,
+    {
+        if !(utilization > 0.0 && utilization <= 1.0) {
+            return Err(anyhow!("invalid utilization: {}.", utilization));
+        }
+        let mut total = 0;
+        let mut sampled = 0;
+        // 简单的 LCG, 不需要多好的随机性
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..samples {
+            let mut block_id = self.root;
+            loop {
+                let read = self.engine.fetch_read(block_id)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+                if node.is_leaf() {
+                    for (key, value) in node.keys.iter().zip(node.values.iter()) {
+                        total += entry_size(key, value);
+                        sampled += 1;
+                    }
+                    break;
+                }
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                block_id = node.pointers[(seed >> 33) as usize % node.pointers.len()];
+            }
+        }
+        if sampled == 0 {
+            return Ok(Advice { way: self.way, avg_entry_size: 0.0, sampled });
+        }
+
+        let avg_entry_size = total as f64 / sampled as f64;
+        let way = (page_size as f64 * utilization / avg_entry_size.max(1.0)) as usize;
+        Ok(Advice { way: way.max(2), avg_entry_size, sampled })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_advise() {
+        let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        assert_eq!(tree.advise(4096, 0.5, 8, |_, _| 16).unwrap().way, 4);
+
+        for i in 0..100u64 {
+            tree.insert(i, vec![0u8; 56]).unwrap();
+        }
+        let advice = tree.advise(4096, 0.5, 8, |_, v: &Vec<u8>| 8 + v.len()).unwrap();
+        assert_eq!(advice.avg_entry_size, 64.0);
+        assert_eq!(advice.way, 32);
+        assert!(advice.sampled > 0);
+        assert!(tree.advise(4096, 0.0, 8, |_, _| 1).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use anyhow::{Ok, Result};
+use std::ops::{Add, RangeBounds};
+
+use crate::{
+    block::BlockEngine,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Agg {
+    Count,
+    // 下面三个是对 value 的聚合
+    Min,
+    Max,
+    Sum,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AggResult<V> {
+    Count(usize),
+    // 范围为空时是 None
+    Value(Option<V>),
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 在叶子里直接折叠, 条目不会被 clone
+    pub fn fold_range<R, A, F>(&self, range: R, init: A, mut f: F) -> Result<A>
+    where
+        R: RangeBounds<K>,
+        F: FnMut(A, &K, &V) -> A,
+    {
+        let mut acc = Some(init);
+        self.for_each_leaf_in_range(self.root, range.start_bound(), range.end_bound(), |node, range| {
+            for i in range {
+                acc = acc.take().map(|acc| f(acc, &node.keys[i], &node.values[i]));
+            }
+            true
+        })?;
+        Ok(acc.unwrap())
+    }
+
+    pub fn aggregate<R>(&self, range: R, agg: Agg) -> Result<AggResult<V>>
+    where
+        R: RangeBounds<K>,
+        V: Ord + Add<Output = V>,
+    {
+        if agg == Agg::Count {
+            return Ok(AggResult::Count(self.count_range(range)?));
+        }
+
+        let value = self.fold_range(range, None, |acc: Option<V>, _, value| {
+            let Some(acc) = acc else {
+                return Some(value.clone());
+            };
+            Some(match agg {
+                Agg::Min if *value < acc => value.clone(),
+                Agg::Max if *value > acc => value.clone(),
+                Agg::Sum => acc + value.clone(),
+                _ => acc,
+            })
+        })?;
+        Ok(AggResult::Value(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_aggregate() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..100 {
+            tree.insert(i, (i * 7) % 13).unwrap();
+        }
+        assert_eq!(tree.aggregate(.., Agg::Count).unwrap(), AggResult::Count(100));
+        assert_eq!(tree.aggregate(10..20, Agg::Count).unwrap(), AggResult::Count(10));
+        assert_eq!(tree.aggregate(10..20, Agg::Min).unwrap(), AggResult::Value(Some(0)));
+        assert_eq!(tree.aggregate(10..20, Agg::Max).unwrap(), AggResult::Value(Some(12)));
+        let sum = (10..20).map(|i| (i * 7) % 13).sum();
+        assert_eq!(tree.aggregate(10..20, Agg::Sum).unwrap(), AggResult::Value(Some(sum)));
+        assert_eq!(tree.aggregate(200.., Agg::Sum).unwrap(), AggResult::Value(None));
+        assert_eq!(tree.aggregate(200.., Agg::Count).unwrap(), AggResult::Count(0));
+
+        let keys = tree.fold_range(..=5, vec![], |mut acc, k, _| {
+            acc.push(*k);
+            acc
+        });
+        assert_eq!(keys.unwrap(), vec![0, 1, 2, 3, 4, 5]);
+    }
+}
@@ -0,0 +1,434 @@
+use anyhow::{anyhow, Ok, Result};
+use std::{
+    future::Future,
+    marker::PhantomData,
+    ops::RangeBounds,
+    pin::Pin,
+    sync::{mpsc, Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    block::{BlockEngine, BlockId, TreeMeta},
+    error::Error,
+    order::KeyOrder,
+    tree::BPlusTreeNode,
+};
+
+// 异步的 block engine, block 按值进出, 不会有 guard 跨过 await
+// 不依赖具体的 runtime, 返回的 future 在别的线程做完 I/O 之后用 waker 叫醒
+pub trait AsyncBlockEngine {
+    type Item;
+    fn alloc_write(&mut self, item: Self::Item) -> impl Future<Output = Result<BlockId>> + Send;
+    // 返回 block 内容的一份拷贝
+    fn fetch_read(&self, block_id: BlockId) -> impl Future<Output = Result<Option<Self::Item>>> + Send;
+    // f 在拿着写锁的时候调用, 返回 f 的结果
+    fn fetch_write<R, F>(&mut self, block_id: BlockId, f: F) -> impl Future<Output = Result<R>> + Send
+    where
+        F: FnOnce(&mut Option<Self::Item>) -> R + Send + 'static,
+        R: Send + 'static;
+    fn load_meta(&self) -> impl Future<Output = Result<Option<TreeMeta>>> + Send;
+    fn flush(&mut self, meta: TreeMeta) -> impl Future<Output = Result<()>> + Send;
+    // 不管 durability 都落盘, 默认和 flush 一样
+    fn sync(&mut self, meta: TreeMeta) -> impl Future<Output = Result<()>> + Send {
+        self.flush(meta)
+    }
+}
+
+type Job<E> = Box<dyn FnOnce(&mut E) + Send>;
+
+// 把同步的 engine 放到单独的线程上, 所有操作排队交给它做, 调用的 executor 线程不会被磁盘 I/O 卡住
+pub struct Offload<E> {
+    jobs: Option<mpsc::Sender<Job<E>>>,
+    handle: Option<JoinHandle<E>>,
+}
+
+impl<E> Offload<E>
+where
+    E: BlockEngine + Send + 'static,
+{
+    pub fn new(mut engine: E) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job<E>>();
+        let handle = thread::spawn(move || {
+            for job in queue {
+                job(&mut engine);
+            }
+            engine
+        });
+        Offload { jobs: Some(jobs), handle: Some(handle) }
+    }
+
+    // 等排着的操作都做完, 拿回 engine
+    pub fn into_inner(mut self) -> Result<E> {
+        self.jobs.take();
+        let handle = self.handle.take().ok_or_else(|| anyhow!("block engine worker stopped."))?;
+        handle.join().map_err(|_| anyhow!("block engine worker panicked."))
+    }
+
+    fn run<R, F>(&self, f: F) -> Reply<R>
+    where
+        F: FnOnce(&mut E) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (mut done, reply) = reply();
+        let job: Job<E> = Box::new(move |engine| done.complete(f(engine)));
+        // 发不出去时 job 被丢掉, Done 会把 future 叫醒并报错
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(job);
+        }
+        reply
+    }
+}
+
+impl<E> Drop for Offload<E> {
+    fn drop(&mut self) {
+        self.jobs.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl<E> AsyncBlockEngine for Offload<E>
+where
+    E: BlockEngine + Send + 'static,
+    E::Item: Clone + Send + 'static,
+{
+    type Item = E::Item;
+
+    fn alloc_write(&mut self, item: E::Item) -> impl Future<Output = Result<BlockId>> + Send {
+        self.run(move |engine| engine.alloc_write(item))
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> impl Future<Output = Result<Option<E::Item>>> + Send {
+        self.run(move |engine| Ok(engine.fetch_read(block_id)?.content.clone()))
+    }
+
+    fn fetch_write<R, F>(&mut self, block_id: BlockId, f: F) -> impl Future<Output = Result<R>> + Send
+    where
+        F: FnOnce(&mut Option<E::Item>) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.run(move |engine| Ok(f(&mut engine.fetch_write(block_id)?.content)))
+    }
+
+    fn load_meta(&self) -> impl Future<Output = Result<Option<TreeMeta>>> + Send {
+        self.run(|engine| Ok(engine.load_meta()))
+    }
+
+    fn flush(&mut self, meta: TreeMeta) -> impl Future<Output = Result<()>> + Send {
+        self.run(move |engine| engine.flush(meta))
+    }
+}
+
+struct Slot<R> {
+    value: Option<Result<R>>,
+    waker: Option<Waker>,
+}
+
+// 工作线程那边拿着, 没来得及给结果就被丢掉 (engine panic 或者线程已经退出) 时也要叫醒等着的 future
+pub(crate) struct Done<R>(Option<Arc<Mutex<Slot<R>>>>);
+
+// Done 交给做事的线程, Reply 是等结果的 future
+pub(crate) fn reply<R>() -> (Done<R>, Reply<R>) {
+    let slot = Arc::new(Mutex::new(Slot { value: None, waker: None }));
+    (Done(Some(slot.clone())), Reply(slot))
+}
+
+impl<R> Done<R> {
+    pub(crate) fn complete(&mut self, value: Result<R>) {
+        let Some(slot) = self.0.take() else {
+            return;
+        };
+        let Result::Ok(mut slot) = slot.lock() else {
+            return;
+        };
+        slot.value = Some(value);
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<R> Drop for Done<R> {
+    fn drop(&mut self) {
+        self.complete(Err(anyhow!("block engine worker stopped.")));
+    }
+}
+
+pub struct Reply<R>(Arc<Mutex<Slot<R>>>);
+
+impl<R> Future for Reply<R> {
+    type Output = Result<R>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<R>> {
+        let Result::Ok(mut slot) = self.0.lock() else {
+            return Poll::Ready(Err(Error::LockPoisoned.into()));
+        };
+        match slot.value.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// 建在 AsyncBlockEngine 上的 B+ 树, 结点和 BPlusTree 的一样, flush 过的树两边都能打开
+// 只有插入, 查找和范围查询, 结点按 way 分裂, 没有 snapshot 和按字节算的容量
+pub struct AsyncBPlusTree<K, V, E>
+where
+    E: AsyncBlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    way: usize,
+    engine: E,
+    root: BlockId,
+    len: usize,
+    order: KeyOrder<K>,
+    _marker: PhantomData<V>,
+}
+
+impl<K, V, E> AsyncBPlusTree<K, V, E>
+where
+    E: AsyncBlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    pub async fn new(way: usize, mut engine: E) -> Result<Self> {
+        let root = engine.alloc_write(BPlusTreeNode::new_leaf(way)).await?;
+        Ok(AsyncBPlusTree { way, engine, root, len: 0, order: KeyOrder::default(), _marker: PhantomData })
+    }
+
+    // 接着用 engine 里上次 flush 时的树
+    pub async fn open(engine: E) -> Result<Self> {
+        let Some(meta) = engine.load_meta().await? else {
+            return Err(anyhow!("engine has no flushed tree."));
+        };
+        if meta.order != 0 {
+            return Err(anyhow!("tree was written with key order {} but opened with 0.", meta.order));
+        }
+        let (way, root, len) = (meta.way, meta.root, meta.len);
+        Ok(AsyncBPlusTree { way, engine, root, len, order: KeyOrder::default(), _marker: PhantomData })
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        let (root, way, len) = (self.root, self.way, self.len);
+        self.engine.flush(TreeMeta { root, way, len, persistent: false, order: self.order.id() }).await
+    }
+
+    // 和 flush 一样, 只是 engine 支持的话不看 durability 一定 fsync
+    pub async fn sync(&mut self) -> Result<()> {
+        let (root, way, len) = (self.root, self.way, self.len);
+        self.engine.sync(TreeMeta { root, way, len, persistent: false, order: self.order.id() }).await
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn into_engine(self) -> E {
+        self.engine
+    }
+
+    async fn node(&self, block_id: BlockId) -> Result<BPlusTreeNode<K, V>> {
+        self.engine.fetch_read(block_id).await?.ok_or_else(|| Error::EmptyBlock(block_id).into())
+    }
+
+    async fn put(&mut self, block_id: BlockId, node: BPlusTreeNode<K, V>) -> Result<()> {
+        self.engine.fetch_write(block_id, move |block| *block = Some(node)).await
+    }
+
+    pub async fn search(&self, key: &K) -> Result<Option<V>> {
+        let mut node = self.node(self.root).await?;
+        while !node.is_leaf() {
+            node = self.node(node.pointers[node.child_index(key, &self.order)]).await?;
+        }
+        Ok(self.order.search(&node.keys, key).ok().map(|pos| node.values[pos].clone()))
+    }
+
+    // 按顺序返回 range 里的所有条目; 只往下走和 range 相交的子树, 不依赖叶子之间的链接
+    pub async fn range<R>(&self, range: R) -> Result<Vec<(K, V)>>
+    where
+        R: RangeBounds<K>,
+    {
+        let mut entries = vec![];
+        let mut stack = vec![self.root];
+        while let Some(block_id) = stack.pop() {
+            let node = self.node(block_id).await?;
+            if node.is_leaf() {
+                for (key, value) in node.keys.iter().zip(&node.values) {
+                    let order = &self.order;
+                    if order.after_start(range.start_bound(), key) && order.before_end(range.end_bound(), key) {
+                        entries.push((key.clone(), value.clone()));
+                    }
+                }
+                continue;
+            }
+            // 子树 i 里的 key 在 [keys[i - 1], keys[i]) 里, 倒着压栈, 先出来的是左边的
+            for (i, &child) in node.pointers.iter().enumerate().rev() {
+                let below_start = i < node.keys.len() && !self.order.after_start(range.start_bound(), &node.keys[i]);
+                let above_end = i > 0 && !self.order.before_end(range.end_bound(), &node.keys[i - 1]);
+                if !below_start && !above_end {
+                    stack.push(child);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    // key 已经存在时替换 value, 返回旧的
+    // 先记下从 root 到叶子的路径, 改完叶子再从下往上改计数, 处理分裂
+    pub async fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        let mut path = vec![];
+        let mut block_id = self.root;
+        let mut node = self.node(block_id).await?;
+        while !node.is_leaf() {
+            let pos = node.child_index(&key, &self.order);
+            let child = node.pointers[pos];
+            path.push((block_id, node, pos));
+            block_id = child;
+            node = self.node(block_id).await?;
+        }
+
+        let old = match self.order.search(&node.keys, &key) {
+            Result::Ok(pos) => Some(std::mem::replace(&mut node.values[pos], value)),
+            Err(pos) => {
+                node.keys.insert(pos, key);
+                node.values.insert(pos, value);
+                None
+            }
+        };
+        // 分裂出来的 (分隔 key, 右结点, 右结点的条目数)
+        let mut split = None;
+        if node.is_overflow() {
+            let mut right = BPlusTreeNode::new_leaf(node.way);
+            let mid = node.keys.len() / 2;
+            right.keys = node.keys.split_off(mid);
+            right.values = node.values.split_off(mid);
+            right.prev = Some(block_id);
+            right.next = node.next;
+            let (mid, count, next) = (right.keys[0].clone(), right.keys.len(), right.next);
+            let right_id = self.engine.alloc_write(right).await?;
+            node.next = Some(right_id);
+            if let Some(next) = next {
+                let relink = move |block: &mut Option<BPlusTreeNode<K, V>>| {
+                    if let Some(next) = block {
+                        next.prev = Some(right_id);
+                    }
+                };
+                self.engine.fetch_write(next, relink).await?;
+            }
+            split = Some((mid, right_id, count));
+        }
+        let mut count = node.entry_count();
+        self.put(block_id, node).await?;
+        // 只替换了 value 时上面的结点都不用改
+        if old.is_some() {
+            return Ok(old);
+        }
+
+        while let Some((parent_id, mut parent, pos)) = path.pop() {
+            parent.counts[pos] += 1;
+            if let Some((mid, right_id, right_count)) = split.take() {
+                parent.counts[pos] -= right_count;
+                parent.keys.insert(pos, mid);
+                parent.pointers.insert(pos + 1, right_id);
+                parent.counts.insert(pos + 1, right_count);
+                parent.maxes.insert(pos + 1, None);
+                if parent.is_overflow() {
+                    let (mid, right) = parent.split_inner(parent.keys.len() / 2);
+                    let right_count = right.entry_count();
+                    split = Some((mid, self.engine.alloc_write(right).await?, right_count));
+                }
+            }
+            count = parent.entry_count();
+            self.put(parent_id, parent).await?;
+        }
+        // root 分裂时在上面加一层
+        if let Some((mid, right_id, right_count)) = split {
+            let mut root = BPlusTreeNode::new_inner(self.way);
+            root.keys = vec![mid];
+            root.pointers = vec![self.root, right_id];
+            root.counts = vec![count, right_count];
+            root.maxes = vec![None, None];
+            self.root = self.engine.alloc_write(root).await?;
+        }
+        self.len += 1;
+        Ok(None)
+    }
+}
+
+#[cfg(all(test, feature = "file"))]
+mod tests {
+    use std::{ops::Bound, sync::Arc, task::Wake, thread::Thread};
+
+    use crate::{
+        file::{FileBlockEngine, FileOptions},
+        tree::BPlusTree,
+    };
+
+    use super::*;
+
+    // 测试里不引入 runtime, 在当前线程上 park 着等
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct Unpark(Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn test_async_tree() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-aio-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, pool_size: 8, ..FileOptions::default() };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap();
+        block_on(async {
+            let mut tree = AsyncBPlusTree::new(5, Offload::new(engine)).await.unwrap();
+            for i in 0..1000 {
+                assert_eq!(tree.insert(i * 7 % 1000, i).await.unwrap(), None);
+            }
+            assert_eq!(tree.insert(7, 0).await.unwrap(), Some(1));
+            assert_eq!(tree.len(), 1000);
+            assert_eq!(tree.search(&7).await.unwrap(), Some(0));
+            assert_eq!(tree.search(&1000).await.unwrap(), None);
+            let keys: Vec<_> = tree.range(100..=200).await.unwrap().into_iter().map(|(key, _)| key).collect();
+            assert_eq!(keys, (100..=200).collect::<Vec<_>>());
+            assert_eq!(tree.range((Bound::Excluded(997), Bound::Unbounded)).await.unwrap().len(), 2);
+            tree.flush().await.unwrap();
+        });
+
+        // 同步的树打开之后结构是对的, 接着插入之后异步的树也能打开
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        let mut tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        tree.insert(1000, 1000).unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        block_on(async {
+            let tree = AsyncBPlusTree::open(Offload::new(engine)).await.unwrap();
+            assert_eq!(tree.len(), 1001);
+            assert_eq!(tree.range(990..).await.unwrap().len(), 11);
+            tree.into_engine().into_inner().unwrap();
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+}
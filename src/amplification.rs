@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use crate::{
+    block::BlockEngine,
+    tree::{BPlusTree, BPlusTreeNode, TreeStats},
+};
+
+pub(crate) type EntrySizeFn<K, V> = dyn Fn(&K, &V) -> usize + Send + Sync;
+
+// 一条数据写进了叶子, 按 entry_size 记进 logical_bytes; 新插入的, 覆盖的和原地改过的 value 都走这里
+// 只借用 stats 和 entry_size 两个字段, 拿着叶子的 guard 时也能记
+pub(crate) fn record_written<K, V>(stats: &mut TreeStats, entry_size: &Option<Arc<EntrySizeFn<K, V>>>, key: &K, value: &V) {
+    if let Some(entry_size) = entry_size {
+        stats.logical_bytes += entry_size(key, value) as u64;
+    }
+}
+
+// 树写进来的字节数和 engine 实际写的字节数, 拿来和 LSM 之类的结构比较
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Amplification {
+    // 写进来的 key 和 value, 按 set_entry_size 给的大小算
+    pub logical_bytes: u64,
+    // engine 写进存储的, 数据页, superblock, 日志和双写缓冲都算
+    pub physical_bytes: u64,
+    // physical_bytes / logical_bytes, 还没写过时是 0
+    pub write: f64,
+    pub live_bytes: u64,
+    pub file_bytes: u64,
+    // file_bytes / live_bytes, 没有在用的 block 时是 0
+    pub space: f64,
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 每条数据算多少字节, 之后写进来的数据按它累加到 TreeStats::logical_bytes 里, 见 record_written; 只在内存里, open 之后要重新设
+    // 不设的话 logical_bytes 一直是 0, 写放大也就没有意义
+    pub fn set_entry_size<F>(&mut self, entry_size: F)
+    where
+        F: Fn(&K, &V) -> usize + Send + Sync + 'static,
+    {
+        self.entry_size = Some(Arc::new(entry_size));
+    }
+
+    // 计数都是从创建或者打开起算的, 打开之前写的不算
+    pub fn amplification(&self) -> Amplification {
+        let logical_bytes = self.stats().logical_bytes;
+        let engine = self.engine_stats();
+        let ratio = |a: u64, b: u64| if b == 0 { 0.0 } else { a as f64 / b as f64 };
+        Amplification {
+            logical_bytes,
+            physical_bytes: engine.bytes_written,
+            write: ratio(engine.bytes_written, logical_bytes),
+            live_bytes: engine.live_bytes,
+            file_bytes: engine.file_bytes,
+            space: ratio(engine.file_bytes, engine.live_bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_amplification_in_memory() {
+        let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        tree.set_entry_size(|_: &u32, value: &String| 4 + value.len());
+        for i in 0..100 {
+            tree.insert(i, "x".repeat(i as usize % 10)).unwrap();
+        }
+        // 覆盖也算写进来的
+        tree.insert(0, "abc".to_string()).unwrap();
+        let amplification = tree.amplification();
+        assert_eq!(amplification.logical_bytes, 400 + 450 + 7);
+        assert_eq!((amplification.physical_bytes, amplification.write, amplification.space), (0, 0.0, 0.0));
+
+        // 不经过 insert 的写也按写完的 value 算, 没有写成的不算
+        let before = tree.stats().logical_bytes;
+        tree.update_with(&1, |value| value.push_str("yy")).unwrap();
+        *tree.get_mut(&2).unwrap().unwrap() = "zzzz".to_string();
+        assert!(tree.compare_exchange(&3, &"nope".to_string(), "w".to_string()).unwrap().is_err());
+        tree.compare_exchange(&3, &"xxx".to_string(), "w".to_string()).unwrap().unwrap();
+        tree.entry(4).unwrap().and_modify(|value| value.clear()).unwrap();
+        assert_eq!(tree.insert_batch(vec![(200, "ab".to_string()), (5, String::new())]).unwrap(), 1);
+        assert_eq!(tree.stats().logical_bytes - before, 7 + 8 + 5 + 4 + 6 + 4);
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_amplification() {
+        use crate::{
+            file::{FileBlockEngine, FileOptions},
+            superblock::Superblock,
+        };
+
+        let path = std::env::temp_dir().join(format!("bplus-tree-amplification-{}.db", std::process::id()));
+        for wal in [false, true] {
+            let options = FileOptions { page_size: 512, pool_size: 8, wal, ..FileOptions::default() };
+            let mut tree = BPlusTree::new(8, FileBlockEngine::create(&path, options).unwrap()).unwrap();
+            tree.set_entry_size(|_: &u64, _: &u64| 16);
+            for i in 0..1000u64 {
+                tree.insert(i * 7 % 1000, i).unwrap();
+            }
+            tree.flush().unwrap();
+            let amplification = tree.amplification();
+            assert_eq!(amplification.logical_bytes, 16000);
+            // 每一页都是整页写的, 淘汰了又改的页还要再写
+            assert!(amplification.write > 1.0);
+            let superblocks = (Superblock::first_block_page(512) * 512) as u64;
+            assert_eq!(amplification.live_bytes, amplification.file_bytes - superblocks);
+
+            // 删掉大部分之后回收的页还在文件里
+            for i in 0..900u64 {
+                tree.delete(&i).unwrap();
+            }
+            tree.flush().unwrap();
+            let after = tree.amplification();
+            assert!(after.physical_bytes > amplification.physical_bytes);
+            assert_eq!(after.file_bytes, amplification.file_bytes);
+            assert!(after.live_bytes < amplification.live_bytes / 2 && after.space > 2.0);
+        }
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(crate::wal::Wal::path(&path)).unwrap();
+    }
+}
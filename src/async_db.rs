@@ -0,0 +1,198 @@
+use anyhow::Result;
+use std::{marker::PhantomData, ops::RangeBounds, path::Path, sync::Arc};
+
+use crate::{
+    db::{Db, Key, Tree, Value},
+    runtime::{Runtime, Threads},
+};
+
+// Db 的异步版本, 读写文件和拿锁都用 R::unblock 交给 runtime 的线程去做, 不挡 executor 的工作线程
+pub struct AsyncDb<R = Threads> {
+    db: Arc<Db>,
+    _runtime: PhantomData<fn() -> R>,
+}
+
+// 返回的 future 要在 tokio runtime 里 poll
+#[cfg(feature = "tokio")]
+pub type TokioDb = AsyncDb<crate::runtime::Tokio>;
+
+#[cfg(feature = "smol")]
+pub type SmolDb = AsyncDb<crate::runtime::Smol>;
+
+// AsyncDb 里一棵树的句柄, 和 Tree 一样 clone 出来的是同一棵树
+pub struct AsyncTree<K, V, R = Threads> {
+    tree: Tree<K, V>,
+    _runtime: PhantomData<fn() -> R>,
+}
+
+impl<K, V, R> Clone for AsyncTree<K, V, R> {
+    fn clone(&self) -> Self {
+        AsyncTree { tree: self.tree.clone(), _runtime: PhantomData }
+    }
+}
+
+// R::unblock 只管交出去, f 自己的错误在里面一层
+async fn run<R, T, F>(f: F) -> Result<T>
+where
+    R: Runtime,
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    R::unblock(f).await?
+}
+
+impl<R: Runtime> AsyncDb<R> {
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        Ok(Self::new(run::<R, _, _>(move || Db::open(path)).await?))
+    }
+
+    pub fn new(db: Db) -> Self {
+        AsyncDb { db: Arc::new(db), _runtime: PhantomData }
+    }
+
+    // 只拿一下树表的锁, 不用交出去
+    pub fn tree<K: Key, V: Value>(&self, name: &str) -> Result<AsyncTree<K, V, R>> {
+        Ok(AsyncTree { tree: self.db.tree(name)?, _runtime: PhantomData })
+    }
+
+    pub fn tree_names(&self) -> Result<Vec<String>> {
+        self.db.tree_names()
+    }
+
+    // 写完并 fsync 之后才返回, 见 Db::flush
+    pub async fn flush(&self) -> Result<()> {
+        let db = self.db.clone();
+        run::<R, _, _>(move || db.flush()).await
+    }
+
+    pub async fn rollback(&self) -> Result<()> {
+        let db = self.db.clone();
+        run::<R, _, _>(move || db.rollback()).await
+    }
+
+    // f 整个在 runtime 的线程上跑, 里面用同步的 Db, 见 Db::transaction
+    pub async fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Db) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.db.clone();
+        run::<R, _, _>(move || db.transaction(f)).await
+    }
+}
+
+impl<K, V, R> AsyncTree<K, V, R>
+where
+    K: Key + Send + 'static,
+    V: Value + Send + 'static,
+    R: Runtime,
+{
+    pub fn name(&self) -> &str {
+        self.tree.name()
+    }
+
+    pub async fn get(&self, key: K) -> Result<Option<V>> {
+        let tree = self.tree.clone();
+        run::<R, _, _>(move || tree.get(&key)).await
+    }
+
+    pub async fn insert(&self, key: K, value: V) -> Result<Option<V>> {
+        let tree = self.tree.clone();
+        run::<R, _, _>(move || tree.insert(key, value)).await
+    }
+
+    pub async fn remove(&self, key: K) -> Result<Option<V>> {
+        let tree = self.tree.clone();
+        run::<R, _, _>(move || tree.remove(&key)).await
+    }
+
+    pub async fn len(&self) -> Result<usize> {
+        let tree = self.tree.clone();
+        run::<R, _, _>(move || tree.len()).await
+    }
+
+    pub async fn range<B>(&self, range: B) -> Result<Vec<(K, V)>>
+    where
+        B: RangeBounds<K> + Send + 'static,
+    {
+        let tree = self.tree.clone();
+        run::<R, _, _>(move || tree.range(range)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use std::{
+        future::Future,
+        task::{Context, Poll, Wake, Waker},
+        thread::{self, Thread},
+    };
+
+    use super::*;
+
+    // 哪个 runtime 都不用, 在当前线程上 park 着等
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct Unpark(Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    async fn fill<R: Runtime>(path: &Path) {
+        let db = AsyncDb::<R>::open(path).await.unwrap();
+        let tree = db.tree::<u64, String>("users").unwrap();
+        for i in 0..100u64 {
+            tree.insert(i, format!("user-{}", i)).await.unwrap();
+        }
+        db.flush().await.unwrap();
+        let ret = db
+            .transaction(|db| {
+                db.tree::<u64, String>("users")?.remove(&0)?;
+                Err::<(), _>(anyhow!("abort"))
+            })
+            .await;
+        assert!(ret.is_err());
+        assert_eq!(tree.get(0).await.unwrap(), Some("user-0".to_string()));
+        assert_eq!(tree.remove(1).await.unwrap(), Some("user-1".to_string()));
+        assert_eq!(tree.range(..3).await.unwrap(), vec![(0, "user-0".to_string()), (2, "user-2".to_string())]);
+        // 没 flush 的删除重新打开之后还在
+        drop((tree, db));
+        let db = AsyncDb::<R>::open(path).await.unwrap();
+        assert_eq!(db.tree::<u64, String>("users").unwrap().len().await.unwrap(), 100);
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(crate::wal::Wal::path(path)).unwrap();
+    }
+
+    #[test]
+    fn test_async_db() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-async-db-{}.db", std::process::id()));
+        block_on(fill::<Threads>(&path));
+
+        #[cfg(feature = "tokio")]
+        {
+            let path = std::env::temp_dir().join(format!("bplus-tree-async-db-tokio-{}.db", std::process::id()));
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            runtime.block_on(fill::<crate::runtime::Tokio>(&path));
+        }
+
+        #[cfg(feature = "smol")]
+        {
+            let path = std::env::temp_dir().join(format!("bplus-tree-async-db-smol-{}.db", std::process::id()));
+            block_on(fill::<crate::runtime::Smol>(&path));
+        }
+    }
+}
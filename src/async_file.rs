@@ -0,0 +1,174 @@
+use anyhow::{anyhow, Result};
+use std::{future::Future, marker::PhantomData, sync::Arc};
+
+use crate::{
+    aio::AsyncBlockEngine,
+    block::{BlockEngine, BlockId, TreeMeta},
+    codec::{BincodeCodec, NodeCodec},
+    file::FileBlockEngine,
+    runtime::{Runtime, Threads},
+};
+
+// FileBlockEngine 的异步版本, 每个操作用 R::unblock 交给 runtime 的线程去做
+// 和 Offload 只有一个工作线程不同, 几个读可以同时在不同的线程上等磁盘
+// flush 按 FileOptions::durability 决定要不要 fsync, sync 一定 fsync
+pub struct AsyncFileEngine<B, C = BincodeCodec, R = Threads> {
+    engine: Arc<FileBlockEngine<B, C>>,
+    _runtime: PhantomData<fn() -> R>,
+}
+
+// 返回的 future 要在 tokio runtime 里 poll
+#[cfg(feature = "tokio")]
+pub type TokioFileEngine<B, C = BincodeCodec> = AsyncFileEngine<B, C, crate::runtime::Tokio>;
+
+#[cfg(feature = "smol")]
+pub type SmolFileEngine<B, C = BincodeCodec> = AsyncFileEngine<B, C, crate::runtime::Smol>;
+
+impl<B, C, R> AsyncFileEngine<B, C, R>
+where
+    B: Clone + Send + Sync + 'static,
+    C: NodeCodec<B> + Send + Sync + 'static,
+    R: Runtime,
+{
+    pub fn new(engine: FileBlockEngine<B, C>) -> Self {
+        AsyncFileEngine { engine: Arc::new(engine), _runtime: PhantomData }
+    }
+
+    // 之前的 future 都 await 完之后才能拿回来
+    pub fn into_inner(self) -> Result<FileBlockEngine<B, C>> {
+        Arc::try_unwrap(self.engine).map_err(|_| anyhow!("block engine is still used by a pending task."))
+    }
+
+    // 第一次 poll 时才交出去, 和 await 的顺序一致
+    fn run<T, F>(&self, f: F) -> impl Future<Output = Result<T>> + Send + 'static
+    where
+        F: FnOnce(&FileBlockEngine<B, C>) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let engine = self.engine.clone();
+        async move { R::unblock(move || f(&engine)).await? }
+    }
+}
+
+impl<B, C, R> AsyncBlockEngine for AsyncFileEngine<B, C, R>
+where
+    B: Clone + Send + Sync + 'static,
+    C: NodeCodec<B> + Send + Sync + 'static,
+    R: Runtime,
+{
+    type Item = B;
+
+    fn alloc_write(&mut self, item: B) -> impl Future<Output = Result<BlockId>> + Send {
+        self.run(move |engine| engine.alloc_write(item))
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> impl Future<Output = Result<Option<B>>> + Send {
+        self.run(move |engine| Ok(engine.fetch_read(block_id)?.content.clone()))
+    }
+
+    fn fetch_write<T, F>(&mut self, block_id: BlockId, f: F) -> impl Future<Output = Result<T>> + Send
+    where
+        F: FnOnce(&mut Option<B>) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run(move |engine| Ok(f(&mut engine.fetch_write(block_id)?.content)))
+    }
+
+    fn load_meta(&self) -> impl Future<Output = Result<Option<TreeMeta>>> + Send {
+        self.run(|engine| Ok(engine.load_meta()))
+    }
+
+    fn flush(&mut self, meta: TreeMeta) -> impl Future<Output = Result<()>> + Send {
+        self.run(move |engine| engine.commit_meta(meta, false))
+    }
+
+    fn sync(&mut self, meta: TreeMeta) -> impl Future<Output = Result<()>> + Send {
+        self.run(move |engine| engine.commit_meta(meta, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        task::{Context, Poll, Wake, Waker},
+        thread::{self, Thread},
+    };
+
+    use crate::{
+        aio::AsyncBPlusTree,
+        file::{Durability, FileOptions},
+        tree::{BPlusTree, BPlusTreeNode},
+    };
+
+    use super::*;
+
+    // 哪个 runtime 都不用, 在当前线程上 park 着等
+    fn block_on<F: Future>(future: F) -> F::Output {
+        struct Unpark(Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    // 插入, flush, 再插入后 sync, 最后用同步的树检查
+    async fn fill<R: Runtime>(engine: FileBlockEngine<BPlusTreeNode<u32, u32>>) -> FileBlockEngine<BPlusTreeNode<u32, u32>> {
+        let mut tree = AsyncBPlusTree::new(5, AsyncFileEngine::<_, _, R>::new(engine)).await.unwrap();
+        for i in 0..500 {
+            tree.insert(i * 7 % 500, i).await.unwrap();
+        }
+        tree.flush().await.unwrap();
+        for i in 500..600 {
+            tree.insert(i, i).await.unwrap();
+        }
+        // durability 是 Never 时 flush 不 fsync, sync 照样 fsync
+        tree.sync().await.unwrap();
+        assert_eq!(tree.range(495..505).await.unwrap().len(), 10);
+        tree.into_engine().into_inner().unwrap()
+    }
+
+    fn check(path: &std::path::Path, options: FileOptions) {
+        let tree = BPlusTree::open(FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(path, options).unwrap()).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.len(), 600);
+        assert_eq!(tree.search(&7).unwrap(), Some(1));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_async_file_engine() {
+        let options = FileOptions { page_size: 512, pool_size: 8, durability: Durability::Never, ..FileOptions::default() };
+        let path = std::env::temp_dir().join(format!("bplus-tree-async-file-{}.db", std::process::id()));
+        let engine = FileBlockEngine::create(&path, options).unwrap();
+        drop(block_on(fill::<Threads>(engine)));
+        check(&path, options);
+
+        #[cfg(feature = "tokio")]
+        {
+            let path = std::env::temp_dir().join(format!("bplus-tree-async-file-tokio-{}.db", std::process::id()));
+            let engine = FileBlockEngine::create(&path, options).unwrap();
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            drop(runtime.block_on(fill::<crate::runtime::Tokio>(engine)));
+            check(&path, options);
+        }
+
+        #[cfg(feature = "smol")]
+        {
+            let path = std::env::temp_dir().join(format!("bplus-tree-async-file-smol-{}.db", std::process::id()));
+            let engine = FileBlockEngine::create(&path, options).unwrap();
+            drop(block_on(fill::<crate::runtime::Smol>(engine)));
+            check(&path, options);
+        }
+    }
+}
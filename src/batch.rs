@@ -0,0 +1,473 @@
+use anyhow::{Ok, Result};
+use std::cmp::Ordering;
+
+use crate::{
+    amplification::record_written,
+    block::{BlockEngine, BlockId},
+    build::{chunk_sizes, min_leaf_keys},
+    error::Error,
+    order::KeyOrder,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 插入之后多出来的兄弟结点: (分隔 key, block id)
+type Pieces<K> = Vec<(K, BlockId)>;
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 一次插入一批条目, 同一个 key 出现多次时后面的覆盖前面的, 已经存在的 key 替换 value
+    // 按 key 排序后一起往下走, 每个叶子只下降一次, 所有条目放进去之后再按需要分裂成几个
+    // 返回新增的条目数
+    pub fn insert_batch(&mut self, mut entries: Vec<(K, V)>) -> Result<usize> {
+        if entries.is_empty() {
+            return Ok(0);
+        }
+        entries.sort_by(|a, b| self.order.cmp(&a.0, &b.0));
+        entries.reverse();
+        entries.dedup_by(|a, b| self.order.eq(&a.0, &b.0));
+        entries.reverse();
+
+        // 按字节算时分成几个、要预留多少 block 都取决于每个条目的大小, 先检查完再逐条插入
+        if self.capacity.is_bytes() {
+            for (key, value) in &entries {
+                self.capacity.check_entry(key, value)?;
+            }
+            return self.insert_each(entries);
+        }
+
+        let needed = self.batch_blocks_needed(self.root, &entries)?;
+        let written = entries.len() as u64;
+        self.reserve(needed)?;
+        let ret = self.insert_batch_root(entries);
+        self.release_reserved();
+        let inserted = ret?;
+        self.len += inserted;
+        self.stats.inserts += written;
+        self.record_history();
+        Ok(inserted)
+    }
+
+    // 逐条插入, 先冻结插入前的版本, 中途出错 (比如空间不够) 时回到它, 整批要么都进去要么都不进去
+    fn insert_each(&mut self, entries: Vec<(K, V)>) -> Result<usize> {
+        let base = self.freeze();
+        let stats = self.stats;
+        let mut inserted = 0;
+        let mut ret = Ok(());
+        for (key, value) in entries {
+            match self.insert_unrecorded(key, value) {
+                Result::Ok(old) => inserted += usize::from(old.is_none()),
+                Err(e) => {
+                    ret = Err(e);
+                    break;
+                }
+            }
+        }
+        if let Err(e) = ret {
+            let root = std::mem::replace(&mut self.root, base.root);
+            (self.len, self.stats) = (base.len, stats);
+            self.release(root)?;
+            return Err(e);
+        }
+        self.release(base.root)?;
+        self.record_history();
+        Ok(inserted)
+    }
+
+    // 一次查多个 key, 结果和 keys 一一对应
+    // key 排序后一起往下走, 相邻的 key 共用路径上的结点, 每个结点只读一次
+    pub fn get_many(&self, keys: &[K]) -> Result<Vec<Option<V>>> {
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| self.order.cmp(&keys[a], &keys[b]));
+        let mut ret = vec![None; keys.len()];
+        if !order.is_empty() {
+            self.get_many_helper(self.root, keys, &order, &mut ret)?;
+        }
+        Ok(ret)
+    }
+
+    // order 是 keys 的下标, 按 key 排好序
+    fn get_many_helper(&self, block_id: BlockId, keys: &[K], order: &[usize], ret: &mut [Option<V>]) -> Result<()> {
+        let read = self.engine.fetch_read(block_id)?;
+        let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+        if node.is_leaf() {
+            for &i in order {
+                if let Result::Ok(pos) = self.order.search(&node.keys, &keys[i]) {
+                    ret[i] = Some(node.values[pos].clone());
+                }
+            }
+            return Ok(());
+        }
+        let mut groups = vec![];
+        let mut start = 0;
+        while start < order.len() {
+            let pos = node.child_index(&keys[order[start]], &self.order);
+            let end = start + order[start..].partition_point(|&i| node.child_index(&keys[i], &self.order) == pos);
+            groups.push((node.pointers[pos], start..end));
+            start = end;
+        }
+        drop(read);
+        // 要下去的孩子一批交给 engine, 有缓存的 engine 可以一起读进来; 和 readahead 一样只是提示, 失败了不管
+        if groups.len() > 1 {
+            let children: Vec<_> = groups.iter().map(|(child, _)| *child).collect();
+            self.engine.prefetch(&children).ok();
+        }
+        for (child, range) in groups {
+            self.get_many_helper(child, keys, &order[range], ret)?;
+        }
+        Ok(())
+    }
+
+    fn insert_batch_root(&mut self, entries: Vec<(K, V)>) -> Result<usize> {
+        let (root, mut pieces, inserted) = self.insert_batch_helper(self.root, entries)?;
+        self.root = root;
+        // root 分裂成几个时往上一层层建, 直到只剩一个
+        while !pieces.is_empty() {
+            let mut node = BPlusTreeNode::new_inner(self.way);
+            node.pointers.push(self.root);
+            node.counts.push(self.subtree_count(self.root)?);
+            node.maxes.push(self.subtree_max(self.root)?);
+            for (key, block_id) in pieces {
+                node.keys.push(key);
+                node.pointers.push(block_id);
+                node.counts.push(self.subtree_count(block_id)?);
+                node.maxes.push(self.subtree_max(block_id)?);
+            }
+            let rest = self.split_wide_inner(&mut node);
+            self.root = self.alloc_node(node)?;
+            pieces = rest.into_iter().map(|(key, node)| Ok((key, self.alloc_node(node)?))).collect::<Result<_>>()?;
+        }
+        Ok(inserted)
+    }
+
+    // entries 已经排好序且没有重复, 返回 (结点的 block id, 多出来的兄弟, 新增的条目数)
+    fn insert_batch_helper(&mut self, block_id: BlockId, entries: Vec<(K, V)>) -> Result<(BlockId, Pieces<K>, usize)> {
+        let block_id = self.own(block_id)?;
+        let mut node = self.take_node(block_id)?;
+        if node.is_leaf() {
+            for (key, value) in &entries {
+                record_written(&mut self.stats, &self.entry_size, key, value);
+            }
+            let old_len = node.keys.len();
+            let (keys, values) = (std::mem::take(&mut node.keys), std::mem::take(&mut node.values));
+            (node.keys, node.values) = merge_sorted(keys.into_iter().zip(values), entries, &self.order).into_iter().unzip();
+            let inserted = node.keys.len() - old_len;
+            if !node.is_overflow() {
+                self.put_node(block_id, node)?;
+                return Ok((block_id, vec![], inserted));
+            }
+            return Ok((block_id, self.split_wide_leaf(block_id, node)?, inserted));
+        }
+
+        // 排好序的条目按子结点分组, 每组是连续的一段
+        let mut groups: Vec<(usize, Vec<(K, V)>)> = vec![];
+        for (key, value) in entries {
+            let pos = node.child_index(&key, &self.order);
+            match groups.last_mut() {
+                Some((last, group)) if *last == pos => group.push((key, value)),
+                _ => groups.push((pos, vec![(key, value)])),
+            }
+        }
+        let pointers = node.pointers.clone();
+        self.put_node(block_id, node)?;
+
+        let mut inserted = 0;
+        let mut results = vec![];
+        for (pos, group) in groups {
+            let (child, pieces, n) = self.insert_batch_helper(pointers[pos], group)?;
+            let mut sums = vec![(self.subtree_count(child)?, self.subtree_max(child)?)];
+            for (_, piece) in &pieces {
+                sums.push((self.subtree_count(*piece)?, self.subtree_max(*piece)?));
+            }
+            inserted += n;
+            results.push((pos, child, pieces, sums));
+        }
+        // 从后往前放回去, 前面的下标不会因为插入而移动
+        let mut node = self.take_node(block_id)?;
+        for (pos, child, pieces, mut sums) in results.into_iter().rev() {
+            node.pointers[pos] = child;
+            (node.counts[pos], node.maxes[pos]) = sums.remove(0);
+            for (i, ((key, piece), (count, max))) in pieces.into_iter().zip(sums).enumerate() {
+                node.keys.insert(pos + i, key);
+                node.pointers.insert(pos + i + 1, piece);
+                node.counts.insert(pos + i + 1, count);
+                node.maxes.insert(pos + i + 1, max);
+            }
+        }
+        if !node.is_overflow() {
+            self.put_node(block_id, node)?;
+            return Ok((block_id, vec![], inserted));
+        }
+        let rest = self.split_wide_inner(&mut node);
+        self.put_node(block_id, node)?;
+        let pieces = rest.into_iter().map(|(key, node)| Ok((key, self.alloc_node(node)?))).collect::<Result<_>>()?;
+        Ok((block_id, pieces, inserted))
+    }
+
+    // 叶子分成若干个, 第一个留在原来的 block 里, 其余的新分配并接进叶子链
+    fn split_wide_leaf(&mut self, block_id: BlockId, mut node: BPlusTreeNode<K, V>) -> Result<Pieces<K>> {
+        let sizes = chunk_sizes(node.keys.len(), self.way, self.way, min_leaf_keys(self.way));
+        let mut rest = vec![];
+        for &size in sizes[1..].iter().rev() {
+            let at = node.keys.len() - size;
+            let mut piece = BPlusTreeNode::new_leaf(self.way);
+            piece.keys = node.keys.split_off(at);
+            piece.values = node.values.split_off(at);
+            rest.push(piece);
+        }
+        rest.reverse();
+        let next = node.next;
+        let mut prev = block_id;
+        let mut pieces = vec![];
+        for mut piece in rest {
+            piece.prev = Some(prev);
+            let key = piece.keys[0].clone();
+            let piece_id = self.alloc_node(piece)?;
+            if prev == block_id {
+                node.next = Some(piece_id);
+            } else if let Some(prev) = self.engine.fetch_write(prev)?.as_mut() {
+                prev.next = Some(piece_id);
+            }
+            pieces.push((key, piece_id));
+            prev = piece_id;
+            self.stats.splits += 1;
+        }
+        self.put_node(block_id, node)?;
+        if let Some(last) = self.engine.fetch_write(prev)?.as_mut() {
+            last.next = next;
+        }
+        // 持久化模式下右边的邻居可能被旧版本共享, 不去动它
+        if let (Some(next), false) = (next, self.persistent) {
+            if let Some(node) = self.engine.fetch_write(next)?.as_mut() {
+                node.prev = Some(prev);
+            }
+        }
+        Ok(pieces)
+    }
+
+    // 孩子太多的内部结点分成若干个, 每个都在 [way / 2 + 1, way + 1] 个孩子之间
+    // node 里留下第一个, 返回其余的
+    fn split_wide_inner(&mut self, node: &mut BPlusTreeNode<K, V>) -> Vec<(K, BPlusTreeNode<K, V>)> {
+        let sizes = chunk_sizes(node.pointers.len(), self.way + 1, self.way + 1, self.way / 2 + 1);
+        let mut rest = vec![];
+        for &size in sizes[1..].iter().rev() {
+            let at = node.pointers.len() - size;
+            let mut piece = BPlusTreeNode::new_inner(self.way);
+            piece.keys = node.keys.split_off(at - 1);
+            let key = piece.keys.remove(0);
+            piece.pointers = node.pointers.split_off(at);
+            piece.counts = node.counts.split_off(at);
+            piece.maxes = node.maxes.split_off(at);
+            rest.push((key, piece));
+        }
+        rest.reverse();
+        self.stats.splits += rest.len() as u64;
+        rest
+    }
+
+    // 只读地估计这一批最多要分配几个 block: 路径上要复制的结点, 加上每层分裂出来的结点
+    // 叶子的大小用父结点里的 counts, 不用读叶子; 替换已有 key 的条目也按新增算, 只会多估
+    fn batch_blocks_needed(&self, root: BlockId, entries: &[(K, V)]) -> Result<usize> {
+        let read = self.engine.fetch_read(root)?;
+        let node = read.as_ref().ok_or(Error::EmptyBlock(root))?;
+        let (mut needed, mut width) = if node.is_leaf() {
+            let pieces = self.leaf_pieces(node.keys.len() + entries.len());
+            (usize::from(self.is_shared(root)) + pieces - 1, pieces)
+        } else {
+            drop(read);
+            let (needed, extra) = self.batch_inner_needed(root, entries, self.height(root)?)?;
+            (needed, extra + 1)
+        };
+        // root 分裂之后新建的几层
+        while width > 1 {
+            width = self.inner_pieces(width);
+            needed += width;
+        }
+        Ok(needed)
+    }
+
+    // 返回 (要分配的 block 数, 这个结点分裂出来的兄弟数)
+    fn batch_inner_needed(&self, block_id: BlockId, entries: &[(K, V)], height: usize) -> Result<(usize, usize)> {
+        let read = self.engine.fetch_read(block_id)?;
+        let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+        let mut needed = usize::from(self.is_shared(block_id));
+        let mut width = node.pointers.len();
+        let mut start = 0;
+        while start < entries.len() {
+            let pos = node.child_index(&entries[start].0, &self.order);
+            let end = start + entries[start..].partition_point(|(key, _)| node.child_index(key, &self.order) == pos);
+            let child = node.pointers[pos];
+            let extra = if height == 1 {
+                let extra = self.leaf_pieces(node.counts[pos] + end - start) - 1;
+                needed += usize::from(self.is_shared(child)) + extra;
+                extra
+            } else {
+                let (child_needed, extra) = self.batch_inner_needed(child, &entries[start..end], height - 1)?;
+                needed += child_needed;
+                extra
+            };
+            width += extra;
+            start = end;
+        }
+        let extra = self.inner_pieces(width) - 1;
+        Ok((needed + extra, extra))
+    }
+
+    fn is_shared(&self, block_id: BlockId) -> bool {
+        self.persistent && !self.owned.contains(&block_id)
+    }
+
+    // 分别和 split_wide_leaf / split_wide_inner 分出来的个数一致
+    fn leaf_pieces(&self, len: usize) -> usize {
+        if len <= self.way {
+            return 1;
+        }
+        chunk_sizes(len, self.way, self.way, min_leaf_keys(self.way)).len()
+    }
+
+    fn inner_pieces(&self, width: usize) -> usize {
+        if width <= self.way + 1 {
+            return 1;
+        }
+        chunk_sizes(width, self.way + 1, self.way + 1, self.way / 2 + 1).len()
+    }
+}
+
+// 两个有序序列归并, key 相同时用 right 的
+fn merge_sorted<K: Ord, V>(left: impl Iterator<Item = (K, V)>, right: Vec<(K, V)>, order: &KeyOrder<K>) -> Vec<(K, V)> {
+    let mut left = left.peekable();
+    let mut right = right.into_iter().peekable();
+    let mut ret = vec![];
+    loop {
+        let order = match (left.peek(), right.peek()) {
+            (None, None) => return ret,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(l), Some(r)) => order.cmp(&l.0, &r.0),
+        };
+        match order {
+            Ordering::Less => ret.extend(left.next()),
+            Ordering::Greater => ret.extend(right.next()),
+            Ordering::Equal => {
+                left.next();
+                ret.extend(right.next());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{block::MemoryBlockEngine, capacity::NodeCapacity, tree::tests::check_shape};
+
+    use super::*;
+
+    #[test]
+    fn test_insert_batch() {
+        for way in [2, 3, 4, 7] {
+            for persistent in [false, true] {
+                let mut tree = BPlusTree::new(way, MemoryBlockEngine::new()).unwrap();
+                let mut expected = BTreeMap::new();
+                let mut seed = 3u32;
+                for round in 0..20u32 {
+                    let frozen = persistent.then(|| (tree.freeze(), expected.clone()));
+                    let mut batch = vec![];
+                    for i in 0..round * 13 {
+                        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                        batch.push(((seed >> 16) % 1000, round * 1000 + i));
+                    }
+                    let before = expected.len();
+                    for (key, value) in batch.clone() {
+                        expected.insert(key, value);
+                    }
+                    assert_eq!(tree.insert_batch(batch).unwrap(), expected.len() - before);
+                    assert_eq!(tree.len(), expected.len());
+                    check_shape(&tree, tree.root, true);
+                    assert_eq!(tree.iter().collect::<Vec<_>>(), expected.clone().into_iter().collect::<Vec<_>>());
+                    assert_eq!(tree.iter().rev().count(), expected.len());
+                    if let Some((version, before)) = frozen {
+                        for (key, value) in before {
+                            assert_eq!(tree.search_at(version, &key).unwrap(), Some(value));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_get_many() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        tree.insert_batch((0..100).map(|i| (i * 2, i)).collect()).unwrap();
+        let keys = [50, 3, 198, 0, 50, 200, 7, 8];
+        let expected: Vec<_> = keys.iter().map(|key| tree.search(key).unwrap()).collect();
+        assert_eq!(tree.get_many(&keys).unwrap(), expected);
+        assert_eq!(expected[0], Some(25));
+        assert!(tree.get_many(&[]).unwrap().is_empty());
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_get_many_prefetch() {
+        use crate::file::{FileBlockEngine, FileOptions};
+
+        let path = std::env::temp_dir().join(format!("bplus-tree-get-many-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 1024, pool_size: 256, ..FileOptions::default() };
+        let mut tree = BPlusTree::new(32, FileBlockEngine::create(&path, options).unwrap()).unwrap();
+        tree.insert_batch((0..5000u32).map(|i| (i, i)).collect()).unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+
+        // 缓存是空的, 每个 parent 下面要读的叶子一批预读进来, 不是取的时候才一个个读
+        let tree = BPlusTree::<u32, u32, _>::open(FileBlockEngine::open(&path, options).unwrap()).unwrap();
+        let keys: Vec<_> = (0..5000u32).step_by(7).rev().collect();
+        assert!(tree.get_many(&keys).unwrap().into_iter().zip(&keys).all(|(value, key)| value == Some(*key)));
+        let stats = tree.engine_stats();
+        assert!(stats.misses * 3 < stats.reads);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_insert_batch_reserves_up_front() {
+        // 空间不够时整批失败, 树保持原样
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::with_capacity(8)).unwrap();
+        tree.insert_batch((0..6).map(|i| (i, i)).collect()).unwrap();
+        assert!(tree.insert_batch((10..100).map(|i| (i, i)).collect()).is_err());
+        assert_eq!(tree.keys().collect::<Vec<_>>(), (0..6).collect::<Vec<_>>());
+        tree.insert_batch((6..10).map(|i| (i, i)).collect()).unwrap();
+        assert_eq!(tree.len(), 10);
+    }
+
+    #[test]
+    fn test_insert_batch_stats() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        tree.insert_batch((0..100).map(|i| (i, i)).collect()).unwrap();
+        tree.insert_batch((50..150).map(|i| (i, i)).collect()).unwrap();
+        let stats = tree.stats();
+        assert_eq!(stats.inserts, 200);
+        // 分裂出来的结点各算一次, 最开始的叶子和每次长高时新建的 root 不算
+        let shape = tree.verify().unwrap();
+        assert_eq!(stats.splits as usize, shape.inner_nodes + shape.leaves - shape.height);
+    }
+
+    #[test]
+    fn test_insert_batch_by_bytes_atomic() {
+        // 按字节算时逐条插入, 中途空间不够也要整批回退
+        let capacity = NodeCapacity::bytes(40, |_: &u32, _: Option<&u32>| 10);
+        let mut tree = BPlusTree::with_node_capacity(8, MemoryBlockEngine::with_capacity(12), capacity).unwrap();
+        tree.insert_batch((0..6).map(|i| (i, i)).collect()).unwrap();
+        let stats = tree.stats();
+        assert!(tree.insert_batch((3..100).map(|i| (i, i + 1)).collect()).is_err());
+        assert_eq!(tree.iter().collect::<Vec<_>>(), (0..6).map(|i| (i, i)).collect::<Vec<_>>());
+        assert_eq!(tree.stats(), stats);
+        tree.verify().unwrap();
+        assert_eq!(tree.insert_batch((3..10).map(|i| (i, i + 1)).collect()).unwrap(), 4);
+        assert_eq!(tree.len(), 10);
+        assert_eq!(tree.stats().inserts, stats.inserts + 7);
+    }
+}
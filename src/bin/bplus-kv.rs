@@ -0,0 +1,271 @@
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    ops::Bound,
+    process::ExitCode,
+};
+
+use anyhow::{anyhow, bail, Result};
+use bplus_tree::db::{Db, Tree};
+
+const USAGE: &str = "usage: bplus-kv <file> [--tree <name>] <command>
+commands:
+  put <key> <value>
+  get <key>
+  del <key>
+  scan [--from <key>] [--to <key>] [--limit <n>]
+  import --csv <path|->
+  export --json [<path|->]";
+
+// key 和 value 都是字符串, 存在 Db 里名字叫 --tree 的树里, 默认是 default
+// 每次运行拿 <file>.lock 上的排它锁, 别的 bplus-kv 在用同一个文件时等它结束
+// get / del 的 key 不存在时退出码是 1
+fn main() -> Result<ExitCode> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let name = take_option(&mut args, "--tree")?.unwrap_or_else(|| "default".to_string());
+    let [path, command, args @ ..] = args.as_slice() else {
+        bail!("{}", USAGE);
+    };
+    let lock = OpenOptions::new().create(true).truncate(false).write(true).open(format!("{}.lock", path))?;
+    lock.lock()?;
+    let db = Db::open(path)?;
+    let tree = db.tree::<String, String>(&name)?;
+    let found = match command.as_str() {
+        "put" => {
+            let [key, value] = args else { bail!("{}", USAGE) };
+            tree.insert(key.clone(), value.clone())?;
+            true
+        }
+        "get" => {
+            let [key] = args else { bail!("{}", USAGE) };
+            let value = tree.get(key)?;
+            if let Some(value) = &value {
+                println!("{}", value);
+            }
+            value.is_some()
+        }
+        "del" => {
+            let [key] = args else { bail!("{}", USAGE) };
+            tree.remove(key)?.is_some()
+        }
+        "scan" => {
+            scan(&tree, args.to_vec())?;
+            true
+        }
+        "import" => {
+            let [flag, source] = args else { bail!("{}", USAGE) };
+            if flag != "--csv" {
+                bail!("{}", USAGE);
+            }
+            let count = import_csv(&tree, source)?;
+            eprintln!("imported {} entries", count);
+            true
+        }
+        "export" => {
+            let (Some(flag), target) = (args.first(), args.get(1)) else { bail!("{}", USAGE) };
+            if flag != "--json" || args.len() > 2 {
+                bail!("{}", USAGE);
+            }
+            export_json(&tree, target.map_or("-", |target| target.as_str()))?;
+            true
+        }
+        _ => bail!("{}", USAGE),
+    };
+    // 提交之后顺便把日志写回数据文件, 下一次打开不用重放; 只读的命令不写
+    if matches!(command.as_str(), "put" | "del" | "import") {
+        db.checkpoint()?;
+    }
+    Ok(if found { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+// 取出 flag 和它后面的值
+fn take_option(args: &mut Vec<String>, flag: &str) -> Result<Option<String>> {
+    let Some(pos) = args.iter().position(|arg| arg == flag) else {
+        return Ok(None);
+    };
+    if pos + 1 >= args.len() {
+        return Err(anyhow!("{} needs a value.", flag));
+    }
+    args.remove(pos);
+    Ok(Some(args.remove(pos)))
+}
+
+// --from 包含, --to 不包含, 每行一条, key 和 value 之间是 tab
+fn scan(tree: &Tree<String, String>, mut args: Vec<String>) -> Result<()> {
+    let from = take_option(&mut args, "--from")?.map_or(Bound::Unbounded, Bound::Included);
+    let to = take_option(&mut args, "--to")?.map_or(Bound::Unbounded, Bound::Excluded);
+    let limit = match take_option(&mut args, "--limit")? {
+        Some(limit) => limit.parse()?,
+        None => usize::MAX,
+    };
+    if !args.is_empty() {
+        bail!("{}", USAGE);
+    }
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut count = 0;
+    if limit > 0 {
+        tree.scan((from, to), |key, value| {
+            writeln!(out, "{}\t{}", key, value)?;
+            count += 1;
+            Ok(count < limit)
+        })?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// 每行 key,value 两列, 字段可以用双引号括起来, 引号里的 "" 是一个引号; 整个文件在一次提交里
+fn import_csv(tree: &Tree<String, String>, source: &str) -> Result<usize> {
+    let reader: Box<dyn BufRead> = match source {
+        "-" => Box::new(io::stdin().lock()),
+        path => Box::new(BufReader::new(File::open(path)?)),
+    };
+    let mut count = 0;
+    for (no, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = parse_csv_line(line).map_err(|e| anyhow!("line {}: {}", no + 1, e))?;
+        if fields.len() != 2 {
+            bail!("line {}: expected 2 fields, got {}.", no + 1, fields.len());
+        }
+        let value = fields.pop().unwrap_or_default();
+        let key = fields.pop().unwrap_or_default();
+        tree.insert(key, value)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn parse_csv_line(line: &str) -> Result<Vec<String>> {
+    let mut fields = vec![];
+    let mut chars = line.chars().peekable();
+    loop {
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => bail!("unterminated quoted field."),
+                }
+            }
+            if !matches!(chars.peek(), None | Some(',')) {
+                bail!("unexpected character after quoted field.");
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' {
+                    break;
+                }
+                field.push(c);
+                chars.next();
+            }
+        }
+        fields.push(field);
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+// 一个 json 数组, 每个元素是 {"key": .., "value": ..}, 按 key 排好序
+fn export_json(tree: &Tree<String, String>, target: &str) -> Result<()> {
+    let out: Box<dyn Write> = match target {
+        "-" => Box::new(io::stdout().lock()),
+        path => Box::new(File::create(path)?),
+    };
+    let mut out = BufWriter::new(out);
+    write!(out, "[")?;
+    // 边读边写, 逗号放在下一个元素前面, 不用先知道一共有几个
+    let mut sep = "";
+    tree.scan(.., |key, value| {
+        write!(out, "{}\n  {{\"key\": {}, \"value\": {}}}", sep, json_string(&key), json_string(&value))?;
+        sep = ",";
+        Ok(true)
+    })?;
+    writeln!(out, "\n]")?;
+    out.flush()?;
+    Ok(())
+}
+
+// 双引号括起来, 引号, 反斜杠和控制字符转义
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_csv_line() {
+        assert_eq!(parse_csv_line("a,b").unwrap(), args(&["a", "b"]));
+        assert_eq!(parse_csv_line(",").unwrap(), args(&["", ""]));
+        assert_eq!(parse_csv_line("\"a,b\",\"say \"\"hi\"\"\"").unwrap(), args(&["a,b", "say \"hi\""]));
+        assert_eq!(parse_csv_line("\"\",x\"y").unwrap(), args(&["", "x\"y"]));
+        assert!(parse_csv_line("\"abc").is_err());
+        assert!(parse_csv_line("\"a\"b,c").is_err());
+    }
+
+    #[test]
+    fn test_take_option() {
+        let mut rest = args(&["--from", "a", "--limit", "3", "x"]);
+        assert_eq!(take_option(&mut rest, "--limit").unwrap(), Some("3".to_string()));
+        assert_eq!(take_option(&mut rest, "--to").unwrap(), None);
+        assert_eq!(rest, args(&["--from", "a", "x"]));
+        assert!(take_option(&mut args(&["a", "--tree"]), "--tree").is_err());
+    }
+
+    #[test]
+    fn test_scan_and_export() {
+        let path = env::temp_dir().join(format!("bplus-tree-kv-{}.db", std::process::id()));
+        let json = path.with_extension("json");
+        let db = Db::open(&path).unwrap();
+        let tree = db.tree::<String, String>("default").unwrap();
+        assert!(scan(&tree, args(&["--limit", "many"])).is_err());
+        assert!(scan(&tree, args(&["--from"])).is_err());
+        assert!(scan(&tree, args(&["--from", "a", "b"])).is_err());
+        scan(&tree, args(&["--limit", "0"])).unwrap();
+
+        export_json(&tree, json.to_str().unwrap()).unwrap();
+        assert_eq!(std::fs::read_to_string(&json).unwrap(), "[\n]\n");
+        tree.insert("b".to_string(), "say \"hi\"".to_string()).unwrap();
+        tree.insert("a".to_string(), "1".to_string()).unwrap();
+        export_json(&tree, json.to_str().unwrap()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&json).unwrap(),
+            "[\n  {\"key\": \"a\", \"value\": \"1\"},\n  {\"key\": \"b\", \"value\": \"say \\\"hi\\\"\"}\n]\n"
+        );
+        drop((tree, db));
+        for path in [json, path.clone(), format!("{}.wal", path.display()).into()] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
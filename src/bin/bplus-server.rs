@@ -0,0 +1,21 @@
+use std::{env, net::TcpListener};
+
+use bplus_tree::{block::MemoryBlockEngine, server::Server, tree::BPlusTree};
+
+fn main() -> anyhow::Result<()> {
+    // bplus-server [addr] [--resp]
+    let args: Vec<String> = env::args().skip(1).collect();
+    let resp = args.iter().any(|arg| arg == "--resp");
+    let addr = args.iter().find(|arg| !arg.starts_with("--")).cloned().unwrap_or_else(|| "127.0.0.1:7878".to_string());
+    let listener = TcpListener::bind(&addr)?;
+    println!("listening on {}", listener.local_addr()?);
+    let server = Server::new(BPlusTree::new(64, MemoryBlockEngine::new())?);
+    if resp {
+        #[cfg(feature = "resp")]
+        return Ok(server.serve_resp(listener)?);
+        #[cfg(not(feature = "resp"))]
+        anyhow::bail!("built without the resp feature.");
+    }
+    server.serve(listener)?;
+    Ok(())
+}
@@ -1,51 +1,263 @@
-use std::{ops::{Deref, DerefMut}, sync::{atomic::{AtomicUsize, Ordering}, RwLock, RwLockReadGuard, RwLockWriteGuard}};
+use std::{collections::BTreeMap, ops::{Deref, DerefMut}, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc, Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError}};
 use anyhow::{anyhow, Ok, Result};
 
+use crate::error::Error;
+
 // block engine 是 bptree 下面的一层抽象
 // 有了这层抽象 bptree 的实现可以无需区分 disk / memory only
 
 pub type BlockId = usize;
 
+// 持久化的 engine 在 flush 时记下的树, 重新打开时靠它接上
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TreeMeta {
+    pub root: BlockId,
+    pub way: usize,
+    // 条目数
+    pub len: usize,
+    // 是在持久化模式下写的, 叶子之间的链接不可信, 打开之后也要按持久化模式改
+    pub persistent: bool,
+    // key 顺序的 id, 见 KeyOrder::id; 打开时和给的顺序对不上就报错
+    pub order: u64,
+}
+
+// engine 从创建或者打开起的计数, 用来调 buffer pool 的大小
+// 没有缓存的 engine 只有一部分有意义, 其余的是 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockEngineStats {
+    // 从存储读进来和写回存储的 block 数
+    pub reads: u64,
+    pub writes: u64,
+    // 取 block 时缓存命中和不命中的次数
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // 现在缓存里改过还没写回的 block 数
+    pub dirty: usize,
+    pub allocations: u64,
+    // 写进存储的字节数, 数据页, superblock, 日志和双写缓冲都算
+    pub bytes_written: u64,
+    // 在用的 block 占的字节数和整个数据文件的字节数, 空闲页和 superblock 只算在后者里
+    pub live_bytes: u64,
+    pub file_bytes: u64,
+}
+
 pub struct Block<B> {
-    valid: bool,
-    id: BlockId,
-    content: Option<B>
+    pub(crate) valid: bool,
+    pub(crate) id: BlockId,
+    pub(crate) content: Option<B>
 }
 
 pub trait BlockEngine {
     type Item;
-    fn alloc_block(&mut self) -> BlockId;
-    fn alloc_write(&mut self, item: Self::Item) -> Result<BlockId> {
-        let id = self.alloc_block();
+    // 分配和回收也只要 &self, engine 可以放在 Arc 里给好几个线程一起用; flush 之类的维护操作还是要独占
+    fn alloc_block(&self) -> Result<BlockId>;
+    fn alloc_write(&self, item: Self::Item) -> Result<BlockId> {
+        let id = self.alloc_block()?;
         let mut block = self.fetch_write(id)?;
         block.content = Some(item);
         block.valid = true;
         Ok(id)
     }
-    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<Self::Item>>;
-    fn fetch_write(&mut self, block_id: BlockId) -> Result<BlockWriteGuard<Self::Item>>;
-    fn delete(&mut self, block_id: BlockId) -> Result<Option<Self::Item>>;
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, Self::Item>>;
+    // 和 fetch_read 一样只要 &self, 每个 block 各自有锁, 同时拿着几个 block 的写 guard 也不需要 unsafe
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, Self::Item>>;
+    // 别的线程拿着写锁或者读锁时不等, 返回 None; 给要放开所有锁再重来的并发写者用
+    // 默认和 fetch_write 一样会等, 用 buffer pool 的 engine 拿不到锁时 frame 还可能被换掉, 没法不等
+    fn try_fetch_write(&self, block_id: BlockId) -> Result<Option<BlockWriteGuard<'_, Self::Item>>> {
+        self.fetch_write(block_id).map(Some)
+    }
+    fn delete(&self, block_id: BlockId) -> Result<Option<Self::Item>>;
     
     // memory only 可以不实现
     // write back 不需要 engine 的内部状态
     fn write_back(block_id: BlockId, block: &Block<Self::Item>);
+
+    // 持久化的 engine 重新打开时返回上次 flush 时的树, memory only 没有
+    fn load_meta(&self) -> Option<TreeMeta> {
+        None
+    }
+
+    // 有名字的 snapshot, 下一次 flush 时和 meta 一起提交, 重新打开时靠 load_snapshots 找回来
+    // 不能持久化的 engine 什么都不做, 重新打开之后就没有 snapshot 了
+    fn stage_snapshots(&self, _snapshots: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        Ok(())
+    }
+
+    fn load_snapshots(&self) -> Result<BTreeMap<String, TreeMeta>> {
+        Ok(BTreeMap::new())
+    }
+
+    // 把修改过的 block 连同 meta 一起写到持久存储上, memory only 可以不实现
+    fn flush(&mut self, _meta: TreeMeta) -> Result<()> {
+        Ok(())
+    }
+
+    // flush 之后把日志里的修改写进存储, 清空日志; 没有日志的 engine 和 flush 一样
+    fn checkpoint(&mut self, meta: TreeMeta) -> Result<()> {
+        self.flush(meta)
+    }
+
+    // flush 之后把存储末尾空闲的 block 还回去, 返回去掉了多少个; 做不到的 engine 只 flush
+    fn vacuum(&mut self, meta: TreeMeta) -> Result<usize> {
+        self.flush(meta)?;
+        Ok(0)
+    }
+
+    fn stats(&self) -> BlockEngineStats {
+        BlockEngineStats::default()
+    }
+
+    // 提示 engine 这些 block 马上要读, 有缓存的 engine 可以一批读进来, 返回读了几个
+    // 只是提示, 不存在或者已经回收的 block 跳过; 没有缓存的 engine 什么都不做
+    fn prefetch(&self, _block_ids: &[BlockId]) -> Result<usize> {
+        Ok(0)
+    }
+
+    // 和 root 放在一起的一小段用户数据, 比如 schema 的版本
+    // 会持久化的 engine 要在下一次换 root 时一起原子地写下去; 默认不支持
+    fn set_user_metadata(&mut self, _bytes: &[u8]) -> Result<()> {
+        Err(anyhow!("user metadata is not supported by this engine."))
+    }
+
+    fn user_metadata(&self) -> Result<Vec<u8>> {
+        Err(anyhow!("user metadata is not supported by this engine."))
+    }
+
+    // 现在在缓存里的 block, 关闭前记下来, 下次打开后交给 prefetch 就能恢复缓存; 没有缓存的 engine 返回空
+    fn resident(&self) -> Result<Vec<BlockId>> {
+        Ok(vec![])
+    }
+
+    // 分配过的 block 数 (id 从 0 开始连续) 和空闲的 block, 给 fsck 找泄漏的 block; 说不清的 engine 返回 None
+    fn block_usage(&self) -> Option<(usize, Vec<BlockId>)> {
+        None
+    }
+
+    // pin 住的 block 一直留在缓存里, 不会被淘汰, 也不能删除, unpin 的次数和 pin 的一样多之后才放开
+    // 给拿着 block 里的位置跨好几次操作的 cursor 用, 没有缓存的 engine 什么都不做
+    fn pin(&self, _block_id: BlockId) -> Result<()> {
+        Ok(())
+    }
+
+    fn unpin(&self, _block_id: BlockId) -> Result<()> {
+        Ok(())
+    }
+
+    // 进入一个 epoch, 返回它的编号; leave_epoch 之前这期间 delete 的 block 不会被重新分配出去
+    // 给不拿着锁也记着 block id 的并发读者用, 见 EpochBlockEngine; 其它 engine 什么都不做
+    fn enter_epoch(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    fn leave_epoch(&self, _epoch: u64) {}
+}
+
+// pin 住一个 block, drop 时 unpin
+pub struct BlockPin<'a, E: BlockEngine + ?Sized> {
+    engine: &'a E,
+    block_id: BlockId,
+}
+
+impl <'a, E: BlockEngine + ?Sized> BlockPin<'a, E> {
+    pub fn new(engine: &'a E, block_id: BlockId) -> Result<Self> {
+        engine.pin(block_id)?;
+        Ok(BlockPin { engine, block_id })
+    }
+
+    pub fn block_id(&self) -> BlockId {
+        self.block_id
+    }
+}
+
+impl <'a, E: BlockEngine + ?Sized> Drop for BlockPin<'a, E> {
+    fn drop(&mut self) {
+        // pin 成功过, unpin 不会因为次数不对失败
+        let _ = self.engine.unpin(self.block_id);
+    }
+}
+
+// 进入 engine 的一个 epoch, drop 时离开
+pub struct EpochGuard<'a, E: BlockEngine + ?Sized> {
+    engine: &'a E,
+    epoch: u64,
+}
+
+impl <'a, E: BlockEngine + ?Sized> EpochGuard<'a, E> {
+    pub fn new(engine: &'a E) -> Result<Self> {
+        let epoch = engine.enter_epoch()?;
+        Ok(EpochGuard { engine, epoch })
+    }
+}
+
+impl <'a, E: BlockEngine + ?Sized> Drop for EpochGuard<'a, E> {
+    fn drop(&mut self) {
+        self.engine.leave_epoch(self.epoch);
+    }
 }
 
 pub struct BlockReadGuard<'a, B> {
-    rwlock_guard: RwLockReadGuard<'a, Block<B>>,
+    pub(crate) rwlock_guard: RwLockReadGuard<'a, Block<B>>,
 }
 
 pub struct BlockWriteGuard<'a, B> {
-    rwlock_guard: RwLockWriteGuard<'a, Block<B>>,
-    write_back: fn(BlockId, &Block<B>) -> () 
+    pub(crate) rwlock_guard: RwLockWriteGuard<'a, Block<B>>,
+    pub(crate) write_back: fn(BlockId, &Block<B>)
 }
 
 pub struct MemoryBlockEngine<B> {
     // 纯内存存储下给每个 block 都上一把 rwlock 会不会开销太大？
     // disk 下内存中的 block cache 数量是固定的
-    blocks: Vec<RwLock<Block<B>>>,
-    next_block_id: AtomicUsize,
-    free_list: Vec<BlockId>
+    blocks: Slots<RwLock<Block<B>>>,
+    free_list: Mutex<Vec<BlockId>>,
+    user_metadata: Vec<u8>,
+    // 最多能有多少个 block, None 表示不限制
+    capacity: Option<usize>,
+    allocations: AtomicU64
+}
+
+const SLOT_CHUNK: usize = 64;
+
+// 分块的数组, 第 k 块放 SLOT_CHUNK << k 个元素, 用到时才分配; 分好的块不会再挪动
+// 长度只增不减, 只要 &self 就能往后加, 别的线程拿着元素的引用时也可以加
+pub(crate) struct Slots<T> {
+    chunks: Vec<OnceLock<Box<[T]>>>,
+    len: AtomicUsize,
+    // 按下标造出还没用过的元素
+    init: fn(usize) -> T,
+}
+
+impl <T> Slots<T> {
+    pub(crate) fn new(len: usize, init: fn(usize) -> T) -> Self {
+        Slots { chunks: (0..usize::BITS).map(|_| OnceLock::new()).collect(), len: AtomicUsize::new(len), init }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    // 长度加一, 返回新元素的下标; 已经有 limit 个时返回 None
+    pub(crate) fn push(&self, limit: Option<usize>) -> Option<usize> {
+        self.len
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |len| limit.is_none_or(|limit| len < limit).then_some(len + 1))
+            .ok()
+    }
+
+    // 长度至少变成 len, 下标小于 len 的都能取到
+    pub(crate) fn grow(&self, len: usize) {
+        self.len.fetch_max(len, Ordering::AcqRel);
+    }
+
+    pub(crate) fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len() {
+            return None;
+        }
+        let n = index + SLOT_CHUNK;
+        let k = (n.ilog2() - SLOT_CHUNK.ilog2()) as usize;
+        let base = (SLOT_CHUNK << k) - SLOT_CHUNK;
+        let chunk = self.chunks[k].get_or_init(|| (base..base + (SLOT_CHUNK << k)).map(self.init).collect());
+        Some(&chunk[index - base])
+    }
 }
 
 impl <B> Deref for Block<B> {
@@ -99,53 +311,205 @@ impl <B> BlockEngine for MemoryBlockEngine<B> {
         // do nothing
     }
     
-    fn alloc_block(&mut self) -> BlockId {
-        let mut block_id: BlockId = 0;
-        if !self.free_list.is_empty() {
-            block_id = self.free_list.pop().unwrap()
-        } else {
-            block_id = self.next_block_id.fetch_add(1, Ordering::SeqCst);
-            self.blocks.push(RwLock::new(Block { valid: false, content: None, id: block_id }));
-        }
+    fn alloc_block(&self) -> Result<BlockId> {
+        let reused = self.free_list.lock().map_err(|_| Error::LockPoisoned)?.pop();
+        let block_id = match reused {
+            Some(block_id) => block_id,
+            None => self.blocks.push(self.capacity).ok_or(Error::StorageFull)?,
+        };
         // make it vaild
-        self.blocks[block_id].write().unwrap().valid = true;
-        block_id
+        self.block(block_id)?.write().map_err(|_| Error::LockPoisoned)?.valid = true;
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        Ok(block_id)
     }
     
-    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<Self::Item>> {
-        if block_id >= self.next_block_id.load(Ordering::SeqCst) {
-            return Err(anyhow!("invaild block id: {}.", block_id))
-        }
-        let anyhow::Result::Ok(read) = self.blocks[block_id].read() else {
-            return Err(anyhow!("failed to aquire read lock."))
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, Self::Item>> {
+        let anyhow::Result::Ok(read) = self.block(block_id)?.read() else {
+            return Err(Error::LockPoisoned.into())
         };
         
         Ok(BlockReadGuard { rwlock_guard: read })
     }
     
-    fn fetch_write(&mut self, block_id: BlockId) -> Result<BlockWriteGuard<Self::Item>> {
-        if block_id >= self.next_block_id.load(Ordering::SeqCst) {
-            return Err(anyhow!("invaild block id: {}.", block_id))
-        }
-        let anyhow::Result::Ok(write) = self.blocks[block_id].write() else {
-            return Err(anyhow!("failed to aquire write lock."))
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, Self::Item>> {
+        let anyhow::Result::Ok(write) = self.block(block_id)?.write() else {
+            return Err(Error::LockPoisoned.into())
         };
 
         Ok(BlockWriteGuard { rwlock_guard: write, write_back: |block_id: BlockId, block: &Block<Self::Item>| Self::write_back(block_id, block) })
     }
-    
-    fn delete(&mut self, block_id: BlockId) -> Result<Option<Self::Item>> {
-        if block_id >= self.next_block_id.load(Ordering::SeqCst) || self.free_list.contains(&block_id) {
-            return Err(anyhow!("invaild block id: {}.", block_id))
-        }
-        self.free_list.push(block_id);
-        Ok(self.blocks[block_id].write().unwrap().content.take())
+
+    fn try_fetch_write(&self, block_id: BlockId) -> Result<Option<BlockWriteGuard<'_, Self::Item>>> {
+        let write = match self.block(block_id)?.try_write() {
+            anyhow::Result::Ok(write) => write,
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Poisoned(_)) => return Err(Error::LockPoisoned.into()),
+        };
+        Ok(Some(BlockWriteGuard { rwlock_guard: write, write_back: |block_id: BlockId, block: &Block<Self::Item>| Self::write_back(block_id, block) }))
     }
     
+    // 回收过的 block 不是 valid 的, 再回收一次是错的
+    fn delete(&self, block_id: BlockId) -> Result<Option<Self::Item>> {
+        let content = {
+            let mut block = self.block(block_id)?.write().map_err(|_| Error::LockPoisoned)?;
+            if !block.valid {
+                return Err(Error::InvalidBlock(block_id).into())
+            }
+            block.valid = false;
+            block.content.take()
+        };
+        self.free_list.lock().map_err(|_| Error::LockPoisoned)?.push(block_id);
+        Ok(content)
+    }
+
+    fn stats(&self) -> BlockEngineStats {
+        BlockEngineStats { allocations: self.allocations.load(Ordering::Relaxed), ..BlockEngineStats::default() }
+    }
+
+    // 没有要提交的东西, 设了就生效
+    fn set_user_metadata(&mut self, bytes: &[u8]) -> Result<()> {
+        self.user_metadata = bytes.to_vec();
+        Ok(())
+    }
+
+    fn user_metadata(&self) -> Result<Vec<u8>> {
+        Ok(self.user_metadata.clone())
+    }
+
+    fn block_usage(&self) -> Option<(usize, Vec<BlockId>)> {
+        Some((self.blocks.len(), self.free_list.lock().ok()?.clone()))
+    }
 }
 
 impl <B> MemoryBlockEngine<B> {
     pub fn new() -> Self {
-        Self { blocks: vec![], next_block_id: AtomicUsize::new(0), free_list: vec![] }
+        Self {
+            blocks: Slots::new(0, |id| RwLock::new(Block { valid: false, content: None, id })),
+            free_list: Mutex::new(vec![]),
+            user_metadata: vec![],
+            capacity: None,
+            allocations: AtomicU64::new(0),
+        }
+    }
+
+    fn block(&self, block_id: BlockId) -> Result<&RwLock<Block<B>>> {
+        Ok(self.blocks.get(block_id).ok_or(Error::InvalidBlock(block_id))?)
+    }
+
+    // 最多分配 capacity 个 block, 用完之后 alloc_block 返回 StorageFull
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { capacity: Some(capacity), ..Self::new() }
+    }
+
+    pub fn set_capacity(&mut self, capacity: Option<usize>) {
+        self.capacity = capacity;
+    }
+}
+
+impl <B> Default for MemoryBlockEngine<B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 放在 Arc 里的 engine 可以 clone 给好几棵树一起用, 比如 split_off 切出来的树和原来的树
+// 要独占的维护操作只在没有别的树拿着它时能做, flush 换 root 时分不清是哪棵树的, 几棵树要一起提交用 TreeGroup
+impl<E: BlockEngine> BlockEngine for Arc<E> {
+    type Item = E::Item;
+
+    fn alloc_block(&self) -> Result<BlockId> {
+        (**self).alloc_block()
     }
+
+    fn alloc_write(&self, item: Self::Item) -> Result<BlockId> {
+        (**self).alloc_write(item)
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, Self::Item>> {
+        (**self).fetch_read(block_id)
+    }
+
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, Self::Item>> {
+        (**self).fetch_write(block_id)
+    }
+
+    fn try_fetch_write(&self, block_id: BlockId) -> Result<Option<BlockWriteGuard<'_, Self::Item>>> {
+        (**self).try_fetch_write(block_id)
+    }
+
+    fn delete(&self, block_id: BlockId) -> Result<Option<Self::Item>> {
+        (**self).delete(block_id)
+    }
+
+    fn write_back(block_id: BlockId, block: &Block<Self::Item>) {
+        E::write_back(block_id, block)
+    }
+
+    fn load_meta(&self) -> Option<TreeMeta> {
+        (**self).load_meta()
+    }
+
+    fn stage_snapshots(&self, snapshots: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        (**self).stage_snapshots(snapshots)
+    }
+
+    fn load_snapshots(&self) -> Result<BTreeMap<String, TreeMeta>> {
+        (**self).load_snapshots()
+    }
+
+    fn flush(&mut self, meta: TreeMeta) -> Result<()> {
+        exclusive(self)?.flush(meta)
+    }
+
+    fn checkpoint(&mut self, meta: TreeMeta) -> Result<()> {
+        exclusive(self)?.checkpoint(meta)
+    }
+
+    fn vacuum(&mut self, meta: TreeMeta) -> Result<usize> {
+        exclusive(self)?.vacuum(meta)
+    }
+
+    fn stats(&self) -> BlockEngineStats {
+        (**self).stats()
+    }
+
+    fn prefetch(&self, block_ids: &[BlockId]) -> Result<usize> {
+        (**self).prefetch(block_ids)
+    }
+
+    fn set_user_metadata(&mut self, bytes: &[u8]) -> Result<()> {
+        exclusive(self)?.set_user_metadata(bytes)
+    }
+
+    fn user_metadata(&self) -> Result<Vec<u8>> {
+        (**self).user_metadata()
+    }
+
+    fn resident(&self) -> Result<Vec<BlockId>> {
+        (**self).resident()
+    }
+
+    fn block_usage(&self) -> Option<(usize, Vec<BlockId>)> {
+        (**self).block_usage()
+    }
+
+    fn pin(&self, block_id: BlockId) -> Result<()> {
+        (**self).pin(block_id)
+    }
+
+    fn unpin(&self, block_id: BlockId) -> Result<()> {
+        (**self).unpin(block_id)
+    }
+
+    fn enter_epoch(&self) -> Result<u64> {
+        (**self).enter_epoch()
+    }
+
+    fn leave_epoch(&self, epoch: u64) {
+        (**self).leave_epoch(epoch)
+    }
+}
+
+fn exclusive<E>(engine: &mut Arc<E>) -> Result<&mut E> {
+    Arc::get_mut(engine).ok_or_else(|| anyhow!("engine is shared with other trees."))
 }
@@ -0,0 +1,500 @@
+use anyhow::{anyhow, Ok, Result};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+use crate::{
+    amplification::record_written,
+    block::{BlockEngine, BlockId},
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 自底向上地用有序数据构建一棵子树, 叶子按 fill_factor 填充
+pub(crate) struct Builder<K, V> {
+    way: usize,
+    // 每个叶子的目标条目数
+    leaf_target: usize,
+    pending: Vec<(K, V)>,
+    // 已经写好的叶子: (最小 key, block id, 条目数)
+    leaves: Vec<(K, BlockId, usize)>,
+    // 分配过的所有 block, 中途失败时用来回收
+    allocated: Vec<BlockId>,
+}
+
+impl<K: Ord + Clone, V: Clone> Builder<K, V> {
+    pub(crate) fn new(way: usize, fill_factor: f64) -> Result<Self> {
+        if way < 2 {
+            return Err(anyhow!("way must be at least 2, got {}.", way));
+        }
+        if !(fill_factor > 0.0 && fill_factor <= 1.0) {
+            return Err(anyhow!("invalid fill factor: {}.", fill_factor));
+        }
+        let leaf_target = ((way as f64 * fill_factor).round() as usize).clamp(min_leaf_keys(way), way);
+        Ok(Builder { way, leaf_target, pending: vec![], leaves: vec![], allocated: vec![] })
+    }
+
+    pub(crate) fn push<E>(&mut self, tree: &mut BPlusTree<K, V, E>, key: K, value: V) -> Result<()>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    {
+        let last = self.pending.last().map(|(k, _)| k).or(self.leaves.last().map(|(k, _, _)| k));
+        if last.is_some_and(|last| !tree.order.lt(last, &key)) {
+            return Err(anyhow!("input of the builder must be strictly ascending."));
+        }
+        self.pending.push((key, value));
+        // 至少留 min 个在手上, 保证最后一个叶子不会太空
+        // 写叶子失败时这个 key 也不算 push 进来了, 调用方可以原样再 push 一次
+        if self.pending.len() == self.leaf_target + min_leaf_keys(self.way) {
+            if let Err(e) = self.emit_leaf(tree, self.leaf_target) {
+                self.pending.pop();
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // 返回新子树的 root, 失败时已经建好的结点都会被回收
+    pub(crate) fn finish<E>(mut self, tree: &mut BPlusTree<K, V, E>) -> Result<BlockId>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    {
+        let ret = self.build(tree);
+        if ret.is_err() {
+            self.abort(tree);
+        }
+        ret
+    }
+
+    // 放弃构建, 回收已经分配的 block
+    pub(crate) fn abort<E>(self, tree: &mut BPlusTree<K, V, E>)
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    {
+        for block_id in self.allocated {
+            let _ = tree.engine.delete(block_id);
+        }
+    }
+
+    fn build<E>(&mut self, tree: &mut BPlusTree<K, V, E>) -> Result<BlockId>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    {
+        if self.pending.is_empty() && self.leaves.is_empty() {
+            let block_id = tree.alloc_node(BPlusTreeNode::new_leaf(self.way))?;
+            self.allocated.push(block_id);
+            return Ok(block_id);
+        }
+        if self.pending.len() > self.way {
+            self.emit_leaf(tree, self.pending.len() / 2)?;
+        }
+        if !self.pending.is_empty() {
+            self.emit_leaf(tree, self.pending.len())?;
+        }
+
+        // 一层一层往上建内部结点
+        let mut level = std::mem::take(&mut self.leaves);
+        while level.len() > 1 {
+            let mut upper = Vec::with_capacity(level.len() / self.way + 1);
+            for (min_key, node) in group_level(self.way, self.leaf_target, level) {
+                let count = node.entry_count();
+                let block_id = tree.alloc_node(node)?;
+                self.allocated.push(block_id);
+                upper.push((min_key, block_id, count));
+            }
+            level = upper;
+        }
+        let root = level.pop().map(|(_, block_id, _)| block_id).ok_or_else(|| anyhow!("builder produced no root."))?;
+        // 新建的结点里 maxes 都是空的, 有 augment 时整棵子树补一遍
+        if tree.augment.is_some() {
+            tree.refresh_maxes(root)?;
+        }
+        Ok(root)
+    }
+
+    // 把 pending 的前 n 条写成一个叶子
+    // 会失败的步骤 (分配, 拿写锁) 都在动 pending 之前, 失败时 pending 和已经写好的叶子都不变
+    fn emit_leaf<E>(&mut self, tree: &mut BPlusTree<K, V, E>, n: usize) -> Result<()>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    {
+        let block_id = tree.alloc_node(BPlusTreeNode::new_leaf(self.way))?;
+        let prev = self.leaves.last().map(|(_, block_id, _)| *block_id);
+        let linked = tree.engine.fetch_write(block_id).and_then(|guard| {
+            if let Some(prev) = prev {
+                if let Some(node) = tree.engine.fetch_write(prev)?.as_mut() {
+                    node.next = Some(block_id);
+                }
+            }
+            Ok(guard)
+        });
+        let mut guard = match linked {
+            Result::Ok(guard) => guard,
+            Err(e) => {
+                let _ = tree.engine.delete(block_id);
+                return Err(e);
+            }
+        };
+        let node = guard.as_mut().ok_or(Error::EmptyBlock(block_id))?;
+        node.prev = prev;
+        (node.keys, node.values) = self.pending.drain(..n).unzip();
+        let min_key = node.keys[0].clone();
+        let count = node.keys.len();
+        drop(guard);
+        self.allocated.push(block_id);
+        self.leaves.push((min_key, block_id, count));
+        Ok(())
+    }
+}
+
+// 把一层的结点 (最小 key, block id, 条目数) 分组, 每组成为上一层的一个内部结点, 返回 (最小 key, 结点)
+fn group_level<K: Ord, V>(way: usize, leaf_target: usize, level: Vec<(K, BlockId, usize)>) -> Vec<(K, BPlusTreeNode<K, V>)> {
+    let mut children = level.into_iter();
+    chunk_sizes(children.len(), leaf_target + 1, way + 1, way / 2 + 1)
+        .into_iter()
+        .filter_map(|size| {
+            let mut node = BPlusTreeNode::new_inner(way);
+            let mut min_key = None;
+            for (key, block_id, count) in children.by_ref().take(size) {
+                if min_key.is_none() {
+                    min_key = Some(key);
+                } else {
+                    node.keys.push(key);
+                }
+                node.pointers.push(block_id);
+                node.counts.push(count);
+                node.maxes.push(None);
+            }
+            min_key.map(|min_key| (min_key, node))
+        })
+        .collect()
+}
+
+// 一层并行分配的结果放进 allocated; 有失败的就回收 allocated 里所有的 block, 返回第一个错误
+#[cfg(feature = "rayon")]
+fn settle<K, E: BlockEngine>(
+    engine: &E,
+    results: Vec<Result<(K, BlockId, usize)>>,
+    allocated: &mut Vec<BlockId>,
+) -> Result<Vec<(K, BlockId, usize)>> {
+    let mut level = Vec::with_capacity(results.len());
+    let mut error = None;
+    for result in results {
+        match result {
+            Result::Ok(entry) => {
+                allocated.push(entry.1);
+                level.push(entry);
+            }
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+    match error {
+        Some(e) => {
+            for block_id in allocated.drain(..) {
+                let _ = engine.delete(block_id);
+            }
+            Err(e)
+        }
+        None => Ok(level),
+    }
+}
+
+pub(crate) fn min_leaf_keys(way: usize) -> usize {
+    way.div_ceil(2)
+}
+
+// 把 len 个元素尽量均匀地分成若干组, 每组大小接近 target 且落在 [min, max] 之间
+pub(crate) fn chunk_sizes(len: usize, target: usize, max: usize, min: usize) -> Vec<usize> {
+    let n = len.div_ceil(target).clamp(len.div_ceil(max), (len / min).max(1));
+    (0..n).map(|i| len / n + usize::from(i < len % n)).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RebuildOptions {
+    pub way: usize,
+    // 叶子的填充率, (0, 1]
+    pub fill_factor: f64,
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 从严格升序的数据自底向上建树, 叶子按 fill_factor 填充, 输入不是升序时返回错误
+    pub fn bulk_load<I>(way: usize, engine: E, fill_factor: f64, iter: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut builder = Builder::new(way, fill_factor)?;
+        let mut tree = BPlusTree::new(way, engine)?;
+        let mut len = 0;
+        for (key, value) in iter {
+            record_written(&mut tree.stats, &tree.entry_size, &key, &value);
+            if let Err(e) = builder.push(&mut tree, key, value) {
+                builder.abort(&mut tree);
+                return Err(e);
+            }
+            len += 1;
+        }
+        let root = builder.finish(&mut tree)?;
+        let empty_root = tree.root;
+        tree.root = root;
+        tree.len = len;
+        tree.free_subtree(empty_root)?;
+        Ok(tree)
+    }
+
+    // 用新的参数把整棵树重新紧凑地建一遍, 建好之后再切换 root
+    // 旧的 root 在切换前一直可读, 已有的 snapshot / 历史版本不受影响
+    // builder 只按 way 和 fill_factor 切分结点, 按字节算时改成把条目按顺序逐条插进新的 root, fill_factor 不起作用
+    pub fn rebuild(&mut self, options: RebuildOptions) -> Result<()> {
+        let mut builder = Builder::new(options.way, options.fill_factor)?;
+        let old_root = self.root;
+        if self.capacity.is_bytes() {
+            self.rebuild_by_insert(options.way)?;
+        } else {
+            if let Err(e) = self.rebuild_feed(&mut builder) {
+                builder.abort(self);
+                return Err(e);
+            }
+            self.root = builder.finish(self)?;
+            self.way = options.way;
+        }
+        // 旧版本还在用的结点留着
+        self.release(old_root)?;
+        self.record_history();
+        Ok(())
+    }
+
+    // 失败时回到旧的 root 和 way; 重新插入不算写, stats 不变
+    fn rebuild_by_insert(&mut self, way: usize) -> Result<()> {
+        let mut leaves = vec![];
+        self.leaf_ids(self.root, &mut leaves)?;
+        let root = self.alloc_node(BPlusTreeNode::new_leaf(way))?;
+        let (old_root, old_way, len, stats) = (std::mem::replace(&mut self.root, root), self.way, self.len, self.stats);
+        self.way = way;
+        self.len = 0;
+        let ret = self.rebuild_insert(leaves);
+        self.stats = stats;
+        if let Err(e) = ret {
+            let root = std::mem::replace(&mut self.root, old_root);
+            (self.way, self.len) = (old_way, len);
+            self.release(root)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn rebuild_insert(&mut self, leaves: Vec<BlockId>) -> Result<()> {
+        for leaf in leaves {
+            let entries: Vec<(K, V)> = {
+                let read = self.engine.fetch_read(leaf)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(leaf))?;
+                node.keys.iter().cloned().zip(node.values.iter().cloned()).collect()
+            };
+            for (key, value) in entries {
+                self.insert_unrecorded(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rebuild_feed(&mut self, builder: &mut Builder<K, V>) -> Result<()> {
+        let mut leaves = vec![];
+        self.leaf_ids(self.root, &mut leaves)?;
+        for leaf in leaves {
+            let entries: Vec<(K, V)> = {
+                let read = self.engine.fetch_read(leaf)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(leaf))?;
+                node.keys.iter().cloned().zip(node.values.iter().cloned()).collect()
+            };
+            for (key, value) in entries {
+                builder.push(self, key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>> + Sync,
+    K: Ord + Clone + Send + Sync,
+    V: Clone + Send + Sync,
+{
+    // bulk_load 的并行版本, 输入要整个放在内存里: 切成叶子后并行地写叶子, 再一层层并行地建上面的内部结点
+    // 叶子的大小和 bulk_load 一样按 fill_factor 定, 切法不完全一样; 输入不是严格升序时返回错误
+    pub fn par_bulk_load(way: usize, engine: E, fill_factor: f64, mut entries: Vec<(K, V)>) -> Result<Self> {
+        let leaf_target = Builder::<K, V>::new(way, fill_factor)?.leaf_target;
+        let mut tree = BPlusTree::new(way, engine)?;
+        if entries.par_windows(2).any(|pair| !tree.order.lt(&pair[0].0, &pair[1].0)) {
+            return Err(anyhow!("input of the builder must be strictly ascending."));
+        }
+        let len = entries.len();
+        if len == 0 {
+            return Ok(tree);
+        }
+        for (key, value) in &entries {
+            record_written(&mut tree.stats, &tree.entry_size, key, value);
+        }
+        let mut chunks = vec![];
+        for size in chunk_sizes(len, leaf_target, way, min_leaf_keys(way)).into_iter().rev() {
+            chunks.push(entries.split_off(entries.len() - size));
+        }
+        chunks.reverse();
+
+        // 新建的树不是持久化模式, 也没有预留的 block, 直接在 engine 上分配和 alloc_node 一样
+        let engine = &tree.engine;
+        let mut allocated = vec![];
+        let leaves = chunks
+            .into_par_iter()
+            .map(|entries| {
+                let mut node = BPlusTreeNode::new_leaf(way);
+                (node.keys, node.values) = entries.into_iter().unzip();
+                let (min_key, count) = (node.keys[0].clone(), node.keys.len());
+                Ok((min_key, engine.alloc_write(node)?, count))
+            })
+            .collect();
+        let leaves = settle(engine, leaves, &mut allocated)?;
+        // 叶子都有了 block id 之后再串起来
+        let linked = leaves.par_iter().enumerate().try_for_each(|(i, (_, block_id, _))| {
+            if let Some(node) = engine.fetch_write(*block_id)?.as_mut() {
+                node.prev = i.checked_sub(1).map(|i| leaves[i].1);
+                node.next = leaves.get(i + 1).map(|(_, block_id, _)| *block_id);
+            }
+            Ok(())
+        });
+        if let Err(e) = linked {
+            for block_id in allocated {
+                let _ = engine.delete(block_id);
+            }
+            return Err(e);
+        }
+        let mut level = leaves;
+        while level.len() > 1 {
+            let upper = group_level(way, leaf_target, level)
+                .into_par_iter()
+                .map(|(min_key, node)| {
+                    let count = node.entry_count();
+                    Ok((min_key, engine.alloc_write(node)?, count))
+                })
+                .collect();
+            level = settle(engine, upper, &mut allocated)?;
+        }
+
+        let empty_root = tree.root;
+        tree.root = level[0].1;
+        tree.len = len;
+        tree.free_subtree(empty_root)?;
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::MemoryBlockEngine,
+        capacity::NodeCapacity,
+        fsck,
+        snapshot::{AsOf, Retention},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_chunk_sizes() {
+        assert_eq!(chunk_sizes(3, 2, 3, 2), vec![3]);
+        assert_eq!(chunk_sizes(10, 4, 5, 2), vec![4, 3, 3]);
+        assert_eq!(chunk_sizes(1, 4, 5, 2), vec![1]);
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let tree = BPlusTree::bulk_load(4, MemoryBlockEngine::new(), 1.0, (0..1000).map(|i| (i, i * 2))).unwrap();
+        assert_eq!(tree.iter().count(), 1000);
+        assert_eq!(tree.search(&500).unwrap(), Some(1000));
+        assert_eq!(tree.range(998..).next_back(), Some((999, 1998)));
+
+        let mut leaves = vec![];
+        tree.leaf_ids(tree.root, &mut leaves).unwrap();
+        assert_eq!(leaves.len(), 250);
+
+        let unsorted = BPlusTree::bulk_load(4, MemoryBlockEngine::new(), 1.0, vec![(2, 0), (1, 0)]);
+        assert!(unsorted.is_err());
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_par_bulk_load() {
+        let entries: Vec<_> = (0..20000).map(|i| (i, i * 2)).collect();
+        let tree = BPlusTree::par_bulk_load(8, MemoryBlockEngine::new(), 0.75, entries).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.len(), 20000);
+        assert!(tree.iter().eq((0..20000).map(|i| (i, i * 2))));
+        assert_eq!(tree.range(19998..).next_back(), Some((19999, 39998)));
+
+        let empty: BPlusTree<i32, i32, _> = BPlusTree::par_bulk_load(8, MemoryBlockEngine::new(), 1.0, vec![]).unwrap();
+        assert!(empty.is_empty());
+        let one = BPlusTree::par_bulk_load(8, MemoryBlockEngine::new(), 1.0, vec![(1, 1)]).unwrap();
+        assert_eq!(one.search(&1).unwrap(), Some(1));
+        assert!(BPlusTree::par_bulk_load(4, MemoryBlockEngine::new(), 1.0, vec![(2, 0), (1, 0)]).is_err());
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let mut tree = BPlusTree::new(2, MemoryBlockEngine::new()).unwrap();
+        for i in (0..200).rev() {
+            tree.insert(i, i.to_string()).unwrap();
+        }
+        let version = tree.freeze();
+        tree.rebuild(RebuildOptions { way: 8, fill_factor: 0.75 }).unwrap();
+        for i in 0..200 {
+            assert_eq!(tree.search(&i).unwrap(), Some(i.to_string()));
+            assert_eq!(tree.search_at(version, &i).unwrap(), Some(i.to_string()));
+        }
+        tree.insert(1000, "x".to_string()).unwrap();
+        assert_eq!(tree.search(&1000).unwrap(), Some("x".to_string()));
+
+        let mut empty: BPlusTree<i32, i32, _> = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        empty.rebuild(RebuildOptions { way: 4, fill_factor: 1.0 }).unwrap();
+        assert_eq!(empty.search(&1).unwrap(), None);
+        assert!(empty.rebuild(RebuildOptions { way: 1, fill_factor: 1.0 }).is_err());
+    }
+
+    #[test]
+    fn test_rebuild_by_bytes() {
+        let capacity = NodeCapacity::bytes(60, |_: &i32, value: Option<&String>| 4 + value.map_or(0, |value| value.len()));
+        let fill = |tree: &mut BPlusTree<i32, String, MemoryBlockEngine<_>>| {
+            for i in 0..200 {
+                tree.insert(i, "v".repeat((i % 10) as usize)).unwrap();
+            }
+        };
+        let mut tree = BPlusTree::with_node_capacity(4, MemoryBlockEngine::new(), capacity.clone()).unwrap();
+        fill(&mut tree);
+        let shape = tree.verify().unwrap();
+        tree.set_retention(Some(Retention::Versions(10)));
+        let (seq, stats) = (tree.seq(), tree.stats());
+        tree.rebuild(RebuildOptions { way: 16, fill_factor: 1.0 }).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.way, 16);
+        assert_eq!((tree.seq(), tree.stats()), (seq + 1, stats));
+        assert!((0..200).all(|i| tree.search(&i).unwrap().is_some_and(|value| value.len() == (i % 10) as usize)));
+        assert_eq!(tree.as_of(AsOf::Seq(seq)).unwrap().iter().count(), 200);
+
+        // 放不下第二份时失败, 树保持原样, 新建的结点都回收了
+        let blocks = shape.inner_nodes + shape.leaves + 3;
+        let mut tree = BPlusTree::with_node_capacity(4, MemoryBlockEngine::with_capacity(blocks), capacity).unwrap();
+        fill(&mut tree);
+        assert!(tree.rebuild(RebuildOptions { way: 16, fill_factor: 1.0 }).is_err());
+        assert_eq!(tree.way, 4);
+        assert_eq!(tree.verify().unwrap(), shape);
+        assert_eq!(tree.len(), 200);
+        let report = fsck::verify_tree(&tree, fsck::VerifyMode::Full).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.reachable + report.free, report.blocks);
+    }
+}
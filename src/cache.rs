@@ -0,0 +1,380 @@
+use anyhow::{Ok, Result};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    block::{Block, BlockEngine, BlockEngineStats, BlockId, BlockReadGuard, BlockWriteGuard, TreeMeta},
+    error::Error,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    // 写直接写到 slow, fast 里的副本作废, 下次读的时候再从 slow 拿
+    WriteThrough,
+    // 写只写 fast, flush 时才把改过的写回 slow
+    WriteBack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheOptions {
+    // fast 里最多放多少个 block
+    pub capacity: usize,
+    pub policy: CachePolicy,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions { capacity: 1024, policy: CachePolicy::WriteBack }
+    }
+}
+
+// 两个 engine 叠起来: slow (磁盘或者对象存储) 里是全部的 block, fast (一般是内存) 里放一部分的副本, 对树是透明的
+// block id 都是 slow 的, fast 里的 block 用 fast 自己分配的 id, 两边的对应记在 entries 里
+// 读的时候 fast 里没有就从 slow 读一份放进去; fast 满了就直接读写 slow, 不换出
+// 换出只在 flush 时做 (要 &mut), 上次 flush 之后没用过的拿掉, 给新的 block 腾地方; WriteThrough 作废的副本也是这时才删
+// 和 TieredBlockEngine 不同, fast 是任意的 engine, 也不看访问次数, 用到就放进去
+// 和 buffer pool 没有关系, slow 自己有没有缓存都可以
+pub struct CachedEngine<F, S> {
+    fast: F,
+    slow: S,
+    // slow 的 id -> fast 的 id
+    entries: Mutex<HashMap<BlockId, BlockId>>,
+    // 上次 flush 之后用过的
+    used: Mutex<HashSet<BlockId>>,
+    // fast 里改过, 还没写回 slow 的, 只有 WriteBack 才有
+    dirty: Mutex<HashSet<BlockId>>,
+    // WriteThrough 时作废的副本在 fast 里的 id, 别的线程可能还在读, flush 时才删
+    stale: Mutex<Vec<BlockId>>,
+    // 在 fast 里找到的次数
+    fast_hits: AtomicU64,
+    options: CacheOptions,
+}
+
+impl<F, S, B> CachedEngine<F, S>
+where
+    F: BlockEngine<Item = B>,
+    S: BlockEngine<Item = B>,
+    B: Clone,
+{
+    // fast 要是空的, 里面原来的 block 不会被用到
+    pub fn new(fast: F, slow: S, options: CacheOptions) -> Self {
+        CachedEngine {
+            fast,
+            slow,
+            entries: Mutex::new(HashMap::new()),
+            used: Mutex::new(HashSet::new()),
+            dirty: Mutex::new(HashSet::new()),
+            stale: Mutex::new(vec![]),
+            fast_hits: AtomicU64::new(0),
+            options,
+        }
+    }
+
+    // fast 里现在有几个 block
+    pub fn cached_len(&self) -> usize {
+        self.entries.lock().map_or(0, |entries| entries.len())
+    }
+
+    pub fn fast_hits(&self) -> u64 {
+        self.fast_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn slow(&self) -> &S {
+        &self.slow
+    }
+
+    // 改过的先写回 slow
+    pub fn into_parts(mut self) -> Result<(F, S)> {
+        self.write_dirty()?;
+        Ok((self.fast, self.slow))
+    }
+
+    // 在 fast 里就返回 fast 的 id, 不在就从 slow 复制一份进去; fast 满了返回 None
+    // 复制完放进 entries 之前一直拿着 slow 的读锁, 写 slow 的要等放进去了才能让副本作废, 不会留下旧的副本
+    // 两个线程同时放进去时留先放的那个
+    fn lookup(&self, block_id: BlockId) -> Result<Option<BlockId>> {
+        self.used.lock().map_err(|_| Error::LockPoisoned)?.insert(block_id);
+        {
+            let entries = self.entries.lock().map_err(|_| Error::LockPoisoned)?;
+            if let Some(&fast_id) = entries.get(&block_id) {
+                self.fast_hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(fast_id));
+            }
+            if entries.len() >= self.options.capacity {
+                return Ok(None);
+            }
+        }
+        let slow = self.slow.fetch_read(block_id)?;
+        let fast_id = self.fast.alloc_block()?;
+        {
+            let mut fast = self.fast.fetch_write(fast_id)?;
+            fast.valid = slow.valid;
+            fast.content = slow.content.clone();
+        }
+        let mut entries = self.entries.lock().map_err(|_| Error::LockPoisoned)?;
+        let existing = entries.get(&block_id).copied();
+        if existing.is_none() {
+            entries.insert(block_id, fast_id);
+        }
+        drop(entries);
+        drop(slow);
+        if let Some(existing) = existing {
+            self.fast.delete(fast_id)?;
+            return Ok(Some(existing));
+        }
+        Ok(Some(fast_id))
+    }
+
+    // 拿着 slow 的写锁时调用, 副本只从 entries 里拿掉, 不等正在读它的线程
+    fn invalidate(&self, block_id: BlockId) -> Result<()> {
+        let fast_id = self.entries.lock().map_err(|_| Error::LockPoisoned)?.remove(&block_id);
+        if let Some(fast_id) = fast_id {
+            self.stale.lock().map_err(|_| Error::LockPoisoned)?.push(fast_id);
+        }
+        Ok(())
+    }
+
+    // fast 里的留着, 只把改过的复制一份写回 slow
+    fn write_dirty(&mut self) -> Result<()> {
+        let dirty = std::mem::take(self.dirty.get_mut().map_err(|_| Error::LockPoisoned)?);
+        let entries = self.entries.get_mut().map_err(|_| Error::LockPoisoned)?;
+        for block_id in dirty {
+            let fast = self.fast.fetch_read(entries[&block_id])?;
+            let mut slow = self.slow.fetch_write(block_id)?;
+            slow.valid = fast.valid;
+            slow.content = fast.content.clone();
+        }
+        Ok(())
+    }
+
+    // 上次 flush 之后没用过的和作废了的从 fast 里拿掉, 改过的已经写回了
+    fn shrink(&mut self) -> Result<()> {
+        for fast_id in std::mem::take(self.stale.get_mut().map_err(|_| Error::LockPoisoned)?) {
+            self.fast.delete(fast_id)?;
+        }
+        let used = std::mem::take(self.used.get_mut().map_err(|_| Error::LockPoisoned)?);
+        let entries = self.entries.get_mut().map_err(|_| Error::LockPoisoned)?;
+        let unused: Vec<_> = entries.keys().copied().filter(|block_id| !used.contains(block_id)).collect();
+        for block_id in unused {
+            let fast_id = entries.remove(&block_id).unwrap();
+            self.fast.delete(fast_id)?;
+        }
+        Ok(())
+    }
+}
+
+impl<F, S, B> BlockEngine for CachedEngine<F, S>
+where
+    F: BlockEngine<Item = B>,
+    S: BlockEngine<Item = B>,
+    B: Clone,
+{
+    type Item = B;
+
+    // 复用的 id 在 delete 时已经从 fast 里拿掉了, 新的 block 等到读写时才放进去
+    fn alloc_block(&self) -> Result<BlockId> {
+        self.slow.alloc_block()
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, B>> {
+        match self.lookup(block_id)? {
+            Some(fast_id) => self.fast.fetch_read(fast_id),
+            None => self.slow.fetch_read(block_id),
+        }
+    }
+
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, B>> {
+        if self.options.policy == CachePolicy::WriteThrough {
+            let guard = self.slow.fetch_write(block_id)?;
+            self.invalidate(block_id)?;
+            return Ok(guard);
+        }
+        match self.lookup(block_id)? {
+            Some(fast_id) => {
+                self.dirty.lock().map_err(|_| Error::LockPoisoned)?.insert(block_id);
+                self.fast.fetch_write(fast_id)
+            }
+            None => self.slow.fetch_write(block_id),
+        }
+    }
+
+    fn try_fetch_write(&self, block_id: BlockId) -> Result<Option<BlockWriteGuard<'_, B>>> {
+        if self.options.policy == CachePolicy::WriteThrough {
+            let guard = self.slow.try_fetch_write(block_id)?;
+            if guard.is_some() {
+                self.invalidate(block_id)?;
+            }
+            return Ok(guard);
+        }
+        match self.lookup(block_id)? {
+            Some(fast_id) => {
+                let guard = self.fast.try_fetch_write(fast_id)?;
+                if guard.is_some() {
+                    self.dirty.lock().map_err(|_| Error::LockPoisoned)?.insert(block_id);
+                }
+                Ok(guard)
+            }
+            None => self.slow.try_fetch_write(block_id),
+        }
+    }
+
+    // WriteBack 时 fast 里的比 slow 的新, 返回 fast 里的
+    fn delete(&self, block_id: BlockId) -> Result<Option<B>> {
+        self.dirty.lock().map_err(|_| Error::LockPoisoned)?.remove(&block_id);
+        self.used.lock().map_err(|_| Error::LockPoisoned)?.remove(&block_id);
+        let fast_id = self.entries.lock().map_err(|_| Error::LockPoisoned)?.remove(&block_id);
+        let fast = match fast_id {
+            Some(fast_id) => self.fast.delete(fast_id)?,
+            None => None,
+        };
+        let slow = self.slow.delete(block_id)?;
+        match fast_id {
+            Some(_) if self.options.policy == CachePolicy::WriteBack => Ok(fast),
+            _ => Ok(slow),
+        }
+    }
+
+    fn write_back(block_id: BlockId, block: &Block<B>) {
+        S::write_back(block_id, block)
+    }
+
+    fn load_meta(&self) -> Option<TreeMeta> {
+        self.slow.load_meta()
+    }
+
+    fn stage_snapshots(&self, snapshots: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        self.slow.stage_snapshots(snapshots)
+    }
+
+    fn load_snapshots(&self) -> Result<BTreeMap<String, TreeMeta>> {
+        self.slow.load_snapshots()
+    }
+
+    fn flush(&mut self, meta: TreeMeta) -> Result<()> {
+        self.write_dirty()?;
+        self.shrink()?;
+        self.slow.flush(meta)
+    }
+
+    fn checkpoint(&mut self, meta: TreeMeta) -> Result<()> {
+        self.write_dirty()?;
+        self.shrink()?;
+        self.slow.checkpoint(meta)
+    }
+
+    // 截掉的只会是空闲的 block, 已经不在 fast 里了
+    fn vacuum(&mut self, meta: TreeMeta) -> Result<usize> {
+        self.write_dirty()?;
+        self.slow.vacuum(meta)
+    }
+
+    // fast 里已经有的不用预读
+    fn prefetch(&self, block_ids: &[BlockId]) -> Result<usize> {
+        let entries = self.entries.lock().map_err(|_| Error::LockPoisoned)?;
+        let slow: Vec<_> = block_ids.iter().copied().filter(|block_id| !entries.contains_key(block_id)).collect();
+        drop(entries);
+        self.slow.prefetch(&slow)
+    }
+
+    fn resident(&self) -> Result<Vec<BlockId>> {
+        let mut resident: Vec<_> = self.entries.lock().map_err(|_| Error::LockPoisoned)?.keys().copied().collect();
+        resident.extend(self.slow.resident()?);
+        resident.sort_unstable();
+        resident.dedup();
+        Ok(resident)
+    }
+
+    fn block_usage(&self) -> Option<(usize, Vec<BlockId>)> {
+        self.slow.block_usage()
+    }
+
+    fn set_user_metadata(&mut self, bytes: &[u8]) -> Result<()> {
+        self.slow.set_user_metadata(bytes)
+    }
+
+    fn user_metadata(&self) -> Result<Vec<u8>> {
+        self.slow.user_metadata()
+    }
+
+    // fast 里的只在 &mut 的时候换出, 只要 pin 住 slow 里的
+    fn pin(&self, block_id: BlockId) -> Result<()> {
+        self.slow.pin(block_id)
+    }
+
+    fn unpin(&self, block_id: BlockId) -> Result<()> {
+        self.slow.unpin(block_id)
+    }
+
+    fn enter_epoch(&self) -> Result<u64> {
+        self.slow.enter_epoch()
+    }
+
+    fn leave_epoch(&self, epoch: u64) {
+        self.slow.leave_epoch(epoch)
+    }
+
+    // fast 命中也算命中, 改过还没写回 slow 的也算 dirty
+    fn stats(&self) -> BlockEngineStats {
+        let slow = self.slow.stats();
+        BlockEngineStats {
+            hits: slow.hits + self.fast_hits.load(Ordering::Relaxed),
+            dirty: slow.dirty + self.dirty.lock().map_or(0, |dirty| dirty.len()),
+            ..slow
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        block::MemoryBlockEngine,
+        order::KeyOrder,
+        tree::{BPlusTree, BPlusTreeNode},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_cached_engine() {
+        for policy in [CachePolicy::WriteBack, CachePolicy::WriteThrough] {
+            let options = CacheOptions { capacity: 16, policy };
+            let engine = CachedEngine::new(
+                MemoryBlockEngine::<BPlusTreeNode<u32, u32>>::new(),
+                MemoryBlockEngine::new(),
+                options,
+            );
+            let mut tree = BPlusTree::new(4, engine).unwrap();
+            let mut expected = BTreeMap::new();
+            for round in 0..10 {
+                for i in round * 100..round * 100 + 100 {
+                    tree.insert(i, i).unwrap();
+                    expected.insert(i, i);
+                }
+                for key in round * 100..round * 100 + 10 {
+                    assert_eq!(tree.search(&key).unwrap(), Some(key));
+                }
+                tree.delete(&(round * 100 + 50)).unwrap();
+                expected.remove(&(round * 100 + 50));
+                assert!(tree.engine.cached_len() <= 16);
+                tree.flush().unwrap();
+            }
+            tree.verify().unwrap();
+            assert!(expected.iter().all(|(key, value)| tree.search(key).unwrap() == Some(*value)));
+            assert!(tree.engine.stats().hits > 0);
+
+            // 改过的都写回了 slow, 只用 slow 也是完整的树
+            let meta = tree.meta();
+            let (_, slow) = tree.engine.into_parts().unwrap();
+            let tree = BPlusTree::from_root(meta.way, slow, meta.root, meta.len, KeyOrder::default());
+            tree.verify().unwrap();
+            assert!(expected.iter().all(|(key, value)| tree.search(key).unwrap() == Some(*value)));
+        }
+    }
+}
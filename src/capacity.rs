@@ -0,0 +1,218 @@
+use anyhow::{anyhow, Ok, Result};
+use std::sync::Arc;
+
+use crate::tree::BPlusTreeNode;
+
+type CellSizeFn<K, V> = dyn Fn(&K, Option<&V>) -> usize + Send + Sync;
+
+// 结点什么时候算满, 默认只看 key 的个数有没有超过 way
+// 按字节算时叶子里的每个 key 连同 value, 内部结点里的每个 key 各算一个 cell, 一个结点的 cell 加起来不能超过 bytes
+// way 仍然是 key 个数的上限; insert / delete / append / split_off 按字节分裂合并, insert_batch 和 rebuild 退回逐条插入 (失败时整批回退)
+pub struct NodeCapacity<K, V> {
+    bytes: Option<(usize, Arc<CellSizeFn<K, V>>)>,
+}
+
+impl<K, V> Clone for NodeCapacity<K, V> {
+    fn clone(&self) -> Self {
+        NodeCapacity { bytes: self.bytes.clone() }
+    }
+}
+
+impl<K, V> Default for NodeCapacity<K, V> {
+    fn default() -> Self {
+        NodeCapacity { bytes: None }
+    }
+}
+
+impl<K: Ord, V> NodeCapacity<K, V> {
+    // cell_size 的第二个参数是叶子里的 value, 内部结点的 cell 传 None
+    pub fn bytes<F>(bytes: usize, cell_size: F) -> Self
+    where
+        F: Fn(&K, Option<&V>) -> usize + Send + Sync + 'static,
+    {
+        NodeCapacity { bytes: Some((bytes, Arc::new(cell_size))) }
+    }
+
+    // 单个 cell 最多占 1/4, 这样分裂出来的两半一定放得下, 合并不下时向兄弟借一个也不会溢出
+    pub fn max_cell(&self) -> Option<usize> {
+        self.bytes.as_ref().map(|(bytes, _)| bytes / 4)
+    }
+
+    pub(crate) fn is_bytes(&self) -> bool {
+        self.bytes.is_some()
+    }
+
+    // 是同一个 capacity clone 出来的, 按它分出来的结点在另一棵树里一定也放得下
+    pub(crate) fn same(&self, other: &Self) -> bool {
+        match (&self.bytes, &other.bytes) {
+            (None, None) => true,
+            (Some((bytes, cell_size)), Some((other_bytes, other_cell_size))) => {
+                bytes == other_bytes && Arc::ptr_eq(cell_size, other_cell_size)
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn check_entry(&self, key: &K, value: &V) -> Result<()> {
+        let Some((bytes, cell_size)) = &self.bytes else {
+            return Ok(());
+        };
+        let size = cell_size(key, Some(value)).max(cell_size(key, None));
+        if size > bytes / 4 {
+            return Err(anyhow!("entry of {} bytes exceeds the limit of {} bytes.", size, bytes / 4));
+        }
+        Ok(())
+    }
+
+    // 结点里所有 cell 的字节数, 只按个数算时是 0
+    pub(crate) fn used(&self, node: &BPlusTreeNode<K, V>) -> usize {
+        let Some((_, cell_size)) = &self.bytes else {
+            return 0;
+        };
+        if node.is_leaf() {
+            node.keys.iter().zip(&node.values).map(|(key, value)| cell_size(key, Some(value))).sum()
+        } else {
+            node.keys.iter().map(|key| cell_size(key, None)).sum()
+        }
+    }
+
+    pub(crate) fn is_overflow(&self, node: &BPlusTreeNode<K, V>) -> bool {
+        node.is_overflow() || self.bytes.as_ref().is_some_and(|(bytes, _)| self.used(node) > *bytes)
+    }
+
+    // 再放进一个 cell 就可能溢出
+    pub(crate) fn is_nearly_full(&self, node: &BPlusTreeNode<K, V>) -> bool {
+        node.keys.len() >= node.way || self.bytes.as_ref().is_some_and(|(bytes, _)| self.used(node) + bytes / 4 > *bytes)
+    }
+
+    // 按字节算时 key 少于 min_keys 并且用了不到 1/4 的字节才算太空
+    pub(crate) fn is_underflow(&self, node: &BPlusTreeNode<K, V>) -> bool {
+        node.keys.len() < node.min_keys() && self.bytes.as_ref().is_none_or(|(bytes, _)| self.used(node) * 4 < *bytes)
+    }
+
+    // verify 检查的下限, 按字节算时太空的结点借用之后可能还是太空, 只要求不是空的
+    pub(crate) fn min_keys(&self, node: &BPlusTreeNode<K, V>) -> usize {
+        if self.is_bytes() {
+            1
+        } else {
+            node.min_keys()
+        }
+    }
+
+    // 太空的孩子向 sibling 借一个还是和它合并, separator 是两者之间的分隔 key
+    // 按字节算时合并之后放得下就合并
+    pub(crate) fn should_borrow(
+        &self,
+        left: &BPlusTreeNode<K, V>,
+        right: &BPlusTreeNode<K, V>,
+        sibling: &BPlusTreeNode<K, V>,
+        separator: &K,
+    ) -> bool {
+        if !self.is_bytes() {
+            return sibling.keys.len() > sibling.min_keys();
+        }
+        !self.can_merge(left, right, separator)
+    }
+
+    // left 和 right 连同内部结点之间的 separator 能不能放进一个结点
+    pub(crate) fn can_merge(&self, left: &BPlusTreeNode<K, V>, right: &BPlusTreeNode<K, V>, separator: &K) -> bool {
+        let mut keys = left.keys.len() + right.keys.len();
+        let mut used = self.used(left) + self.used(right);
+        if !left.is_leaf() {
+            keys += 1;
+            used += self.bytes.as_ref().map_or(0, |(_, cell_size)| cell_size(separator, None));
+        }
+        keys <= left.way && self.bytes.as_ref().is_none_or(|(bytes, _)| used <= *bytes)
+    }
+
+    // 溢出的结点从哪里分开, 叶子的右半边从这里开始, 内部结点这个位置的 key 上移
+    // 按字节算时让两半的字节数尽量接近
+    pub(crate) fn split_point(&self, node: &BPlusTreeNode<K, V>) -> usize {
+        let len = node.keys.len();
+        let Some((_, cell_size)) = &self.bytes else {
+            return len / 2;
+        };
+        let total = self.used(node);
+        let (mut prefix, mut best, mut point) = (0usize, usize::MAX, len / 2);
+        for (i, key) in node.keys.iter().enumerate() {
+            if i > 0 && (prefix * 2).abs_diff(total) < best {
+                best = (prefix * 2).abs_diff(total);
+                point = i;
+            }
+            prefix += cell_size(key, node.values.get(i));
+        }
+        // 两边的 key 都不能超过 way, 也都不能是空的, 内部结点两边至少还要各留一个 key
+        if node.is_leaf() {
+            point.clamp(len.saturating_sub(node.way), node.way).clamp(1, len - 1)
+        } else {
+            point.clamp(len.saturating_sub(node.way + 1), node.way).clamp(1, len - 2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{block::MemoryBlockEngine, tree::BPlusTree};
+
+    use super::*;
+
+    #[test]
+    fn test_node_capacity() {
+        let capacity = NodeCapacity::bytes(80, |key: &String, value: Option<&String>| key.len() + value.map_or(8, String::len));
+        assert_eq!(capacity.max_cell(), Some(20));
+        let mut leaf = BPlusTreeNode::new_leaf(64);
+        for (key, value) in [("a", 10), ("b", 10), ("c", 10), ("d", 10), ("e", 15), ("f", 15)] {
+            leaf.keys.push(key.repeat(value));
+            leaf.values.push(if value > 10 { "v".repeat(5) } else { String::new() });
+        }
+        assert_eq!(capacity.used(&leaf), 80);
+        assert!(!capacity.is_overflow(&leaf) && capacity.is_nearly_full(&leaf));
+        leaf.keys.push("g".repeat(10));
+        leaf.values.push(String::new());
+        // 10 10 10 10 20 20 10, 分成 40 和 50 最平均
+        assert!(capacity.is_overflow(&leaf));
+        assert_eq!(capacity.split_point(&leaf), 4);
+        // key 的个数太少, 但字节数够多
+        assert!(!capacity.is_underflow(&leaf) && NodeCapacity::default().is_underflow(&leaf));
+        assert!(capacity.check_entry(&"x".repeat(20), &"y".repeat(6)).is_err());
+
+        // 只按个数算时和结点自己的判断一样
+        let count = NodeCapacity::<String, String>::default();
+        assert_eq!((count.is_overflow(&leaf), count.split_point(&leaf)), (false, 3));
+    }
+
+    #[test]
+    fn test_tree_by_bytes() {
+        // 分隔 key 的大小差别很大, 借用时换上更大的分隔 key 会让 parent 溢出
+        let capacity = NodeCapacity::bytes(120, |key: &u32, value: Option<&u32>| match value {
+            Some(_) => 10,
+            None if key.is_multiple_of(2) => 30,
+            None => 1,
+        });
+        for persistent in [false, true] {
+            let mut tree = BPlusTree::with_node_capacity(64, MemoryBlockEngine::new(), capacity.clone()).unwrap();
+            let mut expected = BTreeMap::new();
+            for i in 0..2000u32 {
+                if persistent && i % 500 == 0 {
+                    tree.freeze();
+                }
+                tree.insert(i * 7 % 2000, i).unwrap();
+                expected.insert(i * 7 % 2000, i);
+            }
+            // 成段地删, 让一些叶子变得很空而旁边的兄弟还很满
+            for i in 0..2000u32 {
+                let key = i * 13 % 2000;
+                if (key / 10) % 3 != 0 {
+                    assert_eq!(tree.delete(&key).unwrap(), expected.remove(&key));
+                }
+                if i % 100 == 0 {
+                    tree.verify().unwrap();
+                }
+            }
+            tree.verify().unwrap();
+            assert!(tree.iter().eq(expected));
+        }
+    }
+}
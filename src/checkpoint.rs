@@ -0,0 +1,112 @@
+use anyhow::Error;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    block::BlockEngine,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 后台线程, 每隔 interval 拿一次写锁做 checkpoint, 日志不会无限变长
+// 失败时交给 on_error, 下一轮接着做
+pub struct Checkpointer {
+    stop: Sender<()>,
+    checkpoints: Arc<AtomicU64>,
+    handle: JoinHandle<()>,
+}
+
+impl Checkpointer {
+    pub fn spawn<K, V, E, F>(tree: Arc<RwLock<BPlusTree<K, V, E>>>, interval: Duration, mut on_error: F) -> Checkpointer
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>> + Send + Sync + 'static,
+        K: Ord + Clone + Send + Sync + 'static,
+        V: Clone + Send + Sync + 'static,
+        F: FnMut(Error) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let checkpoints = Arc::new(AtomicU64::new(0));
+        let handle = {
+            let checkpoints = checkpoints.clone();
+            thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let Result::Ok(mut tree) = tree.write() else {
+                        return;
+                    };
+                    match tree.checkpoint() {
+                        Result::Ok(()) => {
+                            checkpoints.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) => on_error(e),
+                    }
+                }
+            })
+        };
+        Checkpointer { stop, checkpoints, handle }
+    }
+
+    // 已经成功做了多少次
+    pub fn checkpoints(&self) -> u64 {
+        self.checkpoints.load(Ordering::Relaxed)
+    }
+
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.join();
+    }
+}
+
+#[cfg(all(test, feature = "file"))]
+mod tests {
+    use std::time::Instant;
+
+    use crate::file::{FileBlockEngine, FileOptions};
+
+    use super::*;
+
+    #[test]
+    fn test_checkpointer() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-checkpoint-{}.db", std::process::id()));
+        let wal = std::env::temp_dir().join(format!("bplus-tree-checkpoint-{}.db.wal", std::process::id()));
+        let options = FileOptions { page_size: 512, pool_size: 8, wal: true, ..FileOptions::default() };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap();
+        let mut tree = BPlusTree::new(8, engine).unwrap();
+        for i in 0..300 {
+            tree.insert(i, i).unwrap();
+        }
+        // flush 只提交日志, checkpoint 之后日志才清空
+        tree.flush().unwrap();
+        assert!(std::fs::metadata(&wal).unwrap().len() > 0);
+        tree.checkpoint().unwrap();
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+
+        let tree = Arc::new(RwLock::new(tree));
+        let checkpointer = Checkpointer::spawn(tree.clone(), Duration::from_millis(10), |e| panic!("{}", e));
+        for i in 300..600 {
+            tree.write().unwrap().insert(i, i).unwrap();
+        }
+        // 等插入完之后的一次
+        let (done, start) = (checkpointer.checkpoints(), Instant::now());
+        while checkpointer.checkpoints() <= done && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(checkpointer.checkpoints() > done);
+        checkpointer.stop();
+        drop(tree);
+
+        // 最后一次 checkpoint 之后没有修改, 日志是空的, 文件自己就是完整的
+        assert_eq!(std::fs::metadata(&wal).unwrap().len(), 0);
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        let tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert!(tree.iter().eq((0..600).map(|i| (i, i))));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&wal).unwrap();
+    }
+}
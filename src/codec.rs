@@ -0,0 +1,288 @@
+use anyhow::{anyhow, Ok, Result};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{block::BlockId, tree::BPlusTreeNode};
+
+// 把 block 编码成字节, 给存在磁盘上的 engine 用
+pub trait NodeCodec<B> {
+    // 编码结果追加到 buf 后面
+    fn encode(&self, block: &B, buf: &mut Vec<u8>) -> Result<()>;
+    fn decode(&self, bytes: &[u8]) -> Result<B>;
+}
+
+// 单独编码一个 value, 配合 CompactWith 把叶子里的 value 交给别的格式, 比如 protobuf
+pub trait ValueCodec<V> {
+    // 编码结果追加到 buf 后面
+    fn encode_value(&self, value: &V, buf: &mut Vec<u8>) -> Result<()>;
+    fn decode_value(&self, bytes: &[u8]) -> Result<V>;
+}
+
+// 默认的编码, 任何实现了 serde 的类型直接用 bincode
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl<B> NodeCodec<B> for BincodeCodec
+where
+    B: Serialize + DeserializeOwned,
+{
+    fn encode(&self, block: &B, buf: &mut Vec<u8>) -> Result<()> {
+        bincode::serialize_into(buf, block)?;
+        Ok(())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<B> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+const LEAF: u8 = 1;
+const HAS_PREV: u8 = 2;
+const HAS_NEXT: u8 = 4;
+const HAS_HIGH_KEY: u8 = 8;
+const HAS_MAXES: u8 = 16;
+
+// BPlusTreeNode 专用的紧凑格式, 整数都用 varint:
+// 1 字节 flag (是否叶子, 有没有 prev / next / high key / maxes), way, [prev], [next], [high key],
+// keys, 叶子接着是 values, 内部结点接着是 pointers, counts 和 [maxes], 每个数组前面是长度; 没有 augment 的树不存 maxes
+// key 和 value 用 varint 模式的 bincode 逐个编码
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompactCodec;
+
+impl<K, V> NodeCodec<BPlusTreeNode<K, V>> for CompactCodec
+where
+    K: Ord + Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    fn encode(&self, node: &BPlusTreeNode<K, V>, buf: &mut Vec<u8>) -> Result<()> {
+        encode_compact(node, buf, put_items)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<BPlusTreeNode<K, V>> {
+        decode_compact(bytes, get_items)
+    }
+}
+
+// CompactCodec 的格式, 只是叶子里的 value 交给 VC 编码, 每个 value 前面是它的长度; key 还是 bincode
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompactWith<VC>(pub VC);
+
+impl<K, V, VC> NodeCodec<BPlusTreeNode<K, V>> for CompactWith<VC>
+where
+    K: Ord + Serialize + DeserializeOwned,
+    VC: ValueCodec<V>,
+{
+    fn encode(&self, node: &BPlusTreeNode<K, V>, buf: &mut Vec<u8>) -> Result<()> {
+        encode_compact(node, buf, |buf, values| {
+            put_varint(buf, values.len() as u64);
+            let mut value_buf = vec![];
+            for value in values {
+                value_buf.clear();
+                self.0.encode_value(value, &mut value_buf)?;
+                put_varint(buf, value_buf.len() as u64);
+                buf.extend_from_slice(&value_buf);
+            }
+            Ok(())
+        })
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<BPlusTreeNode<K, V>> {
+        decode_compact(bytes, |bytes| {
+            let mut values = vec![];
+            for _ in 0..get_varint(bytes)? {
+                let len = get_varint(bytes)? as usize;
+                if len > bytes.len() {
+                    return Err(anyhow!("node is truncated."));
+                }
+                let (value, rest) = bytes.split_at(len);
+                *bytes = rest;
+                values.push(self.0.decode_value(value)?);
+            }
+            Ok(values)
+        })
+    }
+}
+
+// put_values 写叶子里的 values, 前面的 key 和后面内部结点的字段两种格式一样
+fn encode_compact<K, V, F>(node: &BPlusTreeNode<K, V>, buf: &mut Vec<u8>, put_values: F) -> Result<()>
+where
+    K: Ord + Serialize,
+    F: FnOnce(&mut Vec<u8>, &[V]) -> Result<()>,
+{
+    let mut flag = 0;
+    if node.is_leaf {
+        flag |= LEAF;
+    }
+    if node.prev.is_some() {
+        flag |= HAS_PREV;
+    }
+    if node.next.is_some() {
+        flag |= HAS_NEXT;
+    }
+    if node.high_key.is_some() {
+        flag |= HAS_HIGH_KEY;
+    }
+    let has_maxes = node.maxes.iter().any(Option::is_some);
+    if has_maxes {
+        flag |= HAS_MAXES;
+    }
+    buf.push(flag);
+    put_varint(buf, node.way as u64);
+    for block_id in node.prev.iter().chain(&node.next) {
+        put_varint(buf, *block_id as u64);
+    }
+    if let Some(high_key) = &node.high_key {
+        bincode::DefaultOptions::new().serialize_into(&mut *buf, high_key)?;
+    }
+    put_items(buf, &node.keys)?;
+    if node.is_leaf {
+        put_values(buf, &node.values)?;
+    } else {
+        put_varint(buf, node.pointers.len() as u64);
+        for &block_id in &node.pointers {
+            put_varint(buf, block_id as u64);
+        }
+        put_varint(buf, node.counts.len() as u64);
+        for &count in &node.counts {
+            put_varint(buf, count as u64);
+        }
+        if has_maxes {
+            put_items(buf, &node.maxes)?;
+        }
+    }
+    Ok(())
+}
+
+fn decode_compact<K, V, F>(mut bytes: &[u8], get_values: F) -> Result<BPlusTreeNode<K, V>>
+where
+    K: Ord + DeserializeOwned,
+    F: FnOnce(&mut &[u8]) -> Result<Vec<V>>,
+{
+    let bytes = &mut bytes;
+    let flag = *bytes.first().ok_or_else(|| anyhow!("node is empty."))?;
+    *bytes = &bytes[1..];
+    let is_leaf = flag & LEAF != 0;
+    let way = get_varint(bytes)? as usize;
+    let prev = if flag & HAS_PREV != 0 { Some(get_varint(bytes)? as BlockId) } else { None };
+    let next = if flag & HAS_NEXT != 0 { Some(get_varint(bytes)? as BlockId) } else { None };
+    let high_key = match flag & HAS_HIGH_KEY != 0 {
+        true => Some(bincode::DefaultOptions::new().deserialize_from(&mut *bytes)?),
+        false => None,
+    };
+    let keys = get_items(bytes)?;
+    let (values, pointers, counts, maxes) = if is_leaf {
+        (get_values(bytes)?, vec![], vec![], vec![])
+    } else {
+        let pointers: Vec<_> = (0..get_varint(bytes)?).map(|_| get_varint(bytes).map(|id| id as BlockId)).collect::<Result<_>>()?;
+        let counts = (0..get_varint(bytes)?).map(|_| get_varint(bytes).map(|count| count as usize)).collect::<Result<_>>()?;
+        let maxes = match flag & HAS_MAXES != 0 {
+            true => get_items(bytes)?,
+            false => pointers.iter().map(|_| None).collect(),
+        };
+        (vec![], pointers, counts, maxes)
+    };
+    if !bytes.is_empty() {
+        return Err(anyhow!("node has {} trailing bytes.", bytes.len()));
+    }
+    Ok(BPlusTreeNode { way, is_leaf, keys, values, prev, next, high_key, pointers, counts, maxes })
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn get_varint(bytes: &mut &[u8]) -> Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or_else(|| anyhow!("node is truncated."))?;
+        *bytes = rest;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(anyhow!("varint is too long."))
+}
+
+fn put_items<T: Serialize>(buf: &mut Vec<u8>, items: &[T]) -> Result<()> {
+    put_varint(buf, items.len() as u64);
+    for item in items {
+        bincode::DefaultOptions::new().serialize_into(&mut *buf, item)?;
+    }
+    Ok(())
+}
+
+fn get_items<T: DeserializeOwned>(bytes: &mut &[u8]) -> Result<Vec<T>> {
+    // 长度来自文件, 不能拿来预分配
+    (0..get_varint(bytes)?).map(|_| Ok(bincode::DefaultOptions::new().deserialize_from(&mut *bytes)?)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        file::{FileBlockEngine, FileOptions},
+        tree::BPlusTree,
+    };
+
+    use super::*;
+
+    fn round_trip<C: NodeCodec<BPlusTreeNode<u32, String>>>(codec: C, node: &BPlusTreeNode<u32, String>) -> usize {
+        let mut buf = vec![];
+        codec.encode(node, &mut buf).unwrap();
+        let decoded = codec.decode(&buf).unwrap();
+        assert_eq!((decoded.way, decoded.is_leaf, decoded.prev, decoded.next), (node.way, node.is_leaf, node.prev, node.next));
+        assert_eq!(decoded.high_key, node.high_key);
+        assert_eq!((&decoded.keys, &decoded.values), (&node.keys, &node.values));
+        assert_eq!((&decoded.pointers, &decoded.counts, &decoded.maxes), (&node.pointers, &node.counts, &node.maxes));
+        buf.len()
+    }
+
+    #[test]
+    fn test_codecs() {
+        let mut leaf = BPlusTreeNode::new_leaf(64);
+        leaf.keys = (0..64).map(|i| i * 1000).collect();
+        leaf.values = (0..64).map(|i| format!("v{}", i)).collect();
+        leaf.next = Some(300);
+        let mut inner = BPlusTreeNode::<u32, String>::new_leaf(64);
+        inner.is_leaf = false;
+        inner.keys = (1..64).map(|i| i * 1000).collect();
+        inner.pointers = (0..64).collect();
+        inner.counts = vec![40; 64];
+        inner.maxes = (0..64).map(|i| Some(i * 1000 + 999)).collect();
+        inner.next = Some(7);
+        inner.high_key = Some(64000);
+
+        for node in [&leaf, &inner] {
+            let compact = round_trip(CompactCodec, node);
+            let bincode = round_trip(BincodeCodec, node);
+            assert!(compact * 2 < bincode, "{} vs {}", compact, bincode);
+        }
+
+        // 截断或者多出来的字节都要报错
+        let mut buf = vec![];
+        CompactCodec.encode(&leaf, &mut buf).unwrap();
+        let codec: &dyn NodeCodec<BPlusTreeNode<u32, String>> = &CompactCodec;
+        assert!(codec.decode(&buf[..buf.len() - 1]).is_err());
+        buf.push(0);
+        assert!(codec.decode(&buf).is_err());
+
+        // 同样的页大小, 紧凑格式能放下更大的结点
+        let path = std::env::temp_dir().join(format!("bplus-tree-codec-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, ..FileOptions::default() };
+        let engine = FileBlockEngine::create_with_codec(&path, options, CompactCodec).unwrap();
+        let mut tree = BPlusTree::new(64, engine).unwrap();
+        for i in 0..1000u32 {
+            tree.insert(i, i.to_string()).unwrap();
+        }
+        tree.flush().unwrap();
+        let engine = FileBlockEngine::open_with_codec(&path, options, CompactCodec).unwrap();
+        let tree: BPlusTree<u32, String, _> = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.search(&999).unwrap(), Some("999".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
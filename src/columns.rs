@@ -0,0 +1,125 @@
+use anyhow::{anyhow, Ok, Result};
+use arrow_array::{
+    Array, ArrayRef, BinaryArray, BooleanArray, Float32Array, Float64Array, Int32Array, Int64Array, StringArray,
+    UInt32Array, UInt64Array,
+};
+use arrow_schema::{DataType, Field};
+use std::sync::Arc;
+
+// 能存成 arrow 里一列的类型, Option<T> 是可以为 null 的列
+pub trait ArrowColumn: Sized {
+    fn data_type() -> DataType;
+    fn nullable() -> bool;
+    // 类型不对或者不能为 null 的列里有 null 时返回错误
+    fn read(array: &dyn Array, row: usize) -> Result<Self>;
+    fn array(values: Vec<Self>) -> ArrayRef;
+}
+
+macro_rules! arrow_column {
+    ($ty:ty, $array:ty, $data_type:expr) => {
+        impl ArrowColumn for $ty {
+            fn data_type() -> DataType {
+                $data_type
+            }
+
+            fn nullable() -> bool {
+                false
+            }
+
+            fn read(array: &dyn Array, row: usize) -> Result<Self> {
+                match <Option<$ty> as ArrowColumn>::read(array, row)? {
+                    Some(value) => Ok(value),
+                    None => Err(anyhow!("unexpected null in row {}.", row)),
+                }
+            }
+
+            fn array(values: Vec<Self>) -> ArrayRef {
+                Arc::new(values.into_iter().map(Some).collect::<$array>())
+            }
+        }
+
+        impl ArrowColumn for Option<$ty> {
+            fn data_type() -> DataType {
+                $data_type
+            }
+
+            fn nullable() -> bool {
+                true
+            }
+
+            fn read(array: &dyn Array, row: usize) -> Result<Self> {
+                let array = array.as_any().downcast_ref::<$array>().ok_or_else(|| {
+                    anyhow!("expected a column of {}, got {}.", $data_type, array.data_type())
+                })?;
+                Ok((!array.is_null(row)).then(|| array.value(row).to_owned()))
+            }
+
+            fn array(values: Vec<Self>) -> ArrayRef {
+                Arc::new(values.into_iter().collect::<$array>())
+            }
+        }
+    };
+}
+
+arrow_column!(bool, BooleanArray, DataType::Boolean);
+arrow_column!(i32, Int32Array, DataType::Int32);
+arrow_column!(i64, Int64Array, DataType::Int64);
+arrow_column!(u32, UInt32Array, DataType::UInt32);
+arrow_column!(u64, UInt64Array, DataType::UInt64);
+arrow_column!(f32, Float32Array, DataType::Float32);
+arrow_column!(f64, Float64Array, DataType::Float64);
+arrow_column!(String, StringArray, DataType::Utf8);
+arrow_column!(Vec<u8>, BinaryArray, DataType::Binary);
+
+// value 对应的几列: 单独一个 ArrowColumn 是一列, 元组里每个元素一列
+pub trait ArrowRow: Sized {
+    const WIDTH: usize;
+    fn fields(names: &[&str]) -> Vec<Field>;
+    fn read(columns: &[&dyn Array], row: usize) -> Result<Self>;
+    fn arrays(rows: Vec<Self>) -> Vec<ArrayRef>;
+}
+
+impl<T: ArrowColumn> ArrowRow for T {
+    const WIDTH: usize = 1;
+
+    fn fields(names: &[&str]) -> Vec<Field> {
+        vec![Field::new(names[0], T::data_type(), T::nullable())]
+    }
+
+    fn read(columns: &[&dyn Array], row: usize) -> Result<Self> {
+        <T as ArrowColumn>::read(columns[0], row)
+    }
+
+    fn arrays(rows: Vec<Self>) -> Vec<ArrayRef> {
+        vec![T::array(rows)]
+    }
+}
+
+macro_rules! arrow_row {
+    ($width:expr; $($ty:ident $i:tt),+) => {
+        impl<$($ty: ArrowColumn),+> ArrowRow for ($($ty,)+) {
+            const WIDTH: usize = $width;
+
+            fn fields(names: &[&str]) -> Vec<Field> {
+                vec![$(Field::new(names[$i], $ty::data_type(), $ty::nullable())),+]
+            }
+
+            fn read(columns: &[&dyn Array], row: usize) -> Result<Self> {
+                Ok(($(<$ty as ArrowColumn>::read(columns[$i], row)?,)+))
+            }
+
+            #[allow(non_snake_case)]
+            fn arrays(rows: Vec<Self>) -> Vec<ArrayRef> {
+                let ($(mut $ty,)+) = ($(Vec::<$ty>::with_capacity(rows.len()),)+);
+                for row in rows {
+                    $($ty.push(row.$i);)+
+                }
+                vec![$(<$ty as ArrowColumn>::array($ty)),+]
+            }
+        }
+    };
+}
+
+arrow_row!(2; A 0, B 1);
+arrow_row!(3; A 0, B 1, C 2);
+arrow_row!(4; A 0, B 1, C 2, D 3);
@@ -0,0 +1,106 @@
+use anyhow::{anyhow, Ok, Result};
+
+// 页内容的压缩方式, 每页的标记里记着自己用的哪一种, 所以换了设置之后新旧页可以混在一起
+// 压缩之后结点可以比页大, way 可以按压缩后的大小来定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "lz4")]
+    Lz4,
+    // 压缩级别, 和 zstd 命令行的一样
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+const NONE: u8 = 0;
+const LZ4: u8 = 1;
+const ZSTD: u8 = 2;
+
+impl Compression {
+    // 返回写进页标记的压缩方式和压缩后的内容, 压缩之后没有变小就原样存
+    pub(crate) fn compress(self, data: Vec<u8>) -> Result<(u8, Vec<u8>)> {
+        let compressed: Option<(u8, Vec<u8>)> = match self {
+            Compression::None => None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some((LZ4, lz4_flex::compress_prepend_size(&data))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Some((ZSTD, zstd::bulk::compress(&data, level)?)),
+        };
+        match compressed {
+            Some((id, compressed)) if compressed.len() < data.len() => Ok((id, compressed)),
+            _ => Ok((NONE, data)),
+        }
+    }
+}
+
+pub(crate) fn decompress(id: u8, data: &[u8]) -> Result<Vec<u8>> {
+    match id {
+        NONE => Ok(data.to_vec()),
+        #[cfg(feature = "lz4")]
+        LZ4 => Ok(lz4_flex::decompress_size_prepended(data)?),
+        #[cfg(feature = "zstd")]
+        ZSTD => Ok(zstd::stream::decode_all(data)?),
+        #[cfg(not(feature = "lz4"))]
+        LZ4 => Err(anyhow!("page is compressed with lz4 but the lz4 feature is disabled.")),
+        #[cfg(not(feature = "zstd"))]
+        ZSTD => Err(anyhow!("page is compressed with zstd but the zstd feature is disabled.")),
+        id => Err(anyhow!("unknown compression {}.", id)),
+    }
+}
+
+#[cfg(all(test, feature = "lz4", feature = "zstd"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressed_pages() {
+        use crate::{
+            file::{FileBlockEngine, FileOptions},
+            tree::{BPlusTree, BPlusTreeNode},
+        };
+
+        let value = |i: u32| format!(r#"{{"id":{},"name":"user","email":"user@example.com","active":true}}"#, i);
+        let path = std::env::temp_dir().join(format!("bplus-tree-compress-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 1024, ..FileOptions::default() };
+        let open = |compression, create| {
+            let options = FileOptions { compression, ..options };
+            if create {
+                let engine = FileBlockEngine::<BPlusTreeNode<u32, String>>::create(&path, options).unwrap();
+                BPlusTree::new(64, engine).unwrap()
+            } else {
+                BPlusTree::open(FileBlockEngine::open(&path, options).unwrap()).unwrap()
+            }
+        };
+
+        // 64 个 value 的叶子不压缩放不进 1K 的页
+        let mut tree = open(Compression::None, true);
+        for i in 0..500 {
+            tree.insert(i, value(i)).unwrap();
+        }
+        assert!(tree.flush().is_err());
+
+        let mut tree = open(Compression::Lz4, true);
+        for i in 0..500 {
+            tree.insert(i, value(i)).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        // 用 zstd 接着写, lz4 的页照样能读
+        let mut tree = open(Compression::Zstd(3), false);
+        for i in 500..1000 {
+            tree.insert(i, value(i)).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        let tree = open(Compression::None, false);
+        assert_eq!(tree.verify().unwrap().entries, 1000);
+        assert!(tree.iter().eq((0..1000).map(|i| (i, value(i)))));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(Compression::Lz4.compress(vec![1, 2, 3]).unwrap(), (NONE, vec![1, 2, 3]));
+        assert!(decompress(7, &[]).is_err());
+    }
+}
@@ -0,0 +1,1099 @@
+use anyhow::{anyhow, Ok, Result};
+use std::{
+    ops::{Bound, ControlFlow, RangeBounds},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    block::{BlockEngine, BlockId, BlockReadGuard, BlockWriteGuard, EpochGuard, Slots, TreeMeta},
+    error::Error,
+    latch::{LatchCounters, LatchStats},
+    order::KeyOrder,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatchOptions {
+    // 一次写操作最多等多久锁, 超过时返回 Error::LockTimeout; None 时一直重来
+    pub timeout: Option<Duration>,
+    // 第一次睡多久, 之后每次翻倍
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    // 记下 latch_stats, 每次拿锁都要改几个共享的计数, 默认不记
+    pub stats: bool,
+}
+
+impl Default for LatchOptions {
+    fn default() -> Self {
+        LatchOptions {
+            timeout: Some(Duration::from_secs(10)),
+            backoff: Duration::from_micros(10),
+            max_backoff: Duration::from_millis(1),
+            stats: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Root,
+    Inner,
+    Leaf,
+}
+
+// 可以放在 Arc 里给多个线程用的树, 读者和写者都可以并发
+// 结点是 B-link 的: 每层的结点都有 high key 和指向右边的链接, 分裂后走到左半边的线程顺着链接往右走, 分裂不用通知读者
+// 读者先乐观地往下走, 每个结点只在读它时拿一下锁, 靠结点的版本号发现借用和合并, 冲突多了退回到锁耦合:
+// 从 root 往下一层层拿读锁, 拿到孩子的锁之后才放开父结点的
+// 写者也先乐观地找到叶子, 只锁叶子; 叶子可能分裂或者合并时再从 root 一层层拿写锁,
+// 走到安全的结点 (插入不会分裂, 删除不会合并) 时放开上面的锁, 不相干的子树上的写者互不阻塞
+// 写者拿写锁都不等, 拿不到时放开手里所有的锁, 按 LatchOptions 等一会儿从头再来, 不会有两个写者互相等着对方的锁
+// engine 的 try_fetch_write 也会等的话 (用 buffer pool 的 engine) 超时只是尽力而为
+// 每次操作都在 engine 的一个 epoch 里, 用 EpochBlockEngine 时读者手里的 block id 在操作结束前不会被复用
+// 写的时候不维护 counts 和叶子的 prev, 那样每次都要锁住整条路径; flush 和 into_inner 时重新算
+// 打开 LatchOptions::stats 之后按 root / 内部结点 / 叶子分开记下拿锁, 抢锁的次数和等了多久, 见 latch_stats
+// 持久化模式和按字节算的结点还不支持
+pub struct ConcurrentBPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    way: usize,
+    engine: E,
+    // 写者从 root 往下走时先拿写锁, 确定 root 不会变了再放开; 读者只在拿到 root 结点的读锁之前拿着读锁
+    root: RwLock<BlockId>,
+    order: KeyOrder<K>,
+    len: AtomicUsize,
+    // 按 block id 存的版本号, 写者借用或者合并期间是奇数, 改完加一; 不写到存储上
+    versions: Slots<AtomicU64>,
+    latch: LatchOptions,
+    // 有几层, 只用来把锁分到 latch_stats 的哪一层里, 和 root 一起变, 别的线程看到的可能差一点
+    height: AtomicUsize,
+    // 按 Level 的顺序
+    counters: [LatchCounters; 3],
+}
+
+// 乐观读最多重来几次
+const OPTIMISTIC_RETRIES: usize = 4;
+
+// 拿不到锁时先只让出 cpu 重来几次, 之后才开始睡
+const LATCH_SPINS: usize = 4;
+
+// 乐观地走到的叶子, 走到它之前的那个结点 (父结点或者左边的兄弟) 和那时的版本号, 在叶子上读到的东西
+type OptimisticRead<R> = (BlockId, Option<(BlockId, u64)>, R);
+
+// 写者从上往下拿着的锁, root 也可能变时还拿着 root 的锁, nodes[i] 是 ids[i] 的
+struct Latches<'a, K: Ord, V> {
+    root: Option<RwLockWriteGuard<'a, BlockId>>,
+    nodes: Vec<BlockWriteGuard<'a, BPlusTreeNode<K, V>>>,
+    ids: Vec<BlockId>,
+    // 确定要改的结点的版本号
+    marks: Vec<Mark<'a>>,
+    // 删除时 siblings[i] 是 nodes[i + 1] 的兄弟, 借用或者合并时用
+    siblings: Vec<(BlockId, BlockWriteGuard<'a, BPlusTreeNode<K, V>>)>,
+}
+
+// 拿着时版本号是奇数, drop 时加一变回偶数
+struct Mark<'a>(&'a AtomicU64);
+
+impl<'a> Mark<'a> {
+    fn new(version: &'a AtomicU64) -> Self {
+        version.fetch_add(1, Ordering::SeqCst);
+        Mark(version)
+    }
+}
+
+impl Drop for Mark<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// 一次写操作里拿不到锁之后的等待, 过了 timeout 返回 Error::LockTimeout
+// 等的时间记在最近一次拿不到的锁所在的层上, 操作结束时每层记一次
+struct Backoff<'a> {
+    options: &'a LatchOptions,
+    counters: &'a [LatchCounters; 3],
+    start: Instant,
+    retries: usize,
+    sleep: Duration,
+    blocked: Level,
+    waited: [Duration; 3],
+}
+
+impl<'a> Backoff<'a> {
+    fn new(options: &'a LatchOptions, counters: &'a [LatchCounters; 3]) -> Self {
+        Backoff {
+            options,
+            counters,
+            start: Instant::now(),
+            retries: 0,
+            sleep: options.backoff,
+            blocked: Level::Root,
+            waited: [Duration::ZERO; 3],
+        }
+    }
+
+    fn block(&mut self, level: Level) {
+        self.blocked = level;
+    }
+
+    // 调用之前要放开所有的锁
+    fn wait(&mut self) -> Result<()> {
+        if self.options.timeout.is_some_and(|timeout| self.start.elapsed() >= timeout) {
+            return Err(Error::LockTimeout.into());
+        }
+        let start = Instant::now();
+        self.retries += 1;
+        if self.retries <= LATCH_SPINS {
+            thread::yield_now();
+        } else {
+            thread::sleep(self.sleep);
+            self.sleep = (self.sleep * 2).min(self.options.max_backoff);
+        }
+        self.waited[self.blocked as usize] += start.elapsed();
+        Ok(())
+    }
+}
+
+impl Drop for Backoff<'_> {
+    fn drop(&mut self) {
+        if !self.options.stats {
+            return;
+        }
+        for (counters, &waited) in self.counters.iter().zip(&self.waited) {
+            if !waited.is_zero() {
+                counters.record_wait(waited);
+            }
+        }
+    }
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 顺便算好每个结点的 high key 和右链接
+    pub fn into_concurrent(self) -> Result<ConcurrentBPlusTree<K, V, E>> {
+        if self.persistent {
+            return Err(anyhow!("persistent tree can not be shared between threads."));
+        }
+        if self.capacity.is_bytes() {
+            return Err(anyhow!("tree with byte capacity can not be shared between threads."));
+        }
+        // 并发写的时候没法沿路径维护 maxes
+        if self.augment.is_some() {
+            return Err(anyhow!("tree with an augment can not be shared between threads."));
+        }
+        let mut tree = ConcurrentBPlusTree {
+            way: self.way,
+            engine: self.engine,
+            root: RwLock::new(self.root),
+            order: self.order,
+            len: AtomicUsize::new(self.len),
+            versions: Slots::new(0, |_| AtomicU64::new(0)),
+            latch: LatchOptions::default(),
+            height: AtomicUsize::new(0),
+            counters: Default::default(),
+        };
+        tree.repair()?;
+        Ok(tree)
+    }
+}
+
+impl<K, V, E> ConcurrentBPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn new(way: usize, engine: E) -> Result<ConcurrentBPlusTree<K, V, E>> {
+        BPlusTree::new(way, engine)?.into_concurrent()
+    }
+
+    // 算好 counts 和叶子链表之后变回普通的树
+    pub fn into_inner(mut self) -> Result<BPlusTree<K, V, E>> {
+        self.repair()?;
+        let root = self.root.into_inner().map_err(|_| Error::LockPoisoned)?;
+        let len = self.len.into_inner();
+        Ok(BPlusTree::from_root(self.way, self.engine, root, len, self.order))
+    }
+
+    // 每次都要重新算 counts, 所有结点都会写一遍
+    pub fn flush(&mut self) -> Result<()> {
+        self.repair()?;
+        let root = *self.root.get_mut().map_err(|_| Error::LockPoisoned)?;
+        let len = *self.len.get_mut();
+        self.engine.flush(TreeMeta { root, way: self.way, len, persistent: false, order: self.order.id() })
+    }
+
+    pub fn latch_options(&self) -> LatchOptions {
+        self.latch
+    }
+
+    // 放进 Arc 之前设置
+    pub fn set_latch_options(&mut self, options: LatchOptions) {
+        self.latch = options;
+    }
+
+    // 从 into_concurrent 或者上次 reset_latch_stats 起的, 打开 LatchOptions::stats 之后才有
+    // 写者拿不到锁, 乐观的读者撞上正在改的结点, 读者要等 root 指针的锁, 都算一次抢锁
+    // 写者等的是拿不到锁之后退避的时间; 读者在结点的读锁上等的时间和 engine 读页的时间分不开, 只记等 root 指针的锁的
+    pub fn latch_stats(&self) -> LatchStats {
+        LatchStats {
+            root: self.counters[Level::Root as usize].load(),
+            inner: self.counters[Level::Inner as usize].load(),
+            leaf: self.counters[Level::Leaf as usize].load(),
+        }
+    }
+
+    pub fn reset_latch_stats(&self) {
+        self.counters.iter().for_each(LatchCounters::reset);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn search(&self, key: &K) -> Result<Option<V>> {
+        self.search_map(key, |value| Some(value.clone()))
+    }
+
+    // 在叶子的读锁下用 key 的 value 调用 f, 不用把整个 value 复制出来
+    pub(crate) fn search_map<T, F>(&self, key: &K, f: F) -> Result<Option<T>>
+    where
+        F: Fn(&V) -> Option<T>,
+    {
+        let _epoch = EpochGuard::new(&self.engine)?;
+        self.read(Some(key), |node| self.order.search(&node.keys, key).ok().and_then(|pos| f(&node.values[pos])))
+    }
+
+    // 每个叶子是在它自己的读锁下读的, 整个结果不是同一时刻的样子
+    // 不拿着锁走叶子链表, 读完一个叶子后从 root 重新找它的 high key 所在的叶子, 写者锁兄弟的顺序就不用和读者一致
+    pub fn range<R>(&self, range: R) -> Result<Vec<(K, V)>>
+    where
+        R: RangeBounds<K>,
+    {
+        self.range_map(range, |value| Some(value.clone()))
+    }
+
+    // 和 range 一样, 每个 value 换成 f 的结果, f 返回 None 的条目跳过
+    pub(crate) fn range_map<R, T, F>(&self, range: R, f: F) -> Result<Vec<(K, T)>>
+    where
+        R: RangeBounds<K>,
+        F: Fn(&V) -> Option<T>,
+    {
+        let _epoch = EpochGuard::new(&self.engine)?;
+        let mut entries = vec![];
+        let mut start = range.start_bound().cloned();
+        loop {
+            let key = match &start {
+                Bound::Included(key) | Bound::Excluded(key) => Some(key),
+                Bound::Unbounded => None,
+            };
+            // 叶子里过了 end 的 key 时不用再往右读
+            let (leaf_entries, high_key) = self.read(key, |node| {
+                let mut leaf_entries = vec![];
+                for (key, value) in node.keys.iter().zip(&node.values) {
+                    if !self.order.after_start(start.as_ref(), key) {
+                        continue;
+                    }
+                    if !self.order.before_end(range.end_bound(), key) {
+                        return (leaf_entries, None);
+                    }
+                    if let Some(value) = f(value) {
+                        leaf_entries.push((key.clone(), value));
+                    }
+                }
+                (leaf_entries, node.high_key.clone())
+            })?;
+            entries.extend(leaf_entries);
+            match high_key {
+                Some(high_key) => start = Bound::Included(high_key),
+                None => return Ok(entries),
+            }
+        }
+    }
+
+    // 在 key 所在的叶子上调用 f, 先乐观地读几次, 一直和写者冲突的话退回到锁耦合
+    fn read<R, F>(&self, key: Option<&K>, f: F) -> Result<R>
+    where
+        F: Fn(&BPlusTreeNode<K, V>) -> R,
+    {
+        for _ in 0..OPTIMISTIC_RETRIES {
+            if let Some((_, _, ret)) = self.read_optimistic(key, &f) {
+                return Ok(ret);
+            }
+        }
+        let leaf = self.read_leaf(key)?;
+        let node = leaf.as_ref().ok_or(anyhow!("leaf disappeared during read."))?;
+        Ok(f(node))
+    }
+
+    // 同一时间只拿一个结点的读锁, key 不小于结点的 high key 时结点分裂过, 往右走, 否则往下走
+    // 走到下一个结点之后前一个的版本号没变, 说明走过去的时候它还指着那里
+    // 前一个结点被借用或者合并改过, 或者读到了回收掉的 block 时返回 None, 从头再来
+    fn read_optimistic<R, F>(&self, key: Option<&K>, f: &F) -> Option<OptimisticRead<R>>
+    where
+        F: Fn(&BPlusTreeNode<K, V>) -> R,
+    {
+        let mut root = Some(self.read_root().ok()?);
+        let mut block_id = root.as_deref().copied()?;
+        let mut source: Option<(BlockId, u64)> = None;
+        let (mut depth, mut source_depth) = (0, 0);
+        loop {
+            let (version, step) = {
+                let read = self.engine.fetch_read(block_id).ok()?;
+                // 拿到 root 结点的锁之后 root 换掉也没关系, 增高时它成了左半边, 变矮时换 root 的写者会改它
+                drop(root.take());
+                self.acquired(self.level(depth));
+                let version = self.version(block_id).load(Ordering::SeqCst);
+                let Some(node) = read.as_ref() else {
+                    self.contended(self.level(depth));
+                    return None;
+                };
+                match (key, &node.high_key) {
+                    (Some(key), Some(high_key)) if !self.order.lt(key, high_key) => (version, ControlFlow::Continue((node.next?, depth))),
+                    _ if node.is_leaf() => (version, ControlFlow::Break(f(node))),
+                    _ => (version, ControlFlow::Continue((node.pointers[key.map_or(0, |key| node.child_index(key, &self.order))], depth + 1))),
+                }
+            };
+            if version % 2 == 1 {
+                self.contended(self.level(depth));
+                return None;
+            }
+            if source.is_some_and(|(source_id, source_version)| self.version(source_id).load(Ordering::SeqCst) != source_version) {
+                self.contended(self.level(source_depth));
+                return None;
+            }
+            match step {
+                ControlFlow::Break(ret) => return Some((block_id, source, ret)),
+                ControlFlow::Continue((next, next_depth)) => {
+                    source = Some((block_id, version));
+                    source_depth = depth;
+                    block_id = next;
+                    depth = next_depth;
+                }
+            }
+        }
+    }
+
+    fn version(&self, block_id: BlockId) -> &AtomicU64 {
+        self.versions.grow(block_id + 1);
+        self.versions.get(block_id).unwrap()
+    }
+
+    // 第 depth 层的结点算在 latch_stats 的哪一层
+    fn level(&self, depth: usize) -> Level {
+        match depth {
+            0 => Level::Root,
+            depth if depth + 1 >= self.height.load(Ordering::Relaxed) => Level::Leaf,
+            _ => Level::Inner,
+        }
+    }
+
+    fn acquired(&self, level: Level) {
+        if self.latch.stats {
+            self.counters[level as usize].acquisitions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn contended(&self, level: Level) {
+        if self.latch.stats {
+            self.counters[level as usize].contentions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // 写者确认 root 不会变之前一直拿着 root 指针的写锁, 读者在这里等的时间全是抢锁的
+    fn read_root(&self) -> Result<RwLockReadGuard<'_, BlockId>> {
+        if !self.latch.stats {
+            return Ok(self.root.read().map_err(|_| Error::LockPoisoned)?);
+        }
+        self.acquired(Level::Root);
+        match self.root.try_read() {
+            Result::Ok(root) => return Ok(root),
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Poisoned(_)) => return Err(Error::LockPoisoned.into()),
+        }
+        self.contended(Level::Root);
+        let start = Instant::now();
+        let root = self.root.read().map_err(|_| Error::LockPoisoned)?;
+        self.counters[Level::Root as usize].record_wait(start.elapsed());
+        Ok(root)
+    }
+
+    // 读锁一层层往下拿到 key 所在的叶子, key 是 None 时找最左边的叶子
+    // 拿着父结点的锁时孩子不会分裂, 不用往右走
+    fn read_leaf(&self, key: Option<&K>) -> Result<BlockReadGuard<'_, BPlusTreeNode<K, V>>> {
+        let root = self.read_root()?;
+        let mut block_id = *root;
+        let mut guard = self.engine.fetch_read(block_id)?;
+        drop(root);
+        self.acquired(Level::Root);
+        let mut depth = 0;
+        loop {
+            let node = guard.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                return Ok(guard);
+            }
+            block_id = node.pointers[key.map_or(0, |key| node.child_index(key, &self.order))];
+            guard = self.engine.fetch_read(block_id)?;
+            depth += 1;
+            self.acquired(self.level(depth));
+        }
+    }
+
+    // 乐观地找到 key 所在的叶子, 只拿它的写锁; 叶子是安全的, key 还在它的范围里, 走过来的那个结点也没变时才用
+    // root 是叶子时拿到锁之前 root 可能已经换了, 交给 latch_path; 叶子的锁别人拿着时也交给它
+    fn latch_leaf<F>(&self, key: &K, safe: F) -> Option<Latches<'_, K, V>>
+    where
+        F: Fn(&BPlusTreeNode<K, V>) -> bool,
+    {
+        let (leaf_id, source, _) = self.read_optimistic(Some(key), &|_| ())?;
+        let (source_id, source_version) = source?;
+        let Some(guard) = self.engine.try_fetch_write(leaf_id).ok()? else {
+            self.contended(Level::Leaf);
+            return None;
+        };
+        self.acquired(Level::Leaf);
+        let node = guard.as_ref()?;
+        let inside = node.high_key.as_ref().is_none_or(|high_key| self.order.lt(key, high_key));
+        if !node.is_leaf() || !inside || !safe(node) {
+            return None;
+        }
+        // 借用和合并都要先锁住这个叶子, 拿着锁时版本号没变就不会再变
+        if self.version(source_id).load(Ordering::SeqCst) != source_version {
+            return None;
+        }
+        Some(Latches { root: None, nodes: vec![guard], ids: vec![leaf_id], marks: vec![], siblings: vec![] })
+    }
+
+    // key 已经存在时替换 value, 返回旧的
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>> {
+        let mut old = None;
+        self.upsert(key, |current| match current {
+            Some(current) => {
+                old = Some(std::mem::replace(current, value));
+                None
+            }
+            None => Some(value),
+        })?;
+        Ok(old)
+    }
+
+    // 在叶子的写锁下用 key 现在的 value 调用 f, key 不存在时把 f 返回的 value 插进去; key 存在时 f 的返回值不用
+    pub(crate) fn upsert<F>(&self, key: K, f: F) -> Result<()>
+    where
+        F: FnOnce(Option<&mut V>) -> Option<V>,
+    {
+        let _epoch = EpochGuard::new(&self.engine)?;
+        let mut backoff = Backoff::new(&self.latch, &self.counters);
+        let mut latches = loop {
+            // 大多数插入不会分裂, 先只锁叶子试一次; key 已经在叶子里时满了也不会分裂
+            let latched = match self.latch_leaf(&key, |node| node.keys.len() < node.way || self.order.search(&node.keys, &key).is_ok()) {
+                Some(latches) => Some(latches),
+                None => self.latch_path(&key, |node, _| node.keys.len() < node.way, false, &mut backoff)?,
+            };
+            match latched {
+                Some(latches) => break latches,
+                None => backoff.wait()?,
+            }
+        };
+        let leaf_id = latches.ids[latches.ids.len() - 1];
+        let leaf = latches.nodes.last_mut().and_then(|guard| guard.as_mut()).ok_or(Error::EmptyBlock(leaf_id))?;
+        let pos = match self.order.search(&leaf.keys, &key) {
+            Result::Ok(pos) => {
+                f(Some(&mut leaf.values[pos]));
+                return Ok(());
+            }
+            Err(pos) => pos,
+        };
+        let Some(value) = f(None) else {
+            return Ok(());
+        };
+        // 锁着的结点除了最上面那个都可能分裂, 锁着 root 时还要一个新 root; 先分配好, 失败时树还没动
+        let needed = match latches.root {
+            Some(_) => latches.nodes.len() + 1,
+            None => latches.nodes.len() - 1,
+        };
+        let mut spare = self.alloc_spare(needed)?;
+        let ret = self.insert_latched(latches, pos, key, value, &mut spare);
+        for block_id in spare {
+            let _ = self.engine.delete(block_id);
+        }
+        ret?;
+        self.len.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // key 放在叶子的 pos 处
+    fn insert_latched(&self, latches: Latches<'_, K, V>, pos: usize, key: K, value: V, spare: &mut Vec<BlockId>) -> Result<()> {
+        let Latches { mut root, mut nodes, ids, .. } = latches;
+        // 从叶子往上, 溢出的结点分裂, 分隔 key 放进上一层
+        let (mut entry, mut split) = (Some((key, value)), None);
+        for (i, guard) in nodes.iter_mut().enumerate().rev() {
+            let block_id = ids[i];
+            let node = guard.as_mut().ok_or(Error::EmptyBlock(block_id))?;
+            match split.take() {
+                Some((mid, right_id)) => {
+                    let pos = node.child_index(&mid, &self.order);
+                    node.keys.insert(pos, mid);
+                    node.pointers.insert(pos + 1, right_id);
+                    node.counts.insert(pos + 1, 0);
+                    node.maxes.insert(pos + 1, None);
+                }
+                None => {
+                    if let Some((key, value)) = entry.take() {
+                        node.keys.insert(pos, key);
+                        node.values.insert(pos, value);
+                    }
+                }
+            }
+            if !node.is_overflow() {
+                return Ok(());
+            }
+            let right_id = spare.pop().ok_or(anyhow!("no spare block left for a split."))?;
+            let (mid, right) = split_node(node, block_id, right_id);
+            // 放开左半边的锁之前写好右半边, 别的线程顺着右链接过来时能读到
+            **self.engine.fetch_write(right_id)? = Some(right);
+            split = Some((mid, right_id));
+        }
+        // 最上面锁着的结点也分裂了, 说明它是 root 而且锁着 root
+        let (Some((mid, right_id)), Some(root)) = (split, root.as_mut()) else {
+            return Err(anyhow!("root split without the root latch."));
+        };
+        let mut node = BPlusTreeNode::new_inner(self.way);
+        node.keys = vec![mid];
+        node.pointers = vec![**root, right_id];
+        node.counts = vec![0, 0];
+        node.maxes = vec![None, None];
+        let root_id = spare.pop().ok_or(anyhow!("no spare block left for a split."))?;
+        **self.engine.fetch_write(root_id)? = Some(node);
+        **root = root_id;
+        self.height.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &K) -> Result<Option<V>> {
+        self.delete_if(key, |_| true)
+    }
+
+    // key 存在时在叶子的写锁下调用 f, f 返回 true 时才删掉, 返回删掉的 value
+    pub(crate) fn delete_if<F>(&self, key: &K, f: F) -> Result<Option<V>>
+    where
+        F: FnOnce(&mut V) -> bool,
+    {
+        let _epoch = EpochGuard::new(&self.engine)?;
+        let mut backoff = Backoff::new(&self.latch, &self.counters);
+        let latches = loop {
+            // 删了之后不会太空的叶子只锁它自己
+            let latched = match self.latch_leaf(key, |node| node.keys.len() > node.min_keys()) {
+                Some(latches) => Some(latches),
+                // root 是叶子时怎么删都行, 是内部结点时只剩一个 key 就可能变矮
+                None => self.latch_path(
+                    key,
+                    |node, is_root| match is_root {
+                        true => node.is_leaf() || node.keys.len() > 1,
+                        false => node.keys.len() > node.min_keys(),
+                    },
+                    true,
+                    &mut backoff,
+                )?,
+            };
+            if let Some(mut latches) = latched {
+                if self.latch_siblings(&mut latches, key, &mut backoff)? {
+                    break latches;
+                }
+            }
+            backoff.wait()?;
+        };
+        let ret = self.delete_latched(latches, key, f)?;
+        if ret.is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(ret)
+    }
+
+    fn delete_latched<F>(&self, latches: Latches<'_, K, V>, key: &K, f: F) -> Result<Option<V>>
+    where
+        F: FnOnce(&mut V) -> bool,
+    {
+        let Latches { mut root, mut nodes, ids, marks: _marks, mut siblings } = latches;
+        let leaf_id = ids[ids.len() - 1];
+        let leaf = nodes.last_mut().and_then(|guard| guard.as_mut()).ok_or(Error::EmptyBlock(leaf_id))?;
+        let Result::Ok(pos) = self.order.search(&leaf.keys, key) else {
+            return Ok(None);
+        };
+        if !f(&mut leaf.values[pos]) {
+            return Ok(None);
+        }
+        leaf.keys.remove(pos);
+        let value = leaf.values.remove(pos);
+
+        // 从叶子往上, 太空的结点向兄弟借一个, 借不到就合并; 下面处理完的结点先放锁
+        while nodes.len() > 1 {
+            let mut child = nodes.pop().unwrap();
+            let child_id = ids[nodes.len()];
+            let child_node = child.as_mut().ok_or(Error::EmptyBlock(child_id))?;
+            if child_node.keys.len() >= child_node.min_keys() {
+                break;
+            }
+            let parent_id = ids[nodes.len() - 1];
+            let parent = nodes.last_mut().and_then(|guard| guard.as_mut()).ok_or(Error::EmptyBlock(parent_id))?;
+            let pos = parent.child_index(key, &self.order);
+            let li = if pos > 0 { pos - 1 } else { pos };
+            let (sibling_id, mut sibling) = siblings.pop().ok_or(anyhow!("sibling of block {} is not latched.", child_id))?;
+            let _mark = Mark::new(self.version(sibling_id));
+            let sibling_node = sibling.as_mut().ok_or(Error::EmptyBlock(sibling_id))?;
+            let borrow = sibling_node.keys.len() > sibling_node.min_keys();
+            let (left, right, right_id) = match pos > 0 {
+                true => (sibling_node, child_node, child_id),
+                false => (child_node, sibling_node, sibling_id),
+            };
+            if borrow {
+                borrow_entry(parent, li, left, right, pos > 0);
+                break;
+            }
+            merge(parent, li, left, right);
+            // 右边的结点从父结点和左边的兄弟上摘掉了, 还拿着它的 id 的线程会发现走过来的结点的版本号变了
+            drop(child);
+            drop(sibling);
+            self.engine.delete(right_id)?;
+        }
+
+        // root 只剩一个孩子时树变矮一层, 这时一定锁着 root
+        if let Some(root) = root.as_mut() {
+            let root_id = **root;
+            let only_child = nodes[0]
+                .as_ref()
+                .and_then(|node| (!node.is_leaf() && node.keys.is_empty()).then(|| node.pointers[0]));
+            if let Some(child) = only_child {
+                **root = child;
+                self.height.fetch_sub(1, Ordering::Relaxed);
+                drop(nodes);
+                self.engine.delete(root_id)?;
+            }
+        }
+        Ok(Some(value))
+    }
+
+    // 从 root 往下一层层拿写锁, 和读者拿锁的顺序一样; 拿到一个安全的结点时放开它上面所有的锁
+    // root 的锁也一样, root 结点安全时就不会换 root 了
+    // mark 时剩下的结点改之前先改版本号; 只有删除要, 分裂之后乐观的读者顺着右链接就能找到 key
+    // 哪个锁拿不到时放开已经拿到的, 返回 None, 它在哪一层记在 backoff 里
+    fn latch_path<F>(&self, key: &K, safe: F, mark: bool, backoff: &mut Backoff<'_>) -> Result<Option<Latches<'_, K, V>>>
+    where
+        F: Fn(&BPlusTreeNode<K, V>, bool) -> bool,
+    {
+        let root = match self.root.try_write() {
+            Result::Ok(root) => root,
+            Err(TryLockError::WouldBlock) => {
+                self.contended(Level::Root);
+                backoff.block(Level::Root);
+                return Ok(None);
+            }
+            Err(TryLockError::Poisoned(_)) => return Err(Error::LockPoisoned.into()),
+        };
+        self.acquired(Level::Root);
+        let mut block_id = *root;
+        let mut latches = Latches { root: Some(root), nodes: vec![], ids: vec![], marks: vec![], siblings: vec![] };
+        let mut is_root = true;
+        let mut depth = 0;
+        loop {
+            let level = self.level(depth);
+            let Some(guard) = self.engine.try_fetch_write(block_id)? else {
+                self.contended(level);
+                backoff.block(level);
+                return Ok(None);
+            };
+            self.acquired(level);
+            let node = guard.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if safe(node, is_root) {
+                latches.root = None;
+                latches.nodes.clear();
+                latches.ids.clear();
+            }
+            let child = (!node.is_leaf()).then(|| node.pointers[node.child_index(key, &self.order)]);
+            latches.nodes.push(guard);
+            latches.ids.push(block_id);
+            let Some(child) = child else {
+                if mark {
+                    latches.marks = latches.ids.iter().map(|&block_id| Mark::new(self.version(block_id))).collect();
+                }
+                return Ok(Some(latches));
+            };
+            block_id = child;
+            is_root = false;
+            depth += 1;
+        }
+    }
+
+    // 删掉 key 之后叶子会太空时, 在改任何东西之前把锁着的每层结点的兄弟也锁上
+    // 兄弟的锁可能在别的写者手里, 拿着路径上的锁等它会和它互相等; 拿不到时返回 false, 调用方放开所有锁重来
+    fn latch_siblings<'a>(&'a self, latches: &mut Latches<'a, K, V>, key: &K, backoff: &mut Backoff<'_>) -> Result<bool> {
+        let leaf_id = latches.ids[latches.ids.len() - 1];
+        let leaf = latches.nodes.last().and_then(|guard| guard.as_ref()).ok_or(Error::EmptyBlock(leaf_id))?;
+        if leaf.keys.len() > leaf.min_keys() || self.order.search(&leaf.keys, key).is_err() {
+            return Ok(true);
+        }
+        for i in 1..latches.nodes.len() {
+            let parent = latches.nodes[i - 1].as_ref().ok_or(Error::EmptyBlock(latches.ids[i - 1]))?;
+            let pos = parent.child_index(key, &self.order);
+            let sibling_id = if pos > 0 { parent.pointers[pos - 1] } else { parent.pointers[pos + 1] };
+            // 锁着的最下面一个是叶子, 兄弟都不是 root
+            let level = if i + 1 == latches.nodes.len() { Level::Leaf } else { Level::Inner };
+            match self.engine.try_fetch_write(sibling_id)? {
+                Some(sibling) => {
+                    self.acquired(level);
+                    latches.siblings.push((sibling_id, sibling));
+                }
+                None => {
+                    self.contended(level);
+                    backoff.block(level);
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn alloc_spare(&self, n: usize) -> Result<Vec<BlockId>> {
+        let mut spare = vec![];
+        while spare.len() < n {
+            match self.engine.alloc_block() {
+                Result::Ok(block_id) => spare.push(block_id),
+                Err(e) => {
+                    for block_id in spare {
+                        let _ = self.engine.delete(block_id);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(spare)
+    }
+
+    // 重新算每个内部结点的 counts 和每个结点的 high key, 把每一层按顺序重新串起来
+    fn repair(&mut self) -> Result<()> {
+        let root = *self.root.get_mut().map_err(|_| Error::LockPoisoned)?;
+        let mut levels = vec![];
+        self.repair_node(root, None, 0, &mut levels)?;
+        *self.height.get_mut() = levels.len();
+        for level in &levels {
+            for (i, &block_id) in level.iter().enumerate() {
+                if let Some(node) = self.engine.fetch_write(block_id)?.as_mut() {
+                    node.next = level.get(i + 1).copied();
+                    if node.is_leaf() {
+                        node.prev = i.checked_sub(1).map(|i| level[i]);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 返回子树里的条目数, levels[depth] 按从左到右的顺序记下这一层的结点
+    fn repair_node(&self, block_id: BlockId, high_key: Option<K>, depth: usize, levels: &mut Vec<Vec<BlockId>>) -> Result<usize> {
+        if levels.len() == depth {
+            levels.push(vec![]);
+        }
+        levels[depth].push(block_id);
+        let (keys, pointers) = {
+            let mut write = self.engine.fetch_write(block_id)?;
+            let node = write.as_mut().ok_or(Error::EmptyBlock(block_id))?;
+            node.high_key = high_key.clone();
+            if node.is_leaf() {
+                return Ok(node.keys.len());
+            }
+            (node.keys.clone(), node.pointers.clone())
+        };
+        let counts = pointers
+            .iter()
+            .enumerate()
+            .map(|(i, &child)| self.repair_node(child, keys.get(i).cloned().or(high_key.clone()), depth + 1, levels))
+            .collect::<Result<Vec<_>>>()?;
+        let count = counts.iter().sum();
+        if let Some(node) = self.engine.fetch_write(block_id)?.as_mut() {
+            node.counts = counts;
+        }
+        Ok(count)
+    }
+}
+
+// 从中间分开, 返回 (分隔 key, 右半边); 右半边接过原来的 high key 和右链接, 右边叶子原来的 prev 不管
+fn split_node<K: Ord + Clone, V>(
+    node: &mut BPlusTreeNode<K, V>,
+    block_id: BlockId,
+    right_id: BlockId,
+) -> (K, BPlusTreeNode<K, V>) {
+    let mid = node.keys.len() / 2;
+    let (mid, mut right) = if node.is_leaf() {
+        let mut right = BPlusTreeNode::new_leaf(node.way);
+        right.keys = node.keys.split_off(mid);
+        right.values = node.values.split_off(mid);
+        right.prev = Some(block_id);
+        (right.keys[0].clone(), right)
+    } else {
+        node.split_inner(mid)
+    };
+    right.high_key = node.high_key.replace(mid.clone());
+    right.next = node.next.replace(right_id);
+    (mid, right)
+}
+
+// parent.keys[li] 两边的 left 和 right, from_left 时从 left 借最后一个给 right, 否则从 right 借第一个给 left
+fn borrow_entry<K: Ord + Clone, V>(
+    parent: &mut BPlusTreeNode<K, V>,
+    li: usize,
+    left: &mut BPlusTreeNode<K, V>,
+    right: &mut BPlusTreeNode<K, V>,
+    from_left: bool,
+) {
+    match (left.is_leaf(), from_left) {
+        (true, true) => {
+            let last = left.keys.len() - 1;
+            right.keys.insert(0, left.keys.remove(last));
+            right.values.insert(0, left.values.remove(last));
+            parent.keys[li] = right.keys[0].clone();
+        }
+        (true, false) => {
+            left.keys.push(right.keys.remove(0));
+            left.values.push(right.values.remove(0));
+            parent.keys[li] = right.keys[0].clone();
+        }
+        // 内部结点借用时分隔 key 经过 parent 转一圈
+        (false, true) => {
+            let last = left.keys.len() - 1;
+            let separator = std::mem::replace(&mut parent.keys[li], left.keys.remove(last));
+            right.keys.insert(0, separator);
+            right.pointers.insert(0, left.pointers.remove(last + 1));
+            right.counts.insert(0, left.counts.remove(last + 1));
+            right.maxes.insert(0, left.maxes.remove(last + 1));
+        }
+        (false, false) => {
+            let separator = std::mem::replace(&mut parent.keys[li], right.keys.remove(0));
+            left.keys.push(separator);
+            left.pointers.push(right.pointers.remove(0));
+            left.counts.push(right.counts.remove(0));
+            left.maxes.push(right.maxes.remove(0));
+        }
+    }
+    left.high_key = Some(parent.keys[li].clone());
+}
+
+// right 整个并进 left, 从 parent 上摘掉, 留下空的 right 给调用方回收
+fn merge<K: Ord, V>(parent: &mut BPlusTreeNode<K, V>, li: usize, left: &mut BPlusTreeNode<K, V>, right: &mut BPlusTreeNode<K, V>) {
+    let separator = parent.keys.remove(li);
+    parent.pointers.remove(li + 1);
+    parent.counts.remove(li + 1);
+    parent.maxes.remove(li + 1);
+    if !left.is_leaf() {
+        left.keys.push(separator);
+        left.pointers.append(&mut right.pointers);
+        left.counts.append(&mut right.counts);
+        left.maxes.append(&mut right.maxes);
+    }
+    left.keys.append(&mut right.keys);
+    left.values.append(&mut right.values);
+    left.next = right.next;
+    left.high_key = right.high_key.take();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    type Tree = ConcurrentBPlusTree<u32, u32, MemoryBlockEngine<BPlusTreeNode<u32, u32>>>;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    // 每个结点的 high key 是父结点给它的上界, 每一层从左到右串起来
+    fn check_links(tree: &Tree) {
+        let mut level = vec![(*tree.root.read().unwrap(), None)];
+        while !level.is_empty() {
+            let mut below = vec![];
+            for (i, &(block_id, high_key)) in level.iter().enumerate() {
+                let read = tree.engine.fetch_read(block_id).unwrap();
+                let node = read.as_ref().unwrap();
+                assert_eq!(node.high_key, high_key);
+                assert_eq!(node.next, level.get(i + 1).map(|&(block_id, _)| block_id));
+                for (j, &child) in node.pointers.iter().enumerate() {
+                    below.push((child, node.keys.get(j).copied().or(high_key)));
+                }
+            }
+            level = below;
+        }
+    }
+
+    #[test]
+    fn test_readers_with_writers() {
+        assert_send_sync::<BPlusTree<u32, u32, MemoryBlockEngine<BPlusTreeNode<u32, u32>>>>();
+        assert_send_sync::<Tree>();
+
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..500 {
+            tree.insert(i * 2, i).unwrap();
+        }
+        let tree: Arc<Tree> = Arc::new(tree.into_concurrent().unwrap());
+        check_links(&tree);
+        // 写者只动奇数的 key, 各写各的, 读者一直能看到所有偶数的 key
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let tree = tree.clone();
+                thread::spawn(move || {
+                    for round in 0..20 {
+                        for i in (0..500).step_by(7) {
+                            assert_eq!(tree.search(&(i * 2)).unwrap(), Some(i));
+                        }
+                        let entries = tree.range(round * 40..round * 40 + 100).unwrap();
+                        assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+                        let evens: Vec<_> = entries.iter().filter(|(key, _)| key % 2 == 0).map(|&(key, _)| key).collect();
+                        assert_eq!(evens, (round * 40..round * 40 + 100).step_by(2).collect::<Vec<_>>());
+                    }
+                })
+            })
+            .collect();
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let tree = tree.clone();
+                thread::spawn(move || {
+                    for i in (0..500).filter(|i| i % 4 == writer) {
+                        assert_eq!(tree.insert(i * 2 + 1, i).unwrap(), None);
+                    }
+                    for i in (0..500).filter(|i| i % 4 == writer && i % 3 != 0) {
+                        assert_eq!(tree.delete(&(i * 2 + 1)).unwrap(), Some(i));
+                    }
+                })
+            })
+            .collect();
+        writers.into_iter().for_each(|writer| writer.join().unwrap());
+        readers.into_iter().for_each(|reader| reader.join().unwrap());
+
+        // 分裂和合并时维护好了, 不用等 repair
+        let tree = Arc::into_inner(tree).unwrap();
+        check_links(&tree);
+        assert_eq!(tree.len(), 500 + 167);
+        assert_eq!(tree.range(..).unwrap().len(), 667);
+        let tree = tree.into_inner().unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.iter().count(), 667);
+        assert_eq!(tree.search(&7).unwrap(), Some(3));
+        assert_eq!(tree.search(&3).unwrap(), None);
+    }
+
+    #[test]
+    fn test_latch_timeout() {
+        let mut tree: Tree = ConcurrentBPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..30 {
+            tree.insert(i, i).unwrap();
+        }
+        let timeout = Duration::from_millis(50);
+        tree.set_latch_options(LatchOptions { timeout: Some(timeout), ..LatchOptions::default() });
+        let (leaf_id, _, _) = tree.read_optimistic(Some(&0), &|_| ()).unwrap();
+        let first_key = |tree: &Tree| tree.engine.fetch_read(leaf_id).unwrap().as_ref().unwrap().keys[0];
+
+        // 慢的读者一直拿着叶子的读锁
+        let key = first_key(&tree);
+        let guard = tree.engine.fetch_read(leaf_id).unwrap();
+        let start = Instant::now();
+        let err = tree.insert(key, 100).unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::LockTimeout));
+        assert!(start.elapsed() >= timeout);
+        drop(guard);
+
+        // 删掉之后最左边的叶子要和右边的兄弟合并, 兄弟的锁在别人手里时超时, 什么都没改
+        loop {
+            let read = tree.engine.fetch_read(leaf_id).unwrap();
+            let leaf = read.as_ref().unwrap();
+            if leaf.keys.len() <= leaf.min_keys() {
+                break;
+            }
+            let key = leaf.keys[0];
+            drop(read);
+            tree.delete(&key).unwrap();
+        }
+        let key = first_key(&tree);
+        let sibling_id = tree.engine.fetch_read(leaf_id).unwrap().as_ref().unwrap().next.unwrap();
+        let len = tree.len();
+        let sibling = tree.engine.fetch_read(sibling_id).unwrap();
+        let err = tree.delete(&key).unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::LockTimeout));
+        assert_eq!((tree.search(&key).unwrap(), tree.len()), (Some(key), len));
+        drop(sibling);
+
+        // 别人拿一会儿就放开时重来几次就能删掉
+        tree.set_latch_options(LatchOptions::default());
+        let sibling = tree.engine.fetch_read(sibling_id).unwrap();
+        thread::scope(|scope| {
+            let deleter = scope.spawn(|| tree.delete(&key).unwrap());
+            thread::sleep(Duration::from_millis(20));
+            drop(sibling);
+            assert_eq!(deleter.join().unwrap(), Some(key));
+        });
+        check_links(&tree);
+        assert_eq!(tree.len(), len - 1);
+        tree.into_inner().unwrap().verify().unwrap();
+    }
+
+    #[test]
+    fn test_latch_stats() {
+        let mut tree: Tree = ConcurrentBPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..30 {
+            tree.insert(i, i).unwrap();
+        }
+        // 默认不记
+        assert_eq!(tree.search(&1).unwrap(), Some(1));
+        assert_eq!(tree.latch_stats(), LatchStats::default());
+
+        tree.set_latch_options(LatchOptions { stats: true, ..LatchOptions::default() });
+        assert_eq!(tree.search(&1).unwrap(), Some(1));
+        let stats = tree.latch_stats();
+        assert!(stats.root.acquisitions >= 2 && stats.inner.acquisitions >= 1 && stats.leaf.acquisitions == 1);
+        assert_eq!(stats.root.contentions + stats.inner.contentions + stats.leaf.contentions, 0);
+        assert_eq!(stats.leaf.wait_quantile(0.5), None);
+
+        // 写者拿不到叶子的锁, 退避的时间记在叶子那一层
+        let (leaf_id, _, _) = tree.read_optimistic(Some(&0), &|_| ()).unwrap();
+        let guard = tree.engine.fetch_read(leaf_id).unwrap();
+        thread::scope(|scope| {
+            let writer = scope.spawn(|| tree.insert(0, 100).unwrap());
+            thread::sleep(Duration::from_millis(20));
+            drop(guard);
+            assert_eq!(writer.join().unwrap(), Some(0));
+        });
+        let stats = tree.latch_stats();
+        assert!(stats.leaf.contentions >= 2);
+        assert_eq!(stats.leaf.waits.iter().sum::<u64>(), 1);
+        assert!(stats.leaf.wait_quantile(1.0).unwrap() >= Duration::from_millis(10));
+        assert_eq!(stats.root.waits.iter().sum::<u64>(), 0);
+
+        // 读者等 root 指针的锁
+        tree.reset_latch_stats();
+        let root = tree.root.write().unwrap();
+        thread::scope(|scope| {
+            let reader = scope.spawn(|| tree.search(&5).unwrap());
+            thread::sleep(Duration::from_millis(20));
+            drop(root);
+            assert_eq!(reader.join().unwrap(), Some(5));
+        });
+        let stats = tree.latch_stats();
+        assert_eq!(stats.root.contentions, 1);
+        assert!(stats.root.wait_quantile(0.5).unwrap() >= Duration::from_millis(10));
+        assert_eq!(stats.leaf.contentions, 0);
+    }
+}
@@ -0,0 +1,321 @@
+use anyhow::{anyhow, Ok, Result};
+use std::ops::Bound;
+
+use crate::{
+    block::{BlockEngine, BlockPin},
+    error::Error,
+    iter::LeafCursor,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 游标的位置: 某个叶子里的第 pos 个条目, 或者首尾之间的 ghost 位置 (leaf 为空)
+// 和 std 的 linked_list::Cursor 一样是环形的, 最后一个条目的 next 是 ghost, ghost 的 next 是第一个条目
+#[derive(Clone)]
+struct Position {
+    leaf: LeafCursor,
+    pos: usize,
+}
+
+impl Position {
+    fn ghost() -> Position {
+        Position { leaf: LeafCursor::empty(), pos: 0 }
+    }
+
+    // 第一个 >= start 的条目, 没有时是 ghost
+    fn seek<K, V, E>(tree: &BPlusTree<K, V, E>, start: Bound<&K>) -> Result<Position>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        let mut position = Position { leaf: LeafCursor::seek(tree, tree.root, start)?, pos: 0 };
+        if let Some(leaf) = position.leaf.leaf() {
+            let read = tree.engine.fetch_read(leaf)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(leaf))?;
+            position.pos = node.keys.partition_point(|key| !tree.order.after_start(start, key));
+        }
+        position.skip_forward(tree)?;
+        Ok(position)
+    }
+
+    fn is_ghost(&self) -> bool {
+        self.leaf.leaf().is_none()
+    }
+
+    fn leaf_len<K, V, E>(&self, tree: &BPlusTree<K, V, E>) -> Result<Option<usize>>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        let Some(leaf) = self.leaf.leaf() else {
+            return Ok(None);
+        };
+        let read = tree.engine.fetch_read(leaf)?;
+        Ok(Some(read.as_ref().ok_or(Error::EmptyBlock(leaf))?.keys.len()))
+    }
+
+    // pos 超出当前叶子时往右找下一个非空叶子, 到头了变成 ghost
+    fn skip_forward<K, V, E>(&mut self, tree: &BPlusTree<K, V, E>) -> Result<()>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        while let Some(len) = self.leaf_len(tree)? {
+            if self.pos < len {
+                return Ok(());
+            }
+            self.leaf.next(tree)?;
+            self.pos = 0;
+        }
+        Ok(())
+    }
+
+    fn entry<K, V, E>(&self, tree: &BPlusTree<K, V, E>) -> Result<Option<(K, V)>>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        let Some(leaf) = self.leaf.leaf() else {
+            return Ok(None);
+        };
+        let read = tree.engine.fetch_read(leaf)?;
+        let node = read.as_ref().ok_or(Error::EmptyBlock(leaf))?;
+        Ok(Some((node.keys[self.pos].clone(), node.values[self.pos].clone())))
+    }
+
+    fn move_next<K, V, E>(&mut self, tree: &BPlusTree<K, V, E>) -> Result<()>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        if self.is_ghost() {
+            *self = Position::seek(tree, Bound::Unbounded)?;
+            return Ok(());
+        }
+        self.pos += 1;
+        self.skip_forward(tree)
+    }
+
+    fn move_prev<K, V, E>(&mut self, tree: &BPlusTree<K, V, E>) -> Result<()>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        if self.is_ghost() {
+            self.leaf = LeafCursor::seek_back(tree, tree.root, Bound::Unbounded)?;
+        } else if self.pos > 0 {
+            self.pos -= 1;
+            return Ok(());
+        } else {
+            self.leaf.prev(tree)?;
+        }
+        // 往左找一个非空叶子, 停在它的最后一个条目上
+        while let Some(len) = self.leaf_len(tree)? {
+            if len > 0 {
+                self.pos = len - 1;
+                return Ok(());
+            }
+            self.leaf.prev(tree)?;
+        }
+        Ok(())
+    }
+}
+
+// 只读游标, 移动时不会重新从 root 往下走
+// 当前叶子一直 pin 着, 停在原地的游标不会让它被 buffer pool 淘汰
+pub struct Cursor<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    tree: &'a BPlusTree<K, V, E>,
+    position: Position,
+    pin: Option<BlockPin<'a, E>>,
+}
+
+impl<'a, K, V, E> Cursor<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 当前条目, 在 ghost 位置时是 None
+    pub fn current(&self) -> Result<Option<(K, V)>> {
+        self.position.entry(self.tree)
+    }
+
+    // 移到下一个条目并返回它, 和 Iterator 不同, 走到 ghost 之后还能接着走
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<(K, V)>> {
+        self.position.move_next(self.tree)?;
+        self.repin()?;
+        self.current()
+    }
+
+    pub fn prev(&mut self) -> Result<Option<(K, V)>> {
+        self.position.move_prev(self.tree)?;
+        self.repin()?;
+        self.current()
+    }
+
+    // 换了叶子时先 pin 新的再放开旧的
+    fn repin(&mut self) -> Result<()> {
+        let leaf = self.position.leaf.leaf();
+        if self.pin.as_ref().map(BlockPin::block_id) != leaf {
+            self.pin = leaf.map(|leaf| BlockPin::new(&self.tree.engine, leaf)).transpose()?;
+        }
+        Ok(())
+    }
+}
+
+// 可写游标, 修改之后会按 key 重新定位, 单纯移动不会
+pub struct CursorMut<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    tree: &'a mut BPlusTree<K, V, E>,
+    position: Position,
+}
+
+impl<'a, K, V, E> CursorMut<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn current(&self) -> Result<Option<(K, V)>> {
+        self.position.entry(self.tree)
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<(K, V)>> {
+        self.position.move_next(self.tree)?;
+        self.current()
+    }
+
+    pub fn prev(&mut self) -> Result<Option<(K, V)>> {
+        self.position.move_prev(self.tree)?;
+        self.current()
+    }
+
+    // 删除当前条目, 游标移到它的下一个
+    pub fn remove_current(&mut self) -> Result<Option<(K, V)>> {
+        let Some((key, _)) = self.current()? else {
+            return Ok(None);
+        };
+        let value = self.tree.delete(&key)?;
+        self.position = Position::seek(self.tree, Bound::Excluded(&key))?;
+        Ok(value.map(|value| (key, value)))
+    }
+
+    // 在当前条目前面插入, key 必须落在前一个条目和当前条目之间, 游标不动
+    pub fn insert_before(&mut self, key: K, value: V) -> Result<()> {
+        let mut prev = self.position.clone();
+        prev.move_prev(self.tree)?;
+        let prev = prev.entry(self.tree)?.map(|(key, _)| key);
+        let current = self.current()?.map(|(key, _)| key);
+        self.insert_between(prev, current, key, value)
+    }
+
+    // 在当前条目后面插入, 游标不动
+    pub fn insert_after(&mut self, key: K, value: V) -> Result<()> {
+        let mut next = self.position.clone();
+        next.move_next(self.tree)?;
+        let next = next.entry(self.tree)?.map(|(key, _)| key);
+        let current = self.current()?.map(|(key, _)| key);
+        self.insert_between(current, next, key, value)
+    }
+
+    // ghost 位置两边分别是最后一个和第一个条目, 所以 low >= high 时说明跨过了 ghost
+    fn insert_between(&mut self, low: Option<K>, high: Option<K>, key: K, value: V) -> Result<()> {
+        let order = &self.tree.order;
+        let above = low.as_ref().is_none_or(|low| order.lt(low, &key));
+        let below = high.as_ref().is_none_or(|high| order.lt(&key, high));
+        let wraps = matches!((&low, &high), (Some(low), Some(high)) if !order.lt(low, high));
+        if !(if wraps { above || below } else { above && below }) {
+            return Err(anyhow!("key is out of order at the cursor position."));
+        }
+        let current = self.current()?.map(|(key, _)| key);
+        self.tree.insert_unique(key, value)?;
+        self.position = match current {
+            Some(current) => Position::seek(self.tree, Bound::Included(&current))?,
+            None => Position::ghost(),
+        };
+        Ok(())
+    }
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 停在第一个 >= key 的条目上, 没有时在 ghost 位置
+    pub fn cursor(&self, key: &K) -> Result<Cursor<'_, K, V, E>> {
+        let mut cursor = Cursor { position: Position::seek(self, Bound::Included(key))?, tree: self, pin: None };
+        cursor.repin()?;
+        Ok(cursor)
+    }
+
+    pub fn cursor_mut(&mut self, key: &K) -> Result<CursorMut<'_, K, V, E>> {
+        Ok(CursorMut { position: Position::seek(self, Bound::Included(key))?, tree: self })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_cursor() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..50 {
+            tree.insert(i * 2, i).unwrap();
+        }
+        let mut cursor = tree.cursor(&31).unwrap();
+        assert_eq!(cursor.current().unwrap(), Some((32, 16)));
+        assert_eq!(cursor.prev().unwrap(), Some((30, 15)));
+        assert_eq!(cursor.next().unwrap(), Some((32, 16)));
+        let mut cursor = tree.cursor(&98).unwrap();
+        assert_eq!(cursor.next().unwrap(), None);
+        assert_eq!(cursor.next().unwrap(), Some((0, 0)));
+        assert_eq!(cursor.prev().unwrap(), None);
+        assert_eq!(cursor.prev().unwrap(), Some((98, 49)));
+    }
+
+    #[test]
+    fn test_cursor_mut() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..50 {
+            tree.insert(i * 2, i).unwrap();
+        }
+        // 边走边删掉 4 的倍数
+        let mut cursor = tree.cursor_mut(&0).unwrap();
+        while let Some((key, _)) = cursor.current().unwrap() {
+            if key % 4 == 0 {
+                cursor.remove_current().unwrap();
+            } else {
+                cursor.next().unwrap();
+            }
+        }
+        let mut cursor = tree.cursor_mut(&10).unwrap();
+        cursor.insert_before(9, 0).unwrap();
+        cursor.insert_after(11, 0).unwrap();
+        assert!(cursor.insert_after(20, 0).is_err());
+        assert_eq!(cursor.current().unwrap(), Some((10, 5)));
+        assert_eq!(cursor.next().unwrap(), Some((11, 0)));
+
+        let keys: Vec<_> = tree.keys().take(6).collect();
+        assert_eq!(keys, vec![2, 6, 9, 10, 11, 14]);
+        assert_eq!(tree.len(), 27);
+    }
+}
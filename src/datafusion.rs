@@ -0,0 +1,313 @@
+use anyhow::anyhow;
+use arrow_array::RecordBatch;
+use arrow_schema::{Field, Schema, SchemaRef};
+use async_trait::async_trait;
+use datafusion::{
+    catalog::Session,
+    common::Column,
+    datasource::{TableProvider, TableType},
+    error::{DataFusionError, Result},
+    logical_expr::{expr::BinaryExpr, Between, Expr, Operator, TableProviderFilterPushDown},
+    physical_plan::{memory::MemoryExec, ExecutionPlan},
+    scalar::ScalarValue,
+};
+use std::{
+    any::Any,
+    cmp::Ordering,
+    fmt,
+    ops::Bound,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    block::BlockEngine,
+    columns::{ArrowColumn, ArrowRow},
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// scan 时每攒够这么多行做一个 RecordBatch
+const BATCH_ROWS: usize = 8192;
+
+// 把树当成 DataFusion 里的一张只读表: key 一列, value 解码成一列或者几列, 见 ArrowRow
+// key 列和常量的比较 (=, <, <=, >, >=, BETWEEN) 下推成树上的范围扫描, 其它条件留给 DataFusion 自己过滤
+// 每次 scan 拿着读锁把范围里的条目读进内存, 写的时候用 tree() 拿到同一棵树
+pub struct TreeTable<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    tree: Arc<RwLock<BPlusTree<K, V, E>>>,
+    schema: SchemaRef,
+    // 自定义的 key 顺序和 DataFusion 里的比较不一样, 这时不下推
+    pushdown: bool,
+}
+
+impl<K, V, E> TreeTable<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone + ArrowColumn,
+    V: Clone + ArrowRow,
+{
+    pub fn new(tree: Arc<RwLock<BPlusTree<K, V, E>>>, key_col: &str, value_cols: &[&str]) -> anyhow::Result<Self> {
+        if value_cols.len() != V::WIDTH {
+            return Err(anyhow!("value needs {} columns, got {}.", V::WIDTH, value_cols.len()));
+        }
+        let mut fields = vec![Field::new(key_col, K::data_type(), K::nullable())];
+        fields.extend(V::fields(value_cols));
+        let pushdown = tree.read().map_err(|_| Error::LockPoisoned)?.order.id() == 0;
+        Ok(TreeTable { tree, schema: Arc::new(Schema::new(fields)), pushdown })
+    }
+
+    pub fn tree(&self) -> Arc<RwLock<BPlusTree<K, V, E>>> {
+        self.tree.clone()
+    }
+
+    fn is_key(&self, column: &Column) -> bool {
+        column.name == *self.schema.field(0).name()
+    }
+
+    // 类型对不上或者是 null 的常量不下推
+    fn key(&self, value: &ScalarValue) -> Option<K> {
+        if value.is_null() {
+            return None;
+        }
+        let array = value.to_array().ok()?;
+        <K as ArrowColumn>::read(array.as_ref(), 0).ok()
+    }
+
+    // key 列和常量比较的条件对应的 key 范围, 别的条件返回 None
+    fn key_range(&self, filter: &Expr) -> Option<(Bound<K>, Bound<K>)> {
+        if !self.pushdown {
+            return None;
+        }
+        match filter {
+            Expr::BinaryExpr(BinaryExpr { left, op, right }) => {
+                // 常量在左边时把比较反过来, 5 < key 就是 key > 5
+                let (op, value) = match (left.as_ref(), right.as_ref()) {
+                    (Expr::Column(column), Expr::Literal(value)) if self.is_key(column) => (*op, value),
+                    (Expr::Literal(value), Expr::Column(column)) if self.is_key(column) => (op.swap()?, value),
+                    _ => return None,
+                };
+                let key = self.key(value)?;
+                Some(match op {
+                    Operator::Eq => (Bound::Included(key.clone()), Bound::Included(key)),
+                    Operator::Lt => (Bound::Unbounded, Bound::Excluded(key)),
+                    Operator::LtEq => (Bound::Unbounded, Bound::Included(key)),
+                    Operator::Gt => (Bound::Excluded(key), Bound::Unbounded),
+                    Operator::GtEq => (Bound::Included(key), Bound::Unbounded),
+                    _ => return None,
+                })
+            }
+            Expr::Between(Between { expr, negated: false, low, high }) => match (expr.as_ref(), low.as_ref(), high.as_ref()) {
+                (Expr::Column(column), Expr::Literal(low), Expr::Literal(high)) if self.is_key(column) => {
+                    Some((Bound::Included(self.key(low)?), Bound::Included(self.key(high)?)))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn read(&self, start: Bound<K>, end: Bound<K>, limit: Option<usize>) -> anyhow::Result<Vec<RecordBatch>> {
+        let mut batches = vec![];
+        if is_empty(&start, &end) {
+            return Ok(batches);
+        }
+        let tree = self.tree.read().map_err(|_| Error::LockPoisoned)?;
+        let mut rows = vec![];
+        let mut batch = |rows: Vec<(K, V)>| -> anyhow::Result<()> {
+            let (keys, values): (Vec<_>, Vec<_>) = rows.into_iter().unzip();
+            let mut columns = vec![K::array(keys)];
+            columns.extend(V::arrays(values));
+            batches.push(RecordBatch::try_new(self.schema.clone(), columns)?);
+            Ok(())
+        };
+        for entry in tree.try_range((start, end)).take(limit.unwrap_or(usize::MAX)) {
+            rows.push(entry?);
+            if rows.len() == BATCH_ROWS {
+                batch(std::mem::take(&mut rows))?;
+            }
+        }
+        if !rows.is_empty() {
+            batch(rows)?;
+        }
+        Ok(batches)
+    }
+}
+
+impl<K, V, E> fmt::Debug for TreeTable<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TreeTable").field("schema", &self.schema).finish()
+    }
+}
+
+#[async_trait]
+impl<K, V, E> TableProvider for TreeTable<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>> + Send + Sync + 'static,
+    K: Ord + Clone + ArrowColumn + Send + Sync + 'static,
+    V: Clone + ArrowRow + Send + Sync + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    // 下推的条件换成范围之后是精确的, DataFusion 不用再过滤一遍
+    fn supports_filters_pushdown(&self, filters: &[&Expr]) -> Result<Vec<TableProviderFilterPushDown>> {
+        Ok(filters
+            .iter()
+            .map(|filter| match self.key_range(filter) {
+                Some(_) => TableProviderFilterPushDown::Exact,
+                None => TableProviderFilterPushDown::Unsupported,
+            })
+            .collect())
+    }
+
+    // 所有下推的条件取交集, 只扫一次; 有 limit 时读够就停
+    async fn scan(
+        &self,
+        _state: &dyn Session,
+        projection: Option<&Vec<usize>>,
+        filters: &[Expr],
+        limit: Option<usize>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        let (mut start, mut end) = (Bound::Unbounded, Bound::Unbounded);
+        for (low, high) in filters.iter().filter_map(|filter| self.key_range(filter)) {
+            start = tighter(start, low, Ordering::Greater);
+            end = tighter(end, high, Ordering::Less);
+        }
+        let batches = self.read(start, end, limit).map_err(|e| DataFusionError::External(e.into()))?;
+        Ok(Arc::new(MemoryExec::try_new(&[batches], self.schema.clone(), projection.cloned())?))
+    }
+}
+
+// 两个下界里大的那个 (prefer 是 Greater), 或者两个上界里小的那个 (prefer 是 Less)
+fn tighter<K: Ord>(a: Bound<K>, b: Bound<K>, prefer: Ordering) -> Bound<K> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => match x.cmp(y) {
+            Ordering::Equal if matches!(a, Bound::Excluded(_)) => a,
+            Ordering::Equal => b,
+            order if order == prefer => a,
+            _ => b,
+        },
+    }
+}
+
+fn is_empty<K: Ord>(start: &Bound<K>, end: &Bound<K>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::{Array, UInt64Array};
+    use datafusion::{
+        logical_expr::{col, lit},
+        physical_plan::displayable,
+        prelude::SessionContext,
+    };
+
+    use crate::{block::MemoryBlockEngine, order::KeyOrder};
+
+    use super::*;
+
+    fn keys(batches: &[RecordBatch]) -> Vec<u64> {
+        let mut keys = vec![];
+        for batch in batches {
+            let column = batch.column(0).as_any().downcast_ref::<UInt64Array>().unwrap();
+            keys.extend((0..column.len()).map(|row| column.value(row)));
+        }
+        keys
+    }
+
+    #[test]
+    fn test_tree_table() {
+        let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        for i in 0..20000u64 {
+            let score = (i % 3 != 0).then_some(i as f64 / 2.0);
+            tree.insert(i, (format!("name-{}", i), score)).unwrap();
+        }
+        let tree = Arc::new(RwLock::new(tree));
+        assert!(TreeTable::new(tree.clone(), "id", &["name"]).is_err());
+        let table = Arc::new(TreeTable::new(tree.clone(), "id", &["name", "score"]).unwrap());
+
+        // key 列上的比较可以下推, 别的列和别的比较不行
+        let id = || col("id");
+        let pushdown = table
+            .supports_filters_pushdown(&[
+                &id().gt_eq(lit(10u64)),
+                &lit(10u64).lt(id()),
+                &id().between(lit(1u64), lit(2u64)),
+                &id().not_eq(lit(10u64)),
+                &id().lt(lit("x")),
+                &col("score").gt(lit(1.0)),
+            ])
+            .unwrap();
+        use TableProviderFilterPushDown::{Exact, Unsupported};
+        assert_eq!(pushdown, vec![Exact, Exact, Exact, Unsupported, Unsupported, Unsupported]);
+        // 倒过来的 key 顺序里 id >= 10 不是一段范围
+        let reversed = BPlusTree::<u64, String, _>::with_order(4, MemoryBlockEngine::new(), KeyOrder::new(|a: &u64, b: &u64| b.cmp(a))).unwrap();
+        let reversed = TreeTable::new(Arc::new(RwLock::new(reversed)), "id", &["name"]).unwrap();
+        assert_eq!(reversed.supports_filters_pushdown(&[&id().gt_eq(lit(10u64))]).unwrap(), vec![Unsupported]);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let ctx = SessionContext::new();
+            ctx.register_table("t", table.clone()).unwrap();
+            let sql = |sql: &'static str| {
+                let ctx = ctx.clone();
+                async move { ctx.sql(sql).await.unwrap().collect().await.unwrap() }
+            };
+
+            // 下推之后 DataFusion 不再过滤
+            let query = "SELECT id, name FROM t WHERE id >= 100 AND id < 105";
+            let plan = ctx.sql(query).await.unwrap().create_physical_plan().await.unwrap();
+            assert!(!displayable(plan.as_ref()).indent(true).to_string().contains("FilterExec"));
+            let batches = sql(query).await;
+            assert_eq!(keys(&batches), vec![100, 101, 102, 103, 104]);
+            assert_eq!(batches[0].num_columns(), 2);
+            assert_eq!(keys(&sql("SELECT id FROM t WHERE 19995 < id").await), vec![19996, 19997, 19998, 19999]);
+            assert_eq!(keys(&sql("SELECT id FROM t WHERE id BETWEEN 7 AND 9 AND id <> 8").await), vec![7, 9]);
+            assert!(keys(&sql("SELECT id FROM t WHERE id > 10 AND id < 5").await).is_empty());
+            // 和 value 列上的条件一起用, value 列交给 DataFusion 过滤
+            let batches = sql("SELECT id FROM t WHERE id <= 12 AND score IS NULL").await;
+            assert_eq!(keys(&batches), vec![0, 3, 6, 9, 12]);
+            let batches = sql("SELECT count(*) FROM t WHERE id = 15000").await;
+            assert_eq!(batches[0].column(0).as_any().downcast_ref::<arrow_array::Int64Array>().unwrap().value(0), 1);
+
+            // 范围扫描只读范围里的条目, limit 读够就停
+            let state = ctx.state();
+            let filters = [id().gt(lit(50u64)), id().lt_eq(lit(60u64))];
+            let plan = table.scan(&state, None, &filters, None).await.unwrap();
+            assert_eq!(keys(&datafusion::physical_plan::collect(plan, ctx.task_ctx()).await.unwrap()), (51..=60).collect::<Vec<_>>());
+            let plan = table.scan(&state, None, &[], Some(3)).await.unwrap();
+            assert_eq!(keys(&datafusion::physical_plan::collect(plan, ctx.task_ctx()).await.unwrap()), vec![0, 1, 2]);
+            let plan = table.scan(&state, Some(&vec![0]), &[], None).await.unwrap();
+            let batches = datafusion::physical_plan::collect(plan, ctx.task_ctx()).await.unwrap();
+            assert_eq!(batches.iter().map(RecordBatch::num_rows).sum::<usize>(), 20000);
+            assert!(batches.iter().all(|batch| batch.num_rows() <= BATCH_ROWS && batch.num_columns() == 1));
+
+            // 写进树的马上能查到
+            tree.write().unwrap().insert(30000, ("late".to_string(), None)).unwrap();
+            assert_eq!(keys(&sql("SELECT id FROM t WHERE id > 19999").await), vec![30000]);
+        });
+    }
+}
@@ -0,0 +1,386 @@
+use anyhow::{anyhow, Ok, Result};
+use std::{
+    any::type_name,
+    collections::BTreeMap,
+    marker::PhantomData,
+    ops::RangeBounds,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{
+    capacity::NodeCapacity,
+    error::Error,
+    file::{FileBlockEngine, FileOptions, PAGE_HEADER_LEN},
+    group::{GroupEngine, TreeGroup},
+    order::KeyOrder,
+    tree::BPlusTree,
+};
+
+type RawTree = BPlusTree<Vec<u8>, Vec<u8>, GroupEngine<Vec<u8>, Vec<u8>>>;
+
+// 结点里除了 cell 之外的字段: way, is_leaf, 各个 Vec 的长度, prev / next 和 high_key, 留得宽一点
+const NODE_OVERHEAD: usize = 128;
+
+// Db 里存的 key 和 value 和字节互转
+pub trait Value: Sized {
+    fn encode(&self) -> Vec<u8>;
+    fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+// 编码之后按字节比较的顺序和 Ord 一样, 树直接按字节排序
+pub trait Key: Value + Ord {}
+
+macro_rules! impl_unsigned {
+    ($($t:ty),*) => {$(
+        impl Value for $t {
+            fn encode(&self) -> Vec<u8> {
+                self.to_be_bytes().to_vec()
+            }
+
+            fn decode(bytes: &[u8]) -> Result<Self> {
+                Ok(<$t>::from_be_bytes(bytes.try_into()?))
+            }
+        }
+
+        impl Key for $t {}
+    )*};
+}
+
+// 符号位取反之后负数排在正数前面
+macro_rules! impl_signed {
+    ($($t:ty),*) => {$(
+        impl Value for $t {
+            fn encode(&self) -> Vec<u8> {
+                (*self ^ <$t>::MIN).to_be_bytes().to_vec()
+            }
+
+            fn decode(bytes: &[u8]) -> Result<Self> {
+                Ok(<$t>::from_be_bytes(bytes.try_into()?) ^ <$t>::MIN)
+            }
+        }
+
+        impl Key for $t {}
+    )*};
+}
+
+impl_unsigned!(u8, u16, u32, u64, u128);
+impl_signed!(i8, i16, i32, i64, i128);
+
+impl Value for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(String::from_utf8(bytes.to_vec())?)
+    }
+}
+
+impl Key for String {}
+
+impl Value for Vec<u8> {
+    fn encode(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Key for Vec<u8> {}
+
+// 一个文件里几棵有名字的树, engine / buffer pool / wal / catalog 都由 Db 装好
+// 树按编码之后的字节排序, 见 Key, 文件里不用记比较函数
+// 每棵树开着 shadow paging, 修改在 flush 之前都不会落盘, flush 把所有树一起提交, 没 flush 的修改关掉之后就没了
+pub struct Db {
+    group: TreeGroup<Vec<u8>, Vec<u8>>,
+    way: usize,
+    capacity: NodeCapacity<Vec<u8>, Vec<u8>>,
+    trees: Mutex<BTreeMap<String, Slot>>,
+    // 同时只有一个事务
+    transaction: Mutex<()>,
+}
+
+struct Slot {
+    types: &'static str,
+    tree: Arc<Mutex<RawTree>>,
+}
+
+impl Db {
+    // 默认开着 wal, 一次 flush 里所有树要么都提交了要么都没有
+    pub fn open(path: impl AsRef<Path>) -> Result<Db> {
+        Self::open_with_options(path, FileOptions { wal: true, ..FileOptions::default() })
+    }
+
+    // 文件不存在时新建
+    pub fn open_with_options(path: impl AsRef<Path>, options: FileOptions) -> Result<Db> {
+        let path = path.as_ref();
+        let engine = match path.exists() {
+            true => FileBlockEngine::open(path, options)?,
+            false => FileBlockEngine::create(path, options)?,
+        };
+        // 按字节分裂, 结点一定放得进一页; 每个 cell 至少有两个 8 字节的长度, way 不会先到
+        let bytes = engine.page_size() - PAGE_HEADER_LEN - NODE_OVERHEAD;
+        let capacity = NodeCapacity::bytes(bytes, |key: &Vec<u8>, value: Option<&Vec<u8>>| match value {
+            Some(value) => 8 + key.len() + 8 + value.len(),
+            // key, pointer, count 和 maxes 里的 None
+            None => 8 + key.len() + 8 + 8 + 1,
+        });
+        Ok(Db {
+            group: TreeGroup::new(engine),
+            way: bytes / 16,
+            capacity,
+            trees: Mutex::new(BTreeMap::new()),
+            transaction: Mutex::new(()),
+        })
+    }
+
+    // 打开名字叫 name 的树, 还没有时新建一棵, flush 之后才记进文件
+    // 同一个名字在一个 Db 里只有一棵, 再次打开拿到的是同一棵树, K 和 V 要和第一次一样
+    pub fn tree<K: Key, V: Value>(&self, name: &str) -> Result<Tree<K, V>> {
+        let types = type_name::<(K, V)>();
+        let mut trees = self.trees()?;
+        if let Some(slot) = trees.get(name) {
+            if slot.types != types {
+                return Err(anyhow!("tree {} is open as {}, not {}.", name, slot.types, types));
+            }
+            return Ok(Tree { name: name.to_string(), tree: slot.tree.clone(), _marker: PhantomData });
+        }
+        let mut tree = self.group.tree_with_order(name, self.way, KeyOrder::default(), self.capacity.clone())?;
+        tree.enable_shadow_paging()?;
+        let tree = Arc::new(Mutex::new(tree));
+        trees.insert(name.to_string(), Slot { types, tree: tree.clone() });
+        Ok(Tree { name: name.to_string(), tree, _marker: PhantomData })
+    }
+
+    // 文件里提交过的树和这次新建还没 flush 的树
+    pub fn tree_names(&self) -> Result<Vec<String>> {
+        let mut names = self.group.names()?;
+        for name in self.trees()?.keys() {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    // 所有打开过的树的修改一起提交
+    pub fn flush(&self) -> Result<()> {
+        let trees = self.trees()?;
+        for slot in trees.values() {
+            lock(&slot.tree)?.commit()?;
+        }
+        self.group.commit()
+    }
+
+    // flush 之后把日志里的页写回数据文件, 日志清空
+    pub fn checkpoint(&self) -> Result<()> {
+        let trees = self.trees()?;
+        for slot in trees.values() {
+            lock(&slot.tree)?.commit()?;
+        }
+        self.group.checkpoint()
+    }
+
+    // 开始前先 flush 之前的修改; f 成功时它对所有树的修改一起 flush, 失败时全部回滚, 返回 f 的错误
+    // 事务之间互斥, 但不挡事务外的写: 这期间别的线程对这些树的修改也跟着提交或者回滚
+    // f 里不要调用 flush, 否则一半的修改会先提交
+    pub fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Db) -> Result<T>,
+    {
+        let _transaction = self.transaction.lock().map_err(|_| Error::LockPoisoned)?;
+        self.flush()?;
+        match f(self) {
+            Result::Ok(ret) => {
+                self.flush()?;
+                Ok(ret)
+            }
+            Err(e) => {
+                self.rollback()?;
+                Err(e)
+            }
+        }
+    }
+
+    // 扔掉上一次 flush 之后所有树的修改
+    pub fn rollback(&self) -> Result<()> {
+        let trees = self.trees()?;
+        for slot in trees.values() {
+            let mut tree = lock(&slot.tree)?;
+            tree.rollback()?;
+            // 回到了提交的 root, 重新暂存一次, group 才不会把它当成改了一半的树
+            tree.flush()?;
+        }
+        Ok(())
+    }
+
+    fn trees(&self) -> Result<MutexGuard<'_, BTreeMap<String, Slot>>> {
+        Ok(self.trees.lock().map_err(|_| Error::LockPoisoned)?)
+    }
+}
+
+fn lock(tree: &Mutex<RawTree>) -> Result<MutexGuard<'_, RawTree>> {
+    Ok(tree.lock().map_err(|_| Error::LockPoisoned)?)
+}
+
+// Db 里一棵树的句柄, clone 出来的和 Db::tree 再次拿到的都是同一棵树
+// 每个操作拿一次树的锁, 多个线程可以共用
+pub struct Tree<K, V> {
+    name: String,
+    tree: Arc<Mutex<RawTree>>,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V> Clone for Tree<K, V> {
+    fn clone(&self) -> Self {
+        Tree { name: self.name.clone(), tree: self.tree.clone(), _marker: PhantomData }
+    }
+}
+
+impl<K: Key, V: Value> Tree<K, V> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        lock(&self.tree)?.search(&key.encode())?.map(|value| V::decode(&value)).transpose()
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        lock(&self.tree)?.contains_key(&key.encode())
+    }
+
+    // 返回旧的 value; 编码之后太大放不进一页的 1/4 时报错
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>> {
+        lock(&self.tree)?.insert(key.encode(), value.encode())?.map(|value| V::decode(&value)).transpose()
+    }
+
+    pub fn remove(&self, key: &K) -> Result<Option<V>> {
+        lock(&self.tree)?.delete(&key.encode())?.map(|value| V::decode(&value)).transpose()
+    }
+
+    pub fn len(&self) -> Result<usize> {
+        Ok(lock(&self.tree)?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(lock(&self.tree)?.is_empty())
+    }
+
+    // 拿着树的锁读完整个范围
+    pub fn range<R>(&self, range: R) -> Result<Vec<(K, V)>>
+    where
+        R: RangeBounds<K>,
+    {
+        let mut entries = vec![];
+        self.scan(range, |key, value| {
+            entries.push((key, value));
+            Ok(true)
+        })?;
+        Ok(entries)
+    }
+
+    // 按顺序把范围里的条目一条条交给 f, f 返回 false 时停下, 不用把整个范围放进内存
+    // 整个过程拿着树的锁, f 里不能再用这棵树
+    pub fn scan<R, F>(&self, range: R, mut f: F) -> Result<()>
+    where
+        R: RangeBounds<K>,
+        F: FnMut(K, V) -> Result<bool>,
+    {
+        let range = (range.start_bound().map(|key| key.encode()), range.end_bound().map(|key| key.encode()));
+        let tree = lock(&self.tree)?;
+        for entry in tree.try_range(range) {
+            let (key, value) = entry?;
+            if !f(K::decode(&key)?, V::decode(&value)?)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fsck, wal::Wal};
+
+    use super::*;
+
+    #[test]
+    fn test_key_encoding() {
+        let mut keys = vec![i64::MIN, -300, -1, 0, 1, 256, i64::MAX];
+        let mut encoded: Vec<_> = keys.iter().map(|key| key.encode()).collect();
+        encoded.sort();
+        let decoded: Vec<_> = encoded.iter().map(|bytes| i64::decode(bytes).unwrap()).collect();
+        keys.sort();
+        assert_eq!(decoded, keys);
+        assert!(u32::decode(&[1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_db() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-db-{}.db", std::process::id()));
+        let db = Db::open(&path).unwrap();
+        let users = db.tree::<u64, String>("users").unwrap();
+        let emails = db.tree::<String, u64>("emails").unwrap();
+        // 同一个名字换了类型
+        assert!(db.tree::<String, String>("users").is_err());
+        // 名字太长, superblock 的 catalog 里放不下
+        assert!(db.tree::<u64, u64>(&"x".repeat(250)).is_err());
+        for i in 0..500u64 {
+            users.insert(i, format!("user-{}", i)).unwrap();
+            emails.insert(format!("user-{}@example.com", i), i).unwrap();
+        }
+        assert_eq!(users.insert(0, "user-0".to_string()).unwrap(), Some("user-0".to_string()));
+        db.flush().unwrap();
+
+        // 失败的事务两棵树都回滚
+        let ret = db.transaction(|db| {
+            let users = db.tree::<u64, String>("users")?;
+            users.remove(&0)?;
+            db.tree::<String, u64>("emails")?.remove(&"user-0@example.com".to_string())?;
+            Err::<(), _>(anyhow!("abort"))
+        });
+        assert!(ret.is_err());
+        assert_eq!((users.len().unwrap(), emails.len().unwrap()), (500, 500));
+        db.transaction(|db| {
+            db.tree::<u64, String>("users")?.insert(500, "user-500".to_string())?;
+            db.tree::<String, u64>("emails")?.insert("user-500@example.com".to_string(), 500)?;
+            Ok(())
+        })
+        .unwrap();
+        // 没 flush 的修改关掉之后就没了
+        users.insert(501, "user-501".to_string()).unwrap();
+        drop((users, emails, db));
+
+        let db = Db::open(&path).unwrap();
+        assert_eq!(db.tree_names().unwrap(), vec!["emails", "users"]);
+        let users = db.tree::<u64, String>("users").unwrap();
+        assert_eq!(users.len().unwrap(), 501);
+        assert_eq!(users.get(&500).unwrap(), Some("user-500".to_string()));
+        assert_eq!(users.get(&501).unwrap(), None);
+        let keys: Vec<_> = users.range(254..258).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![254, 255, 256, 257]);
+        let mut keys = vec![];
+        users
+            .scan(254.., |key, _| {
+                keys.push(key);
+                Ok(keys.len() < 3)
+            })
+            .unwrap();
+        assert_eq!(keys, vec![254, 255, 256]);
+        let emails = db.tree::<String, u64>("emails").unwrap();
+        assert_eq!(emails.get(&"user-42@example.com".to_string()).unwrap(), Some(42));
+        drop((users, emails));
+        db.checkpoint().unwrap();
+        drop(db);
+
+        let report = fsck::verify::<Vec<u8>, Vec<u8>>(&path, fsck::VerifyMode::Quick).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(Wal::path(&path)).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{anyhow, Ok, Result};
+use std::{
+    alloc::{self, Layout},
+    fs::File,
+    ops::{Deref, DerefMut},
+    path::Path,
+    ptr::NonNull,
+};
+
+// O_DIRECT 要求缓冲区地址, 文件里的偏移和长度都对齐到设备的逻辑块, 按常见的最大值 4096 对齐
+pub(crate) const DIRECT_ALIGN: usize = 4096;
+
+// 按 DIRECT_ALIGN 对齐的一段内存, 初始化成 0
+pub(crate) struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// 和 Vec<u8> 一样是独占的一块内存
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    pub(crate) fn new(len: usize) -> Self {
+        let layout = Self::layout(len);
+        // SAFETY: layout 至少有 DIRECT_ALIGN 字节, 不是 0
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        AlignedBuf { ptr, len }
+    }
+
+    pub(crate) fn from_slice(buf: &[u8]) -> Self {
+        let mut aligned = Self::new(buf.len());
+        aligned.copy_from_slice(buf);
+        aligned
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len.max(1).next_multiple_of(DIRECT_ALIGN), DIRECT_ALIGN).unwrap()
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: ptr 指向 new 分配的至少 len 字节, 一直有效到 drop
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: 同 deref, &mut self 保证没有别的引用
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        // SAFETY: 和分配时用的是同一个 layout
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
+
+// 每页的偏移和长度都是页大小的倍数, 页大小对齐了就都对齐了
+pub(crate) fn check_page_size(page_size: usize) -> Result<()> {
+    if !page_size.is_multiple_of(DIRECT_ALIGN) {
+        return Err(anyhow!("direct I/O needs a page size that is a multiple of {}, got {}.", DIRECT_ALIGN, page_size));
+    }
+    Ok(())
+}
+
+// 同一个文件再开一个绕过页缓存的句柄, 只用来读写整页
+#[cfg(target_os = "linux")]
+pub(crate) fn open_direct(path: &Path) -> Result<File> {
+    use std::{fs::OpenOptions, os::unix::fs::OpenOptionsExt};
+
+    Ok(OpenOptions::new().read(true).write(true).custom_flags(libc::O_DIRECT).open(path)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn open_direct(_path: &Path) -> Result<File> {
+    Err(anyhow!("direct I/O is only supported on Linux."))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use crate::{
+        file::{FileBlockEngine, FileOptions},
+        tree::{BPlusTree, BPlusTreeNode},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_direct_io() {
+        assert_eq!(AlignedBuf::new(100).as_ptr() as usize % DIRECT_ALIGN, 0);
+        let path = std::env::temp_dir().join(format!("bplus-tree-direct-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, direct_io: true, ..FileOptions::default() };
+        assert!(FileBlockEngine::<u32>::create(&path, options).is_err());
+
+        // pool 很小, 大部分 block 都要经过不走页缓存的淘汰和重新读入
+        let options = FileOptions { pool_size: 4, direct_io: true, ..FileOptions::default() };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, String>>::create(&path, options).unwrap();
+        let mut tree = BPlusTree::new(16, engine).unwrap();
+        for i in 0..1000 {
+            tree.insert(i, format!("value {}", i)).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, String>>::open(&path, options).unwrap();
+        let tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.search(&999).unwrap(), Some("value 999".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{Ok, Result};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+// 页号, 长度, crc32
+const RECORD_HEADER_LEN: usize = 8 + 4 + 4;
+
+// 双写缓冲, 不开 wal 时页是直接覆盖写的, 写到一半崩溃会留下半新半旧的页
+// 覆盖之前先把整页追加到旁边的 .dwb 文件里并 fsync, 数据文件 fsync 之后再清空
+// 打开时缓冲里还有的页, 数据文件里那一页校验和对不上就用缓冲里的恢复
+pub(crate) struct DoubleWrite {
+    file: File,
+    end: u64,
+    // 追加过的字节数, 清空了也不减
+    written: u64,
+}
+
+impl DoubleWrite {
+    pub(crate) fn path(data: &Path) -> PathBuf {
+        let mut path = data.as_os_str().to_owned();
+        path.push(".dwb");
+        path.into()
+    }
+
+    pub(crate) fn create(data: &Path) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(Self::path(data))?;
+        Ok(DoubleWrite { file, end: 0, written: 0 })
+    }
+
+    // 返回缓冲里每一页最后写的那份, 校验和不对的是追加到一半的, 丢掉
+    pub(crate) fn open(data: &Path) -> Result<(Self, HashMap<usize, Vec<u8>>)> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(Self::path(data))?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        let mut pages = HashMap::new();
+        let mut rest = &buf[..];
+        while let Some((page_no, image)) = read_record(&mut rest) {
+            pages.insert(page_no, image);
+        }
+        Ok((DoubleWrite { file, end: buf.len() as u64, written: 0 }, pages))
+    }
+
+    // 返回之后才能覆盖数据文件里的这一页
+    pub(crate) fn write(&mut self, page_no: usize, image: &[u8]) -> Result<()> {
+        self.write_batch(&[(page_no, image)])
+    }
+
+    // 一批页只 fsync 一次
+    pub(crate) fn write_batch(&mut self, pages: &[(usize, &[u8])]) -> Result<()> {
+        let mut records = vec![];
+        for &(page_no, image) in pages {
+            let start = records.len();
+            records.extend_from_slice(&(page_no as u64).to_le_bytes());
+            records.extend_from_slice(&(image.len() as u32).to_le_bytes());
+            records.extend_from_slice(&[0; 4]);
+            records.extend_from_slice(image);
+            let checksum = record_checksum(&records[start..]);
+            records[start + 12..start + 16].copy_from_slice(&checksum.to_le_bytes());
+        }
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&records)?;
+        self.file.sync_data()?;
+        self.end += records.len() as u64;
+        self.written += records.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn written(&self) -> u64 {
+        self.written
+    }
+
+    // 数据文件 fsync 之后调用
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        if self.end > 0 {
+            self.file.set_len(0)?;
+            self.file.sync_data()?;
+            self.end = 0;
+        }
+        Ok(())
+    }
+}
+
+fn read_record(rest: &mut &[u8]) -> Option<(usize, Vec<u8>)> {
+    let header = rest.get(..RECORD_HEADER_LEN)?;
+    let page_no = u64::from_le_bytes(header[0..8].try_into().ok()?) as usize;
+    let len = u32::from_le_bytes(header[8..12].try_into().ok()?) as usize;
+    let stored = u32::from_le_bytes(header[12..16].try_into().ok()?);
+    let record = rest.get(..RECORD_HEADER_LEN + len)?;
+    if record_checksum(record) != stored {
+        return None;
+    }
+    *rest = &rest[RECORD_HEADER_LEN + len..];
+    Some((page_no, record[RECORD_HEADER_LEN..].to_vec()))
+}
+
+// 跳过存校验和的 12..16
+fn record_checksum(record: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&record[..12]);
+    hasher.update(&record[16..]);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::{BlockEngine, TreeMeta},
+        file::{FileBlockEngine, FileOptions},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_torn_page() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-doublewrite-{}.db", std::process::id()));
+        for double_write in [true, false] {
+            let options = FileOptions { page_size: 256, pool_size: 2, double_write, ..FileOptions::default() };
+            let mut engine = FileBlockEngine::<u64>::create(&path, options).unwrap();
+            for i in 0..10 {
+                engine.alloc_write(i).unwrap();
+            }
+            engine.flush(TreeMeta { root: 0, way: 4, len: 0, persistent: false, order: 0 }).unwrap();
+            // pool 只有两个 frame, block 0 被淘汰时覆盖写回文件, 然后崩溃了
+            for i in 0..10 {
+                **engine.fetch_write(i).unwrap() = Some(i as u64 + 100);
+            }
+            drop(engine);
+
+            // block 0 在第 4 页, 后半页还是旧的
+            let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+            file.seek(SeekFrom::Start(4 * 256 + 128)).unwrap();
+            file.write_all(&[0xee; 128]).unwrap();
+            drop(file);
+
+            let engine = FileBlockEngine::<u64>::open(&path, options).unwrap();
+            let block = engine.fetch_read(0).map(|block| **block);
+            if double_write {
+                assert_eq!(block.unwrap(), Some(100));
+                assert_eq!(std::fs::metadata(DoubleWrite::path(&path)).unwrap().len(), 0);
+            } else {
+                assert!(block.is_err());
+            }
+        }
+        // 不开双写时新建文件会删掉旧的缓冲
+        assert!(!DoubleWrite::path(&path).exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{Ok, Result};
+use std::{fmt::Debug, io::Write};
+
+use crate::{
+    block::{BlockEngine, BlockId},
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 树的形状, 不带 block id, 同样的数据按同样的顺序插入得到的结果相同, 可以直接在测试里比较
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebugTree<K, V> {
+    Inner { keys: Vec<K>, children: Vec<DebugTree<K, V>> },
+    Leaf { keys: Vec<K>, values: Vec<V> },
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 每个结点一行, 子结点多缩进两格, 读不出来的 block 打印错误后跳过
+    pub fn dump(&self, w: &mut impl Write) -> Result<()>
+    where
+        K: Debug,
+        V: Debug,
+    {
+        self.dump_helper(w, self.root, 0)
+    }
+
+    fn dump_helper(&self, w: &mut impl Write, block_id: BlockId, depth: usize) -> Result<()>
+    where
+        K: Debug,
+        V: Debug,
+    {
+        let indent = " ".repeat(depth * 2);
+        let read = match self.engine.fetch_read(block_id) {
+            Result::Ok(read) => read,
+            Err(e) => {
+                writeln!(w, "{}<{}>", indent, e)?;
+                return Ok(());
+            }
+        };
+        let Some(node) = read.as_ref() else {
+            writeln!(w, "{}<{}>", indent, Error::EmptyBlock(block_id))?;
+            return Ok(());
+        };
+        if node.is_leaf() {
+            writeln!(w, "{}Leaf: {:?} values: {:?}", indent, node.keys, node.values)?;
+        } else {
+            writeln!(w, "{}Inner: {:?}", indent, node.keys)?;
+            for &child in &node.pointers {
+                self.dump_helper(w, child, depth + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_debug_tree(&self) -> Result<DebugTree<K, V>> {
+        self.debug_tree_helper(self.root)
+    }
+
+    fn debug_tree_helper(&self, block_id: BlockId) -> Result<DebugTree<K, V>> {
+        let read = self.engine.fetch_read(block_id)?;
+        let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+        if node.is_leaf() {
+            return Ok(DebugTree::Leaf { keys: node.keys.clone(), values: node.values.clone() });
+        }
+        let children = node.pointers.iter().map(|&child| self.debug_tree_helper(child)).collect::<Result<_>>()?;
+        Ok(DebugTree::Inner { keys: node.keys.clone(), children })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_debug_tree() {
+        let mut tree = BPlusTree::new(2, MemoryBlockEngine::new()).unwrap();
+        for i in 1..=4 {
+            tree.insert(i, i * 10).unwrap();
+        }
+        let leaf = |keys: Vec<i32>| DebugTree::Leaf { values: keys.iter().map(|k| k * 10).collect(), keys };
+        assert_eq!(
+            tree.to_debug_tree().unwrap(),
+            DebugTree::Inner { keys: vec![2, 3], children: vec![leaf(vec![1]), leaf(vec![2]), leaf(vec![3, 4])] }
+        );
+        tree.delete(&1).unwrap();
+        let mut out = vec![];
+        tree.dump(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "Inner: [3]\n  Leaf: [2] values: [20]\n  Leaf: [3, 4] values: [30, 40]\n");
+    }
+}
@@ -0,0 +1,247 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, Ok, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{
+    block::{Block, BlockEngine, BlockEngineStats, BlockId, BlockReadGuard, BlockWriteGuard, TreeMeta},
+    codec::{BincodeCodec, NodeCodec},
+    error::Error,
+    pool::{BufferPool, PageStore},
+    replacement::Replacement,
+};
+
+const NONCE_LEN: usize = 12;
+
+// 加密之后交给 inner 存, inner 里只有密文
+// 解密后的 block 缓存在自己的 buffer pool 里, 被淘汰或者 flush 时才重新加密写给 inner
+// 每次加密用一个随机的 nonce, 和密文存在一起; block id 作为附加数据, 换了位置的密文解不开
+pub struct EncryptedBlockEngine<E, B, C = BincodeCodec> {
+    pages: Sealed<E, C>,
+    pool: BufferPool<B>,
+}
+
+struct Sealed<E, C> {
+    // PageStore 只给 &self, 写 inner 要加锁
+    inner: Mutex<E>,
+    cipher: Aes256Gcm,
+    codec: C,
+}
+
+impl<E, C> Sealed<E, C> {
+    fn seal(&self, block_id: BlockId, plain: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = (block_id as u64).to_le_bytes();
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, Payload { msg: plain, aad: &aad })
+            .map_err(|_| anyhow!("failed to encrypt block {}.", block_id))?;
+        Ok([nonce.as_slice(), &sealed].concat())
+    }
+
+    fn open(&self, block_id: BlockId, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(Error::Corrupted(block_id, "sealed block is truncated".to_string()).into());
+        }
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+        let aad = (block_id as u64).to_le_bytes();
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad: &aad })
+            .map_err(|_| anyhow!("failed to decrypt block {}, the key may be wrong.", block_id))
+    }
+}
+
+impl<E, B, C> PageStore<B> for Sealed<E, C>
+where
+    E: BlockEngine<Item = Vec<u8>>,
+    C: NodeCodec<B>,
+{
+    fn read_page(&self, block_id: BlockId) -> Result<Option<B>> {
+        let inner = self.inner.lock().map_err(|_| Error::LockPoisoned)?;
+        let block = inner.fetch_read(block_id)?;
+        match block.content.as_ref() {
+            Some(sealed) => Ok(Some(self.codec.decode(&self.open(block_id, sealed)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn write_page(&self, block_id: BlockId, content: Option<&B>) -> Result<()> {
+        let sealed = match content {
+            Some(content) => {
+                let mut plain = vec![];
+                self.codec.encode(content, &mut plain)?;
+                Some(self.seal(block_id, &plain)?)
+            }
+            None => None,
+        };
+        let inner = self.inner.lock().map_err(|_| Error::LockPoisoned)?;
+        inner.fetch_write(block_id)?.content = sealed;
+        Ok(())
+    }
+}
+
+impl<E, B> EncryptedBlockEngine<E, B>
+where
+    E: BlockEngine<Item = Vec<u8>>,
+    B: Serialize + DeserializeOwned,
+{
+    // key 是 AES-256-GCM 的 32 字节密钥, 每次打开都要给同一个
+    // pool_size 是缓存的明文 block 数
+    pub fn new(inner: E, key: &[u8; 32], pool_size: usize) -> Result<Self> {
+        Self::with_codec(inner, key, pool_size, BincodeCodec)
+    }
+}
+
+impl<E, B, C> EncryptedBlockEngine<E, B, C>
+where
+    E: BlockEngine<Item = Vec<u8>>,
+    C: NodeCodec<B>,
+{
+    pub fn with_codec(inner: E, key: &[u8; 32], pool_size: usize, codec: C) -> Result<Self> {
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+        Ok(EncryptedBlockEngine {
+            pages: Sealed { inner: Mutex::new(inner), cipher, codec },
+            pool: BufferPool::new(pool_size, Replacement::Lru)?,
+        })
+    }
+
+    pub fn into_inner(self) -> Result<E> {
+        self.pool.flush(&self.pages)?;
+        self.pages.inner.into_inner().map_err(|_| Error::LockPoisoned.into())
+    }
+}
+
+impl<E, B, C> BlockEngine for EncryptedBlockEngine<E, B, C>
+where
+    E: BlockEngine<Item = Vec<u8>>,
+    C: NodeCodec<B>,
+{
+    type Item = B;
+
+    fn alloc_block(&self) -> Result<BlockId> {
+        let block_id = self.pages.inner.lock().map_err(|_| Error::LockPoisoned)?.alloc_block()?;
+        self.pool.insert_new(block_id, &self.pages)?;
+        Ok(block_id)
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, B>> {
+        self.pool.fetch_read(block_id, &self.pages)
+    }
+
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, B>> {
+        self.pool.fetch_write(block_id, &self.pages)
+    }
+
+    fn delete(&self, block_id: BlockId) -> Result<Option<B>> {
+        let cached = self.pool.remove(block_id)?;
+        let sealed = self.pages.inner.lock().map_err(|_| Error::LockPoisoned)?.delete(block_id)?;
+        match (cached, sealed) {
+            (Some(content), _) => Ok(content),
+            (None, Some(sealed)) => Ok(Some(self.pages.codec.decode(&self.pages.open(block_id, &sealed)?)?)),
+            (None, None) => Ok(None),
+        }
+    }
+
+    // 和 FileBlockEngine 一样, dirty 的 block 在被淘汰或者 flush 时才加密写回
+    fn write_back(_block_id: BlockId, _block: &Block<B>) {}
+
+    fn load_meta(&self) -> Option<TreeMeta> {
+        self.pages.inner.lock().ok()?.load_meta()
+    }
+
+    fn set_user_metadata(&mut self, bytes: &[u8]) -> Result<()> {
+        self.pages.inner.get_mut().map_err(|_| Error::LockPoisoned)?.set_user_metadata(bytes)
+    }
+
+    fn user_metadata(&self) -> Result<Vec<u8>> {
+        self.pages.inner.lock().map_err(|_| Error::LockPoisoned)?.user_metadata()
+    }
+
+    fn stage_snapshots(&self, snapshots: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        self.pages.inner.lock().map_err(|_| Error::LockPoisoned)?.stage_snapshots(snapshots)
+    }
+
+    fn load_snapshots(&self) -> Result<BTreeMap<String, TreeMeta>> {
+        self.pages.inner.lock().map_err(|_| Error::LockPoisoned)?.load_snapshots()
+    }
+
+    fn flush(&mut self, meta: TreeMeta) -> Result<()> {
+        self.pool.flush(&self.pages)?;
+        self.pages.inner.get_mut().map_err(|_| Error::LockPoisoned)?.flush(meta)
+    }
+
+    fn checkpoint(&mut self, meta: TreeMeta) -> Result<()> {
+        self.pool.flush(&self.pages)?;
+        self.pages.inner.get_mut().map_err(|_| Error::LockPoisoned)?.checkpoint(meta)
+    }
+
+    fn vacuum(&mut self, meta: TreeMeta) -> Result<usize> {
+        self.pool.flush(&self.pages)?;
+        self.pages.inner.get_mut().map_err(|_| Error::LockPoisoned)?.vacuum(meta)
+    }
+
+    // 缓存的是明文 block, 命中和淘汰看自己的 pool, 分配看 inner
+    fn stats(&self) -> BlockEngineStats {
+        let allocations = self.pages.inner.lock().map_or(0, |inner| inner.stats().allocations);
+        BlockEngineStats { allocations, ..self.pool.stats() }
+    }
+
+    // 明文缓存在自己的 pool 里, pin 的是这一份
+    fn pin(&self, block_id: BlockId) -> Result<()> {
+        self.pool.pin(block_id, &self.pages)
+    }
+
+    fn unpin(&self, block_id: BlockId) -> Result<()> {
+        self.pool.unpin(block_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        file::{FileBlockEngine, FileOptions},
+        tree::{BPlusTree, BPlusTreeNode},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_encrypted_engine() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-encrypt-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 1024, pool_size: 8, ..FileOptions::default() };
+        let key = [7; 32];
+        let open = |key: &[u8; 32], create| {
+            let inner = if create { FileBlockEngine::create(&path, options) } else { FileBlockEngine::open(&path, options) }
+                .unwrap();
+            EncryptedBlockEngine::<_, BPlusTreeNode<u32, String>>::new(inner, key, 8).unwrap()
+        };
+
+        // pool 很小, 大部分 block 都要加密写出去再读回来
+        let mut tree = BPlusTree::new(8, open(&key, true)).unwrap();
+        for i in 0..1000 {
+            tree.insert(i, format!("alice{}@example.com", i)).unwrap();
+        }
+        for i in (0..1000).step_by(3) {
+            tree.delete(&i).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(!bytes.windows(11).any(|window| window == b"example.com"));
+
+        let tree = BPlusTree::open(open(&key, false)).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.search(&500).unwrap(), Some("alice500@example.com".to_string()));
+        assert_eq!(tree.search(&999).unwrap(), None);
+
+        // 密钥不对时读不出来
+        let engine = open(&[8; 32], false);
+        let root = engine.load_meta().unwrap().root;
+        assert!(engine.fetch_read(root).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
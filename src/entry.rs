@@ -0,0 +1,195 @@
+use anyhow::{anyhow, Ok, Result};
+
+use crate::{
+    amplification::record_written,
+    block::{BlockEngine, BlockId},
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 和 BTreeMap 的 entry 类似, 但 value 在 block 里, 拿不到 &mut V, 返回的都是 clone
+pub enum Entry<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    Occupied(OccupiedEntry<'a, K, V, E>),
+    Vacant(VacantEntry<'a, K, V, E>),
+}
+
+pub struct OccupiedEntry<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    tree: &'a mut BPlusTree<K, V, E>,
+    key: K,
+    // entry 借走了整棵树, 除了自己在持久化模式下复制路径, 叶子不会变
+    leaf: BlockId,
+}
+
+pub struct VacantEntry<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    tree: &'a mut BPlusTree<K, V, E>,
+    key: K,
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn entry(&mut self, key: K) -> Result<Entry<'_, K, V, E>> {
+        let leaf = self.find_leaf(self.root, &key)?;
+        let exists = self
+            .engine
+            .fetch_read(leaf)?
+            .as_ref()
+            .is_some_and(|node| self.order.search(&node.keys, &key).is_ok());
+        Ok(if exists {
+            Entry::Occupied(OccupiedEntry { tree: self, key, leaf })
+        } else {
+            Entry::Vacant(VacantEntry { tree: self, key })
+        })
+    }
+}
+
+impl<'a, K, V, E> Entry<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => &entry.key,
+            Entry::Vacant(entry) => &entry.key,
+        }
+    }
+
+    // 返回操作之后的 value
+    pub fn or_insert(self, default: V) -> Result<V> {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F>(self, f: F) -> Result<V>
+    where
+        F: FnOnce() -> V,
+    {
+        match self {
+            Entry::Occupied(entry) => entry.get(),
+            Entry::Vacant(entry) => entry.insert(f()),
+        }
+    }
+
+    pub fn and_modify<F>(self, f: F) -> Result<Self>
+    where
+        F: FnOnce(&mut V),
+    {
+        Ok(match self {
+            Entry::Occupied(mut entry) => {
+                entry.modify(f)?;
+                Entry::Occupied(entry)
+            }
+            vacant => vacant,
+        })
+    }
+}
+
+impl<'a, K, V, E> OccupiedEntry<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> Result<V> {
+        let read = self.tree.engine.fetch_read(self.leaf)?;
+        let node = read.as_ref().ok_or(Error::EmptyBlock(self.leaf))?;
+        let pos = self.tree.order.search(&node.keys, &self.key).map_err(|_| anyhow!("entry key is gone."))?;
+        Ok(node.values[pos].clone())
+    }
+
+    // 返回旧的 value
+    pub fn insert(&mut self, value: V) -> Result<V> {
+        self.modify(|old| std::mem::replace(old, value))
+    }
+
+    pub fn remove(self) -> Result<V> {
+        self.tree.delete(&self.key)?.ok_or_else(|| anyhow!("entry key is gone."))
+    }
+
+    // 没有 freeze 过的时候直接改记下来的叶子, 否则走复制路径
+    fn modify<R, F>(&mut self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut V) -> R,
+    {
+        if self.tree.persistent {
+            let ret = self
+                .tree
+                .with_leaf_mut(&self.key, |node, pos| f(&mut node.values[pos]))?
+                .ok_or_else(|| anyhow!("entry key is gone."))?;
+            // 叶子被复制到新 block 上了
+            self.leaf = self.tree.find_leaf(self.tree.root, &self.key)?;
+            return Ok(ret);
+        }
+        let mut guard = self.tree.engine.fetch_write(self.leaf)?;
+        let node = guard.as_mut().ok_or(Error::EmptyBlock(self.leaf))?;
+        let pos = self.tree.order.search(&node.keys, &self.key).map_err(|_| anyhow!("entry key is gone."))?;
+        let ret = f(&mut node.values[pos]);
+        record_written(&mut self.tree.stats, &self.tree.entry_size, &node.keys[pos], &node.values[pos]);
+        drop(guard);
+        self.tree.record_history();
+        Ok(ret)
+    }
+}
+
+impl<'a, K, V, E> VacantEntry<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn insert(self, value: V) -> Result<V> {
+        self.tree.insert(self.key, value.clone())?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for word in "a b c a b a d a".split(' ') {
+            tree.entry(word.to_string()).unwrap().and_modify(|n| *n += 1).unwrap().or_insert(1).unwrap();
+        }
+        let counts: Vec<_> = tree.iter().collect();
+        assert_eq!(counts, vec![("a".into(), 4), ("b".into(), 2), ("c".into(), 1), ("d".into(), 1)]);
+
+        let version = tree.freeze();
+        assert_eq!(tree.entry("a".into()).unwrap().and_modify(|n| *n *= 10).unwrap().or_insert(0).unwrap(), 40);
+        assert_eq!(tree.search_at(version, &"a".into()).unwrap(), Some(4));
+        let Entry::Occupied(entry) = tree.entry("b".into()).unwrap() else {
+            panic!("b should exist");
+        };
+        assert_eq!(entry.remove().unwrap(), 2);
+        assert_eq!(tree.search(&"b".into()).unwrap(), None);
+        assert_eq!(tree.entry("e".into()).unwrap().or_insert_with(|| 7).unwrap(), 7);
+    }
+}
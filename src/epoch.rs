@@ -0,0 +1,265 @@
+use anyhow::{Ok, Result};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    block::{Block, BlockEngine, BlockEngineStats, BlockId, BlockReadGuard, BlockWriteGuard, TreeMeta},
+    error::Error,
+};
+
+// 延迟回收的 engine 包装: delete 时先把内容拿走, 等 delete 之前进入的 epoch 都离开了, 才交给 inner 回收
+// 并发的读者放开父结点的锁之后手里还记着孩子的 block id, 这期间它不会被分给别的结点
+// flush 之类的操作要 &mut, 那时没有读者, 攒着的一次都还掉
+pub struct EpochBlockEngine<E> {
+    inner: E,
+    // 当前的 epoch, 每 delete 一次加一
+    epoch: AtomicU64,
+    // 还没离开的 epoch 和里面的读者个数
+    readers: Mutex<BTreeMap<u64, usize>>,
+    retired: Mutex<Retired>,
+}
+
+#[derive(Default)]
+struct Retired {
+    // (delete 时的 epoch, block id), epoch 递增
+    queue: VecDeque<(u64, BlockId)>,
+    // queue 里的 block id, 查重复 delete 不用扫整个 queue
+    ids: HashSet<BlockId>,
+}
+
+impl<E: BlockEngine> EpochBlockEngine<E> {
+    pub fn new(inner: E) -> Self {
+        EpochBlockEngine {
+            inner,
+            epoch: AtomicU64::new(0),
+            readers: Mutex::new(BTreeMap::new()),
+            retired: Mutex::new(Retired::default()),
+        }
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    pub fn into_inner(mut self) -> Result<E> {
+        self.release_all()?;
+        Ok(self.inner)
+    }
+
+    // 删掉了但是还没还给 inner 的 block 个数
+    pub fn retired(&self) -> usize {
+        self.retired.lock().map_or(0, |retired| retired.queue.len())
+    }
+
+    // 还掉比最早的读者还早 delete 的 block
+    // 之后进入的读者拿到的 epoch 比现在攒着的都大, 算完最早的 epoch 就可以放开 readers
+    fn reclaim(&self) -> Result<()> {
+        let oldest = {
+            let readers = self.readers.lock().map_err(|_| Error::LockPoisoned)?;
+            readers.keys().next().copied().unwrap_or(u64::MAX)
+        };
+        let mut retired = self.retired.lock().map_err(|_| Error::LockPoisoned)?;
+        while let Some(&(epoch, block_id)) = retired.queue.front().filter(|(epoch, _)| *epoch < oldest) {
+            retired.queue.pop_front();
+            if let Err(e) = self.inner.delete(block_id) {
+                retired.queue.push_front((epoch, block_id));
+                return Err(e);
+            }
+            retired.ids.remove(&block_id);
+        }
+        Ok(())
+    }
+
+    fn release_all(&mut self) -> Result<()> {
+        let retired = self.retired.get_mut().map_err(|_| Error::LockPoisoned)?;
+        while let Some(&(_, block_id)) = retired.queue.front() {
+            self.inner.delete(block_id)?;
+            retired.queue.pop_front();
+            retired.ids.remove(&block_id);
+        }
+        Ok(())
+    }
+}
+
+impl<E: BlockEngine> BlockEngine for EpochBlockEngine<E> {
+    type Item = E::Item;
+
+    fn alloc_block(&self) -> Result<BlockId> {
+        self.inner.alloc_block()
+    }
+
+    fn alloc_write(&self, item: E::Item) -> Result<BlockId> {
+        self.inner.alloc_write(item)
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, E::Item>> {
+        self.inner.fetch_read(block_id)
+    }
+
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, E::Item>> {
+        self.inner.fetch_write(block_id)
+    }
+
+    fn try_fetch_write(&self, block_id: BlockId) -> Result<Option<BlockWriteGuard<'_, E::Item>>> {
+        self.inner.try_fetch_write(block_id)
+    }
+
+    // 拿着 retired 的锁清空, 这样重复 delete 能发现; 要回收的 block 已经从树上摘掉了, 没有别的线程会等它的写锁
+    fn delete(&self, block_id: BlockId) -> Result<Option<E::Item>> {
+        let content = {
+            let mut retired = self.retired.lock().map_err(|_| Error::LockPoisoned)?;
+            if retired.ids.contains(&block_id) {
+                return Err(Error::InvalidBlock(block_id).into());
+            }
+            let content = self.inner.fetch_write(block_id)?.content.take();
+            retired.queue.push_back((self.epoch.fetch_add(1, Ordering::SeqCst), block_id));
+            retired.ids.insert(block_id);
+            content
+        };
+        self.reclaim()?;
+        Ok(content)
+    }
+
+    fn write_back(block_id: BlockId, block: &Block<E::Item>) {
+        E::write_back(block_id, block)
+    }
+
+    fn load_meta(&self) -> Option<TreeMeta> {
+        self.inner.load_meta()
+    }
+
+    fn stage_snapshots(&self, snapshots: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        self.inner.stage_snapshots(snapshots)
+    }
+
+    fn load_snapshots(&self) -> Result<BTreeMap<String, TreeMeta>> {
+        self.inner.load_snapshots()
+    }
+
+    fn flush(&mut self, meta: TreeMeta) -> Result<()> {
+        self.release_all()?;
+        self.inner.flush(meta)
+    }
+
+    fn checkpoint(&mut self, meta: TreeMeta) -> Result<()> {
+        self.release_all()?;
+        self.inner.checkpoint(meta)
+    }
+
+    fn vacuum(&mut self, meta: TreeMeta) -> Result<usize> {
+        self.release_all()?;
+        self.inner.vacuum(meta)
+    }
+
+    fn stats(&self) -> BlockEngineStats {
+        self.inner.stats()
+    }
+
+    fn set_user_metadata(&mut self, bytes: &[u8]) -> Result<()> {
+        self.inner.set_user_metadata(bytes)
+    }
+
+    fn user_metadata(&self) -> Result<Vec<u8>> {
+        self.inner.user_metadata()
+    }
+
+    fn prefetch(&self, block_ids: &[BlockId]) -> Result<usize> {
+        self.inner.prefetch(block_ids)
+    }
+
+    fn resident(&self) -> Result<Vec<BlockId>> {
+        self.inner.resident()
+    }
+
+    fn pin(&self, block_id: BlockId) -> Result<()> {
+        self.inner.pin(block_id)
+    }
+
+    fn unpin(&self, block_id: BlockId) -> Result<()> {
+        self.inner.unpin(block_id)
+    }
+
+    fn enter_epoch(&self) -> Result<u64> {
+        let mut readers = self.readers.lock().map_err(|_| Error::LockPoisoned)?;
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        *readers.entry(epoch).or_default() += 1;
+        Ok(epoch)
+    }
+
+    // 回收失败的 block 留着下次再还
+    fn leave_epoch(&self, epoch: u64) {
+        if let std::result::Result::Ok(mut readers) = self.readers.lock() {
+            if let Some(count) = readers.get_mut(&epoch) {
+                *count -= 1;
+                if *count == 0 {
+                    readers.remove(&epoch);
+                }
+            }
+        }
+        let _ = self.reclaim();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use crate::{
+        block::{EpochGuard, MemoryBlockEngine},
+        concurrent::ConcurrentBPlusTree,
+        tree::BPlusTreeNode,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_deferred_reclamation() {
+        let engine = EpochBlockEngine::new(MemoryBlockEngine::new());
+        let block_id = engine.alloc_write(1).unwrap();
+        let guard = EpochGuard::new(&engine).unwrap();
+        assert_eq!(engine.delete(block_id).unwrap(), Some(1));
+        assert!(engine.delete(block_id).is_err());
+        assert_eq!(**engine.fetch_read(block_id).unwrap(), None);
+        // 读者还在, 不会复用
+        assert_ne!(engine.alloc_block().unwrap(), block_id);
+        assert_eq!(engine.retired(), 1);
+        // 之后进入的读者不影响回收
+        let later = EpochGuard::new(&engine).unwrap();
+        drop(guard);
+        assert_eq!(engine.retired(), 0);
+        assert_eq!(engine.alloc_block().unwrap(), block_id);
+        drop(later);
+        // 回收之后复用的 block 可以再 delete
+        assert!(engine.delete(block_id).is_ok());
+        assert_eq!(engine.retired(), 0);
+
+        let engine: EpochBlockEngine<MemoryBlockEngine<BPlusTreeNode<u32, u32>>> = EpochBlockEngine::new(MemoryBlockEngine::new());
+        let tree = Arc::new(ConcurrentBPlusTree::new(3, engine).unwrap());
+        let workers: Vec<_> = (0..4)
+            .map(|worker| {
+                let tree = tree.clone();
+                thread::spawn(move || {
+                    for round in 0..5 {
+                        for i in (0..400).filter(|i| i % 4 == worker) {
+                            tree.insert(i, round).unwrap();
+                        }
+                        for i in (0..400).filter(|i| i % 4 == worker) {
+                            assert_eq!(tree.search(&i).unwrap(), Some(round));
+                            assert_eq!(tree.delete(&i).unwrap(), Some(round));
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers.into_iter().for_each(|worker| worker.join().unwrap());
+        let tree = Arc::into_inner(tree).unwrap().into_inner().unwrap();
+        assert!(tree.is_empty());
+        assert_eq!(tree.engine.retired(), 0);
+        tree.verify().unwrap();
+    }
+}
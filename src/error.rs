@@ -0,0 +1,47 @@
+use std::fmt;
+
+use crate::block::BlockId;
+
+// 存储层会返回的错误, 包在 anyhow::Error 里往上传, 调用方可以 downcast_ref::<Error>() 区分
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    // block id 越界或者已经被回收
+    InvalidBlock(BlockId),
+    // block 里没有结点
+    EmptyBlock(BlockId),
+    // 持有锁的线程 panic 了
+    LockPoisoned,
+    // engine 没有空间再分配新 block 了
+    StorageFull,
+    // insert_unique 插入已经存在的 key
+    KeyExists,
+    // verify 发现结点不满足 B+ 树的不变量
+    Corrupted(BlockId, String),
+    // FaultyBlockEngine 故意让操作失败
+    InjectedFault(BlockId),
+    // 只读打开的 engine 上分配, 修改或者 flush
+    ReadOnly,
+    // 事务要写的 key 在事务开始之后被别人改过, 提交失败, 什么都没写
+    WriteConflict,
+    // 并发的写者在 LatchOptions::timeout 之内没拿到要的锁, 什么都没改
+    LockTimeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidBlock(id) => write!(f, "invalid block id: {}.", id),
+            Error::EmptyBlock(id) => write!(f, "empty block: {}.", id),
+            Error::LockPoisoned => write!(f, "lock poisoned."),
+            Error::StorageFull => write!(f, "storage full."),
+            Error::KeyExists => write!(f, "key already exists."),
+            Error::Corrupted(id, reason) => write!(f, "corrupted block {}: {}.", id, reason),
+            Error::InjectedFault(id) => write!(f, "injected fault on block {}.", id),
+            Error::ReadOnly => write!(f, "engine is read-only."),
+            Error::WriteConflict => write!(f, "write conflict."),
+            Error::LockTimeout => write!(f, "timed out waiting for a lock."),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
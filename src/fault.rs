@@ -0,0 +1,308 @@
+use anyhow::{Ok, Result};
+use std::{
+    collections::BTreeMap,
+    fs::OpenOptions,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    block::{Block, BlockEngine, BlockEngineStats, BlockId, BlockReadGuard, BlockWriteGuard, TreeMeta},
+    error::Error,
+};
+
+// 各种故障出现的概率, 0 是从不, 1 是每次
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultOptions {
+    // fetch_read 和 prefetch 失败
+    pub read_error: f64,
+    // alloc_block, fetch_write 和 delete 失败
+    pub write_error: f64,
+    // flush, checkpoint 和 vacuum 失败
+    pub flush_error: f64,
+    // flush, checkpoint 和 vacuum 直接返回成功, 不交给下面的 engine, 相当于 flush 之前就崩溃了
+    pub lose_flushes: bool,
+    // 同样的 seed 和同样的操作顺序, 故障出现在同样的地方
+    pub seed: u64,
+}
+
+impl Default for FaultOptions {
+    fn default() -> Self {
+        FaultOptions { read_error: 0.0, write_error: 0.0, flush_error: 0.0, lose_flushes: false, seed: 0 }
+    }
+}
+
+// splitmix64, 测试用够了, 不用为它加依赖
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+// 测试用的 engine 包装, 按 FaultOptions 让操作随机失败, 失败的操作不会交给下面的 engine
+// 失败时返回 Error::InjectedFault, 测试可以和真的错误分开
+pub struct FaultyBlockEngine<E> {
+    inner: E,
+    options: FaultOptions,
+    rng: Mutex<Rng>,
+    faults: AtomicU64,
+}
+
+impl<E: BlockEngine> FaultyBlockEngine<E> {
+    pub fn new(inner: E, options: FaultOptions) -> Self {
+        FaultyBlockEngine { inner, options, rng: Mutex::new(Rng(options.seed)), faults: AtomicU64::new(0) }
+    }
+
+    // 换一组概率, 随机数从新的 seed 重新开始
+    pub fn set_options(&mut self, options: FaultOptions) {
+        self.options = options;
+        self.rng = Mutex::new(Rng(options.seed));
+    }
+
+    pub fn options(&self) -> FaultOptions {
+        self.options
+    }
+
+    // 到现在为止注入了几次故障
+    pub fn faults(&self) -> u64 {
+        self.faults.load(Ordering::Relaxed)
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    fn inject(&self, p: f64, block_id: BlockId) -> Result<()> {
+        if self.rng.lock().map_err(|_| Error::LockPoisoned)?.chance(p) {
+            self.faults.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::InjectedFault(block_id).into());
+        }
+        Ok(())
+    }
+
+    // flush 类的操作: 可能失败, 可能假装成功
+    fn inject_flush(&self, meta: TreeMeta) -> Result<bool> {
+        self.inject(self.options.flush_error, meta.root)?;
+        Ok(!self.options.lose_flushes)
+    }
+}
+
+impl<E: BlockEngine> BlockEngine for FaultyBlockEngine<E> {
+    type Item = E::Item;
+
+    // 还不知道会分到哪个 block, 错误里是 BlockId::MAX
+    fn alloc_block(&self) -> Result<BlockId> {
+        self.inject(self.options.write_error, BlockId::MAX)?;
+        self.inner.alloc_block()
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, E::Item>> {
+        self.inject(self.options.read_error, block_id)?;
+        self.inner.fetch_read(block_id)
+    }
+
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, E::Item>> {
+        self.inject(self.options.write_error, block_id)?;
+        self.inner.fetch_write(block_id)
+    }
+
+    fn try_fetch_write(&self, block_id: BlockId) -> Result<Option<BlockWriteGuard<'_, E::Item>>> {
+        self.inject(self.options.write_error, block_id)?;
+        self.inner.try_fetch_write(block_id)
+    }
+
+    fn delete(&self, block_id: BlockId) -> Result<Option<E::Item>> {
+        self.inject(self.options.write_error, block_id)?;
+        self.inner.delete(block_id)
+    }
+
+    fn write_back(block_id: BlockId, block: &Block<E::Item>) {
+        E::write_back(block_id, block)
+    }
+
+    fn load_meta(&self) -> Option<TreeMeta> {
+        self.inner.load_meta()
+    }
+
+    fn stage_snapshots(&self, snapshots: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        self.inner.stage_snapshots(snapshots)
+    }
+
+    fn load_snapshots(&self) -> Result<BTreeMap<String, TreeMeta>> {
+        self.inner.load_snapshots()
+    }
+
+    fn flush(&mut self, meta: TreeMeta) -> Result<()> {
+        if self.inject_flush(meta)? {
+            self.inner.flush(meta)?;
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, meta: TreeMeta) -> Result<()> {
+        if self.inject_flush(meta)? {
+            self.inner.checkpoint(meta)?;
+        }
+        Ok(())
+    }
+
+    fn vacuum(&mut self, meta: TreeMeta) -> Result<usize> {
+        if self.inject_flush(meta)? {
+            return self.inner.vacuum(meta);
+        }
+        Ok(0)
+    }
+
+    fn stats(&self) -> BlockEngineStats {
+        self.inner.stats()
+    }
+
+    fn set_user_metadata(&mut self, bytes: &[u8]) -> Result<()> {
+        self.inner.set_user_metadata(bytes)
+    }
+
+    fn user_metadata(&self) -> Result<Vec<u8>> {
+        self.inner.user_metadata()
+    }
+
+    fn prefetch(&self, block_ids: &[BlockId]) -> Result<usize> {
+        self.inject(self.options.read_error, block_ids.first().copied().unwrap_or(BlockId::MAX))?;
+        self.inner.prefetch(block_ids)
+    }
+
+    fn pin(&self, block_id: BlockId) -> Result<()> {
+        self.inner.pin(block_id)
+    }
+
+    fn unpin(&self, block_id: BlockId) -> Result<()> {
+        self.inner.unpin(block_id)
+    }
+
+    fn enter_epoch(&self) -> Result<u64> {
+        self.inner.enter_epoch()
+    }
+
+    fn leave_epoch(&self, epoch: u64) {
+        self.inner.leave_epoch(epoch)
+    }
+}
+
+// 把文件 skip 字节之后随机 count 个字节取反, 返回改了的偏移, 用来测校验和, scrub 和恢复
+// 文件比 skip 短时什么都不改
+pub fn corrupt_bytes(path: impl AsRef<Path>, skip: u64, count: usize, seed: u64) -> Result<Vec<u64>> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let len = file.metadata()?.len();
+    if len <= skip {
+        return Ok(vec![]);
+    }
+    let mut rng = Rng(seed);
+    let mut offsets = vec![];
+    for _ in 0..count {
+        let offset = skip + rng.next() % (len - skip);
+        let mut byte = [0];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut byte)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&[!byte[0]])?;
+        offsets.push(offset);
+    }
+    file.sync_data()?;
+    Ok(offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{block::MemoryBlockEngine, tree::BPlusTree};
+
+    use super::*;
+
+    #[test]
+    fn test_faulty_engine() {
+        let engine = FaultyBlockEngine::new(MemoryBlockEngine::new(), FaultOptions::default());
+        let mut tree = BPlusTree::new(4, engine).unwrap();
+        for i in 0..200 {
+            tree.insert(i, i).unwrap();
+        }
+
+        // 同一个 seed 两次失败的地方一样
+        let options = FaultOptions { read_error: 0.2, seed: 7, ..FaultOptions::default() };
+        let mut runs = vec![];
+        for _ in 0..2 {
+            tree.engine.set_options(options);
+            runs.push((0..200).map(|i| tree.search(&i).is_ok()).collect::<Vec<_>>());
+        }
+        assert_eq!(runs[0], runs[1]);
+        assert!(runs[0].iter().any(|&ok| !ok));
+        let err = tree.first_key_value().and_then(|_| tree.last_key_value()).and_then(|_| tree.verify());
+        assert!(matches!(err.unwrap_err().downcast_ref::<Error>(), Some(Error::InjectedFault(_))));
+
+        // 第一次写就失败了, 树还没改
+        tree.engine.set_options(FaultOptions { write_error: 1.0, ..FaultOptions::default() });
+        let faults = tree.engine.faults();
+        assert!((200..300).all(|i| tree.insert(i, i).is_err()));
+        assert!(tree.engine.faults() > faults);
+        tree.engine.set_options(FaultOptions::default());
+        tree.verify().unwrap();
+        assert_eq!(tree.len(), 200);
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_lost_flush() {
+        use crate::{
+            file::{FileBlockEngine, FileOptions},
+            tree::BPlusTreeNode,
+        };
+
+        let path = std::env::temp_dir().join(format!("bplus-tree-fault-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, pool_size: 4, shadow: true, ..FileOptions::default() };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap();
+        let mut tree = BPlusTree::new(8, FaultyBlockEngine::new(engine, FaultOptions::default())).unwrap();
+        for i in 0..100 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.enable_shadow_paging().unwrap();
+
+        // commit 报了成功, 其实没有写到文件里
+        tree.engine.set_options(FaultOptions { lose_flushes: true, ..FaultOptions::default() });
+        for i in 100..200 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.commit().unwrap();
+        drop(tree);
+
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        let tree = BPlusTree::open(engine).unwrap();
+        assert_eq!(tree.len(), 100);
+        tree.verify().unwrap();
+        drop(tree);
+
+        // 两份 superblock 之后的字节坏了, 校验和对不上
+        let offsets = corrupt_bytes(&path, 1024, 8, 3).unwrap();
+        assert_eq!(offsets, corrupt_bytes(&path, 1024, 8, 3).unwrap());
+        corrupt_bytes(&path, 1024, 8, 3).unwrap();
+        let ret = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options)
+            .and_then(BPlusTree::open)
+            .and_then(|tree| tree.verify());
+        assert!(matches!(ret.unwrap_err().downcast_ref::<Error>(), Some(Error::Corrupted(..))));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1160 @@
+use anyhow::{anyhow, Ok, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    block::{Block, BlockEngine, BlockEngineStats, BlockId, BlockReadGuard, BlockWriteGuard, TreeMeta},
+    codec::{BincodeCodec, NodeCodec},
+    compress::{self, Compression},
+    direct::{self, AlignedBuf},
+    doublewrite::DoubleWrite,
+    error::Error,
+    pool::{BufferPool, PageStore},
+    replacement::Replacement,
+    superblock::{Superblock, MAX_CATALOG, MAX_USER_METADATA, SUPERBLOCK_LEN},
+    wal::Wal,
+};
+#[cfg(all(feature = "uring", target_os = "linux"))]
+use crate::uring::Ring;
+
+const NONE: u64 = u64::MAX;
+
+const PAGE_FREE: u8 = 0;
+const PAGE_USED: u8 = 1;
+// 分配了但是还没有内容的 block
+const PAGE_EMPTY: u8 = 2;
+// 标记的高 4 位是内容的压缩方式
+const COMPRESSION_SHIFT: u8 = 4;
+// 每页开头的标记, 校验和与长度, 剩下的才是 codec 能用的
+pub const PAGE_HEADER_LEN: usize = 9;
+
+// 读到的页校验和对不上时怎么办
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumPolicy {
+    // 返回 Error::Corrupted
+    #[default]
+    Error,
+    // 记一条 warn 日志, 照常解码
+    Log,
+}
+
+// 写回和 flush 之后什么时候 fsync
+// checkpoint, 恢复和双写缓冲里为了保证写的先后顺序的 fsync 不受影响
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    // 每写回一页和每次 flush 都 fsync
+    Always,
+    // 只在 flush 时 fsync, flush 返回之后修改就不会丢了
+    #[default]
+    Flush,
+    // 每 n 次写回或者 flush 才 fsync 一次
+    EveryN(usize),
+    // 离上次 fsync 超过这么久之后, 下一次写回或者 flush 时 fsync, 不会自己醒来
+    Periodic(Duration),
+    // 交给操作系统, 崩溃时可能丢掉 flush 过的修改
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileOptions {
+    // 新建文件时的页大小, 打开已有的文件时用文件里记的
+    pub page_size: usize,
+    // 内存里最多缓存多少个 block
+    pub pool_size: usize,
+    // pool 满了之后的淘汰策略
+    pub replacement: Replacement,
+    pub checksum: ChecksumPolicy,
+    // 写回时怎么压缩, 读的时候按每页自己记的方式解压
+    pub compression: Compression,
+    // 页先写进旁边的 .wal 日志, flush 时才改写文件, 崩溃之后重新打开还是上一次 flush 的样子
+    pub wal: bool,
+    // 不开 wal 时页是直接覆盖写的, 覆盖之前先写一份到旁边的 .dwb 里, 写了一半的页打开时能恢复
+    pub double_write: bool,
+    pub durability: Durability,
+    // 数据页用 O_DIRECT 读写, 不经过操作系统的页缓存, 缓存只有 buffer pool 这一层
+    // 页大小要是 4096 的倍数, 只支持 Linux; superblock, wal 的 checkpoint 和恢复还是走页缓存
+    pub direct_io: bool,
+    // 配合 BPlusTree::enable_shadow_paging 用, 不能和 wal 一起开
+    // 要复用上次 flush 时空闲链表里的页之前, 先把链表从文件里的 superblock 上摘下来, 免得写回的新页弄坏它
+    // 这样两次 flush 之间崩溃, 打开时还是上一次 flush 的样子, 最多漏掉一些空闲页
+    pub shadow: bool,
+    // 只用来打开已有的文件, 文件不要写权限, 几个进程可以同时打开; 分配, 修改和 flush 都返回 Error::ReadOnly
+    // 不能和 wal, 双写缓冲和 direct_io 一起开, 文件旁边留着没重放的日志时要先可写地打开一次
+    pub read_only: bool,
+}
+
+impl Default for FileOptions {
+    fn default() -> Self {
+        FileOptions {
+            page_size: 4096,
+            pool_size: 1024,
+            replacement: Replacement::Lru,
+            checksum: ChecksumPolicy::Error,
+            compression: Compression::None,
+            wal: false,
+            double_write: false,
+            durability: Durability::Flush,
+            direct_io: false,
+            shadow: false,
+            read_only: false,
+        }
+    }
+}
+
+// 按页读写文件, 文件开头是两份 superblock, 后面从 first_page 开始 block i 存在第 first_page + i 页
+// 页的格式: 1 字节标记, 4 字节 CRC32, 空闲页后面是空闲链表里下一页的 block id, 使用中的页后面是 4 字节长度加 codec 编码的内容
+// 内容压缩过时标记的高 4 位记着压缩方式, 长度是压缩之后的
+// CRC32 覆盖除了它自己以外的整页, 包括末尾补的 0
+struct PageFile<C> {
+    file: Mutex<File>,
+    // 开着 direct_io 时读写整页用的另一个句柄
+    direct: Option<Mutex<File>>,
+    wal: Option<Mutex<Wal>>,
+    double_write: Option<Mutex<DoubleWrite>>,
+    // 有的话成批的读写交给 io_uring, 见 UringBlockEngine
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    ring: Option<Mutex<Ring>>,
+    durability: Durability,
+    syncs: Mutex<SyncState>,
+    // 写进数据文件的字节数, 日志和双写缓冲自己记
+    written: AtomicU64,
+    page_size: usize,
+    first_page: usize,
+    codec: C,
+    checksum: ChecksumPolicy,
+    compression: Compression,
+}
+
+struct SyncState {
+    // 上次 fsync 之后写回和 flush 的次数
+    unsynced: usize,
+    last: Instant,
+    syncs: u64,
+}
+
+impl SyncState {
+    fn new() -> Self {
+        SyncState { unsynced: 0, last: Instant::now(), syncs: 0 }
+    }
+}
+
+impl<C> PageFile<C> {
+    fn read_raw(&self, page_no: usize, buf: &mut [u8]) -> Result<()> {
+        if let Some(wal) = &self.wal {
+            if wal.lock().map_err(|_| Error::LockPoisoned)?.read(page_no, buf)? {
+                return Ok(());
+            }
+        }
+        let offset = (page_no * self.page_size) as u64;
+        let mut file = self.data_file()?;
+        if self.direct.is_some() {
+            let mut page = AlignedBuf::new(buf.len());
+            read_at(&mut file, offset, &mut page)?;
+            buf.copy_from_slice(&page);
+            return Ok(());
+        }
+        read_at(&mut file, offset, buf)
+    }
+
+    fn write_raw(&self, page_no: usize, buf: &[u8]) -> Result<()> {
+        if let Some(wal) = &self.wal {
+            let mut wal = wal.lock().map_err(|_| Error::LockPoisoned)?;
+            wal.append(page_no, buf)?;
+            if self.sync_due(false)? {
+                wal.sync()?;
+            }
+            return Ok(());
+        }
+        if let Some(double_write) = &self.double_write {
+            double_write.lock().map_err(|_| Error::LockPoisoned)?.write(page_no, buf)?;
+        }
+        let mut file = self.data_file()?;
+        let offset = (page_no * self.page_size) as u64;
+        match self.direct {
+            Some(_) => write_at(&mut file, offset, &AlignedBuf::from_slice(buf))?,
+            None => write_at(&mut file, offset, buf)?,
+        }
+        self.written.fetch_add(buf.len() as u64, Ordering::Relaxed);
+        if self.sync_due(false)? {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    // 数据文件, 日志和双写缓冲加起来
+    fn bytes_written(&self) -> Result<u64> {
+        let mut written = self.written.load(Ordering::Relaxed);
+        if let Some(wal) = &self.wal {
+            written += wal.lock().map_err(|_| Error::LockPoisoned)?.written();
+        }
+        if let Some(double_write) = &self.double_write {
+            written += double_write.lock().map_err(|_| Error::LockPoisoned)?.written();
+        }
+        Ok(written)
+    }
+
+    // 读写整页用的句柄, fsync 哪个句柄都一样
+    fn data_file(&self) -> Result<MutexGuard<'_, File>> {
+        let file = self.direct.as_ref().unwrap_or(&self.file);
+        Ok(file.lock().map_err(|_| Error::LockPoisoned)?)
+    }
+
+    // 按 durability 决定这次写回或者 flush 之后要不要 fsync
+    fn sync_due(&self, commit: bool) -> Result<bool> {
+        let mut state = self.syncs.lock().map_err(|_| Error::LockPoisoned)?;
+        state.unsynced += 1;
+        let due = match self.durability {
+            Durability::Always => true,
+            Durability::Flush => commit,
+            Durability::EveryN(n) => state.unsynced >= n,
+            Durability::Periodic(interval) => state.last.elapsed() >= interval,
+            Durability::Never => false,
+        };
+        if due {
+            state.unsynced = 0;
+            state.last = Instant::now();
+            state.syncs += 1;
+        }
+        Ok(due)
+    }
+
+    // 之前写的页都落盘之后才换 superblock, force 时不管 durability 都 fsync
+    // 开着 wal 时 superblock 跟着 commit 记录写进日志, 文件要等 checkpoint 才改
+    fn commit(&self, superblock: &mut Superblock, force: bool) -> Result<()> {
+        let sync = self.sync_due(true)? || force;
+        if let Some(wal) = &self.wal {
+            return wal.lock().map_err(|_| Error::LockPoisoned)?.commit(&superblock.encode(), sync);
+        }
+        let mut file = self.file.lock().map_err(|_| Error::LockPoisoned)?;
+        // 没有 fsync 时双写缓冲里的页还要留着
+        if sync {
+            file.sync_data()?;
+            if let Some(double_write) = &self.double_write {
+                double_write.lock().map_err(|_| Error::LockPoisoned)?.clear()?;
+            }
+        }
+        self.written.fetch_add(superblock.encoded_len() as u64, Ordering::Relaxed);
+        write_superblock(&mut file, superblock, sync)
+    }
+
+    // 日志里的页写进文件之后, 在 superblock 里记下日志到了哪里, 之后日志就可以清空了
+    fn checkpoint(&self, superblock: &mut Superblock) -> Result<()> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let mut file = self.file.lock().map_err(|_| Error::LockPoisoned)?;
+        let mut wal = wal.lock().map_err(|_| Error::LockPoisoned)?;
+        wal.checkpoint(&mut file, self.page_size)?;
+        superblock.checkpoint_lsn = wal.lsn();
+        self.written.fetch_add(superblock.encoded_len() as u64, Ordering::Relaxed);
+        write_superblock(&mut file, superblock, true)?;
+        wal.truncate()
+    }
+
+    // 只留下前 block_count 个 block; 文件里的 superblock 已经不指向后面的页了才能截
+    fn truncate(&self, block_count: usize) -> Result<()> {
+        let file = self.file.lock().map_err(|_| Error::LockPoisoned)?;
+        file.set_len(((self.first_page + block_count) * self.page_size) as u64)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: BlockId, page: Vec<u8>) -> Result<()> {
+        let page = seal_page(block_id, page, self.page_size)?;
+        self.write_raw(self.first_page + block_id, &page)
+    }
+
+    fn read_block(&self, block_id: BlockId) -> Result<Vec<u8>> {
+        let mut page = vec![0; self.page_size];
+        self.read_raw(self.first_page + block_id, &mut page)?;
+        check_page(block_id, &page, self.checksum)?;
+        Ok(page)
+    }
+
+    fn write_free(&self, block_id: BlockId, next: Option<BlockId>) -> Result<()> {
+        self.write_block(block_id, encode_free(next))
+    }
+
+    fn read_free(&self, block_id: BlockId) -> Result<Option<BlockId>> {
+        decode_free(block_id, &self.read_block(block_id)?)
+    }
+}
+
+impl<B, C> PageStore<B> for PageFile<C>
+where
+    C: NodeCodec<B>,
+{
+    fn read_page(&self, block_id: BlockId) -> Result<Option<B>> {
+        decode_page(&self.codec, block_id, &self.read_block(block_id)?)
+    }
+
+    fn write_page(&self, block_id: BlockId, content: Option<&B>) -> Result<()> {
+        self.write_block(block_id, encode_page(&self.codec, self.compression, content)?)
+    }
+
+    // 开着 wal 时页要从日志里读, 不走 io_uring
+    fn read_pages(&self, block_ids: &[BlockId]) -> Result<Vec<Option<B>>> {
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        if let (Some(ring), None) = (&self.ring, &self.wal) {
+            let mut pages: Vec<_> = block_ids.iter().map(|_| AlignedBuf::new(self.page_size)).collect();
+            {
+                let file = self.data_file()?;
+                let offset = |block_id| ((self.first_page + block_id) * self.page_size) as u64;
+                let mut reads: Vec<_> =
+                    block_ids.iter().zip(&mut pages).map(|(&block_id, page)| (offset(block_id), &mut page[..])).collect();
+                ring.lock().map_err(|_| Error::LockPoisoned)?.read_at(&file, &mut reads)?;
+            }
+            return block_ids
+                .iter()
+                .zip(&pages)
+                .map(|(&block_id, page)| {
+                    check_page(block_id, page, self.checksum)?;
+                    decode_page(&self.codec, block_id, page)
+                })
+                .collect();
+        }
+        block_ids.iter().map(|&block_id| self.read_page(block_id)).collect()
+    }
+
+    // 双写缓冲整批 fsync 一次, durability 按写回的页数算
+    fn write_pages(&self, pages: &[(BlockId, Option<&B>)]) -> Result<()> {
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        if let (Some(ring), None) = (&self.ring, &self.wal) {
+            let mut images = Vec::with_capacity(pages.len());
+            for &(block_id, content) in pages {
+                let page = seal_page(block_id, encode_page(&self.codec, self.compression, content)?, self.page_size)?;
+                images.push((self.first_page + block_id, AlignedBuf::from_slice(&page)));
+            }
+            if let Some(double_write) = &self.double_write {
+                let batch: Vec<_> = images.iter().map(|(page_no, page)| (*page_no, &page[..])).collect();
+                double_write.lock().map_err(|_| Error::LockPoisoned)?.write_batch(&batch)?;
+            }
+            let file = self.data_file()?;
+            let writes: Vec<_> =
+                images.iter().map(|(page_no, page)| ((page_no * self.page_size) as u64, &page[..])).collect();
+            ring.lock().map_err(|_| Error::LockPoisoned)?.write_at(&file, &writes)?;
+            self.written.fetch_add((images.len() * self.page_size) as u64, Ordering::Relaxed);
+            let mut sync = false;
+            for _ in pages {
+                sync |= self.sync_due(false)?;
+            }
+            if sync {
+                file.sync_data()?;
+            }
+            return Ok(());
+        }
+        pages.iter().try_for_each(|&(block_id, content)| self.write_page(block_id, content))
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)?;
+    Ok(())
+}
+
+fn write_at(file: &mut File, offset: u64, buf: &[u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)?;
+    Ok(())
+}
+
+// 还没有补齐和校验和的页
+pub(crate) fn encode_page<B, C>(codec: &C, compression: Compression, content: Option<&B>) -> Result<Vec<u8>>
+where
+    C: NodeCodec<B>,
+{
+    let mut page = vec![0; PAGE_HEADER_LEN];
+    page[0] = PAGE_EMPTY;
+    if let Some(content) = content {
+        let mut encoded = vec![];
+        codec.encode(content, &mut encoded)?;
+        let (id, encoded) = compression.compress(encoded)?;
+        page[0] = PAGE_USED | id << COMPRESSION_SHIFT;
+        page[5..9].copy_from_slice(&(encoded.len() as u32).to_le_bytes());
+        page.extend_from_slice(&encoded);
+    }
+    Ok(page)
+}
+
+// 调用前要先 check_page
+pub(crate) fn decode_page<B, C>(codec: &C, block_id: BlockId, page: &[u8]) -> Result<Option<B>>
+where
+    C: NodeCodec<B>,
+{
+    match page[0] & ((1 << COMPRESSION_SHIFT) - 1) {
+        PAGE_FREE => Err(Error::InvalidBlock(block_id).into()),
+        PAGE_USED => {
+            let len = u32::from_le_bytes(page[5..9].try_into()?) as usize;
+            let content = page.get(PAGE_HEADER_LEN..PAGE_HEADER_LEN + len).ok_or_else(|| anyhow!("page {} is truncated.", block_id))?;
+            match page[0] >> COMPRESSION_SHIFT {
+                0 => Ok(Some(codec.decode(content)?)),
+                id => Ok(Some(codec.decode(&compress::decompress(id, content)?)?)),
+            }
+        }
+        PAGE_EMPTY => Ok(None),
+        tag => Err(anyhow!("page {} has unknown tag {}.", block_id, tag)),
+    }
+}
+
+pub(crate) fn encode_free(next: Option<BlockId>) -> Vec<u8> {
+    let mut page = vec![PAGE_FREE, 0, 0, 0, 0];
+    page.extend_from_slice(&encode_id(next));
+    page
+}
+
+// 空闲页里记的下一个空闲页
+pub(crate) fn decode_free(block_id: BlockId, page: &[u8]) -> Result<Option<BlockId>> {
+    if page[0] != PAGE_FREE {
+        return Err(anyhow!("page {} is not free.", block_id));
+    }
+    Ok(decode_id(&page[5..13]))
+}
+
+// page 的前 5 字节留给标记和校验和, 补齐到一整页之后算校验和
+pub(crate) fn seal_page(block_id: BlockId, mut page: Vec<u8>, page_size: usize) -> Result<Vec<u8>> {
+    if page.len() > page_size {
+        return Err(anyhow!("block {} needs {} bytes but a page holds {}.", block_id, page.len(), page_size));
+    }
+    page.resize(page_size, 0);
+    let checksum = page_checksum(&page);
+    page[1..5].copy_from_slice(&checksum.to_le_bytes());
+    Ok(page)
+}
+
+pub(crate) fn check_page(block_id: BlockId, page: &[u8], policy: ChecksumPolicy) -> Result<()> {
+    let stored = u32::from_le_bytes(page[1..5].try_into()?);
+    let actual = page_checksum(page);
+    if stored != actual {
+        let reason = format!("checksum is {:08x} but the page stores {:08x}", actual, stored);
+        match policy {
+            ChecksumPolicy::Error => return Err(Error::Corrupted(block_id, reason).into()),
+            ChecksumPolicy::Log => log::warn!("block {}: {}.", block_id, reason),
+        }
+    }
+    Ok(())
+}
+
+// 存在单个文件里的 engine, block 缓存在固定大小的 buffer pool 里
+// 写过的 block 在被淘汰或者 flush 时写回文件, 回收的 block 在 flush 时写成空闲页
+// 淘汰时直接覆盖文件里的旧页, 所以只有 flush 完成的时候文件才是一致的
+// block 的编码方式由 C 决定, 默认是 bincode
+pub struct FileBlockEngine<B, C = BincodeCodec> {
+    pages: PageFile<C>,
+    pool: BufferPool<B>,
+    space: Mutex<Space>,
+    shadow: bool,
+    read_only: bool,
+    allocations: AtomicU64,
+}
+
+// 分配和回收要改的状态, 放在一把锁里, 分配和回收只要 &self
+struct Space {
+    block_count: usize,
+    free_list: Vec<BlockId>,
+    // free_list 里的 block, 查一个 block 是不是空闲的不用扫整个 free_list
+    free: HashSet<BlockId>,
+    // 回收之后还没写进文件的 block
+    freed: BTreeSet<BlockId>,
+    // 上一次 flush 时的状态
+    superblock: Superblock,
+    // set_user_metadata 之后还没 flush 的用户数据
+    user_metadata: Option<Vec<u8>>,
+    // stage_snapshots 之后还没 flush 的 snapshot
+    snapshots: Option<BTreeMap<String, TreeMeta>>,
+}
+
+impl Space {
+    fn check_block(&self, block_id: BlockId) -> Result<()> {
+        if block_id >= self.block_count || self.free.contains(&block_id) {
+            return Err(Error::InvalidBlock(block_id).into());
+        }
+        Ok(())
+    }
+}
+
+impl<B> FileBlockEngine<B>
+where
+    B: Serialize + DeserializeOwned,
+{
+    // 新建文件, 已经存在时清空
+    pub fn create(path: impl AsRef<Path>, options: FileOptions) -> Result<Self> {
+        Self::create_with_codec(path, options, BincodeCodec)
+    }
+
+    pub fn open(path: impl AsRef<Path>, options: FileOptions) -> Result<Self> {
+        Self::open_with_codec(path, options, BincodeCodec)
+    }
+}
+
+impl<B, C> FileBlockEngine<B, C>
+where
+    C: NodeCodec<B>,
+{
+    // 打开时要用和新建时一样的 codec
+    pub fn create_with_codec(path: impl AsRef<Path>, options: FileOptions, codec: C) -> Result<Self> {
+        if options.page_size < SUPERBLOCK_LEN.max(PAGE_HEADER_LEN + 8) {
+            return Err(anyhow!("page size {} is too small.", options.page_size));
+        }
+        if options.direct_io {
+            direct::check_page_size(options.page_size)?;
+        }
+        check_shadow(&options)?;
+        if options.read_only {
+            return Err(anyhow!("can not create a block file in read-only mode."));
+        }
+        let path = path.as_ref();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        // 两份都写上, 之后轮流覆盖
+        let mut superblock = Superblock::new(options.page_size);
+        superblock.write(&mut file)?;
+        write_superblock(&mut file, &mut superblock, true)?;
+        // 旧文件留下的日志和双写缓冲对新文件没有用, 留着的话打开时会被重放
+        for (enabled, stale) in [(options.wal, Wal::path(path)), (options.double_write, DoubleWrite::path(path))] {
+            if !enabled && stale.exists() {
+                std::fs::remove_file(stale)?;
+            }
+        }
+        let wal = if options.wal { Some(Mutex::new(Wal::create(path)?)) } else { None };
+        let double_write = if options.double_write { Some(Mutex::new(DoubleWrite::create(path)?)) } else { None };
+        let direct = if options.direct_io { Some(Mutex::new(direct::open_direct(path)?)) } else { None };
+        Ok(FileBlockEngine {
+            pages: PageFile {
+                file: Mutex::new(file),
+                direct,
+                wal,
+                double_write,
+                #[cfg(all(feature = "uring", target_os = "linux"))]
+                ring: None,
+                durability: options.durability,
+                syncs: Mutex::new(SyncState::new()),
+                written: AtomicU64::new(0),
+                page_size: options.page_size,
+                first_page: Superblock::first_block_page(options.page_size),
+                codec,
+                checksum: options.checksum,
+                compression: options.compression,
+            },
+            pool: BufferPool::new(options.pool_size, options.replacement)?,
+            space: Mutex::new(Space {
+                block_count: 0,
+                free_list: vec![],
+                free: HashSet::new(),
+                freed: BTreeSet::new(),
+                superblock,
+                user_metadata: None,
+                snapshots: None,
+            }),
+            shadow: options.shadow,
+            read_only: options.read_only,
+            allocations: AtomicU64::new(0),
+        })
+    }
+
+    pub fn open_with_codec(path: impl AsRef<Path>, options: FileOptions, codec: C) -> Result<Self> {
+        let path = path.as_ref();
+        check_shadow(&options)?;
+        check_read_only(path, &options)?;
+        let mut file = OpenOptions::new().read(true).write(!options.read_only).open(path)?;
+        let mut superblock = Superblock::read(&mut file)?;
+        let page_size = superblock.page_size;
+        if options.direct_io {
+            direct::check_page_size(page_size)?;
+        }
+        // 双写缓冲里的页在数据文件里校验和对不上的, 是覆盖写到一半崩溃了; 只读时上面检查过没有要恢复的
+        let mut double_write = None;
+        if !options.read_only && (options.double_write || DoubleWrite::path(path).exists()) {
+            let (mut buffer, pages) = DoubleWrite::open(path)?;
+            let mut page = vec![0; page_size];
+            for (page_no, image) in pages {
+                file.seek(SeekFrom::Start((page_no * page_size) as u64))?;
+                if file.read_exact(&mut page).is_err() || !page_intact(&page) {
+                    file.seek(SeekFrom::Start((page_no * page_size) as u64))?;
+                    file.write_all(&image)?;
+                }
+            }
+            file.sync_data()?;
+            buffer.clear()?;
+            double_write = Some(buffer);
+        }
+        let double_write = double_write.filter(|_| options.double_write).map(Mutex::new);
+        // 上次崩溃时留下的日志不管这次开不开 wal 都要重放, 最后一次提交的 superblock 换进文件之后才能清空日志
+        let mut wal = None;
+        if !options.read_only && (options.wal || Wal::path(path).exists()) {
+            let (mut log, committed) = Wal::open(path, &mut file, page_size, superblock.checkpoint_lsn)?;
+            if let Some(committed) = committed {
+                superblock = Superblock { seq: superblock.seq, ..Superblock::decode(&committed)? };
+            }
+            superblock.checkpoint_lsn = log.lsn();
+            write_superblock(&mut file, &mut superblock, true)?;
+            log.truncate()?;
+            wal = Some(log);
+        }
+        let wal = wal.filter(|_| options.wal).map(Mutex::new);
+        let block_count = superblock.block_count;
+        let (checksum, compression) = (options.checksum, options.compression);
+        let first_page = Superblock::first_block_page(page_size);
+        let file = Mutex::new(file);
+        // 恢复都用上面的句柄做完了, 之后的整页读写才换成 O_DIRECT
+        let direct = if options.direct_io { Some(Mutex::new(direct::open_direct(path)?)) } else { None };
+        let pages = PageFile {
+            file,
+            direct,
+            wal,
+            double_write,
+            #[cfg(all(feature = "uring", target_os = "linux"))]
+            ring: None,
+            durability: options.durability,
+            syncs: Mutex::new(SyncState::new()),
+            written: AtomicU64::new(0),
+            page_size,
+            first_page,
+            codec,
+            checksum,
+            compression,
+        };
+
+        // 链表头是最后回收的, 反过来就是回收的顺序
+        let mut free_list = vec![];
+        let mut cursor = superblock.free_head;
+        while let Some(block_id) = cursor {
+            if free_list.len() >= block_count {
+                return Err(anyhow!("free list of the block file has a cycle."));
+            }
+            free_list.push(block_id);
+            cursor = pages.read_free(block_id)?;
+        }
+        free_list.reverse();
+        let free = free_list.iter().copied().collect();
+
+        Ok(FileBlockEngine {
+            pages,
+            pool: BufferPool::new(options.pool_size, options.replacement)?,
+            space: Mutex::new(Space {
+                block_count,
+                free_list,
+                free,
+                freed: BTreeSet::new(),
+                superblock,
+                user_metadata: None,
+                snapshots: None,
+            }),
+            shadow: options.shadow,
+            read_only: options.read_only,
+            allocations: AtomicU64::new(0),
+        })
+    }
+
+    // 空闲链表里每一页指向比它早回收的那一页, 只有新回收的页需要写
+    fn write_freed(&self, space: &mut Space) -> Result<()> {
+        let pos: HashMap<_, _> = space.free_list.iter().enumerate().map(|(pos, &block_id)| (block_id, pos)).collect();
+        while let Some(&block_id) = space.freed.first() {
+            let next = pos[&block_id].checked_sub(1).map(|pos| space.free_list[pos]);
+            self.pages.write_free(block_id, next)?;
+            space.freed.remove(&block_id);
+        }
+        Ok(())
+    }
+
+    // 先写数据页, 再写 superblock, update 改 superblock 里的树
+    fn commit(&self, force: bool, update: impl FnOnce(&mut Superblock)) -> Result<()> {
+        self.pool.flush(&self.pages)?;
+        let mut space = self.space()?;
+        self.write_freed(&mut space)?;
+        update(&mut space.superblock);
+        space.superblock.block_count = space.block_count;
+        space.superblock.free_head = space.free_list.last().copied();
+        if let Some(user_metadata) = space.user_metadata.take() {
+            space.superblock.user_metadata = user_metadata;
+        }
+        if let Some(snapshots) = space.snapshots.take() {
+            space.superblock.snapshots = snapshots;
+        }
+        self.pages.commit(&mut space.superblock, force)
+    }
+
+    // 只要 &self 的 flush, force 时不管 durability 都 fsync, 见 TokioFileEngine
+    pub(crate) fn commit_meta(&self, meta: TreeMeta, force: bool) -> Result<()> {
+        self.check_writable()?;
+        self.commit(force, |superblock| superblock.meta = Some(meta))
+    }
+
+    // 上一次提交时 catalog 里的树, 见 TreeGroup
+    pub(crate) fn committed_trees(&self) -> Result<BTreeMap<String, TreeMeta>> {
+        Ok(self.space()?.superblock.trees.clone())
+    }
+
+    // catalog 里再加上 names 这些树还放不放得下, 已经在里面的名字不重复算
+    pub(crate) fn check_catalog<'a>(&self, names: impl IntoIterator<Item = &'a String>) -> Result<()> {
+        let space = self.space()?;
+        let snapshots = space.snapshots.as_ref().unwrap_or(&space.superblock.snapshots);
+        let mut catalog: BTreeSet<&str> = names.into_iter().map(String::as_str).collect();
+        catalog.extend(space.superblock.trees.keys().map(String::as_str));
+        let len: usize = catalog.iter().map(|name| Superblock::entry_len(name)).sum();
+        if len + Superblock::catalog_len(snapshots) > MAX_CATALOG {
+            return Err(anyhow!("catalog of {} trees does not fit into the superblock.", catalog.len()));
+        }
+        Ok(())
+    }
+
+    // 写回所有的页, 连同这些树的 root 一起提交, 没给的树和主树不变
+    pub(crate) fn commit_trees(&self, trees: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        self.check_writable()?;
+        let (mut catalog, snapshots_len) = {
+            let space = self.space()?;
+            let snapshots = space.snapshots.as_ref().unwrap_or(&space.superblock.snapshots);
+            (space.superblock.trees.clone(), Superblock::catalog_len(snapshots))
+        };
+        catalog.extend(trees.iter().map(|(name, &meta)| (name.clone(), meta)));
+        if Superblock::catalog_len(&catalog) + snapshots_len > MAX_CATALOG {
+            return Err(anyhow!("catalog of {} trees does not fit into the superblock.", catalog.len()));
+        }
+        self.commit(false, |superblock| superblock.trees = catalog)
+    }
+
+    // 和 BlockEngine::checkpoint 一样, 只是不用 &mut
+    pub(crate) fn checkpoint_trees(&self, trees: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        self.commit_trees(trees)?;
+        let mut space = self.space()?;
+        self.pages.checkpoint(&mut space.superblock)
+    }
+
+    // 打开已有的文件时是文件里记的, 不一定是 FileOptions 里的
+    pub fn page_size(&self) -> usize {
+        self.pages.page_size
+    }
+
+    fn space(&self) -> Result<MutexGuard<'_, Space>> {
+        Ok(self.space.lock().map_err(|_| Error::LockPoisoned)?)
+    }
+
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    pub(crate) fn set_ring(&mut self, ring: Ring) {
+        self.pages.ring = Some(Mutex::new(ring));
+    }
+
+    fn check_block(&self, block_id: BlockId) -> Result<()> {
+        if block_id >= self.space()?.block_count {
+            return Err(Error::InvalidBlock(block_id).into());
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly.into());
+        }
+        Ok(())
+    }
+}
+
+impl<B, C> BlockEngine for FileBlockEngine<B, C>
+where
+    C: NodeCodec<B>,
+{
+    type Item = B;
+
+    fn alloc_block(&self) -> Result<BlockId> {
+        self.check_writable()?;
+        let mut space = self.space()?;
+        let (block_id, reused) = match space.free_list.last() {
+            Some(&block_id) => (block_id, true),
+            None => (space.block_count, false),
+        };
+        if reused && self.shadow && space.superblock.free_head.is_some() {
+            // 整条链都摘下来, 下次 flush 时重新写一遍
+            let mut superblock = Superblock { free_head: None, ..space.superblock.clone() };
+            self.pages.commit(&mut superblock, true)?;
+            space.superblock = superblock;
+            let free_list = space.free_list.clone();
+            space.freed.extend(free_list);
+        }
+        self.pool.insert_new(block_id, &self.pages)?;
+        if reused {
+            space.free_list.pop();
+            space.free.remove(&block_id);
+            space.freed.remove(&block_id);
+        } else {
+            space.block_count += 1;
+        }
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        Ok(block_id)
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, B>> {
+        self.check_block(block_id)?;
+        self.pool.fetch_read(block_id, &self.pages)
+    }
+
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, B>> {
+        self.check_writable()?;
+        self.check_block(block_id)?;
+        self.pool.fetch_write(block_id, &self.pages)
+    }
+
+    // 读出内容时不拿着 space 的锁, 回收的 block 没有别人在用, 不会有两个线程同时回收它
+    fn delete(&self, block_id: BlockId) -> Result<Option<B>> {
+        self.check_writable()?;
+        self.space()?.check_block(block_id)?;
+        let content = match self.pool.remove(block_id)? {
+            Some(content) => content,
+            None => self.pages.read_page(block_id)?,
+        };
+        let mut space = self.space()?;
+        space.free_list.push(block_id);
+        space.free.insert(block_id);
+        space.freed.insert(block_id);
+        Ok(content)
+    }
+
+    // 写 guard 释放时还不写文件, dirty 的 block 在被淘汰或者 flush 时写回
+    fn write_back(_block_id: BlockId, _block: &Block<B>) {}
+
+    fn load_meta(&self) -> Option<TreeMeta> {
+        self.space.lock().ok()?.superblock.meta
+    }
+
+    // 和 catalog 一起放在 superblock 里, 放不下时返回错误
+    fn stage_snapshots(&self, snapshots: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        self.check_writable()?;
+        let mut space = self.space()?;
+        if Superblock::catalog_len(&space.superblock.trees) + Superblock::catalog_len(snapshots) > MAX_CATALOG {
+            return Err(anyhow!("{} snapshots do not fit into the superblock.", snapshots.len()));
+        }
+        space.snapshots = Some(snapshots.clone());
+        Ok(())
+    }
+
+    fn load_snapshots(&self) -> Result<BTreeMap<String, TreeMeta>> {
+        Ok(self.space()?.superblock.snapshots.clone())
+    }
+
+    fn flush(&mut self, meta: TreeMeta) -> Result<()> {
+        self.commit_meta(meta, false)
+    }
+
+    fn checkpoint(&mut self, meta: TreeMeta) -> Result<()> {
+        self.flush(meta)?;
+        let space = self.space.get_mut().map_err(|_| Error::LockPoisoned)?;
+        self.pages.checkpoint(&mut space.superblock)
+    }
+
+    // 只截掉文件末尾连着的空闲页, 中间的要挪动 block 才能去掉, engine 不知道谁指向它们, 留给之后分配时复用
+    // 截之前新的 superblock 一定要落盘, 开着 wal 时还要先 checkpoint
+    fn vacuum(&mut self, meta: TreeMeta) -> Result<usize> {
+        self.check_writable()?;
+        let space = self.space.get_mut().map_err(|_| Error::LockPoisoned)?;
+        let mut block_count = space.block_count;
+        while block_count > 0 && space.free.contains(&(block_count - 1)) {
+            block_count -= 1;
+        }
+        let trimmed = space.block_count - block_count;
+        if trimmed == 0 {
+            self.flush(meta)?;
+            return Ok(0);
+        }
+        // 留下的空闲页可能指向截掉的页, 整条链重写一遍
+        space.free_list.retain(|&block_id| block_id < block_count);
+        space.free.retain(|&block_id| block_id < block_count);
+        space.freed = space.free_list.iter().copied().collect();
+        space.block_count = block_count;
+        self.commit(true, |superblock| superblock.meta = Some(meta))?;
+        let space = self.space.get_mut().map_err(|_| Error::LockPoisoned)?;
+        self.pages.checkpoint(&mut space.superblock)?;
+        self.pages.truncate(block_count)?;
+        Ok(trimmed)
+    }
+
+    // 日志和双写缓冲不算在 file_bytes 里, checkpoint 之后日志会清空
+    fn stats(&self) -> BlockEngineStats {
+        let page_size = self.pages.page_size as u64;
+        let (live_bytes, file_bytes) = self.space.lock().map_or((0, 0), |space| {
+            let live = (space.block_count - space.free_list.len()) as u64 * page_size;
+            (live, (self.pages.first_page + space.block_count) as u64 * page_size)
+        });
+        BlockEngineStats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            bytes_written: self.pages.bytes_written().unwrap_or(0),
+            live_bytes,
+            file_bytes,
+            ..self.pool.stats()
+        }
+    }
+
+    // 存在 superblock 里, 下一次 flush 时和 root 一起写进去, 之前崩溃的话还是旧的
+    fn set_user_metadata(&mut self, bytes: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        if bytes.len() > MAX_USER_METADATA {
+            return Err(anyhow!("user metadata is {} bytes, at most {} bytes are allowed.", bytes.len(), MAX_USER_METADATA));
+        }
+        self.space.get_mut().map_err(|_| Error::LockPoisoned)?.user_metadata = Some(bytes.to_vec());
+        Ok(())
+    }
+
+    // 还没 flush 的也算
+    fn user_metadata(&self) -> Result<Vec<u8>> {
+        let space = self.space()?;
+        Ok(space.user_metadata.clone().unwrap_or_else(|| space.superblock.user_metadata.clone()))
+    }
+
+    // 还不在 buffer pool 里的 block 一批读进来, 不存在或者已经回收的 block 跳过
+    fn prefetch(&self, block_ids: &[BlockId]) -> Result<usize> {
+        let block_ids: Vec<_> = {
+            let space = self.space()?;
+            block_ids.iter().copied().filter(|&block_id| space.check_block(block_id).is_ok()).collect()
+        };
+        self.pool.prefetch(&block_ids, &self.pages)
+    }
+
+    fn resident(&self) -> Result<Vec<BlockId>> {
+        self.pool.resident()
+    }
+
+    fn pin(&self, block_id: BlockId) -> Result<()> {
+        self.space()?.check_block(block_id)?;
+        self.pool.pin(block_id, &self.pages)
+    }
+
+    fn unpin(&self, block_id: BlockId) -> Result<()> {
+        self.pool.unpin(block_id)
+    }
+}
+
+fn check_shadow(options: &FileOptions) -> Result<()> {
+    if options.shadow && options.wal {
+        return Err(anyhow!("shadow paging can not be used together with wal."));
+    }
+    Ok(())
+}
+
+// 只读打开时不能恢复, 留着没重放的日志或者双写缓冲的话打开的是不一致的文件
+fn check_read_only(path: &Path, options: &FileOptions) -> Result<()> {
+    if !options.read_only {
+        return Ok(());
+    }
+    if options.wal || options.double_write || options.direct_io {
+        return Err(anyhow!("read-only mode can not be used together with wal, double write or direct I/O."));
+    }
+    check_pending(path)
+}
+
+// 文件旁边的日志和双写缓冲不是空的, 说明上次没有正常关闭, 要先用可写的 FileBlockEngine 打开一次
+pub(crate) fn check_pending(path: &Path) -> Result<()> {
+    for pending in [Wal::path(path), DoubleWrite::path(path)] {
+        if std::fs::metadata(&pending).is_ok_and(|metadata| metadata.len() > 0) {
+            let pending = pending.display();
+            return Err(anyhow!("{} is not applied yet, open the file with FileBlockEngine first.", pending));
+        }
+    }
+    Ok(())
+}
+
+// 写到另一份上, fsync 之后才算换过去了
+pub(crate) fn write_superblock(file: &mut File, superblock: &mut Superblock, sync: bool) -> Result<()> {
+    superblock.seq += 1;
+    superblock.write(file)?;
+    if sync {
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+fn page_intact(page: &[u8]) -> bool {
+    page_checksum(page).to_le_bytes() == page[1..5]
+}
+
+// 跳过存校验和的 1..5
+fn page_checksum(page: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&page[..1]);
+    hasher.update(&page[5..]);
+    hasher.finalize()
+}
+
+fn encode_id(block_id: Option<BlockId>) -> [u8; 8] {
+    block_id.map_or(NONE, |block_id| block_id as u64).to_le_bytes()
+}
+
+fn decode_id(bytes: &[u8]) -> Option<BlockId> {
+    let mut id = [0; 8];
+    id.copy_from_slice(bytes);
+    let id = u64::from_le_bytes(id);
+    (id != NONE).then_some(id as BlockId)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU64;
+
+    use crate::{
+        capacity::NodeCapacity,
+        order::KeyOrder,
+        tree::{BPlusTree, BPlusTreeNode},
+    };
+
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("bplus-tree-{}-{}.db", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_file_engine_reopen() {
+        let path = temp_path("reopen");
+        // pool 只有 16 个 frame, 大部分 block 都要经过淘汰和重新读入
+        let options = FileOptions { page_size: 512, pool_size: 16, replacement: Replacement::LruK(2), ..FileOptions::default() };
+        {
+            let engine = FileBlockEngine::<BPlusTreeNode<u32, String>>::create(&path, options).unwrap();
+            let mut tree = BPlusTree::new(8, engine).unwrap();
+            for i in 0..2000 {
+                tree.insert(i * 7 % 2000, format!("value {}", i * 7 % 2000)).unwrap();
+            }
+            for i in (0..2000).step_by(2) {
+                tree.delete(&i).unwrap();
+            }
+            tree.verify().unwrap();
+            tree.flush().unwrap();
+        }
+        // 淘汰策略不影响文件内容, 换一个打开
+        let options = FileOptions { replacement: Replacement::Clock, ..options };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, String>>::open(&path, options).unwrap();
+        assert!(!engine.space().unwrap().free_list.is_empty());
+        let mut tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.len(), 1000);
+        assert_eq!(tree.search(&999).unwrap(), Some("value 999".to_string()));
+        assert_eq!(tree.keys().step_by(100).collect::<Vec<_>>(), (1..2000).step_by(200).collect::<Vec<_>>());
+
+        // 重新打开之后可以接着写, 回收的 block 会被重新用上, 放不进一页的结点写回时报错
+        let blocks = tree.engine.space().unwrap().block_count;
+        for i in (0..400).step_by(2) {
+            tree.insert(i, String::new()).unwrap();
+        }
+        assert_eq!(tree.engine.space().unwrap().block_count, blocks);
+        tree.insert(2, "x".repeat(1024)).unwrap();
+        assert!(tree.flush().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reopen_with_comparator() {
+        let path = temp_path("comparator");
+        let options = FileOptions { page_size: 512, pool_size: 16, ..FileOptions::default() };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap();
+        let mut tree = BPlusTree::with_comparator(4, engine, |a: &u32, b: &u32| b.cmp(a)).unwrap();
+        for i in 0..300 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        // 用别的顺序打开会把倒序的结点当成正序的来查, 直接拒绝
+        let open = || FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        assert!(BPlusTree::open(open()).is_err());
+        let order = KeyOrder::with_id(NonZeroU64::new(7).unwrap(), |a: &u32, b: &u32| b.cmp(a));
+        assert!(BPlusTree::open_with_order(open(), order, NodeCapacity::default()).is_err());
+        let tree = BPlusTree::open_with_comparator(open(), |a: &u32, b: &u32| b.cmp(a)).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.search(&150).unwrap(), Some(150));
+        assert_eq!(tree.keys().take(2).collect::<Vec<_>>(), vec![299, 298]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_checksum_policy() {
+        let path = temp_path("checksum");
+        let options = FileOptions { page_size: 256, pool_size: 4, ..FileOptions::default() };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap();
+        let mut tree = BPlusTree::new(4, engine).unwrap();
+        for i in 0..100 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        // 改掉 block 0 那一页 (两份 superblock 占了前 4 页) 末尾补的一个 0, 内容本身还能解码
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(5 * 256 - 1)).unwrap();
+        file.write_all(&[1]).unwrap();
+        drop(file);
+
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        let tree = BPlusTree::open(engine).unwrap();
+        let err = tree.verify().unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Corrupted(0, _))));
+
+        let options = FileOptions { checksum: ChecksumPolicy::Log, ..options };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        let tree = BPlusTree::open(engine).unwrap();
+        assert_eq!(tree.verify().unwrap().entries, 100);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_durability() {
+        let path = temp_path("durability");
+        let syncs = |durability| {
+            // pool 只有一个 frame, 每写一个新 block 都要写回上一个
+            let options = FileOptions { page_size: 256, pool_size: 1, durability, ..FileOptions::default() };
+            let mut engine = FileBlockEngine::<u64>::create(&path, options).unwrap();
+            for i in 0..10 {
+                engine.alloc_write(i).unwrap();
+            }
+            engine.flush(TreeMeta { root: 0, way: 4, len: 0, persistent: false, order: 0 }).unwrap();
+            let syncs = engine.pages.syncs.lock().unwrap().syncs;
+            drop(engine);
+            assert_eq!(FileBlockEngine::<u64>::open(&path, options).unwrap().fetch_read(9).unwrap().content, Some(9));
+            syncs
+        };
+        // 9 次淘汰写回, flush 时再写回最后一个, 然后提交
+        assert_eq!(syncs(Durability::Always), 11);
+        assert_eq!(syncs(Durability::Flush), 1);
+        assert_eq!(syncs(Durability::EveryN(4)), 2);
+        assert_eq!(syncs(Durability::Periodic(Duration::ZERO)), 11);
+        assert_eq!(syncs(Durability::Periodic(Duration::from_secs(3600))), 0);
+        assert_eq!(syncs(Durability::Never), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let path = temp_path("vacuum");
+        for wal in [false, true] {
+            let options = FileOptions { page_size: 256, pool_size: 4, wal, ..FileOptions::default() };
+            let meta = TreeMeta { root: 0, way: 4, len: 0, persistent: false, order: 3 };
+            let mut engine = FileBlockEngine::<u64>::create(&path, options).unwrap();
+            for i in 0..20 {
+                engine.alloc_write(i).unwrap();
+            }
+            engine.flush(meta).unwrap();
+            // 10..20 在末尾, 能截掉; 3 在中间, 只能留着
+            for i in [3, 12, 19, 10, 11, 13, 14, 15, 16, 17, 18] {
+                engine.delete(i).unwrap();
+            }
+            assert_eq!(engine.vacuum(meta).unwrap(), 10);
+            assert_eq!(engine.vacuum(meta).unwrap(), 0);
+            drop(engine);
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), (4 + 10) * 256);
+
+            // 重新打开之后先用中间的空闲页, 再往后长
+            let engine = FileBlockEngine::<u64>::open(&path, options).unwrap();
+            assert_eq!(engine.load_meta(), Some(meta));
+            assert_eq!(engine.space().unwrap().free_list, vec![3]);
+            assert!(engine.delete(3).is_err());
+            assert_eq!((engine.alloc_block().unwrap(), engine.alloc_block().unwrap()), (3, 10));
+            assert_eq!(engine.fetch_read(9).unwrap().content, Some(9));
+        }
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(Wal::path(&path)).unwrap();
+    }
+}
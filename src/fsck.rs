@@ -0,0 +1,639 @@
+use anyhow::{Ok, Result};
+#[cfg(feature = "file")]
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashSet;
+#[cfg(feature = "file")]
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::{
+    block::{BlockEngine, BlockId},
+    order::KeyOrder,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+#[cfg(feature = "file")]
+use crate::{
+    block::TreeMeta,
+    codec::{BincodeCodec, NodeCodec},
+    file::{self, ChecksumPolicy},
+    superblock::Superblock,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerifyMode {
+    // 空闲链表, 从 root 能不能走到每一个不空闲的 block, 树里的 block 能不能读
+    #[default]
+    Quick,
+    // 再加上结点里 key 的顺序和分隔 key 的范围, counts, 叶子的深度和叶子链; 查文件时还有每一页的校验和和条目数
+    Full,
+}
+
+// verify 发现的问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyProblem {
+    // 页的校验和对不上, 只有查文件时才有
+    Checksum { block_id: BlockId },
+    // 树里指向的 block 是空的, 越界了, 或者读不出来
+    Unreadable { block_id: BlockId, error: String },
+    // 结点自己的形状不对, 或者和父结点对不上
+    BadNode { block_id: BlockId, reason: String },
+    // 叶子的 prev / next 和从 root 往下看到的顺序不一致
+    BrokenLink { block_id: BlockId, reason: String },
+    // 空闲链表越界, 有重复或者有环, 或者指向不是空闲的页
+    FreeList { block_id: BlockId, reason: String },
+    // 同一个版本里有两个地方指向它, 两棵树都指向它, 或者它既在树里又在空闲链表里; snapshot 和主树共用结点不算
+    DoubleReference { block_id: BlockId },
+    // 既不在树里也不在空闲链表里, 占着空间再也用不上
+    Unreachable { block_id: BlockId },
+    // 和具体的页无关的问题: 文件被截短, 条目数对不上, key 的顺序对不上
+    Meta { reason: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VerifyReport {
+    pub mode: VerifyMode,
+    // engine 分配过的 block 数 (查文件时是 superblock 里记的), 从 root 和保留的版本能走到的, 空闲的
+    pub blocks: usize,
+    pub reachable: usize,
+    pub free: usize,
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// 直接检查 engine 里存着的 block, 不依赖树在内存里的状态; 当前 root, snapshot 和保留的历史版本都查
+// 问题都记在报告里接着往下查, 只有 engine 本身出错时才返回错误
+// engine 说不清用了哪些 block 时 (block_usage 是 None) 不查空闲链表和泄漏
+pub fn verify_tree<K, V, E>(tree: &BPlusTree<K, V, E>, mode: VerifyMode) -> Result<VerifyReport>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    let usage = tree.engine.block_usage();
+    let mut checker = Checker {
+        tree,
+        mode,
+        blocks: usage.as_ref().map(|(blocks, _)| *blocks),
+        free: HashSet::new(),
+        reached: HashSet::new(),
+        leaves: vec![],
+        report: VerifyReport { mode, ..VerifyReport::default() },
+    };
+    if let Some((blocks, free)) = &usage {
+        checker.report.blocks = *blocks;
+        checker.check_free_list(free);
+    }
+    let mut height = None;
+    let mut seen = HashSet::new();
+    checker.check_node(tree.root, None, None, 1, &mut seen, &mut height);
+    // 持久化模式下叶子链不可信, 不查
+    if mode == VerifyMode::Full && !tree.persistent {
+        checker.check_links();
+    }
+    for version in tree.retained_versions() {
+        let mut seen = HashSet::new();
+        let mut height = None;
+        checker.check_node(version.root, None, None, 1, &mut seen, &mut height);
+    }
+    // 持久化模式下被丢掉的旧版本还能通过 freeze 返回的 Version 读, 它们的 block 不算泄漏
+    if let (Some(blocks), false) = (checker.blocks, tree.persistent) {
+        for block_id in 0..blocks {
+            if !checker.free.contains(&block_id) && !checker.reached.contains(&block_id) {
+                checker.problem(VerifyProblem::Unreachable { block_id });
+            }
+        }
+    }
+    checker.report.free = checker.free.len();
+    checker.report.reachable = checker.reached.len();
+    Ok(checker.report)
+}
+
+struct Checker<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    tree: &'a BPlusTree<K, V, E>,
+    mode: VerifyMode,
+    blocks: Option<usize>,
+    free: HashSet<BlockId>,
+    // 所有版本里走到过的
+    reached: HashSet<BlockId>,
+    // 当前版本从左到右的叶子
+    leaves: Vec<(BlockId, Option<BlockId>, Option<BlockId>)>,
+    report: VerifyReport,
+}
+
+impl<K, V, E> Checker<'_, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    fn problem(&mut self, problem: VerifyProblem) {
+        self.report.problems.push(problem);
+    }
+
+    fn check_free_list(&mut self, free: &[BlockId]) {
+        for &block_id in free {
+            if block_id >= self.report.blocks {
+                let reason = format!("points past the last block {}", self.report.blocks);
+                self.problem(VerifyProblem::FreeList { block_id, reason });
+            } else if !self.free.insert(block_id) {
+                self.problem(VerifyProblem::FreeList { block_id, reason: "block is freed twice".to_string() });
+            }
+        }
+    }
+
+    // seen 是这个版本里走到过的; 别的版本已经查过的 block 是冻结的, 共享的子树不再往下走
+    fn check_node(
+        &mut self,
+        block_id: BlockId,
+        lower: Option<&K>,
+        upper: Option<&K>,
+        depth: usize,
+        seen: &mut HashSet<BlockId>,
+        height: &mut Option<usize>,
+    ) {
+        if self.blocks.is_some_and(|blocks| block_id >= blocks) {
+            let error = format!("points past the last block {}", self.report.blocks);
+            return self.problem(VerifyProblem::Unreadable { block_id, error });
+        }
+        if self.free.contains(&block_id) || !seen.insert(block_id) {
+            return self.problem(VerifyProblem::DoubleReference { block_id });
+        }
+        if !self.reached.insert(block_id) {
+            return;
+        }
+        let tree = self.tree;
+        let read = match tree.engine.fetch_read(block_id) {
+            Result::Ok(read) => read,
+            Err(e) => return self.problem(VerifyProblem::Unreadable { block_id, error: e.to_string() }),
+        };
+        let Some(node) = read.as_ref() else {
+            return self.problem(VerifyProblem::Unreadable { block_id, error: "block is empty".to_string() });
+        };
+        let full = self.mode == VerifyMode::Full;
+        if full {
+            if let Some(reason) = self.node_problem(node, lower, upper) {
+                self.problem(VerifyProblem::BadNode { block_id, reason });
+            }
+        }
+
+        if node.is_leaf() {
+            match *height {
+                Some(height) if full && height != depth => {
+                    let reason = format!("leaf at depth {} while others are at {}", depth, height);
+                    self.problem(VerifyProblem::BadNode { block_id, reason });
+                }
+                Some(_) => {}
+                None => *height = Some(depth),
+            }
+            self.leaves.push((block_id, node.prev, node.next));
+            return;
+        }
+        // 形状不对时 keys 和 pointers 对不上, 只往下走 pointers
+        for (i, &child) in node.pointers.iter().enumerate() {
+            let lower = if i == 0 { lower } else { node.keys.get(i - 1) };
+            let upper = if i == node.keys.len() { upper } else { node.keys.get(i) };
+            self.check_node(child, lower, upper, depth + 1, seen, height);
+        }
+    }
+
+    fn node_problem(&self, node: &BPlusTreeNode<K, V>, lower: Option<&K>, upper: Option<&K>) -> Option<String> {
+        let way = self.tree.way;
+        if node.way != way {
+            return Some(format!("way is {} instead of {}", node.way, way));
+        }
+        if node.is_overflow() {
+            return Some(format!("{} keys exceed way {}", node.keys.len(), way));
+        }
+        shape_problem(node).or_else(|| order_problem(&self.tree.order, node, lower, upper))
+    }
+
+    fn check_links(&mut self) {
+        for i in 0..self.leaves.len() {
+            let (block_id, prev, next) = self.leaves[i];
+            let expected_prev = i.checked_sub(1).map(|i| self.leaves[i].0);
+            let expected_next = self.leaves.get(i + 1).map(|leaf| leaf.0);
+            if prev != expected_prev || next != expected_next {
+                let reason =
+                    format!("leaf links are {:?} / {:?} instead of {:?} / {:?}", prev, next, expected_prev, expected_next);
+                self.problem(VerifyProblem::BrokenLink { block_id, reason });
+            }
+        }
+    }
+}
+
+// 结点自己的形状, 和 key 的顺序无关
+fn shape_problem<K: Ord, V>(node: &BPlusTreeNode<K, V>) -> Option<String> {
+    if node.is_leaf() {
+        if node.keys.len() != node.values.len() || !node.pointers.is_empty() || !node.counts.is_empty() {
+            return Some("leaf has mismatched keys and values".to_string());
+        }
+    } else if node.pointers.len() != node.keys.len() + 1
+        || node.counts.len() != node.pointers.len()
+        || node.maxes.len() != node.pointers.len()
+        || !node.values.is_empty()
+    {
+        return Some("inner node has mismatched keys, pointers, counts and maxes".to_string());
+    }
+    None
+}
+
+fn order_problem<K: Ord, V>(
+    order: &KeyOrder<K>,
+    node: &BPlusTreeNode<K, V>,
+    lower: Option<&K>,
+    upper: Option<&K>,
+) -> Option<String> {
+    if node.keys.windows(2).any(|w| !order.lt(&w[0], &w[1])) {
+        return Some("keys are not strictly ascending".to_string());
+    }
+    let in_range =
+        |key: &K| lower.is_none_or(|lower| !order.lt(key, lower)) && upper.is_none_or(|upper| order.lt(key, upper));
+    if !node.keys.iter().all(in_range) {
+        return Some("key outside the range of its separators".to_string());
+    }
+    None
+}
+
+// 不经过 FileBlockEngine 直接检查文件, 文件要是 flush 过的, 旁边留着没重放的日志时报错
+// 问题都记在报告里接着往下查, 只有 superblock 读不出来这种查不下去的情况才返回错误
+#[cfg(feature = "file")]
+pub fn verify<K, V>(path: impl AsRef<Path>, mode: VerifyMode) -> Result<VerifyReport>
+where
+    K: Ord + Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    verify_with::<K, V, _>(path, mode, &BincodeCodec, &KeyOrder::default())
+}
+
+// 树用别的 codec 或者 key 顺序建的时候用这个
+#[cfg(feature = "file")]
+pub fn verify_with<K, V, C>(path: impl AsRef<Path>, mode: VerifyMode, codec: &C, order: &KeyOrder<K>) -> Result<VerifyReport>
+where
+    K: Ord,
+    C: NodeCodec<BPlusTreeNode<K, V>>,
+{
+    let path = path.as_ref();
+    file::check_pending(path)?;
+    let mut file = File::open(path)?;
+    let superblock = Superblock::read(&mut file)?;
+    let mut checker = FileChecker {
+        file,
+        codec,
+        order,
+        mode,
+        page_size: superblock.page_size,
+        first_page: Superblock::first_block_page(superblock.page_size),
+        free: HashSet::new(),
+        reached: HashSet::new(),
+        seen: HashSet::new(),
+        shared: false,
+        leaves: vec![],
+        report: VerifyReport { mode, blocks: superblock.block_count, ..VerifyReport::default() },
+    };
+    checker.check_len()?;
+    checker.check_free_list(superblock.free_head);
+    if superblock.meta.is_none() && superblock.trees.is_empty() && superblock.block_count > 0 {
+        checker.problem(VerifyProblem::Meta { reason: "blocks exist but no tree was flushed".to_string() });
+    }
+    // 主树和 TreeGroup 里的树不能共用 block
+    let trees = superblock.meta.map(|meta| (None, meta));
+    for (name, meta) in trees.into_iter().chain(superblock.trees.iter().map(|(name, &meta)| (Some(name.as_str()), meta))) {
+        checker.check_tree(name.map_or("main tree".to_string(), |name| format!("tree {}", name)), meta);
+    }
+    // snapshot 和主树共用没改过的结点, 共用的结点每个版本都查一遍
+    checker.shared = true;
+    for (name, &meta) in &superblock.snapshots {
+        checker.check_tree(format!("snapshot {}", name), meta);
+    }
+    for block_id in 0..superblock.block_count {
+        if checker.free.contains(&block_id) || checker.reached.contains(&block_id) {
+            continue;
+        }
+        checker.problem(VerifyProblem::Unreachable { block_id });
+        if mode == VerifyMode::Full {
+            checker.read_page(block_id);
+        }
+    }
+    checker.report.free = checker.free.len();
+    checker.report.reachable = checker.reached.len();
+    Ok(checker.report)
+}
+
+#[cfg(feature = "file")]
+struct FileChecker<'a, K, C> {
+    file: File,
+    codec: &'a C,
+    order: &'a KeyOrder<K>,
+    mode: VerifyMode,
+    page_size: usize,
+    first_page: usize,
+    free: HashSet<BlockId>,
+    reached: HashSet<BlockId>,
+    // 正在查的这棵树里走到的
+    seen: HashSet<BlockId>,
+    // 在查 snapshot, 别的树里走到过的结点也可以再走
+    shared: bool,
+    // 从左到右的叶子
+    leaves: Vec<(BlockId, Option<BlockId>, Option<BlockId>)>,
+    report: VerifyReport,
+}
+
+#[cfg(feature = "file")]
+impl<K, C> FileChecker<'_, K, C>
+where
+    K: Ord,
+{
+    fn problem(&mut self, problem: VerifyProblem) {
+        self.report.problems.push(problem);
+    }
+
+    fn check_len(&mut self) -> Result<()> {
+        let expected = ((self.first_page + self.report.blocks) * self.page_size) as u64;
+        let actual = self.file.metadata()?.len();
+        if actual < expected {
+            let reason = format!("file has {} bytes but {} blocks need {}", actual, self.report.blocks, expected);
+            self.problem(VerifyProblem::Meta { reason });
+        }
+        Ok(())
+    }
+
+    // 校验和对不上时记下来, 不返回内容; 读不出来时记成 Unreadable
+    fn read_page(&mut self, block_id: BlockId) -> Option<Vec<u8>> {
+        let mut page = vec![0; self.page_size];
+        let read = self
+            .file
+            .seek(SeekFrom::Start(((self.first_page + block_id) * self.page_size) as u64))
+            .and_then(|_| self.file.read_exact(&mut page));
+        if let Err(e) = read {
+            self.problem(VerifyProblem::Unreadable { block_id, error: e.to_string() });
+            return None;
+        }
+        if file::check_page(block_id, &page, ChecksumPolicy::Error).is_err() {
+            self.problem(VerifyProblem::Checksum { block_id });
+            return None;
+        }
+        Some(page)
+    }
+
+    fn check_free_list(&mut self, mut cursor: Option<BlockId>) {
+        while let Some(block_id) = cursor {
+            if block_id >= self.report.blocks {
+                let reason = format!("points past the last block {}", self.report.blocks);
+                return self.problem(VerifyProblem::FreeList { block_id, reason });
+            }
+            if !self.free.insert(block_id) {
+                return self.problem(VerifyProblem::FreeList { block_id, reason: "list has a cycle".to_string() });
+            }
+            let Some(page) = self.read_page(block_id) else {
+                return;
+            };
+            match file::decode_free(block_id, &page) {
+                Result::Ok(next) => cursor = next,
+                Err(e) => return self.problem(VerifyProblem::FreeList { block_id, reason: e.to_string() }),
+            }
+        }
+    }
+
+    // tree 是报告里用的名字
+    fn check_tree<V>(&mut self, tree: String, meta: TreeMeta)
+    where
+        C: NodeCodec<BPlusTreeNode<K, V>>,
+    {
+        // 别的顺序下 key 的比较都没有意义, 只查结构
+        let ordered = meta.order == self.order.id();
+        if !ordered && self.mode == VerifyMode::Full {
+            let reason = format!("{} uses key order {} but {} was given", tree, meta.order, self.order.id());
+            self.problem(VerifyProblem::Meta { reason });
+        }
+        self.leaves.clear();
+        self.seen.clear();
+        let mut height = None;
+        let entries = self.check_node(meta.root, meta, None, None, 1, ordered, &mut height);
+        if self.mode == VerifyMode::Quick {
+            return;
+        }
+        if entries.is_some_and(|entries| entries != meta.len) {
+            let reason = format!("{} has {} entries but the superblock says {}", tree, entries.unwrap_or(0), meta.len);
+            self.problem(VerifyProblem::Meta { reason });
+        }
+        // 持久化模式下叶子链不可信, 和 BPlusTree::verify 一样不查
+        if meta.persistent {
+            return;
+        }
+        for i in 0..self.leaves.len() {
+            let (block_id, prev, next) = self.leaves[i];
+            let expected_prev = i.checked_sub(1).map(|i| self.leaves[i].0);
+            let expected_next = self.leaves.get(i + 1).map(|leaf| leaf.0);
+            if prev != expected_prev || next != expected_next {
+                let reason =
+                    format!("leaf links are {:?} / {:?} instead of {:?} / {:?}", prev, next, expected_prev, expected_next);
+                self.problem(VerifyProblem::BrokenLink { block_id, reason });
+            }
+        }
+    }
+
+    // 返回子树里的条目数, 子树里有读不出来的页时是 None
+    // 不查结点太空: 按字节算容量的树里 key 少的结点也是合法的
+    #[allow(clippy::too_many_arguments)]
+    fn check_node<V>(
+        &mut self,
+        block_id: BlockId,
+        meta: TreeMeta,
+        lower: Option<&K>,
+        upper: Option<&K>,
+        depth: usize,
+        ordered: bool,
+        height: &mut Option<usize>,
+    ) -> Option<usize>
+    where
+        C: NodeCodec<BPlusTreeNode<K, V>>,
+    {
+        if block_id >= self.report.blocks {
+            let error = format!("points past the last block {}", self.report.blocks);
+            self.problem(VerifyProblem::Unreadable { block_id, error });
+            return None;
+        }
+        let first = self.reached.insert(block_id);
+        if self.free.contains(&block_id) || !self.seen.insert(block_id) || (!first && !self.shared) {
+            self.problem(VerifyProblem::DoubleReference { block_id });
+            return None;
+        }
+        let page = self.read_page(block_id)?;
+        let node = match file::decode_page(self.codec, block_id, &page) {
+            Result::Ok(Some(node)) => node,
+            Result::Ok(None) => {
+                self.problem(VerifyProblem::Unreadable { block_id, error: "block is empty".to_string() });
+                return None;
+            }
+            Err(e) => {
+                self.problem(VerifyProblem::Unreadable { block_id, error: e.to_string() });
+                return None;
+            }
+        };
+        let full = self.mode == VerifyMode::Full;
+        if full {
+            if let Some(reason) = self.node_problem(&node, meta, lower, upper, ordered) {
+                self.problem(VerifyProblem::BadNode { block_id, reason });
+            }
+        }
+
+        if node.is_leaf() {
+            match *height {
+                Some(height) if full && height != depth => {
+                    let reason = format!("leaf at depth {} while others are at {}", depth, height);
+                    self.problem(VerifyProblem::BadNode { block_id, reason });
+                }
+                Some(_) => {}
+                None => *height = Some(depth),
+            }
+            self.leaves.push((block_id, node.prev, node.next));
+            return Some(node.keys.len());
+        }
+
+        // 形状不对时 keys 和 pointers 对不上, 只往下走 pointers
+        let mut entries = Some(0);
+        for (i, &child) in node.pointers.iter().enumerate() {
+            let lower = if i == 0 { lower } else { node.keys.get(i - 1) };
+            let upper = if i == node.keys.len() { upper } else { node.keys.get(i) };
+            let actual = self.check_node(child, meta, lower, upper, depth + 1, ordered, height);
+            if let (Some(actual), Some(&count)) = (actual, node.counts.get(i)) {
+                if full && actual != count {
+                    let reason = format!("count of child {} is {} but it has {} entries", child, count, actual);
+                    self.problem(VerifyProblem::BadNode { block_id, reason });
+                }
+            }
+            entries = entries.zip(actual).map(|(entries, actual)| entries + actual);
+        }
+        entries
+    }
+
+    fn node_problem<V>(
+        &self,
+        node: &BPlusTreeNode<K, V>,
+        meta: TreeMeta,
+        lower: Option<&K>,
+        upper: Option<&K>,
+        ordered: bool,
+    ) -> Option<String> {
+        if node.way != meta.way {
+            return Some(format!("way is {} instead of {}", node.way, meta.way));
+        }
+        if node.is_overflow() {
+            return Some(format!("{} keys exceed way {}", node.keys.len(), meta.way));
+        }
+        shape_problem(node).or_else(|| if ordered { order_problem(self.order, node, lower, upper) } else { None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_verify_tree() {
+        let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        for i in 0..300u32 {
+            tree.insert(i, i).unwrap();
+        }
+        let report = verify_tree(&tree, VerifyMode::Full).unwrap();
+        assert!(report.is_ok());
+        assert_eq!((report.reachable, report.free), (report.blocks, 0));
+
+        let leaked = tree.engine.alloc_write(BPlusTreeNode::new_leaf(4)).unwrap();
+        let report = verify_tree(&tree, VerifyMode::Quick).unwrap();
+        assert_eq!(report.problems, vec![VerifyProblem::Unreachable { block_id: leaked }]);
+        tree.engine.delete(leaked).unwrap();
+        assert!(verify_tree(&tree, VerifyMode::Quick).unwrap().is_ok());
+
+        // 把一个叶子的 key 改乱, quick 只看结构发现不了
+        let leaf = tree.find_leaf(tree.root, &100).unwrap();
+        tree.engine.fetch_write(leaf).unwrap().as_mut().unwrap().keys.reverse();
+        assert!(verify_tree(&tree, VerifyMode::Quick).unwrap().is_ok());
+        let report = verify_tree(&tree, VerifyMode::Full).unwrap();
+        assert_eq!(report.problems.len(), 1);
+        assert!(matches!(&report.problems[0], VerifyProblem::BadNode { block_id, .. } if *block_id == leaf));
+    }
+
+    #[test]
+    fn test_verify_versions() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..100u32 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.create_snapshot("base").unwrap();
+        for i in 0..50u32 {
+            tree.delete(&i).unwrap();
+        }
+        // snapshot 和当前版本共享没改过的子树
+        let report = verify_tree(&tree, VerifyMode::Full).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.reachable + report.free, report.blocks);
+
+        // snapshot 里的 block 被当成空闲的回收了
+        let version = tree.open_snapshot("base").unwrap().version();
+        let root = tree.engine.fetch_read(version.root).unwrap().as_ref().unwrap().pointers[0];
+        tree.engine.delete(root).unwrap();
+        let report = verify_tree(&tree, VerifyMode::Quick).unwrap();
+        assert_eq!(report.problems, vec![VerifyProblem::DoubleReference { block_id: root }]);
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_verify_file() {
+        use std::{fs::OpenOptions, io::Write};
+
+        use crate::file::{FileBlockEngine, FileOptions};
+
+        let path = std::env::temp_dir().join(format!("bplus-tree-fsck-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 256, pool_size: 16, ..FileOptions::default() };
+        let mut tree = BPlusTree::new(4, FileBlockEngine::create(&path, options).unwrap()).unwrap();
+        for i in 0..300u32 {
+            tree.insert(i, i).unwrap();
+        }
+        for i in 0..100u32 {
+            tree.delete(&i).unwrap();
+        }
+        tree.flush().unwrap();
+        let root = tree.root;
+        let leaked = tree.engine.alloc_write(BPlusTreeNode::new_leaf(4)).unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+
+        let report = verify::<u32, u32>(&path, VerifyMode::Full).unwrap();
+        assert_eq!(report.problems, vec![VerifyProblem::Unreachable { block_id: leaked }]);
+        assert!(report.free > 0);
+        assert_eq!(report.reachable + report.free + 1, report.blocks);
+
+        // 用别的顺序查时 key 的顺序不查, 结构照样查
+        let reversed = KeyOrder::new(|a: &u32, b: &u32| b.cmp(a));
+        let report = verify_with::<u32, u32, _>(&path, VerifyMode::Full, &BincodeCodec, &reversed).unwrap();
+        assert!(matches!(report.problems[0], VerifyProblem::Meta { .. }));
+        assert_eq!(report.problems.len(), 2);
+
+        // 改坏 root 那一页的一个字节, quick 也能发现, 它下面的页都变成走不到的了
+        let first_page = Superblock::first_block_page(256);
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(((first_page + root + 1) * 256 - 1) as u64)).unwrap();
+        file.write_all(&[1]).unwrap();
+        drop(file);
+        let report = verify::<u32, u32>(&path, VerifyMode::Quick).unwrap();
+        assert_eq!(report.problems[0], VerifyProblem::Checksum { block_id: root });
+        assert_eq!(report.reachable, 1);
+        assert!(report.problems.len() > 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
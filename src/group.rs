@@ -0,0 +1,309 @@
+use anyhow::{anyhow, Ok, Result};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{
+    block::{Block, BlockEngine, BlockEngineStats, BlockId, BlockReadGuard, BlockWriteGuard, TreeMeta},
+    capacity::NodeCapacity,
+    codec::{BincodeCodec, NodeCodec},
+    error::Error,
+    file::FileBlockEngine,
+    order::KeyOrder,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 几棵有名字的树共用一个 FileBlockEngine, root 按名字记在 superblock 的 catalog 里, 和 engine 自己的主树互不相干
+// 提交分两步: 树的 flush 只把 root 暂存在 group 里, commit 时暂存的 root 和所有写过的页一起提交
+// 开着 wal 时一次 commit 是日志里的一条 commit 记录, 崩溃之后这些树要么都是新的, 要么都是旧的
+// 页是原地改的, 一棵树 flush 之后又改过的话, 它改了一半的页会跟着别的树一起提交, 所以这时 commit 报错
+// 打开的树拿着 group 的一个引用, group 可以在树之前 drop
+pub struct TreeGroup<K: Ord, V, C = BincodeCodec> {
+    shared: Arc<Shared<K, V, C>>,
+}
+
+struct Shared<K: Ord, V, C> {
+    engine: FileBlockEngine<BPlusTreeNode<K, V>, C>,
+    state: Mutex<GroupState>,
+}
+
+#[derive(Default)]
+struct GroupState {
+    // 打开着的树, 同一个名字同时只能打开一次
+    open: HashSet<String>,
+    // 上次 flush 之后改过的树, drop 了也还在, 重新打开并 flush 之后才去掉
+    dirty: HashSet<String>,
+    // flush 过还没提交的 root
+    staged: BTreeMap<String, TreeMeta>,
+}
+
+impl<K, V, C> TreeGroup<K, V, C>
+where
+    K: Ord + Clone,
+    V: Clone,
+    C: NodeCodec<BPlusTreeNode<K, V>>,
+{
+    pub fn new(engine: FileBlockEngine<BPlusTreeNode<K, V>, C>) -> Self {
+        TreeGroup { shared: Arc::new(Shared { engine, state: Mutex::new(GroupState::default()) }) }
+    }
+
+    pub fn engine(&self) -> &FileBlockEngine<BPlusTreeNode<K, V>, C> {
+        &self.shared.engine
+    }
+
+    // 打开名字叫 name 的树, 还没有时用 way 新建一棵; 新建的树 flush 并 commit 之后才记进 catalog
+    pub fn tree(&self, name: &str, way: usize) -> Result<BPlusTree<K, V, GroupEngine<K, V, C>>> {
+        self.tree_with_order(name, way, KeyOrder::default(), NodeCapacity::default())
+    }
+
+    // order 和 capacity 打开已有的树时要和新建时的一样
+    pub fn tree_with_order(
+        &self,
+        name: &str,
+        way: usize,
+        order: KeyOrder<K>,
+        capacity: NodeCapacity<K, V>,
+    ) -> Result<BPlusTree<K, V, GroupEngine<K, V, C>>> {
+        if name.len() > u8::MAX as usize {
+            return Err(anyhow!("tree name is {} bytes, at most {} bytes are allowed.", name.len(), u8::MAX));
+        }
+        {
+            // 打开着的新树 flush 之后都要进 catalog, superblock 里放不下时现在就报错, 不要等到 commit
+            let mut state = self.shared.state()?;
+            if state.open.contains(name) {
+                return Err(anyhow!("tree {} is already open.", name));
+            }
+            let name = name.to_string();
+            self.shared.engine.check_catalog(state.open.iter().chain(state.staged.keys()).chain([&name]))?;
+            state.open.insert(name);
+        }
+        let engine = GroupEngine { shared: self.shared.clone(), name: name.to_string() };
+        if engine.load_meta().is_some() {
+            return BPlusTree::open_with_order(engine, order, capacity);
+        }
+        let mut tree = BPlusTree::with_order(way, engine, order)?;
+        tree.capacity = capacity;
+        Ok(tree)
+    }
+
+    // 提交过的和暂存着的树的名字
+    pub fn names(&self) -> Result<Vec<String>> {
+        let mut names: HashSet<_> = self.shared.engine.committed_trees()?.into_keys().collect();
+        names.extend(self.shared.state()?.staged.keys().cloned());
+        let mut names: Vec<_> = names.into_iter().collect();
+        names.sort();
+        Ok(names)
+    }
+
+    // 暂存的 root 一起提交; 有树在 flush 之后又改过时返回错误, 什么都不写
+    pub fn commit(&self) -> Result<()> {
+        let mut state = self.shared.state()?;
+        check_clean(&state)?;
+        self.shared.engine.commit_trees(&state.staged)?;
+        state.staged.clear();
+        Ok(())
+    }
+
+    // commit 之后把日志里的页写进数据文件, 没开 wal 时和 commit 一样
+    pub fn checkpoint(&self) -> Result<()> {
+        let mut state = self.shared.state()?;
+        check_clean(&state)?;
+        self.shared.engine.checkpoint_trees(&state.staged)?;
+        state.staged.clear();
+        Ok(())
+    }
+}
+
+impl<K: Ord, V, C> Shared<K, V, C> {
+    fn state(&self) -> Result<MutexGuard<'_, GroupState>> {
+        Ok(self.state.lock().map_err(|_| Error::LockPoisoned)?)
+    }
+}
+
+fn check_clean(state: &GroupState) -> Result<()> {
+    if state.dirty.is_empty() {
+        return Ok(());
+    }
+    let mut dirty: Vec<_> = state.dirty.iter().collect();
+    dirty.sort();
+    Err(anyhow!("trees {:?} changed after their last flush.", dirty))
+}
+
+// TreeGroup 里一棵树用的 engine, 读写都交给共用的 FileBlockEngine, flush 只是暂存 root
+pub struct GroupEngine<K: Ord, V, C = BincodeCodec> {
+    shared: Arc<Shared<K, V, C>>,
+    name: String,
+}
+
+impl<K, V, C> GroupEngine<K, V, C>
+where
+    K: Ord + Clone,
+    V: Clone,
+    C: NodeCodec<BPlusTreeNode<K, V>>,
+{
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn touch(&self) -> Result<()> {
+        let mut state = self.shared.state()?;
+        if !state.dirty.contains(&self.name) {
+            state.dirty.insert(self.name.clone());
+        }
+        Ok(())
+    }
+}
+
+impl<K, V, C> BlockEngine for GroupEngine<K, V, C>
+where
+    K: Ord + Clone,
+    V: Clone,
+    C: NodeCodec<BPlusTreeNode<K, V>>,
+{
+    type Item = BPlusTreeNode<K, V>;
+
+    fn alloc_block(&self) -> Result<BlockId> {
+        self.touch()?;
+        self.shared.engine.alloc_block()
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, Self::Item>> {
+        self.shared.engine.fetch_read(block_id)
+    }
+
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, Self::Item>> {
+        self.touch()?;
+        self.shared.engine.fetch_write(block_id)
+    }
+
+    // 只回收 block 不算改了树: 回收的 block 已经不在当前的 root 下面, 和它一起被改的结点会用 fetch_write
+    // shadow paging 在 commit 里 flush 之后才还掉上一个版本, 这时树没有变
+    fn delete(&self, block_id: BlockId) -> Result<Option<Self::Item>> {
+        self.shared.engine.delete(block_id)
+    }
+
+    fn write_back(block_id: BlockId, block: &Block<Self::Item>) {
+        FileBlockEngine::<Self::Item, C>::write_back(block_id, block)
+    }
+
+    // 暂存的比提交过的新
+    fn load_meta(&self) -> Option<TreeMeta> {
+        let staged = self.shared.state().ok()?.staged.get(&self.name).copied();
+        staged.or_else(|| self.shared.engine.committed_trees().ok()?.remove(&self.name))
+    }
+
+    fn flush(&mut self, meta: TreeMeta) -> Result<()> {
+        let mut state = self.shared.state()?;
+        state.staged.insert(self.name.clone(), meta);
+        state.dirty.remove(&self.name);
+        Ok(())
+    }
+
+    fn stats(&self) -> BlockEngineStats {
+        self.shared.engine.stats()
+    }
+
+    fn prefetch(&self, block_ids: &[BlockId]) -> Result<usize> {
+        self.shared.engine.prefetch(block_ids)
+    }
+
+    fn resident(&self) -> Result<Vec<BlockId>> {
+        self.shared.engine.resident()
+    }
+
+    fn pin(&self, block_id: BlockId) -> Result<()> {
+        self.shared.engine.pin(block_id)
+    }
+
+    fn unpin(&self, block_id: BlockId) -> Result<()> {
+        self.shared.engine.unpin(block_id)
+    }
+}
+
+impl<K: Ord, V, C> Drop for GroupEngine<K, V, C> {
+    fn drop(&mut self) {
+        if let std::result::Result::Ok(mut state) = self.shared.state.lock() {
+            state.open.remove(&self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{file::FileOptions, fsck, wal::Wal};
+
+    use super::*;
+
+    #[test]
+    fn test_tree_group() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-group-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, pool_size: 16, wal: true, ..FileOptions::default() };
+        let group = TreeGroup::new(FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap());
+        // 主表和按 value 建的二级索引
+        let mut primary = group.tree("primary", 8).unwrap();
+        let mut index = group.tree("index", 8).unwrap();
+        assert!(group.tree("primary", 8).is_err());
+        for i in 0..200 {
+            primary.insert(i, i * 10).unwrap();
+            index.insert(i * 10, i).unwrap();
+        }
+        primary.flush().unwrap();
+        assert!(group.commit().is_err());
+        index.flush().unwrap();
+        group.commit().unwrap();
+
+        // 只提交了主表的那次没有发生, 崩溃之后两棵树都停在上一次 commit
+        for i in 200..300 {
+            primary.insert(i, i * 10).unwrap();
+            index.insert(i * 10, i).unwrap();
+        }
+        primary.flush().unwrap();
+        assert!(group.commit().is_err());
+        drop((primary, index));
+        drop(group);
+
+        let group = TreeGroup::new(FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap());
+        assert_eq!(group.names().unwrap(), vec!["index", "primary"]);
+        let primary = group.tree("primary", 8).unwrap();
+        let index = group.tree("index", 8).unwrap();
+        assert_eq!((primary.len(), index.len()), (200, 200));
+        primary.verify().unwrap();
+        index.verify().unwrap();
+        assert_eq!(index.search(&1990).unwrap(), Some(199));
+        drop((primary, index));
+        group.checkpoint().unwrap();
+        drop(group);
+
+        // catalog 里的树 fsck 也认得, 没有走不到的页
+        let report = fsck::verify::<u32, u32>(&path, fsck::VerifyMode::Full).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(Wal::path(&path)).unwrap();
+    }
+
+    #[test]
+    fn test_catalog_full() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-group-full-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, pool_size: 16, ..FileOptions::default() };
+        let group = TreeGroup::new(FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap());
+        assert!(group.tree(&"x".repeat(250), 8).is_err());
+        // 放不下的那棵树打开时就报错, 已经打开的树照常提交
+        let mut trees = vec![];
+        while let Result::Ok(mut tree) = group.tree(&format!("tree-{}", trees.len()), 8) {
+            tree.insert(1, 1).unwrap();
+            trees.push(tree);
+        }
+        assert!(!trees.is_empty());
+        for tree in &mut trees {
+            tree.flush().unwrap();
+        }
+        group.commit().unwrap();
+        assert_eq!(group.names().unwrap().len(), trees.len());
+        // 已经在 catalog 里的树还能重新打开
+        let name = trees[0].engine.name().to_string();
+        drop(trees);
+        assert_eq!(group.tree(&name, 8).unwrap().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::{Ok, Result};
+use std::{
+    collections::HashSet,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use crate::{
+    amplification::{record_written, EntrySizeFn},
+    block::{BlockEngine, BlockId, BlockReadGuard, BlockWriteGuard},
+    error::Error,
+    refcount::RefCounts,
+    snapshot::{Frozen, History},
+    tree::{BPlusTree, BPlusTreeNode, TreeStats, Version},
+};
+
+// 叶子里某个 value 的读 guard, 拿着叶子 block 的读锁, 读 value 不需要 clone
+pub struct ValueReadGuard<'a, K: Ord, V> {
+    guard: BlockReadGuard<'a, BPlusTreeNode<K, V>>,
+    pos: usize,
+}
+
+impl<K: Ord, V> ValueReadGuard<'_, K, V> {
+    pub fn key(&self) -> &K {
+        &self.guard.as_ref().expect("leaf checked in get").keys[self.pos]
+    }
+}
+
+impl<K: Ord, V> Deref for ValueReadGuard<'_, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        // get 里检查过叶子不为空, 拿着读锁期间不会变
+        &self.guard.as_ref().expect("leaf checked in get").values[self.pos]
+    }
+}
+
+// 叶子里某个 value 的写 guard, 拿着叶子 block 的写锁
+// drop 时先按改完的 value 记写进来的字节, 字段再按声明顺序 drop: 先释放叶子 (触发 engine 的 write back), 再记一次修改
+pub struct ValueWriteGuard<'a, K: Ord, V> {
+    guard: BlockWriteGuard<'a, BPlusTreeNode<K, V>>,
+    pos: usize,
+    record: RecordOnDrop<'a, K, V>,
+}
+
+impl<K: Ord, V> Drop for ValueWriteGuard<'_, K, V> {
+    fn drop(&mut self) {
+        if let Some(node) = self.guard.as_ref() {
+            let record = &mut self.record;
+            record_written(record.stats, record.entry_size, &node.keys[self.pos], &node.values[self.pos]);
+        }
+    }
+}
+
+struct RecordOnDrop<'a, K, V> {
+    stats: &'a mut TreeStats,
+    entry_size: &'a Option<Arc<EntrySizeFn<K, V>>>,
+    history: &'a mut History,
+    persistent: &'a mut bool,
+    owned: &'a mut HashSet<BlockId>,
+    refs: &'a mut RefCounts,
+    version: Version,
+}
+
+impl<K, V> Drop for RecordOnDrop<'_, K, V> {
+    fn drop(&mut self) {
+        let frozen = Frozen { persistent: self.persistent, owned: self.owned, refs: self.refs };
+        self.history.record(self.version, frozen);
+    }
+}
+
+impl<K: Ord, V> Deref for ValueWriteGuard<'_, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        // get_mut 里检查过叶子不为空, 拿着写锁期间不会变
+        &self.guard.as_ref().expect("leaf checked in get_mut").values[self.pos]
+    }
+}
+
+impl<K: Ord, V> DerefMut for ValueWriteGuard<'_, K, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.guard.as_mut().expect("leaf checked in get_mut").values[self.pos]
+    }
+}
+
+// 只读不 clone, 所以不要求 K / V: Clone
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    pub fn get(&self, key: &K) -> Result<Option<ValueReadGuard<'_, K, V>>> {
+        let mut block_id = self.root;
+        loop {
+            let guard = self.engine.fetch_read(block_id)?;
+            let node = guard.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                let Result::Ok(pos) = self.order.search(&node.keys, key) else {
+                    return Ok(None);
+                };
+                return Ok(Some(ValueReadGuard { guard, pos }));
+            }
+            block_id = node.pointers[node.child_index(key, &self.order)];
+        }
+    }
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 原地修改 value, 持久化模式下先复制路径, 旧版本不受影响
+    pub fn get_mut(&mut self, key: &K) -> Result<Option<ValueWriteGuard<'_, K, V>>> {
+        let Some(leaf) = self.own_leaf(key)? else {
+            return Ok(None);
+        };
+        let version = self.version();
+        let BPlusTree { engine, order, stats, entry_size, history, persistent, owned, refs, .. } = self;
+        let guard = engine.fetch_write(leaf)?;
+        let node = guard.as_ref().ok_or(Error::EmptyBlock(leaf))?;
+        let Result::Ok(pos) = order.search(&node.keys, key) else {
+            return Ok(None);
+        };
+        Ok(Some(ValueWriteGuard {
+            guard,
+            pos,
+            record: RecordOnDrop { stats, entry_size, history, persistent, owned, refs, version },
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{block::MemoryBlockEngine, snapshot::{AsOf, Retention}};
+
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..30u8 {
+            tree.insert(i, vec![i; 1024]).unwrap();
+        }
+        let guard = tree.get(&7).unwrap().unwrap();
+        assert_eq!((*guard.key(), guard.len(), guard[0]), (7, 1024, 7));
+        drop(guard);
+        assert!(tree.get(&100).unwrap().is_none());
+        tree.freeze();
+        tree.delete(&7).unwrap();
+        assert!(tree.get(&7).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..30 {
+            tree.insert(i, vec![i]).unwrap();
+        }
+        tree.get_mut(&5).unwrap().unwrap().push(50);
+        assert_eq!(tree.search(&5).unwrap(), Some(vec![5, 50]));
+        assert!(tree.get_mut(&100).unwrap().is_none());
+
+        let version = tree.freeze();
+        tree.set_retention(Some(Retention::Versions(10)));
+        let seq = tree.seq();
+        *tree.get_mut(&7).unwrap().unwrap() = vec![];
+        assert_eq!(tree.search(&7).unwrap(), Some(vec![]));
+        assert_eq!(tree.search_at(version, &7).unwrap(), Some(vec![7]));
+        // 修改在 guard drop 之后才记成新版本
+        assert_eq!(tree.seq(), seq + 1);
+        assert_eq!(tree.as_of(AsOf::Seq(seq)).unwrap().search(&7).unwrap(), Some(vec![7]));
+        assert_eq!(tree.as_of(AsOf::Seq(seq + 1)).unwrap().search(&7).unwrap(), Some(vec![]));
+    }
+}
@@ -0,0 +1,154 @@
+use anyhow::{anyhow, Ok, Result};
+use std::ops::Range;
+
+use crate::{
+    block::{BlockEngine, BlockId},
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 区间 [start, end) 作为 key, 按 (start, end) 排序存, 同一个 start 可以有多个区间
+// 内部结点里记着每个子树的 max end, 查询时按 start 停止, 按 max end 跳过整个子树
+pub struct IntervalMap<T, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<(T, T), V>>,
+    T: Ord,
+{
+    tree: BPlusTree<(T, T), V, E>,
+}
+
+impl<T, V, E> IntervalMap<T, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<(T, T), V>>,
+    T: Ord + Clone,
+    V: Clone,
+{
+    pub fn new(way: usize, engine: E) -> Result<Self> {
+        let mut tree = BPlusTree::new(way, engine)?;
+        tree.set_augment(|a: &(T, T), b: &(T, T)| a.1.cmp(&b.1))?;
+        Ok(IntervalMap { tree })
+    }
+
+    pub fn tree(&self) -> &BPlusTree<(T, T), V, E> {
+        &self.tree
+    }
+
+    // 同一个区间已经存在时替换 value, 返回旧的
+    pub fn insert(&mut self, interval: Range<T>, value: V) -> Result<Option<V>> {
+        if interval.start >= interval.end {
+            return Err(anyhow!("interval must not be empty."));
+        }
+        self.tree.insert((interval.start, interval.end), value)
+    }
+
+    pub fn remove(&mut self, interval: Range<T>) -> Result<Option<V>> {
+        self.tree.delete(&(interval.start, interval.end))
+    }
+
+    pub fn get(&self, interval: Range<T>) -> Result<Option<V>> {
+        self.tree.search(&(interval.start, interval.end))
+    }
+
+    // 包含 point 的所有区间, 按 start 排序
+    pub fn stab(&self, point: &T) -> Result<Vec<(Range<T>, V)>> {
+        let mut ret = vec![];
+        self.collect(self.tree.root, point, &|start| start > point, &mut ret)?;
+        Ok(ret)
+    }
+
+    // 和 range 有交集的所有区间, 按 start 排序
+    pub fn overlapping(&self, range: Range<T>) -> Result<Vec<(Range<T>, V)>> {
+        let mut ret = vec![];
+        self.collect(self.tree.root, &range.start, &|start| *start >= range.end, &mut ret)?;
+        Ok(ret)
+    }
+
+    // 收集子树里 end > after 的区间, start 满足 stop 时停止, 返回 false 表示已经停止
+    // stop 对 start 是单调的, 分隔 key 的 start 满足 stop 时右边的子树都不用看
+    fn collect<F>(&self, block_id: BlockId, after: &T, stop: &F, ret: &mut Vec<(Range<T>, V)>) -> Result<bool>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let children = {
+            let read = self.tree.engine.fetch_read(block_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                for ((start, end), value) in node.keys.iter().zip(&node.values) {
+                    if stop(start) {
+                        return Ok(false);
+                    }
+                    if end > after {
+                        ret.push((start.clone()..end.clone(), value.clone()));
+                    }
+                }
+                return Ok(true);
+            }
+            let mut children = vec![];
+            for (i, &child) in node.pointers.iter().enumerate() {
+                if i > 0 && stop(&node.keys[i - 1].0) {
+                    break;
+                }
+                if node.maxes[i].as_ref().is_none_or(|(_, end)| end > after) {
+                    children.push(child);
+                }
+            }
+            children
+        };
+        for child in children {
+            if !self.collect(child, after, stop, ret)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_stab_and_overlapping() {
+        let mut map = IntervalMap::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..50u32 {
+            map.insert(i * 10..i * 10 + 15, i).unwrap();
+        }
+        map.insert(0..1000, 1000).unwrap();
+        assert_eq!(map.insert(0..1000, 1001).unwrap(), Some(1000));
+        assert!(map.insert(5..5, 0).is_err());
+
+        let hits: Vec<_> = map.stab(&42).unwrap().into_iter().map(|(_, v)| v).collect();
+        assert_eq!(hits, vec![1001, 3, 4]);
+        assert_eq!(map.stab(&2000).unwrap(), vec![]);
+
+        let hits: Vec<_> = map.overlapping(15..21).unwrap().into_iter().map(|(r, _)| r).collect();
+        assert_eq!(hits, vec![0..1000, 10..25, 20..35]);
+
+        assert_eq!(map.remove(0..1000).unwrap(), Some(1001));
+        assert_eq!(map.stab(&5).unwrap(), vec![(0..15, 0)]);
+        map.tree().verify().unwrap();
+    }
+
+    #[test]
+    fn test_max_end_after_deletes() {
+        let mut map = IntervalMap::new(4, MemoryBlockEngine::new()).unwrap();
+        let intervals: Vec<_> = (0..400u32).map(|i| (i * 7 % 400)..(i * 7 % 400 + i % 13 + 1)).collect();
+        for (i, interval) in intervals.iter().enumerate() {
+            map.insert(interval.clone(), i).unwrap();
+        }
+        for (i, interval) in intervals.iter().enumerate().filter(|(i, _)| i % 3 == 0) {
+            assert_eq!(map.remove(interval.clone()).unwrap(), Some(i));
+        }
+        map.tree().verify().unwrap();
+        for point in [0, 57, 199, 398, 420] {
+            let mut expected: Vec<_> = (0..intervals.len())
+                .filter(|i| i % 3 != 0 && intervals[*i].contains(&point))
+                .map(|i| (intervals[i].clone(), i))
+                .collect();
+            expected.sort_by_key(|(r, _)| (r.start, r.end));
+            assert_eq!(map.stab(&point).unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,907 @@
+use anyhow::{Ok, Result};
+use std::{
+    collections::VecDeque,
+    iter::Map,
+    ops::{Bound, RangeBounds},
+    vec,
+};
+
+use crate::{
+    block::{BlockEngine, BlockId},
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 顺序扫描时最多提前交给 engine 预读的叶子数
+const READAHEAD: usize = 8;
+
+// 在叶子之间移动的游标
+// 叶子链可信的时候直接走 next, 否则 (持久化模式 / 旧版本) 靠 root 到叶子的路径找下一个叶子
+#[derive(Clone)]
+pub(crate) struct LeafCursor {
+    follow_links: bool,
+    // (内部结点, 当前走的子结点下标); 走 next 的时候不更新, 只在预读时对一下
+    path: Vec<(BlockId, usize)>,
+    leaf: Option<BlockId>,
+    // 已经预读过, 还没走到的叶子
+    ahead: VecDeque<BlockId>,
+}
+
+impl LeafCursor {
+    // 定位到 start 所在的叶子
+    pub(crate) fn seek<K, V, E>(tree: &BPlusTree<K, V, E>, root: BlockId, start: Bound<&K>) -> Result<LeafCursor>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        Self::descend(tree, root, |node| match start {
+            Bound::Included(key) | Bound::Excluded(key) => node.child_index(key, &tree.order),
+            Bound::Unbounded => 0,
+        })
+    }
+
+    // 定位到 end 所在的叶子, 反向扫描用
+    pub(crate) fn seek_back<K, V, E>(tree: &BPlusTree<K, V, E>, root: BlockId, end: Bound<&K>) -> Result<LeafCursor>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        Self::descend(tree, root, |node| match end {
+            Bound::Included(key) | Bound::Excluded(key) => node.child_index(key, &tree.order),
+            Bound::Unbounded => node.pointers.len() - 1,
+        })
+    }
+
+    // 从 root 往下走, 每一层由 pick 选子结点
+    fn descend<K, V, E, P>(tree: &BPlusTree<K, V, E>, root: BlockId, mut pick: P) -> Result<LeafCursor>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+        P: FnMut(&BPlusTreeNode<K, V>) -> usize,
+    {
+        let mut cursor = LeafCursor {
+            follow_links: !tree.persistent && root == tree.root,
+            path: vec![],
+            leaf: None,
+            ahead: VecDeque::new(),
+        };
+        let mut block_id = root;
+        loop {
+            let read = tree.engine.fetch_read(block_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                cursor.leaf = Some(block_id);
+                return Ok(cursor);
+            }
+            let pos = pick(node);
+            cursor.path.push((block_id, pos));
+            block_id = node.pointers[pos];
+        }
+    }
+
+    // 什么都不指向的游标, 出错时用
+    pub(crate) fn empty() -> LeafCursor {
+        LeafCursor { follow_links: false, path: vec![], leaf: None, ahead: VecDeque::new() }
+    }
+
+    pub(crate) fn leaf(&self) -> Option<BlockId> {
+        self.leaf
+    }
+
+    // 移到下一个叶子, 没有了返回 None
+    pub(crate) fn next<K, V, E>(&mut self, tree: &BPlusTree<K, V, E>) -> Result<Option<BlockId>>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        let Some(leaf) = self.leaf else {
+            return Ok(None);
+        };
+        self.leaf = if self.follow_links {
+            let read = tree.engine.fetch_read(leaf)?;
+            read.as_ref().ok_or(Error::EmptyBlock(leaf))?.next
+        } else {
+            self.next_by_path(tree)?
+        };
+        Ok(self.leaf)
+    }
+
+    // 往右顺序扫描时每到一个叶子调用一次, 预读的还剩不到一半时把 parent 里后面的叶子一批交给 engine
+    // 分隔 key 已经超过 end 的叶子不预读
+    pub(crate) fn readahead<K, V, E>(&mut self, tree: &BPlusTree<K, V, E>, end: Bound<&K>) -> Result<()>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        let Some(leaf) = self.leaf else {
+            return Ok(());
+        };
+        if self.ahead.front() == Some(&leaf) {
+            self.ahead.pop_front();
+        } else {
+            self.ahead.clear();
+        }
+        if self.ahead.len() > READAHEAD / 2 {
+            return Ok(());
+        }
+        let Some((parent, pos)) = self.locate(tree, leaf)? else {
+            return Ok(());
+        };
+        let read = tree.engine.fetch_read(parent)?;
+        let node = read.as_ref().ok_or(Error::EmptyBlock(parent))?;
+        let wanted: Vec<_> = (pos + 1..node.pointers.len())
+            .take_while(|&i| tree.order.before_end(end, &node.keys[i - 1]))
+            .take(READAHEAD)
+            .map(|i| node.pointers[i])
+            .collect();
+        let fresh: Vec<_> = wanted.iter().copied().filter(|block_id| !self.ahead.contains(block_id)).collect();
+        if !fresh.is_empty() {
+            tree.engine.prefetch(&fresh)?;
+        }
+        self.ahead = wanted.into();
+        Ok(())
+    }
+
+    // 找到叶子的 parent 和它在 parent 里的下标, 叶子就是 root 时返回 None
+    // 沿着 next 走过来时 path 已经过时, 换了 parent 的话用叶子的第一个 key 从 root 重新走一遍
+    fn locate<K, V, E>(&mut self, tree: &BPlusTree<K, V, E>, leaf: BlockId) -> Result<Option<(BlockId, usize)>>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        for retry in [false, true] {
+            let Some(&(parent, _)) = self.path.last() else {
+                return Ok(None);
+            };
+            let pos = {
+                let read = tree.engine.fetch_read(parent)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(parent))?;
+                node.pointers.iter().position(|&child| child == leaf)
+            };
+            if let Some(pos) = pos {
+                self.path.last_mut().unwrap().1 = pos;
+                return Ok(Some((parent, pos)));
+            }
+            if retry || !self.follow_links {
+                break;
+            }
+            let first = {
+                let read = tree.engine.fetch_read(leaf)?;
+                read.as_ref().ok_or(Error::EmptyBlock(leaf))?.keys.first().cloned()
+            };
+            let Some(first) = first else {
+                break;
+            };
+            self.path = Self::seek(tree, tree.root, Bound::Included(&first))?.path;
+        }
+        Ok(None)
+    }
+
+    // 移到上一个叶子, 没有了返回 None
+    pub(crate) fn prev<K, V, E>(&mut self, tree: &BPlusTree<K, V, E>) -> Result<Option<BlockId>>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        let Some(leaf) = self.leaf else {
+            return Ok(None);
+        };
+        self.leaf = if self.follow_links {
+            let read = tree.engine.fetch_read(leaf)?;
+            read.as_ref().ok_or(Error::EmptyBlock(leaf))?.prev
+        } else {
+            self.prev_by_path(tree)?
+        };
+        Ok(self.leaf)
+    }
+
+    fn prev_by_path<K, V, E>(&mut self, tree: &BPlusTree<K, V, E>) -> Result<Option<BlockId>>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        while let Some((block_id, pos)) = self.path.pop() {
+            if pos == 0 {
+                continue;
+            }
+            self.path.push((block_id, pos - 1));
+            let mut child = {
+                let read = tree.engine.fetch_read(block_id)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+                node.pointers[pos - 1]
+            };
+            // 下降到最右边的叶子
+            loop {
+                let read = tree.engine.fetch_read(child)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(child))?;
+                if node.is_leaf() {
+                    return Ok(Some(child));
+                }
+                let last = node.pointers.len() - 1;
+                self.path.push((child, last));
+                child = node.pointers[last];
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_by_path<K, V, E>(&mut self, tree: &BPlusTree<K, V, E>) -> Result<Option<BlockId>>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+        K: Ord + Clone,
+        V: Clone,
+    {
+        while let Some((block_id, pos)) = self.path.pop() {
+            let mut child = {
+                let read = tree.engine.fetch_read(block_id)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+                if pos + 1 >= node.pointers.len() {
+                    continue;
+                }
+                self.path.push((block_id, pos + 1));
+                node.pointers[pos + 1]
+            };
+            // 下降到最左边的叶子
+            loop {
+                let read = tree.engine.fetch_read(child)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(child))?;
+                if node.is_leaf() {
+                    return Ok(Some(child));
+                }
+                self.path.push((child, 0));
+                child = node.pointers[0];
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 对 root 下 [start, end] 范围内的每个叶子调用 f, 传入叶子结点和范围内条目的下标区间
+    // f 返回 false 时提前结束
+    pub(crate) fn for_each_leaf_in_range<F>(&self, root: BlockId, start: Bound<&K>, end: Bound<&K>, mut f: F) -> Result<()>
+    where
+        F: FnMut(&BPlusTreeNode<K, V>, std::ops::Range<usize>) -> bool,
+    {
+        let mut cursor = LeafCursor::seek(self, root, start)?;
+        while let Some(leaf) = cursor.leaf() {
+            {
+                let read = self.engine.fetch_read(leaf)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(leaf))?;
+                let from = node.keys.partition_point(|key| !self.order.after_start(start, key));
+                let to = node.keys.partition_point(|key| self.order.before_end(end, key));
+                if from < to && !f(node, from..to) {
+                    return Ok(());
+                }
+                if to < node.keys.len() {
+                    return Ok(());
+                }
+            }
+            cursor.next(self)?;
+            // 预读只是提示, 失败了照常一个个读
+            cursor.readahead(self, end).ok();
+        }
+        Ok(())
+    }
+}
+
+// 带过滤条件的范围扫描, 条件在叶子里判断, 不满足的条目不会被 clone
+pub struct RangeFiltered<'a, K, V, E, F>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    tree: &'a BPlusTree<K, V, E>,
+    root: BlockId,
+    cursor: LeafCursor,
+    // 两头扫过的部分会从 start / end 里收缩掉, 两边不会重复返回同一个条目
+    start: Bound<K>,
+    end: Bound<K>,
+    predicate: F,
+    buffer: VecDeque<(K, V)>,
+    done: bool,
+    // 反向扫描的游标, 第一次 next_back 时才定位; back_buffer 里是降序的
+    back: Option<LeafCursor>,
+    back_buffer: VecDeque<(K, V)>,
+    back_done: bool,
+    // 读结点出错时迭代提前结束, 错误留在这里
+    error: Option<anyhow::Error>,
+}
+
+impl<'a, K, V, E, F> RangeFiltered<'a, K, V, E, F>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+    F: FnMut(&K, &V) -> bool,
+{
+    // 迭代因为出错而提前结束时返回那个错误, 没出错时是 None
+    // 普通的 next 只会返回 None, 要区分扫完了和出错了时检查它, 或者用 try_range
+    pub fn error(&self) -> Option<&anyhow::Error> {
+        self.error.as_ref()
+    }
+
+    // 出错之后两头都不再读, 已经读出来还没返回的条目也丢掉
+    fn fail(&mut self, e: anyhow::Error) {
+        self.error.get_or_insert(e);
+        self.done = true;
+        self.back_done = true;
+        self.buffer.clear();
+        self.back_buffer.clear();
+    }
+
+    // 把当前叶子里满足条件的条目放进 buffer, 然后移到下一个叶子
+    fn fill(&mut self) -> Result<()> {
+        while self.buffer.is_empty() && !self.done {
+            let Some(leaf) = self.cursor.leaf() else {
+                self.done = true;
+                break;
+            };
+            {
+                let read = self.tree.engine.fetch_read(leaf)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(leaf))?;
+                let mut last = None;
+                for (key, value) in node.keys.iter().zip(node.values.iter()) {
+                    if !self.tree.order.after_start(self.start.as_ref(), key) {
+                        continue;
+                    }
+                    if !self.tree.order.before_end(self.end.as_ref(), key) {
+                        self.done = true;
+                        break;
+                    }
+                    last = Some(key);
+                    if (self.predicate)(key, value) {
+                        self.buffer.push_back((key.clone(), value.clone()));
+                    }
+                }
+                if let Some(last) = last {
+                    self.start = Bound::Excluded(last.clone());
+                }
+            }
+            self.cursor.next(self.tree)?;
+            // 预读只是提示, 失败了照常一个个读
+            if !self.done {
+                self.cursor.readahead(self.tree, self.end.as_ref()).ok();
+            }
+        }
+        Ok(())
+    }
+
+    // 和 fill 对称, 从 end 往左
+    fn fill_back(&mut self) -> Result<()> {
+        if self.back.is_none() && !self.back_done {
+            self.back = Some(LeafCursor::seek_back(self.tree, self.root, self.end.as_ref())?);
+        }
+        while self.back_buffer.is_empty() && !self.back_done {
+            let Some(cursor) = self.back.as_mut() else {
+                break;
+            };
+            let Some(leaf) = cursor.leaf() else {
+                self.back_done = true;
+                break;
+            };
+            {
+                let read = self.tree.engine.fetch_read(leaf)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(leaf))?;
+                let mut first = None;
+                for (key, value) in node.keys.iter().zip(node.values.iter()).rev() {
+                    if !self.tree.order.before_end(self.end.as_ref(), key) {
+                        continue;
+                    }
+                    if !self.tree.order.after_start(self.start.as_ref(), key) {
+                        self.back_done = true;
+                        break;
+                    }
+                    first = Some(key);
+                    if (self.predicate)(key, value) {
+                        self.back_buffer.push_back((key.clone(), value.clone()));
+                    }
+                }
+                if let Some(first) = first {
+                    self.end = Bound::Excluded(first.clone());
+                }
+            }
+            cursor.prev(self.tree)?;
+        }
+        Ok(())
+    }
+}
+
+// 不带过滤条件的范围扫描
+pub type Range<'a, K, V, E> = RangeFiltered<'a, K, V, E, fn(&K, &V) -> bool>;
+pub type Keys<'a, K, V, E> = Map<Range<'a, K, V, E>, fn((K, V)) -> K>;
+pub type Values<'a, K, V, E> = Map<Range<'a, K, V, E>, fn((K, V)) -> V>;
+
+impl<'a, K, V, E, F> Iterator for RangeFiltered<'a, K, V, E, F>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+    F: FnMut(&K, &V) -> bool,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill() {
+            self.fail(e);
+        }
+        // 正向扫完了, 剩下的都在反向的 buffer 里
+        self.buffer.pop_front().or_else(|| self.back_buffer.pop_back())
+    }
+}
+
+impl<'a, K, V, E, F> DoubleEndedIterator for RangeFiltered<'a, K, V, E, F>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+    F: FnMut(&K, &V) -> bool,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.fill_back() {
+            self.fail(e);
+        }
+        self.back_buffer.pop_front().or_else(|| self.buffer.pop_back())
+    }
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 第一个 >= key 的条目
+    pub fn lower_bound(&self, key: &K) -> Result<Option<(K, V)>> {
+        self.first_after(Bound::Included(key))
+    }
+
+    // 第一个 > key 的条目
+    pub fn upper_bound(&self, key: &K) -> Result<Option<(K, V)>> {
+        self.first_after(Bound::Excluded(key))
+    }
+
+    // 只 clone 找到的那一个条目, start 落在叶子末尾时往右走一个叶子
+    fn first_after(&self, start: Bound<&K>) -> Result<Option<(K, V)>> {
+        let mut cursor = LeafCursor::seek(self, self.root, start)?;
+        while let Some(leaf) = cursor.leaf() {
+            {
+                let read = self.engine.fetch_read(leaf)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(leaf))?;
+                let pos = node.keys.partition_point(|key| !self.order.after_start(start, key));
+                if pos < node.keys.len() {
+                    return Ok(Some((node.keys[pos].clone(), node.values[pos].clone())));
+                }
+            }
+            cursor.next(self)?;
+        }
+        Ok(None)
+    }
+
+    pub fn iter(&self) -> Range<'_, K, V, E> {
+        self.range(..)
+    }
+
+    pub fn keys(&self) -> Keys<'_, K, V, E> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> Values<'_, K, V, E> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    // 定位到起点所在的叶子, 之后沿着叶子往右扫
+    // 读结点出错时迭代提前结束, 结束后用 error() 检查, 或者直接用 try_range
+    pub fn range<R>(&self, range: R) -> Range<'_, K, V, E>
+    where
+        R: RangeBounds<K>,
+    {
+        self.range_filtered(range, |_, _| true)
+    }
+
+    pub fn range_filtered<R, F>(&self, range: R, predicate: F) -> RangeFiltered<'_, K, V, E, F>
+    where
+        R: RangeBounds<K>,
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.range_filtered_at(self.root, range, predicate)
+    }
+
+    // 扫 root 下的那个版本, 不是当前 root 时不走叶子链表
+    pub(crate) fn range_filtered_at<R, F>(&self, root: BlockId, range: R, predicate: F) -> RangeFiltered<'_, K, V, E, F>
+    where
+        R: RangeBounds<K>,
+        F: FnMut(&K, &V) -> bool,
+    {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let (cursor, error) = match LeafCursor::seek(self, root, start.as_ref()) {
+            Result::Ok(cursor) => (cursor, None),
+            Err(e) => (LeafCursor::empty(), Some(e)),
+        };
+        let done = error.is_some();
+        RangeFiltered {
+            tree: self,
+            root,
+            cursor,
+            start,
+            end,
+            predicate,
+            buffer: VecDeque::new(),
+            done,
+            back: None,
+            back_buffer: VecDeque::new(),
+            back_done: done,
+            error,
+        }
+    }
+
+    // 只看 key 的版本, 条件不满足时完全不碰 value
+    pub fn range_filtered_by_key<R, F>(&self, range: R, mut predicate: F) -> RangeFiltered<'_, K, V, E, impl FnMut(&K, &V) -> bool>
+    where
+        R: RangeBounds<K>,
+        F: FnMut(&K) -> bool,
+    {
+        self.range_filtered(range, move |key, _| predicate(key))
+    }
+
+    // key 以 prefix 开头的所有条目: 从 prefix 开始往右扫, 碰到第一个不匹配的 key 就停
+    // 要求 K 的顺序和字节序一致 (String / Vec<u8> 都是)
+    pub fn scan_prefix(&self, prefix: &K) -> impl Iterator<Item = (K, V)> + '_
+    where
+        K: AsRef<[u8]>,
+    {
+        let bytes = prefix.as_ref().to_vec();
+        self.range(prefix.clone()..)
+            .take_while(move |(key, _)| key.as_ref().starts_with(&bytes))
+    }
+
+    pub fn try_iter(&self) -> TryRange<'_, K, V, E> {
+        self.try_range(..)
+    }
+
+    // 和 range 一样, 但是读结点出错时先返回一个 Err 再结束, 不会悄悄地少返回条目
+    pub fn try_range<R>(&self, range: R) -> TryRange<'_, K, V, E>
+    where
+        R: RangeBounds<K>,
+    {
+        TryRange { inner: self.range(range) }
+    }
+
+    pub fn try_scan_prefix(&self, prefix: &K) -> impl Iterator<Item = Result<(K, V)>> + '_
+    where
+        K: AsRef<[u8]>,
+    {
+        let bytes = prefix.as_ref().to_vec();
+        self.try_range(prefix.clone()..)
+            .take_while(move |item| item.as_ref().map_or(true, |(key, _)| key.as_ref().starts_with(&bytes)))
+    }
+}
+
+// range 的可失败版本, 出错之后返回一次 Err, 然后两头都结束
+pub struct TryRange<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    pub(crate) inner: Range<'a, K, V, E>,
+}
+
+impl<'a, K, V, E> Iterator for TryRange<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.inner.next() {
+            Some(item) => Some(Ok(item)),
+            None => self.inner.error.take().map(Err),
+        }
+    }
+}
+
+impl<'a, K, V, E> DoubleEndedIterator for TryRange<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match self.inner.next_back() {
+            Some(item) => Some(Ok(item)),
+            None => self.inner.error.take().map(Err),
+        }
+    }
+}
+
+impl<'a, K, V, E> IntoIterator for &'a BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    type Item = (K, V);
+    type IntoIter = Range<'a, K, V, E>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// 消费整棵树的迭代器, 条目直接从叶子里搬出来, 不会 clone
+pub struct IntoIter<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    tree: BPlusTree<K, V, E>,
+    leaves: vec::IntoIter<BlockId>,
+    buffer: VecDeque<(K, V)>,
+    back_buffer: VecDeque<(K, V)>,
+}
+
+impl<K, V, E> IntoIter<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 树已经归这个迭代器了, 旧版本也跟着没了, 共享的叶子也可以直接搬空
+    fn take_leaf(&mut self, leaf: BlockId) -> Option<VecDeque<(K, V)>> {
+        let node = self.tree.engine.fetch_write(leaf).ok()?.take()?;
+        Some(node.keys.into_iter().zip(node.values).collect())
+    }
+}
+
+impl<K, V, E> Iterator for IntoIter<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.is_empty() {
+            let Some(leaf) = self.leaves.next() else {
+                return self.back_buffer.pop_front();
+            };
+            self.buffer = self.take_leaf(leaf)?;
+        }
+        self.buffer.pop_front()
+    }
+}
+
+impl<K, V, E> DoubleEndedIterator for IntoIter<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.back_buffer.is_empty() {
+            let Some(leaf) = self.leaves.next_back() else {
+                return self.buffer.pop_back();
+            };
+            self.back_buffer = self.take_leaf(leaf)?;
+        }
+        self.back_buffer.pop_back()
+    }
+}
+
+impl<K, V, E> IntoIterator for BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, E>;
+
+    // 读不出叶子时迭代器直接结束
+    fn into_iter(self) -> Self::IntoIter {
+        let mut leaves = vec![];
+        if self.leaf_ids(self.root, &mut leaves).is_err() {
+            leaves.clear();
+        }
+        IntoIter { tree: self, leaves: leaves.into_iter(), buffer: VecDeque::new(), back_buffer: VecDeque::new() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::MemoryBlockEngine,
+        fault::{FaultOptions, FaultyBlockEngine},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_range_filtered() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..100 {
+            tree.insert(i, i * 2).unwrap();
+        }
+        let even: Vec<_> = tree.range_filtered(10..20, |k, _| k % 2 == 0).collect();
+        assert_eq!(even, vec![(10, 20), (12, 24), (14, 28), (16, 32), (18, 36)]);
+
+        let big: Vec<_> = tree.range_filtered(.., |_, v| *v >= 190).map(|(k, _)| k).collect();
+        assert_eq!(big, (95..100).collect::<Vec<_>>());
+
+        let keys: Vec<_> = tree.range_filtered_by_key(90..=95, |k| k % 5 == 0).collect();
+        assert_eq!(keys, vec![(90, 180), (95, 190)]);
+
+        // 持久化模式下走路径而不是叶子链
+        tree.freeze();
+        for i in 0..100 {
+            tree.insert(i * 2 + 1000, 0).unwrap();
+        }
+        assert_eq!(tree.range_filtered(.., |_, _| true).count(), 200);
+        assert_eq!(tree.range_filtered(50..1010, |_, _| true).count(), 55);
+    }
+
+    #[test]
+    fn test_range() {
+        let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        for i in 0..200 {
+            tree.insert(i * 2, i).unwrap();
+        }
+        let keys: Vec<_> = tree.range(10..=20).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![10, 12, 14, 16, 18, 20]);
+        let bounds = (Bound::Excluded(11), Bound::Excluded(17));
+        assert_eq!(tree.range(bounds).collect::<Vec<_>>(), vec![(12, 6), (14, 7), (16, 8)]);
+        assert_eq!(tree.range(..).count(), 200);
+        assert_eq!(tree.range(395..).collect::<Vec<_>>(), vec![(396, 198), (398, 199)]);
+        assert_eq!(tree.range(1000..).count(), 0);
+    }
+
+    #[test]
+    fn test_try_range() {
+        let engine = FaultyBlockEngine::new(MemoryBlockEngine::new(), FaultOptions::default());
+        let mut tree = BPlusTree::new(3, engine).unwrap();
+        for i in 0..200 {
+            tree.insert(i, i).unwrap();
+        }
+        assert_eq!(tree.try_iter().collect::<Result<Vec<_>>>().unwrap().len(), 200);
+        let snapshot = tree.snapshot();
+        assert_eq!(tree.at(snapshot).try_range(50..).rev().map(|item| item.unwrap().0).next(), Some(199));
+
+        tree.engine.set_options(FaultOptions { read_error: 0.05, seed: 3, ..FaultOptions::default() });
+        let items: Vec<_> = tree.try_iter().collect();
+        let (last, ok) = items.split_last().unwrap();
+        assert!(last.is_err() && ok.len() < 200);
+        assert!(ok.iter().enumerate().all(|(i, item)| item.as_ref().unwrap().0 == i));
+
+        // 普通的 range 悄悄地停下来, 错误留在 error() 里
+        let mut range = tree.range(..);
+        assert!(range.by_ref().count() < 200 && range.error().is_some());
+        tree.engine.set_options(FaultOptions::default());
+        let mut range = tree.range(..);
+        assert!(range.by_ref().count() == 200 && range.error().is_none());
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in (0..50).rev() {
+            tree.insert(i, i.to_string()).unwrap();
+        }
+        assert_eq!(tree.keys().collect::<Vec<_>>(), (0..50).collect::<Vec<_>>());
+        assert_eq!(tree.values().next_back(), Some("49".to_string()));
+        assert_eq!((&tree).into_iter().count(), 50);
+
+        // 持久化模式下叶子和旧版本共享, 消费时照样搬走
+        tree.freeze();
+        tree.delete(&0).unwrap();
+        let mut owned = tree.into_iter();
+        assert_eq!(owned.next(), Some((1, "1".to_string())));
+        assert_eq!(owned.next_back(), Some((49, "49".to_string())));
+        assert_eq!(owned.count(), 47);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..100 {
+            tree.insert(i * 10, i).unwrap();
+        }
+        assert_eq!(tree.lower_bound(&50).unwrap(), Some((50, 5)));
+        assert_eq!(tree.upper_bound(&50).unwrap(), Some((60, 6)));
+        assert_eq!(tree.lower_bound(&51).unwrap(), Some((60, 6)));
+        assert_eq!(tree.lower_bound(&-1).unwrap(), Some((0, 0)));
+        assert_eq!(tree.upper_bound(&990).unwrap(), None);
+        // 分隔 key 正好是某个叶子的最后一个 key 时要走到下一个叶子
+        for i in 0..100 {
+            assert_eq!(tree.upper_bound(&(i * 10)).unwrap().map(|(k, _)| k), (i < 99).then_some(i * 10 + 10));
+        }
+    }
+
+    #[test]
+    fn test_range_rev() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..100 {
+            tree.insert(i, i).unwrap();
+        }
+        for persistent in [false, true] {
+            if persistent {
+                tree.freeze();
+                tree.insert(1000, 0).unwrap();
+                tree.delete(&1000).unwrap();
+            }
+            let rev: Vec<_> = tree.range(..).rev().map(|(k, _)| k).collect();
+            assert_eq!(rev, (0..100).rev().collect::<Vec<_>>());
+            let rev: Vec<_> = tree.range(10..20).rev().map(|(k, _)| k).collect();
+            assert_eq!(rev, (10..20).rev().collect::<Vec<_>>());
+            let last: Vec<_> = tree.range_filtered(.., |k, _| k % 7 == 0).rev().take(3).map(|(k, _)| k).collect();
+            assert_eq!(last, vec![98, 91, 84]);
+
+            // 两头交替取, 在中间汇合时不重复也不遗漏
+            let mut range = tree.range(5..=50);
+            let mut seen = vec![];
+            loop {
+                match (range.next(), range.next_back()) {
+                    (None, None) => break,
+                    (front, back) => seen.extend(front.into_iter().chain(back).map(|(k, _)| k)),
+                }
+            }
+            seen.sort();
+            assert_eq!(seen, (5..=50).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for path in ["/a", "/bin/ls", "/bin/sh", "/bin2", "/boot", "/usr/bin/env", "/usr/lib"] {
+            tree.insert(path.to_string(), path.len()).unwrap();
+        }
+        let keys: Vec<_> = tree.scan_prefix(&"/bin".to_string()).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["/bin/ls", "/bin/sh", "/bin2"]);
+        assert_eq!(tree.scan_prefix(&"/usr/".to_string()).count(), 2);
+        assert_eq!(tree.scan_prefix(&"/x".to_string()).count(), 0);
+        assert_eq!(tree.scan_prefix(&String::new()).count(), 7);
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_readahead() {
+        use crate::file::{FileBlockEngine, FileOptions};
+
+        let path = std::env::temp_dir().join(format!("bplus-tree-readahead-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 1024, pool_size: 64, ..FileOptions::default() };
+        let mut tree = BPlusTree::new(32, FileBlockEngine::create(&path, options).unwrap()).unwrap();
+        for i in 0..5000u32 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        // 缓存是空的, 大部分叶子应该是预读进来的, 不是取的时候才读
+        let tree = BPlusTree::<u32, u32, _>::open(FileBlockEngine::open(&path, options).unwrap()).unwrap();
+        assert!(tree.iter().map(|(key, _)| key).eq(0..5000));
+        let stats = tree.engine_stats();
+        assert!(stats.misses * 3 < stats.reads);
+        // 范围之外的叶子不预读
+        let tree = BPlusTree::<u32, u32, _>::open(FileBlockEngine::open(&path, options).unwrap()).unwrap();
+        assert_eq!(tree.range(100..110).count(), 10);
+        assert_eq!(tree.engine_stats().reads, tree.engine_stats().misses);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use anyhow::{anyhow, Ok, Result};
+use serde_json::Value;
+
+use crate::{
+    block::BlockEngine,
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// value 是 JSON 文档的树, 可以按 JSON pointer (RFC 6901) 读写文档中的一部分
+impl<K, E> BPlusTree<K, Value, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, Value>>,
+    K: Ord + Clone,
+{
+    // 只 clone pointer 指向的那部分
+    pub fn get_path(&self, key: &K, pointer: &str) -> Result<Option<Value>> {
+        let leaf = self.find_leaf(self.root, key)?;
+        let read = self.engine.fetch_read(leaf)?;
+        let node = read.as_ref().ok_or(Error::EmptyBlock(leaf))?;
+        Ok(self
+            .order
+            .search(&node.keys, key)
+            .ok()
+            .and_then(|pos| node.values[pos].pointer(pointer).cloned()))
+    }
+
+    // 在叶子的写锁下原地修改文档, 返回 pointer 处原来的值
+    // 路径的最后一段可以不存在 (对象里新加一个字段, 或者数组用 "-" 追加), 再往上的部分必须存在
+    pub fn update_path(&mut self, key: &K, pointer: &str, new_value: Value) -> Result<Option<Value>> {
+        self.with_leaf_mut(key, |node, pos| set_pointer(&mut node.values[pos], pointer, new_value))?
+            .ok_or_else(|| anyhow!("no such key."))?
+    }
+}
+
+fn set_pointer(doc: &mut Value, pointer: &str, new_value: Value) -> Result<Option<Value>> {
+    if pointer.is_empty() {
+        return Ok(Some(std::mem::replace(doc, new_value)));
+    }
+    if let Some(target) = doc.pointer_mut(pointer) {
+        return Ok(Some(std::mem::replace(target, new_value)));
+    }
+    let (parent, last) = pointer
+        .rsplit_once('/')
+        .ok_or_else(|| anyhow!("invalid json pointer: {}.", pointer))?;
+    let last = last.replace("~1", "/").replace("~0", "~");
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(map)) => Ok(map.insert(last, new_value)),
+        Some(Value::Array(array)) if last == "-" || last.parse() == std::result::Result::Ok(array.len()) => {
+            array.push(new_value);
+            Ok(None)
+        }
+        _ => Err(anyhow!("json pointer does not exist: {}.", pointer)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_json_path() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..20 {
+            tree.insert(i, json!({ "id": i, "tags": ["a"], "profile": { "name": "n" } })).unwrap();
+        }
+        assert_eq!(tree.get_path(&3, "/profile/name").unwrap(), Some(json!("n")));
+        assert_eq!(tree.get_path(&3, "/missing").unwrap(), None);
+        assert_eq!(tree.get_path(&100, "/id").unwrap(), None);
+
+        let version = tree.freeze();
+        assert_eq!(tree.update_path(&3, "/profile/name", json!("m")).unwrap(), Some(json!("n")));
+        assert_eq!(tree.update_path(&3, "/profile/age", json!(7)).unwrap(), None);
+        assert_eq!(tree.update_path(&3, "/tags/-", json!("b")).unwrap(), None);
+        assert!(tree.update_path(&3, "/nope/deeper", json!(1)).is_err());
+        assert!(tree.update_path(&100, "/id", json!(1)).is_err());
+
+        assert_eq!(
+            tree.search(&3).unwrap(),
+            Some(json!({ "id": 3, "tags": ["a", "b"], "profile": { "name": "m", "age": 7 } }))
+        );
+        assert_eq!(tree.search_at(version, &3).unwrap().unwrap()["profile"]["name"], json!("n"));
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LockResult, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError,
+    },
+    time::{Duration, Instant},
+};
+
+// 等锁时间的直方图有几个桶, 第 0 个是不到 1 微秒的, 第 i 个是 [2^(i-1), 2^i) 微秒, 最后一个也包括更长的
+pub const WAIT_BUCKETS: usize = 24;
+
+// 树上某一层的锁的计数, 用来分清慢是因为 I/O 还是因为抢锁
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LevelLatchStats {
+    // 拿到锁的次数, 读锁写锁都算
+    pub acquisitions: u64,
+    // 没能马上拿到锁的次数
+    pub contentions: u64,
+    // 每次没能马上拿到锁时等了多久
+    pub waits: [u64; WAIT_BUCKETS],
+}
+
+impl LevelLatchStats {
+    // 等过的里第 q (0 到 1) 分位的等待时间, 返回所在的桶的上界, 最后一个桶也返回 2^23 微秒; 没人等过时是 None
+    pub fn wait_quantile(&self, q: f64) -> Option<Duration> {
+        let total: u64 = self.waits.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((total as f64 * q).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        let bucket = self.waits.iter().position(|&count| {
+            seen += count;
+            seen >= rank
+        })?;
+        Some(Duration::from_micros(1 << bucket))
+    }
+}
+
+// 按结点在树里的层分开, root 那一层也包括整棵树的锁和 ConcurrentBPlusTree 里 root 指针的锁
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LatchStats {
+    pub root: LevelLatchStats,
+    pub inner: LevelLatchStats,
+    pub leaf: LevelLatchStats,
+}
+
+#[derive(Default)]
+pub(crate) struct LatchCounters {
+    pub(crate) acquisitions: AtomicU64,
+    pub(crate) contentions: AtomicU64,
+    waits: [AtomicU64; WAIT_BUCKETS],
+}
+
+impl LatchCounters {
+    pub(crate) fn record_wait(&self, wait: Duration) {
+        let micros = wait.as_micros();
+        let bucket = match micros {
+            0 => 0,
+            _ => ((u128::BITS - micros.leading_zeros()) as usize).min(WAIT_BUCKETS - 1),
+        };
+        self.waits[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn load(&self) -> LevelLatchStats {
+        LevelLatchStats {
+            acquisitions: self.acquisitions.load(Ordering::Relaxed),
+            contentions: self.contentions.load(Ordering::Relaxed),
+            waits: std::array::from_fn(|i| self.waits[i].load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.acquisitions.store(0, Ordering::Relaxed);
+        self.contentions.store(0, Ordering::Relaxed);
+        self.waits.iter().for_each(|count| count.store(0, Ordering::Relaxed));
+    }
+}
+
+// 记下拿锁次数, 抢锁次数和等待时间的 RwLock, 用法和 RwLock 一样
+// 整棵树一把锁的时候 (比如 Server) 所有的操作都从 root 进去, 计数算在 root 那一层
+pub struct Latch<T> {
+    lock: RwLock<T>,
+    counters: LatchCounters,
+}
+
+impl<T> Latch<T> {
+    pub fn new(value: T) -> Self {
+        Latch { lock: RwLock::new(value), counters: LatchCounters::default() }
+    }
+
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        self.counters.acquisitions.fetch_add(1, Ordering::Relaxed);
+        match self.lock.try_read() {
+            Result::Ok(guard) => return Result::Ok(guard),
+            Err(TryLockError::Poisoned(e)) => return Err(e),
+            Err(TryLockError::WouldBlock) => {}
+        }
+        self.counters.contentions.fetch_add(1, Ordering::Relaxed);
+        let start = Instant::now();
+        let guard = self.lock.read();
+        self.counters.record_wait(start.elapsed());
+        guard
+    }
+
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        self.counters.acquisitions.fetch_add(1, Ordering::Relaxed);
+        match self.lock.try_write() {
+            Result::Ok(guard) => return Result::Ok(guard),
+            Err(TryLockError::Poisoned(e)) => return Err(e),
+            Err(TryLockError::WouldBlock) => {}
+        }
+        self.counters.contentions.fetch_add(1, Ordering::Relaxed);
+        let start = Instant::now();
+        let guard = self.lock.write();
+        self.counters.record_wait(start.elapsed());
+        guard
+    }
+
+    // 从创建或者上次 reset_stats 起的
+    pub fn stats(&self) -> LevelLatchStats {
+        self.counters.load()
+    }
+
+    pub fn reset_stats(&self) {
+        self.counters.reset();
+    }
+
+    pub fn into_inner(self) -> LockResult<T> {
+        self.lock.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn test_wait_quantile() {
+        let counters = LatchCounters::default();
+        assert_eq!(counters.load().wait_quantile(0.5), None);
+        counters.record_wait(Duration::ZERO);
+        for _ in 0..3 {
+            counters.record_wait(Duration::from_micros(100));
+        }
+        counters.record_wait(Duration::from_secs(3600));
+        let stats = counters.load();
+        assert_eq!(stats.waits[0], 1);
+        assert_eq!(stats.waits[7], 3);
+        assert_eq!(stats.waits[WAIT_BUCKETS - 1], 1);
+        assert_eq!(stats.wait_quantile(0.0), Some(Duration::from_micros(1)));
+        assert_eq!(stats.wait_quantile(0.5), Some(Duration::from_micros(128)));
+        assert_eq!(stats.wait_quantile(1.0), Some(Duration::from_micros(1 << 23)));
+    }
+
+    #[test]
+    fn test_latch() {
+        let latch = Arc::new(Latch::new(0));
+        *latch.write().unwrap() += 1;
+        assert_eq!(*latch.read().unwrap(), 1);
+        assert_eq!(latch.stats().acquisitions, 2);
+        assert_eq!(latch.stats().contentions, 0);
+
+        // 拿着写锁的时候别的线程来读, 等的时间记下来
+        let guard = latch.write().unwrap();
+        let reader = {
+            let latch = latch.clone();
+            thread::spawn(move || *latch.read().unwrap())
+        };
+        while latch.stats().contentions == 0 {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(5));
+        drop(guard);
+        assert_eq!(reader.join().unwrap(), 1);
+        let stats = latch.stats();
+        assert_eq!(stats.acquisitions, 4);
+        assert_eq!(stats.contentions, 1);
+        assert!(stats.wait_quantile(0.5).unwrap() >= Duration::from_millis(4));
+        latch.reset_stats();
+        assert_eq!(latch.stats(), LevelLatchStats::default());
+    }
+}
@@ -0,0 +1,92 @@
+pub mod advise;
+pub mod aggregate;
+pub mod aio;
+pub mod amplification;
+#[cfg(feature = "file")]
+pub mod async_db;
+#[cfg(feature = "file")]
+pub mod async_file;
+pub mod batch;
+pub mod block;
+pub mod build;
+pub mod cache;
+pub mod checkpoint;
+pub mod capacity;
+pub mod concurrent;
+#[cfg(feature = "file")]
+pub mod codec;
+#[cfg(any(feature = "parquet", feature = "datafusion"))]
+pub mod columns;
+#[cfg(feature = "file")]
+pub mod compress;
+pub mod cursor;
+#[cfg(feature = "datafusion")]
+pub mod datafusion;
+#[cfg(feature = "file")]
+pub mod db;
+#[cfg(feature = "file")]
+pub mod direct;
+#[cfg(feature = "file")]
+pub mod doublewrite;
+pub mod dump;
+#[cfg(feature = "encrypt")]
+pub mod encrypt;
+pub mod entry;
+pub mod epoch;
+pub mod error;
+pub mod fault;
+#[cfg(feature = "file")]
+pub mod file;
+pub mod fsck;
+#[cfg(feature = "file")]
+pub mod group;
+pub mod guard;
+pub mod interval;
+pub mod iter;
+pub mod latch;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod migrate;
+pub mod mirror;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod multimap;
+pub mod mvcc;
+#[cfg(feature = "object-store")]
+pub mod object;
+pub mod order;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod partition;
+#[cfg(feature = "file")]
+pub mod pool;
+#[cfg(feature = "prost")]
+pub mod proto;
+pub mod rank;
+pub mod ratelimit;
+pub mod refcount;
+#[cfg(feature = "file")]
+pub mod replacement;
+#[cfg(feature = "resp")]
+pub mod resp;
+pub mod runtime;
+pub mod scrub;
+pub mod set;
+pub mod shadow;
+pub mod shard;
+#[cfg(feature = "file")]
+pub mod slotted;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod snapshot;
+pub mod split;
+#[cfg(feature = "file")]
+pub mod superblock;
+pub mod tier;
+pub mod tree;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub mod uring;
+pub mod verify;
+#[cfg(feature = "file")]
+pub mod wal;
+pub mod warmup;
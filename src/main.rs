@@ -1,6 +0,0 @@
-mod tree;
-mod block;
-
-fn main() {
-    println!("Hello, world!");
-}
@@ -0,0 +1,216 @@
+use anyhow::{anyhow, Ok, Result};
+use std::ops::Bound;
+
+use crate::{
+    block::BlockEngine,
+    build::Builder,
+    tree::{BPlusTree, BPlusTreeNode, Version},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MigrateOptions {
+    // 目标树的参数
+    pub way: usize,
+    pub fill_factor: f64,
+    // 每一步搬多少条
+    pub batch: usize,
+}
+
+// 把一棵树搬到另一个 engine 上
+// 开始时冻结源树, 之后一直读冻结的版本, 所以中途源树被修改也不影响结果
+// 每一步从上次搬到的 key 之后继续, 某一步出错后可以直接再调用 step 接着搬
+pub struct Migration<K, V, D>
+where
+    D: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    version: Version,
+    dst: BPlusTree<K, V, D>,
+    builder: Builder<K, V>,
+    batch: usize,
+    last_key: Option<K>,
+    copied: usize,
+    done: bool,
+}
+
+impl<K, V, D> Migration<K, V, D>
+where
+    D: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn new<S>(src: &mut BPlusTree<K, V, S>, dst_engine: D, options: MigrateOptions) -> Result<Self>
+    where
+        S: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    {
+        let builder = Builder::new(options.way, options.fill_factor)?;
+        Ok(Migration {
+            version: src.freeze(),
+            dst: BPlusTree::with_order(options.way, dst_engine, src.order.clone())?,
+            builder,
+            batch: options.batch.max(1),
+            last_key: None,
+            copied: 0,
+            done: false,
+        })
+    }
+
+    pub fn copied(&self) -> usize {
+        self.copied
+    }
+
+    pub fn last_key(&self) -> Option<&K> {
+        self.last_key.as_ref()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // 搬下一批, 全部搬完后返回 true
+    // src 必须是创建 Migration 时的那棵树
+    pub fn step<S>(&mut self, src: &BPlusTree<K, V, S>) -> Result<bool>
+    where
+        S: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    {
+        if self.done {
+            return Ok(true);
+        }
+        let start = match &self.last_key {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        let mut entries = Vec::with_capacity(self.batch);
+        src.for_each_leaf_in_range(self.version.root, start, Bound::Unbounded, |node, range| {
+            for i in range {
+                entries.push((node.keys[i].clone(), node.values[i].clone()));
+                if entries.len() == self.batch {
+                    return false;
+                }
+            }
+            true
+        })?;
+        // 只有 push 成功的条目才算搬过了, 出错时下一步从第一条没搬的接着来
+        let last_batch = entries.len() < self.batch;
+        for (key, value) in entries {
+            self.builder.push(&mut self.dst, key.clone(), value)?;
+            self.last_key = Some(key);
+            self.copied += 1;
+        }
+        self.done = last_batch;
+        Ok(self.done)
+    }
+
+    pub fn finish(self) -> Result<BPlusTree<K, V, D>> {
+        if !self.done {
+            return Err(anyhow!("migration is not finished, {} entries copied.", self.copied));
+        }
+        let mut dst = self.dst;
+        let root = self.builder.finish(&mut dst)?;
+        let empty_root = dst.root;
+        dst.root = root;
+        dst.len = self.copied;
+        dst.free_subtree(empty_root)?;
+        Ok(dst)
+    }
+}
+
+// 一口气搬完, 每一批之后用已经搬的条数调用 progress
+pub fn migrate_engine<K, V, S, D, F>(
+    src: &mut BPlusTree<K, V, S>,
+    dst_engine: D,
+    options: MigrateOptions,
+    mut progress: F,
+) -> Result<BPlusTree<K, V, D>>
+where
+    S: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    D: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+    F: FnMut(usize),
+{
+    let mut migration = Migration::new(src, dst_engine, options)?;
+    while !migration.step(src)? {
+        progress(migration.copied());
+    }
+    progress(migration.copied());
+    let version = migration.version;
+    let dst = migration.finish()?;
+    src.drop_version(version)?;
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::MemoryBlockEngine,
+        fault::{FaultOptions, FaultyBlockEngine},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_migrate_engine() {
+        let mut src = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..100 {
+            src.insert(i, i.to_string()).unwrap();
+        }
+        let options = MigrateOptions { way: 16, fill_factor: 0.9, batch: 30 };
+        let mut reports = vec![];
+        let dst = migrate_engine(&mut src, MemoryBlockEngine::new(), options, |n| reports.push(n)).unwrap();
+        assert_eq!(reports, vec![30, 60, 90, 100]);
+        for i in 0..100 {
+            assert_eq!(dst.search(&i).unwrap(), Some(i.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_resume_migration() {
+        let mut src = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..50 {
+            src.insert(i, i).unwrap();
+        }
+        let options = MigrateOptions { way: 4, fill_factor: 1.0, batch: 20 };
+        let mut migration = Migration::new(&mut src, MemoryBlockEngine::new(), options).unwrap();
+        assert!(!migration.step(&src).unwrap());
+        assert_eq!(migration.last_key(), Some(&19));
+
+        // 迁移过程中源树的修改不会被搬过去
+        src.insert(1000, 0).unwrap();
+        src.delete(&30).unwrap();
+        while !migration.step(&src).unwrap() {}
+        assert_eq!(migration.copied(), 50);
+        let dst = migration.finish().unwrap();
+        assert_eq!(dst.search(&30).unwrap(), Some(30));
+        assert_eq!(dst.search(&1000).unwrap(), None);
+        assert_eq!(dst.range(..).count(), 50);
+    }
+
+    #[test]
+    fn test_retry_failed_step() {
+        let mut src = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..200 {
+            src.insert(i, i).unwrap();
+        }
+        let options = MigrateOptions { way: 4, fill_factor: 1.0, batch: 7 };
+        let fault = FaultOptions { write_error: 0.3, seed: 7, ..Default::default() };
+        let dst_engine = FaultyBlockEngine::new(MemoryBlockEngine::new(), FaultOptions::default());
+        let mut migration = Migration::new(&mut src, dst_engine, options).unwrap();
+        migration.dst.engine.set_options(fault);
+        let mut failures = 0;
+        for _ in 0..1000 {
+            match migration.step(&src) {
+                Result::Ok(true) => break,
+                Result::Ok(false) => {}
+                Err(_) => failures += 1,
+            }
+        }
+        assert!(failures > 0);
+        assert!(migration.is_done());
+        assert_eq!(migration.copied(), 200);
+        migration.dst.engine.set_options(FaultOptions::default());
+        let dst = migration.finish().unwrap();
+        assert_eq!(dst.range(..).collect::<Vec<_>>(), (0..200).map(|i| (i, i)).collect::<Vec<_>>());
+        dst.verify().unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use anyhow::{Ok, Result};
+use std::cmp::Ordering;
+
+use crate::{
+    block::BlockEngine,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 双写: 每次写同时写到 primary 和 secondary, 读只读 primary
+// 打开 check 之后读的时候也读 secondary 做比较, 不一致的次数记在 mismatches 里
+// 用来在两个 engine 之间无停机迁移, 迁移完了用 into_parts 拿出 secondary
+pub struct MirroredTree<K, V, A, B>
+where
+    A: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    B: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    primary: BPlusTree<K, V, A>,
+    secondary: BPlusTree<K, V, B>,
+    check: bool,
+    mismatches: u64,
+}
+
+impl<K, V, A, B> MirroredTree<K, V, A, B>
+where
+    A: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    B: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone + PartialEq,
+{
+    pub fn new(primary: BPlusTree<K, V, A>, secondary: BPlusTree<K, V, B>) -> Self {
+        MirroredTree { primary, secondary, check: false, mismatches: 0 }
+    }
+
+    pub fn set_check(&mut self, check: bool) {
+        self.check = check;
+    }
+
+    pub fn mismatches(&self) -> u64 {
+        self.mismatches
+    }
+
+    pub fn primary(&self) -> &BPlusTree<K, V, A> {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &BPlusTree<K, V, B> {
+        &self.secondary
+    }
+
+    pub fn into_parts(self) -> (BPlusTree<K, V, A>, BPlusTree<K, V, B>) {
+        (self.primary, self.secondary)
+    }
+
+    // primary 写成功而 secondary 失败时返回错误, 两边可能已经不一致了
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        let ret = self.primary.insert(key.clone(), value.clone())?;
+        let mirrored = self.secondary.insert(key, value)?;
+        if self.check && ret != mirrored {
+            self.mismatches += 1;
+        }
+        Ok(ret)
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<Option<V>> {
+        let ret = self.primary.delete(key)?;
+        let mirrored = self.secondary.delete(key)?;
+        if self.check && ret != mirrored {
+            self.mismatches += 1;
+        }
+        Ok(ret)
+    }
+
+    pub fn search(&mut self, key: &K) -> Result<Option<V>> {
+        let ret = self.primary.search(key)?;
+        if self.check && ret != self.secondary.search(key)? {
+            self.mismatches += 1;
+        }
+        Ok(ret)
+    }
+
+    // 全量比较两边, 返回不一致的 key, 任何一边读结点出错都返回错误
+    pub fn compare_all(&self) -> Result<Vec<K>> {
+        let mut primary = self.primary.try_range(..);
+        let mut secondary = self.secondary.try_range(..);
+        let mut left = primary.next().transpose()?;
+        let mut right = secondary.next().transpose()?;
+        let mut diff = vec![];
+        loop {
+            let order = match (&left, &right) {
+                (None, None) => break,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((pk, _)), Some((sk, _))) => self.primary.order.cmp(pk, sk),
+            };
+            match order {
+                Ordering::Less => {
+                    diff.push(left.take().unwrap().0);
+                    left = primary.next().transpose()?;
+                }
+                Ordering::Greater => {
+                    diff.push(right.take().unwrap().0);
+                    right = secondary.next().transpose()?;
+                }
+                Ordering::Equal => {
+                    let (key, value) = left.take().unwrap();
+                    if right.take().is_none_or(|(_, mirrored)| mirrored != value) {
+                        diff.push(key);
+                    }
+                    left = primary.next().transpose()?;
+                    right = secondary.next().transpose()?;
+                }
+            }
+        }
+        Ok(diff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::MemoryBlockEngine,
+        fault::{FaultOptions, FaultyBlockEngine},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_mirrored_tree() {
+        let primary = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        let secondary = BPlusTree::new(5, MemoryBlockEngine::new()).unwrap();
+        let mut tree = MirroredTree::new(primary, secondary);
+        tree.set_check(true);
+        for i in 0..50 {
+            tree.insert(i, i).unwrap();
+        }
+        assert_eq!(tree.delete(&10).unwrap(), Some(10));
+        assert_eq!(tree.search(&11).unwrap(), Some(11));
+        assert_eq!(tree.mismatches(), 0);
+        assert!(tree.compare_all().unwrap().is_empty());
+
+        // 只改 secondary, 制造不一致
+        let (primary, mut secondary) = tree.into_parts();
+        secondary.delete(&20).unwrap();
+        secondary.insert(100, 0).unwrap();
+        let mut tree = MirroredTree::new(primary, secondary);
+        tree.set_check(true);
+        assert_eq!(tree.search(&20).unwrap(), Some(20));
+        assert_eq!(tree.mismatches(), 1);
+        assert_eq!(tree.compare_all().unwrap(), vec![20, 100]);
+        assert_eq!(tree.insert(100, 100).unwrap(), None);
+        assert_eq!(tree.mismatches(), 2);
+        assert_eq!(tree.compare_all().unwrap(), vec![20]);
+
+        // 读结点出错时不会返回一个不完整的比较结果
+        let (primary, _) = tree.into_parts();
+        let engine = FaultyBlockEngine::new(MemoryBlockEngine::new(), FaultOptions::default());
+        let mut faulty = BPlusTree::new(3, engine).unwrap();
+        for i in 0..50 {
+            faulty.insert(i, i).unwrap();
+        }
+        faulty.engine.set_options(FaultOptions { read_error: 0.3, seed: 2, ..FaultOptions::default() });
+        assert!(MirroredTree::new(primary, faulty).compare_all().is_err());
+    }
+}
@@ -0,0 +1,485 @@
+use anyhow::{anyhow, Ok, Result};
+use memmap2::MmapMut;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs::{File, OpenOptions},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, MutexGuard, RwLock,
+    },
+};
+
+use crate::{
+    block::{Block, BlockEngine, BlockEngineStats, BlockId, BlockReadGuard, BlockWriteGuard, Slots, TreeMeta},
+    codec::{BincodeCodec, NodeCodec},
+    compress::Compression,
+    doublewrite::DoubleWrite,
+    error::Error,
+    file::{self, ChecksumPolicy, PAGE_HEADER_LEN},
+    superblock::{Superblock, MAX_CATALOG, SUPERBLOCK_LEN},
+    wal::Wal,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmapOptions {
+    // 只在新建时用, 打开时用文件里记着的
+    pub page_size: usize,
+    pub checksum: ChecksumPolicy,
+    pub compression: Compression,
+    // 只用来打开已有的文件, 和 FileOptions::read_only 一样, 几个进程可以同时映射同一个文件
+    pub read_only: bool,
+}
+
+impl Default for MmapOptions {
+    fn default() -> Self {
+        MmapOptions {
+            page_size: 4096,
+            checksum: ChecksumPolicy::Error,
+            compression: Compression::None,
+            read_only: false,
+        }
+    }
+}
+
+// 把整个文件映射进内存的 engine, 文件格式和 FileBlockEngine 一样, 两个可以轮流打开同一个文件
+// 没有 buffer pool, block 第一次读时直接从映射的页上解码, 之后留在内存里, 页的缓存交给操作系统
+// write_back 拿不到 engine, 写过的 block 在 flush 时才编码回映射的页, msync 之后再换 superblock
+// 没有日志和双写缓冲, 文件旁边留着没重放的日志时要先用 FileBlockEngine 打开一次
+pub struct MmapBlockEngine<B, C = BincodeCodec> {
+    file: File,
+    map: MmapMut,
+    // 解码过的 block, 分配和回收只要 &self, 已经有的 block 不会挪动
+    blocks: Slots<Slot<B>>,
+    space: Mutex<Space>,
+    // 分配或者拿过写 guard 的 block, flush 时写回
+    dirty: Mutex<BTreeSet<BlockId>>,
+    superblock: Superblock,
+    page_size: usize,
+    first_page: usize,
+    codec: C,
+    checksum: ChecksumPolicy,
+    compression: Compression,
+    read_only: bool,
+    // 已经解码过的算命中, 要从映射的页上解码的算不命中
+    hits: AtomicU64,
+    misses: AtomicU64,
+    writes: u64,
+    allocations: AtomicU64,
+}
+
+// loaded 之前 block 里的内容还没从映射的页上解码
+struct Slot<B> {
+    loaded: AtomicBool,
+    block: RwLock<Block<B>>,
+}
+
+impl<B> Slot<B> {
+    fn new(block_id: BlockId) -> Self {
+        Slot { loaded: AtomicBool::new(false), block: RwLock::new(Block { valid: true, id: block_id, content: None }) }
+    }
+}
+
+struct Space {
+    free_list: Vec<BlockId>,
+    // 回收之后还没写进文件的 block
+    freed: BTreeSet<BlockId>,
+    // stage_snapshots 之后还没 flush 的 snapshot
+    snapshots: Option<BTreeMap<String, TreeMeta>>,
+}
+
+impl<B> MmapBlockEngine<B>
+where
+    B: Serialize + DeserializeOwned,
+{
+    // 新建文件, 已经存在时清空
+    pub fn create(path: impl AsRef<Path>, options: MmapOptions) -> Result<Self> {
+        Self::create_with_codec(path, options, BincodeCodec)
+    }
+
+    pub fn open(path: impl AsRef<Path>, options: MmapOptions) -> Result<Self> {
+        Self::open_with_codec(path, options, BincodeCodec)
+    }
+}
+
+impl<B, C> MmapBlockEngine<B, C>
+where
+    C: NodeCodec<B>,
+{
+    pub fn create_with_codec(path: impl AsRef<Path>, options: MmapOptions, codec: C) -> Result<Self> {
+        if options.page_size < SUPERBLOCK_LEN.max(PAGE_HEADER_LEN + 8) {
+            return Err(anyhow!("page size {} is too small.", options.page_size));
+        }
+        if options.read_only {
+            return Err(anyhow!("can not create a block file in read-only mode."));
+        }
+        let path = path.as_ref();
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        let mut superblock = Superblock::new(options.page_size);
+        superblock.write(&mut file)?;
+        file::write_superblock(&mut file, &mut superblock, true)?;
+        for stale in [Wal::path(path), DoubleWrite::path(path)] {
+            if stale.exists() {
+                std::fs::remove_file(stale)?;
+            }
+        }
+        let first_page = Superblock::first_block_page(options.page_size);
+        file.set_len((first_page * options.page_size) as u64)?;
+        let map = map(&file, false)?;
+        Ok(MmapBlockEngine {
+            file,
+            map,
+            blocks: Slots::new(0, Slot::new),
+            space: Mutex::new(Space { free_list: vec![], freed: BTreeSet::new(), snapshots: None }),
+            dirty: Mutex::new(BTreeSet::new()),
+            superblock,
+            page_size: options.page_size,
+            first_page,
+            codec,
+            checksum: options.checksum,
+            compression: options.compression,
+            read_only: false,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            writes: 0,
+            allocations: AtomicU64::new(0),
+        })
+    }
+
+    pub fn open_with_codec(path: impl AsRef<Path>, options: MmapOptions, codec: C) -> Result<Self> {
+        let path = path.as_ref();
+        file::check_pending(path)?;
+        let mut file = OpenOptions::new().read(true).write(!options.read_only).open(path)?;
+        let superblock = Superblock::read(&mut file)?;
+        let (page_size, block_count) = (superblock.page_size, superblock.block_count);
+        let first_page = Superblock::first_block_page(page_size);
+        if file.metadata()?.len() < ((first_page + block_count) * page_size) as u64 {
+            return Err(anyhow!("block file is truncated."));
+        }
+        let map = map(&file, options.read_only)?;
+        let mut engine = MmapBlockEngine {
+            file,
+            map,
+            blocks: Slots::new(block_count, Slot::new),
+            space: Mutex::new(Space { free_list: vec![], freed: BTreeSet::new(), snapshots: None }),
+            dirty: Mutex::new(BTreeSet::new()),
+            superblock,
+            page_size,
+            first_page,
+            codec,
+            checksum: options.checksum,
+            compression: options.compression,
+            read_only: options.read_only,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            writes: 0,
+            allocations: AtomicU64::new(0),
+        };
+
+        // 链表头是最后回收的, 反过来就是回收的顺序
+        let mut free_list = vec![];
+        let mut cursor = engine.superblock.free_head;
+        while let Some(block_id) = cursor {
+            if block_id >= block_count || free_list.len() >= block_count {
+                return Err(anyhow!("free list of the block file is broken."));
+            }
+            free_list.push(block_id);
+            let page = engine.page(block_id);
+            file::check_page(block_id, page, engine.checksum)?;
+            cursor = file::decode_free(block_id, page)?;
+        }
+        free_list.reverse();
+        engine.space.get_mut().map_err(|_| Error::LockPoisoned)?.free_list = free_list;
+        Ok(engine)
+    }
+
+    fn page(&self, block_id: BlockId) -> &[u8] {
+        let offset = (self.first_page + block_id) * self.page_size;
+        &self.map[offset..offset + self.page_size]
+    }
+
+    fn write_page(&mut self, block_id: BlockId, page: Vec<u8>) -> Result<()> {
+        let page = file::seal_page(block_id, page, self.page_size)?;
+        let offset = (self.first_page + block_id) * self.page_size;
+        self.map[offset..offset + self.page_size].copy_from_slice(&page);
+        Ok(())
+    }
+
+    // 第一次读时从映射的页上解码
+    fn load(&self, block_id: BlockId) -> Result<&RwLock<Block<B>>> {
+        let slot = self.blocks.get(block_id).ok_or(Error::InvalidBlock(block_id))?;
+        if slot.loaded.load(Ordering::Acquire) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(&slot.block);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let page = self.page(block_id);
+        file::check_page(block_id, page, self.checksum)?;
+        let content = file::decode_page(&self.codec, block_id, page)?;
+        // 别的线程先解码好了的话用它的
+        let mut block = slot.block.write().map_err(|_| Error::LockPoisoned)?;
+        if !slot.loaded.load(Ordering::Acquire) {
+            block.content = content;
+            slot.loaded.store(true, Ordering::Release);
+        }
+        Ok(&slot.block)
+    }
+
+    fn space(&self) -> Result<MutexGuard<'_, Space>> {
+        Ok(self.space.lock().map_err(|_| Error::LockPoisoned)?)
+    }
+
+    // 文件至少要放得下 block_count 个 block, 不够时翻倍, 省得每次 flush 都重新映射
+    fn reserve(&mut self, block_count: usize) -> Result<()> {
+        let capacity = self.map.len() / self.page_size - self.first_page;
+        if capacity >= block_count {
+            return Ok(());
+        }
+        let capacity = block_count.max(capacity * 2);
+        self.file.set_len(((self.first_page + capacity) * self.page_size) as u64)?;
+        self.map = map(&self.file, false)?;
+        Ok(())
+    }
+
+    fn check_block(&self, block_id: BlockId) -> Result<()> {
+        if block_id >= self.blocks.len() || self.space()?.freed.contains(&block_id) {
+            return Err(Error::InvalidBlock(block_id).into());
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly.into());
+        }
+        Ok(())
+    }
+}
+
+impl<B, C> BlockEngine for MmapBlockEngine<B, C>
+where
+    C: NodeCodec<B>,
+{
+    type Item = B;
+
+    fn alloc_block(&self) -> Result<BlockId> {
+        self.check_writable()?;
+        let reused = {
+            let mut space = self.space()?;
+            let reused = space.free_list.pop();
+            if let Some(block_id) = reused {
+                space.freed.remove(&block_id);
+            }
+            reused
+        };
+        let block_id = match reused {
+            Some(block_id) => block_id,
+            None => self.blocks.push(None).ok_or(Error::StorageFull)?,
+        };
+        let slot = self.blocks.get(block_id).ok_or(Error::InvalidBlock(block_id))?;
+        *slot.block.write().map_err(|_| Error::LockPoisoned)? = Block { valid: true, id: block_id, content: None };
+        slot.loaded.store(true, Ordering::Release);
+        self.dirty.lock().map_err(|_| Error::LockPoisoned)?.insert(block_id);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        Ok(block_id)
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, B>> {
+        self.check_block(block_id)?;
+        let rwlock_guard = self.load(block_id)?.read().map_err(|_| Error::LockPoisoned)?;
+        Ok(BlockReadGuard { rwlock_guard })
+    }
+
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, B>> {
+        self.check_writable()?;
+        self.check_block(block_id)?;
+        self.dirty.lock().map_err(|_| Error::LockPoisoned)?.insert(block_id);
+        let rwlock_guard = self.load(block_id)?.write().map_err(|_| Error::LockPoisoned)?;
+        Ok(BlockWriteGuard { rwlock_guard, write_back: Self::write_back })
+    }
+
+    // 回收的 block 没有别人在用, 不会有两个线程同时回收它
+    fn delete(&self, block_id: BlockId) -> Result<Option<B>> {
+        self.check_writable()?;
+        self.check_block(block_id)?;
+        // 下次读时重新从映射的页上解码, flush 之后那是一个空闲页
+        let content = {
+            let mut block = self.load(block_id)?.write().map_err(|_| Error::LockPoisoned)?;
+            self.blocks.get(block_id).ok_or(Error::InvalidBlock(block_id))?.loaded.store(false, Ordering::Release);
+            block.content.take()
+        };
+        self.dirty.lock().map_err(|_| Error::LockPoisoned)?.remove(&block_id);
+        let mut space = self.space()?;
+        space.free_list.push(block_id);
+        space.freed.insert(block_id);
+        Ok(content)
+    }
+
+    // 写 guard 释放时拿不到映射, dirty 的 block 在 flush 时写回
+    fn write_back(_block_id: BlockId, _block: &Block<B>) {}
+
+    fn load_meta(&self) -> Option<TreeMeta> {
+        self.superblock.meta
+    }
+
+    fn stage_snapshots(&self, snapshots: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        self.check_writable()?;
+        if Superblock::catalog_len(&self.superblock.trees) + Superblock::catalog_len(snapshots) > MAX_CATALOG {
+            return Err(anyhow!("{} snapshots do not fit into the superblock.", snapshots.len()));
+        }
+        self.space()?.snapshots = Some(snapshots.clone());
+        Ok(())
+    }
+
+    fn load_snapshots(&self) -> Result<BTreeMap<String, TreeMeta>> {
+        Ok(self.superblock.snapshots.clone())
+    }
+
+    // 写回的页和新回收的空闲页 msync 之后才写 superblock
+    fn flush(&mut self, meta: TreeMeta) -> Result<()> {
+        self.check_writable()?;
+        let block_count = self.blocks.len();
+        self.reserve(block_count)?;
+        let dirty = self.dirty.get_mut().map_err(|_| Error::LockPoisoned)?.clone();
+        for block_id in dirty {
+            let page = {
+                let block = self.load(block_id)?.read().map_err(|_| Error::LockPoisoned)?;
+                file::encode_page(&self.codec, self.compression, block.content.as_ref())?
+            };
+            self.write_page(block_id, page)?;
+            self.writes += 1;
+        }
+        // 空闲链表里每一页指向比它早回收的那一页
+        let space = self.space.get_mut().map_err(|_| Error::LockPoisoned)?;
+        let pos: HashMap<_, _> = space.free_list.iter().enumerate().map(|(pos, &block_id)| (block_id, pos)).collect();
+        let next = |block_id| pos[&block_id].checked_sub(1).map(|pos| space.free_list[pos]);
+        let freed: Vec<_> = space.freed.iter().map(|&block_id| (block_id, next(block_id))).collect();
+        let free_head = space.free_list.last().copied();
+        for (block_id, next) in freed {
+            self.write_page(block_id, file::encode_free(next))?;
+        }
+        self.map.flush()?;
+        self.dirty.get_mut().map_err(|_| Error::LockPoisoned)?.clear();
+        let space = self.space.get_mut().map_err(|_| Error::LockPoisoned)?;
+        space.freed.clear();
+        if let Some(snapshots) = space.snapshots.take() {
+            self.superblock.snapshots = snapshots;
+        }
+
+        self.superblock.meta = Some(meta);
+        self.superblock.block_count = block_count;
+        self.superblock.free_head = free_head;
+        file::write_superblock(&mut self.file, &mut self.superblock, true)
+    }
+
+    // 没有淘汰, 解码过的 block 一直留在内存里; 写回的字节数只算整页, 文件的字节数包括预留的空间
+    fn stats(&self) -> BlockEngineStats {
+        let misses = self.misses.load(Ordering::Relaxed);
+        let free = self.space.lock().map_or(0, |space| space.free_list.len());
+        BlockEngineStats {
+            reads: misses,
+            writes: self.writes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses,
+            evictions: 0,
+            dirty: self.dirty.lock().map_or(0, |dirty| dirty.len()),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            bytes_written: self.writes * self.page_size as u64,
+            live_bytes: ((self.blocks.len() - free) * self.page_size) as u64,
+            file_bytes: self.map.len() as u64,
+        }
+    }
+}
+
+// 映射整个文件; 文件在映射期间被别的进程截短或者改写的话读到的内容是错的, 所以是 unsafe
+// 只读时是私有映射, 没有写权限的文件也能映射, 没改过的页和别的进程共享页缓存; engine 不会去写它
+fn map(file: &File, read_only: bool) -> Result<MmapMut> {
+    if read_only {
+        return Ok(unsafe { memmap2::MmapOptions::new().map_copy(file)? });
+    }
+    Ok(unsafe { MmapMut::map_mut(file)? })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        file::{FileBlockEngine, FileOptions},
+        tree::{BPlusTree, BPlusTreeNode},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_mmap_engine() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-mmap-{}.db", std::process::id()));
+        let options = MmapOptions { page_size: 512, ..MmapOptions::default() };
+        let engine = MmapBlockEngine::<BPlusTreeNode<u32, String>>::create(&path, options).unwrap();
+        let mut tree = BPlusTree::new(8, engine).unwrap();
+        for i in 0..2000 {
+            tree.insert(i, format!("value {}", i)).unwrap();
+        }
+        for i in (0..2000).step_by(2) {
+            tree.delete(&i).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        // 和 FileBlockEngine 是同一种文件, 回收的 block 两边都能接着用
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, String>>::open(&path, FileOptions::default()).unwrap();
+        let mut tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        for i in (0..500).step_by(2) {
+            tree.insert(i, format!("value {}", i)).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        let engine = MmapBlockEngine::<BPlusTreeNode<u32, String>>::open(&path, options).unwrap();
+        assert!(!engine.space().unwrap().free_list.is_empty());
+        let tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.len(), 1250);
+        assert_eq!(tree.search(&2).unwrap(), Some("value 2".to_string()));
+        assert_eq!(tree.search(&502).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_only() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-read-only-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, ..FileOptions::default() };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap();
+        let mut tree = BPlusTree::new(8, engine).unwrap();
+        for i in 0..1000 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        // 两种 engine 同时只读打开同一个文件
+        let options = FileOptions { read_only: true, ..options };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        let mut file_tree = BPlusTree::open(engine).unwrap();
+        let options = MmapOptions { read_only: true, ..MmapOptions::default() };
+        let engine = MmapBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        let mut mmap_tree = BPlusTree::open(engine).unwrap();
+        for tree in [&file_tree.iter().collect::<Vec<_>>(), &mmap_tree.iter().collect::<Vec<_>>()] {
+            assert!(tree.iter().copied().eq((0..1000).map(|i| (i, i))));
+        }
+        let read_only = |ret: Result<_>| matches!(ret.unwrap_err().downcast_ref::<Error>(), Some(Error::ReadOnly));
+        assert!(read_only(file_tree.insert(1000, 0).map(drop)));
+        assert!(read_only(mmap_tree.insert(1000, 0).map(drop)));
+        assert!(read_only(file_tree.flush()));
+        assert!(read_only(mmap_tree.flush()));
+        file_tree.verify().unwrap();
+        mmap_tree.verify().unwrap();
+        assert_eq!((file_tree.len(), mmap_tree.search(&10).unwrap()), (1000, Some(10)));
+        drop((file_tree, mmap_tree));
+
+        assert!(MmapBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).is_err());
+        let options = FileOptions { read_only: true, wal: true, ..FileOptions::default() };
+        assert!(FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::{Ok, Result};
+
+use crate::{
+    block::BlockEngine,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 允许重复 key 的 map, 实际存的 key 是 (key, 插入序号), 树里的 key 仍然互不相同
+// 同一个 key 的多个 value 按插入顺序排在一起
+pub struct BPlusMultiMap<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<(K, u64), V>>,
+    K: Ord,
+{
+    tree: BPlusTree<(K, u64), V, E>,
+    next_seq: u64,
+}
+
+impl<K, V, E> BPlusMultiMap<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<(K, u64), V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn new(way: usize, engine: E) -> Result<Self> {
+        Ok(BPlusMultiMap { tree: BPlusTree::new(way, engine)?, next_seq: 0 })
+    }
+
+    pub fn tree(&self) -> &BPlusTree<(K, u64), V, E> {
+        &self.tree
+    }
+
+    // 总条目数, 重复的 key 分别计数
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    // 总是追加, 不会替换已有的 value
+    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
+        self.tree.insert((key, self.next_seq), value)?;
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        Ok(self.entries(key).next().transpose()?.is_some())
+    }
+
+    // key 的所有 value, 按插入顺序
+    pub fn search_all(&self, key: &K) -> Result<Vec<V>> {
+        self.entries(key).map(|entry| entry.map(|(_, value)| value)).collect()
+    }
+
+    // 删掉 key 最早插入的一个 value
+    pub fn remove_one(&mut self, key: &K) -> Result<Option<V>> {
+        let Some((first, _)) = self.entries(key).next().transpose()? else {
+            return Ok(None);
+        };
+        self.tree.delete(&first)
+    }
+
+    // 删掉 key 的所有 value, 返回删掉的个数
+    pub fn remove_all(&mut self, key: &K) -> Result<usize> {
+        self.tree.remove_range((key.clone(), 0)..=(key.clone(), u64::MAX))
+    }
+
+    // 读结点出错时返回 Err, 不会把读不到的 value 当成不存在
+    fn entries(&self, key: &K) -> impl Iterator<Item = Result<((K, u64), V)>> + '_ {
+        self.tree.try_range((key.clone(), 0)..=(key.clone(), u64::MAX))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::MemoryBlockEngine,
+        fault::{FaultOptions, FaultyBlockEngine},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_multimap() {
+        let mut map = BPlusMultiMap::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..60 {
+            map.insert(i % 7, i).unwrap();
+        }
+        assert_eq!(map.len(), 60);
+        assert_eq!(map.search_all(&3).unwrap(), vec![3, 10, 17, 24, 31, 38, 45, 52, 59]);
+        assert!(map.search_all(&7).unwrap().is_empty());
+
+        assert_eq!(map.remove_one(&3).unwrap(), Some(3));
+        assert_eq!(map.search_all(&3).unwrap()[0], 10);
+        assert_eq!(map.remove_all(&3).unwrap(), 8);
+        assert!(!map.contains_key(&3).unwrap() && map.contains_key(&4).unwrap());
+        assert_eq!(map.remove_one(&3).unwrap(), None);
+        assert_eq!(map.len(), 51);
+
+        // 删掉之后再插入的排在后面
+        map.insert(4, 100).unwrap();
+        assert_eq!(map.search_all(&4).unwrap().last(), Some(&100));
+    }
+
+    #[test]
+    fn test_multimap_read_errors() {
+        let engine = FaultyBlockEngine::new(MemoryBlockEngine::new(), FaultOptions::default());
+        let mut map = BPlusMultiMap::new(3, engine).unwrap();
+        for i in 0..60 {
+            map.insert(i % 7, i).unwrap();
+        }
+        map.tree.engine.set_options(FaultOptions { read_error: 1.0, ..FaultOptions::default() });
+        assert!(map.contains_key(&3).is_err());
+        assert!(map.search_all(&3).is_err());
+        assert!(map.remove_one(&3).is_err());
+        map.tree.engine.set_options(FaultOptions::default());
+        assert_eq!(map.len(), 60);
+    }
+}
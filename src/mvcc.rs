@@ -0,0 +1,552 @@
+use anyhow::{Ok, Result};
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::RangeBounds,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError, RwLock,
+    },
+    thread,
+};
+
+use crate::{
+    block::BlockEngine,
+    concurrent::ConcurrentBPlusTree,
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 一个 key 的所有版本, (commit 时间戳, value), 按时间戳递增; value 是 None 的是删除标记
+pub type VersionChain<V> = Vec<(u64, Option<V>)>;
+
+// 多版本的并发树: 每次写都带一个新的 commit 时间戳, 在 key 的版本链上加一个版本, 不覆盖旧的
+// begin_snapshot 拿到的 Snapshot 只看时间戳不超过它的版本, 读的时候不挡写者, 写者也不挡它
+// 旧版本在没有 snapshot 还看得到它时, 由之后对同一个 key 的写或者 purge 清掉
+// 只在内存里: 没有 flush 和 open, 版本链和时间戳都不落盘, 事务的原子性只对同一个进程里的读者成立
+pub struct MvccBPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, VersionChain<V>>>,
+    K: Ord,
+{
+    tree: ConcurrentBPlusTree<K, VersionChain<V>, E>,
+    // 分配出去的最大的时间戳
+    clock: AtomicU64,
+    // 这个时间戳和之前的版本都装好了, 新的 snapshot 从这里读
+    visible: AtomicU64,
+    // 还开着的 snapshot 的时间戳和个数
+    active: Mutex<BTreeMap<u64, usize>>,
+    // 没能撤干净的事务的时间戳, 它们留下的版本谁都看不到, 写同一个 key 或者 purge 时清掉
+    aborted: RwLock<HashSet<u64>>,
+}
+
+// 某个时间戳时的只读视图, drop 之前这一刻的版本不会被清掉
+pub struct Snapshot<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, VersionChain<V>>>,
+    K: Ord,
+{
+    tree: &'a MvccBPlusTree<K, V, E>,
+    ts: u64,
+}
+
+// 在开始时的 snapshot 上读, 写先记在自己这里, 读的时候先看自己写的
+// commit 时所有的写用同一个时间戳装到版本链上, 发布之前谁都看不到, 发布之后一起看到; 失败时一个都看不到
+// 不 commit 直接 drop 和 rollback 一样, 什么都没写
+pub struct Transaction<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, VersionChain<V>>>,
+    K: Ord,
+{
+    snapshot: Snapshot<'a, K, V, E>,
+    // None 是删除
+    writes: BTreeMap<K, Option<V>>,
+}
+
+impl<K, V, E> MvccBPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, VersionChain<V>>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn new(way: usize, engine: E) -> Result<MvccBPlusTree<K, V, E>> {
+        Ok(MvccBPlusTree {
+            tree: BPlusTree::new(way, engine)?.into_concurrent()?,
+            clock: AtomicU64::new(0),
+            visible: AtomicU64::new(0),
+            active: Mutex::new(BTreeMap::new()),
+            aborted: RwLock::new(HashSet::new()),
+        })
+    }
+
+    // 最近一次写完成时的时间戳
+    pub fn ts(&self) -> u64 {
+        self.visible.load(Ordering::SeqCst)
+    }
+
+    pub fn begin_snapshot(&self) -> Result<Snapshot<'_, K, V, E>> {
+        // 在锁里拿时间戳, purge 和写者算 horizon 时要么看到这个 snapshot, 要么算出来的不比它大
+        let mut active = self.active.lock().map_err(|_| Error::LockPoisoned)?;
+        let ts = self.ts();
+        *active.entry(ts).or_default() += 1;
+        Ok(Snapshot { tree: self, ts })
+    }
+
+    pub fn begin(&self) -> Result<Transaction<'_, K, V, E>> {
+        Ok(Transaction { snapshot: self.begin_snapshot()?, writes: BTreeMap::new() })
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.get_at(self.ts(), key)
+    }
+
+    pub fn range<R>(&self, range: R) -> Result<Vec<(K, V)>>
+    where
+        R: RangeBounds<K>,
+    {
+        self.range_at(self.ts(), range)
+    }
+
+    // key 已经存在时返回最新的 value
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>> {
+        let horizon = self.horizon()?;
+        let aborted = self.aborted.read().map_err(|_| Error::LockPoisoned)?;
+        let (mut commit, mut old) = (None, None);
+        let ret = self.tree.upsert(key, |chain| {
+            let ts = self.clock.fetch_add(1, Ordering::SeqCst) + 1;
+            commit = Some(ts);
+            match chain {
+                Some(chain) => {
+                    prune(chain, horizon, &aborted);
+                    old = latest(chain);
+                    install(chain, ts, Some(value));
+                    None
+                }
+                None => Some(vec![(ts, Some(value))]),
+            }
+        });
+        if let Some(ts) = commit {
+            self.publish(ts);
+        }
+        ret?;
+        Ok(old)
+    }
+
+    // 加一个删除标记, 开着的 snapshot 还能看到删之前的 value
+    pub fn delete(&self, key: &K) -> Result<Option<V>> {
+        let horizon = self.horizon()?;
+        let aborted = self.aborted.read().map_err(|_| Error::LockPoisoned)?;
+        let (mut commit, mut old) = (None, None);
+        let ret = self.tree.delete_if(key, |chain| {
+            prune(chain, horizon, &aborted);
+            old = latest(chain);
+            // 已经删过了, 谁都看不到它时从树上拿掉
+            if old.is_none() {
+                return is_dead(chain);
+            }
+            let ts = self.clock.fetch_add(1, Ordering::SeqCst) + 1;
+            commit = Some(ts);
+            install(chain, ts, None);
+            false
+        });
+        if let Some(ts) = commit {
+            self.publish(ts);
+        }
+        ret?;
+        Ok(old)
+    }
+
+    // 清掉所有 snapshot 都看不到的旧版本和没撤干净的事务留下的版本, 只剩删除标记的 key 从树上拿掉, 返回拿掉了几个 key
+    pub fn purge(&self) -> Result<usize> {
+        let horizon = self.horizon()?;
+        let aborted = self.aborted.read().map_err(|_| Error::LockPoisoned)?;
+        let stale = |chain: &VersionChain<V>| chain.len() > 1 || is_dead(chain) || chain.iter().any(|(commit, _)| aborted.contains(commit));
+        let keys = self.tree.range_map(.., |chain| stale(chain).then_some(()))?;
+        let mut purged = 0;
+        for (key, _) in keys {
+            let removed = self.tree.delete_if(&key, |chain| {
+                prune(chain, horizon, &aborted);
+                is_dead(chain)
+            })?;
+            purged += usize::from(removed.is_some());
+        }
+        // 记着的事务的版本都清掉了, 以后也不会再有这些时间戳的版本
+        let cleared: Vec<_> = aborted.iter().copied().collect();
+        drop(aborted);
+        let mut aborted = self.aborted.write().map_err(|_| Error::LockPoisoned)?;
+        cleared.iter().for_each(|ts| {
+            aborted.remove(ts);
+        });
+        Ok(purged)
+    }
+
+    // 事务的写都用时间戳 ts 装上去; 有 key 在 start 之后被改过, 或者中途出错时撤掉已经装上的
+    // 不管成功与否最后都要发布 ts, 不然后面的写者一直等; 没撤干净时先把 ts 记到 aborted 里再发布
+    fn apply(&self, writes: BTreeMap<K, Option<V>>, start: u64, ts: u64, horizon: u64) -> Result<()> {
+        let changed = |chain: &VersionChain<V>| chain.last().is_some_and(|(commit, _)| *commit > start);
+        let aborted = match self.aborted.read() {
+            Result::Ok(aborted) => aborted,
+            Err(_) => {
+                self.publish(ts);
+                return Err(Error::LockPoisoned.into());
+            }
+        };
+        let mut installed = vec![];
+        let mut ret = Ok(());
+        for (key, value) in writes {
+            let (mut conflict, mut done) = (false, false);
+            let step = match value {
+                Some(value) => self.tree.upsert(key.clone(), |chain| {
+                    done = true;
+                    match chain {
+                        Some(chain) => {
+                            prune(chain, horizon, &aborted);
+                            if changed(chain) {
+                                (conflict, done) = (true, false);
+                            } else {
+                                install(chain, ts, Some(value));
+                            }
+                            None
+                        }
+                        None => Some(vec![(ts, Some(value))]),
+                    }
+                }),
+                None => self
+                    .tree
+                    .delete_if(&key, |chain| {
+                        prune(chain, horizon, &aborted);
+                        conflict = changed(chain);
+                        // 已经删过的 key 不用再加删除标记
+                        if !conflict && latest(chain).is_some() {
+                            install(chain, ts, None);
+                            done = true;
+                        }
+                        false
+                    })
+                    .map(|_| ()),
+            };
+            if done {
+                installed.push(key);
+            }
+            ret = match (step, conflict) {
+                (Err(e), _) => Err(e),
+                (_, true) => Err(Error::WriteConflict.into()),
+                _ => continue,
+            };
+            break;
+        }
+        drop(aborted);
+        if ret.is_err() {
+            // 撤的时候也可能失败 (比如锁超时), 留下来的版本靠 aborted 挡住; 报上去的还是原来的错误
+            let failed = installed
+                .iter()
+                .filter(|key| {
+                    let undo = self.tree.delete_if(key, |chain| {
+                        chain.retain(|(commit, _)| *commit != ts);
+                        chain.is_empty()
+                    });
+                    undo.is_err()
+                })
+                .count();
+            if failed > 0 {
+                self.aborted.write().unwrap_or_else(PoisonError::into_inner).insert(ts);
+            }
+        }
+        self.publish(ts);
+        ret
+    }
+
+    fn get_at(&self, ts: u64, key: &K) -> Result<Option<V>> {
+        let aborted = self.aborted.read().map_err(|_| Error::LockPoisoned)?;
+        self.tree.search_map(key, |chain| visible_at(chain, ts, &aborted))
+    }
+
+    fn range_at<R>(&self, ts: u64, range: R) -> Result<Vec<(K, V)>>
+    where
+        R: RangeBounds<K>,
+    {
+        let aborted = self.aborted.read().map_err(|_| Error::LockPoisoned)?;
+        self.tree.range_map(range, |chain| visible_at(chain, ts, &aborted))
+    }
+
+    // 开着的 snapshot 里最早的时间戳, 没有的话是现在的; 比它早的版本里只有它看到的那个还有用
+    fn horizon(&self) -> Result<u64> {
+        let active = self.active.lock().map_err(|_| Error::LockPoisoned)?;
+        Ok(active.keys().next().copied().unwrap_or(self.ts()))
+    }
+
+    // 按时间戳的顺序发布, 前面的写者还没装好时等它; 写者都是先放开叶子的锁再发布, 不会互相等
+    fn publish(&self, ts: u64) {
+        while self.visible.compare_exchange_weak(ts - 1, ts, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            thread::yield_now();
+        }
+    }
+}
+
+impl<K, V, E> Snapshot<'_, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, VersionChain<V>>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn ts(&self) -> u64 {
+        self.ts
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.tree.get_at(self.ts, key)
+    }
+
+    pub fn range<R>(&self, range: R) -> Result<Vec<(K, V)>>
+    where
+        R: RangeBounds<K>,
+    {
+        self.tree.range_at(self.ts, range)
+    }
+}
+
+impl<K, V, E> Transaction<'_, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, VersionChain<V>>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 开始时的时间戳
+    pub fn ts(&self) -> u64 {
+        self.snapshot.ts
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        match self.writes.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.snapshot.get(key),
+        }
+    }
+
+    pub fn range<R>(&self, range: R) -> Result<Vec<(K, V)>>
+    where
+        R: RangeBounds<K>,
+    {
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut entries: BTreeMap<_, _> = self.snapshot.range(bounds.clone())?.into_iter().collect();
+        for (key, value) in self.writes.iter().filter(|(key, _)| bounds.contains(*key)) {
+            match value {
+                Some(value) => entries.insert(key.clone(), value.clone()),
+                None => entries.remove(key),
+            };
+        }
+        Ok(entries.into_iter().collect())
+    }
+
+    // 返回事务里看到的旧 value
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        let old = self.get(&key)?;
+        self.writes.insert(key, Some(value));
+        Ok(old)
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<Option<V>> {
+        let old = self.get(key)?;
+        self.writes.insert(key.clone(), None);
+        Ok(old)
+    }
+
+    // 要写的 key 在事务开始之后被别人改过时返回 Error::WriteConflict, 什么都不写, 可以重新开始一个事务再试
+    pub fn commit(self) -> Result<()> {
+        let Transaction { snapshot, writes } = self;
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let tree = snapshot.tree;
+        let horizon = tree.horizon()?;
+        let ts = tree.clock.fetch_add(1, Ordering::SeqCst) + 1;
+        tree.apply(writes, snapshot.ts, ts, horizon)
+    }
+
+    pub fn rollback(self) {}
+}
+
+impl<K, V, E> Drop for Snapshot<'_, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, VersionChain<V>>>,
+    K: Ord,
+{
+    fn drop(&mut self) {
+        if let std::result::Result::Ok(mut active) = self.tree.active.lock() {
+            if let Some(count) = active.get_mut(&self.ts) {
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(&self.ts);
+                }
+            }
+        }
+    }
+}
+
+fn latest<V: Clone>(chain: &VersionChain<V>) -> Option<V> {
+    chain.last().and_then(|(_, value)| value.clone())
+}
+
+fn visible_at<V: Clone>(chain: &VersionChain<V>, ts: u64, aborted: &HashSet<u64>) -> Option<V> {
+    chain
+        .iter()
+        .rev()
+        .find(|(commit, _)| *commit <= ts && !aborted.contains(commit))
+        .and_then(|(_, value)| value.clone())
+}
+
+// 按时间戳插到合适的位置, 一般就是最后
+fn install<V>(chain: &mut VersionChain<V>, ts: u64, value: Option<V>) {
+    let pos = chain.partition_point(|(commit, _)| *commit <= ts);
+    chain.insert(pos, (ts, value));
+}
+
+// 去掉没撤干净的事务的版本; horizon 时看到的那个版本和之后的都留着, 更早的去掉
+fn prune<V>(chain: &mut VersionChain<V>, horizon: u64, aborted: &HashSet<u64>) {
+    if !aborted.is_empty() {
+        chain.retain(|(commit, _)| !aborted.contains(commit));
+    }
+    if let Some(pos) = chain.iter().rposition(|(commit, _)| *commit <= horizon) {
+        chain.drain(..pos);
+    }
+}
+
+// 只剩一个删除标记或者什么都没剩, 什么时候读都是不存在
+fn is_dead<V>(chain: &VersionChain<V>) -> bool {
+    matches!(chain.as_slice(), [] | [(_, None)])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    type Tree = MvccBPlusTree<u32, u32, MemoryBlockEngine<BPlusTreeNode<u32, VersionChain<u32>>>>;
+
+    #[test]
+    fn test_transactions() {
+        let tree: Arc<Tree> = Arc::new(MvccBPlusTree::new(4, MemoryBlockEngine::new()).unwrap());
+        for i in 0..20 {
+            tree.insert(i, 100).unwrap();
+        }
+        let mut txn = tree.begin().unwrap();
+        assert_eq!(txn.insert(0, 50).unwrap(), Some(100));
+        assert_eq!(txn.delete(&1).unwrap(), Some(100));
+        txn.insert(100, 1).unwrap();
+        assert_eq!((txn.get(&0).unwrap(), txn.get(&1).unwrap(), txn.get(&100).unwrap()), (Some(50), None, Some(1)));
+        assert_eq!(txn.range(..3).unwrap(), vec![(0, 50), (2, 100)]);
+        assert_eq!(tree.get(&0).unwrap(), Some(100));
+        txn.rollback();
+        assert_eq!((tree.get(&0).unwrap(), tree.get(&1).unwrap(), tree.get(&100).unwrap()), (Some(100), Some(100), None));
+
+        // 在账户之间转账, 冲突时重试; 任何时候看到的总数都不变
+        let workers: Vec<_> = (0..4)
+            .map(|worker| {
+                let tree = tree.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let (from, to) = ((worker * 7 + i) % 20, (worker * 3 + i * 11 + 1) % 20);
+                        loop {
+                            let mut txn = tree.begin().unwrap();
+                            let (a, b) = (txn.get(&from).unwrap().unwrap(), txn.get(&to).unwrap().unwrap());
+                            if from != to {
+                                txn.insert(from, a - 1).unwrap();
+                                txn.insert(to, b + 1).unwrap();
+                            }
+                            match txn.commit() {
+                                Result::Ok(()) => break,
+                                Err(e) => assert_eq!(e.downcast_ref::<Error>(), Some(&Error::WriteConflict)),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..50 {
+            let snapshot = tree.begin_snapshot().unwrap();
+            assert_eq!(snapshot.range(..).unwrap().iter().map(|(_, value)| value).sum::<u32>(), 2000);
+        }
+        workers.into_iter().for_each(|worker| worker.join().unwrap());
+        assert_eq!(tree.range(..).unwrap().iter().map(|(_, value)| value).sum::<u32>(), 2000);
+
+        // 事务开始之后别人改了同一个 key, 前面已经装上的删除也要撤掉
+        let balance = tree.get(&0).unwrap();
+        let mut txn = tree.begin().unwrap();
+        txn.delete(&0).unwrap();
+        txn.insert(19, 0).unwrap();
+        tree.insert(19, 1).unwrap();
+        let err = txn.commit().unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::WriteConflict));
+        assert_eq!((tree.get(&0).unwrap(), tree.get(&19).unwrap()), (balance, Some(1)));
+
+        // 撤的时候失败了的事务: 留下的版本记在 aborted 里, 谁都看不到, 再写这些 key 也不算冲突, purge 之后清掉
+        let (before, entries) = (tree.get(&3).unwrap(), tree.range(..).unwrap());
+        let ts = tree.clock.fetch_add(1, Ordering::SeqCst) + 1;
+        tree.tree
+            .upsert(3, |chain| {
+                install(chain.unwrap(), ts, Some(0));
+                None
+            })
+            .unwrap();
+        tree.tree.upsert(100, |_| Some(vec![(ts, Some(0))])).unwrap();
+        tree.aborted.write().unwrap().insert(ts);
+        tree.publish(ts);
+        assert_eq!((tree.get(&3).unwrap(), tree.get(&100).unwrap()), (before, None));
+        assert_eq!(tree.range(..).unwrap(), entries);
+        let mut txn = tree.begin().unwrap();
+        txn.insert(3, 7).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(tree.get(&3).unwrap(), Some(7));
+        assert_eq!(tree.purge().unwrap(), 1);
+        assert!(tree.aborted.read().unwrap().is_empty());
+        assert!(tree.tree.range(..).unwrap().iter().all(|(_, chain)| chain.iter().all(|(commit, _)| *commit != ts)));
+    }
+
+    #[test]
+    fn test_snapshot_reads() {
+        let tree: Arc<Tree> = Arc::new(MvccBPlusTree::new(4, MemoryBlockEngine::new()).unwrap());
+        for i in 0..200 {
+            tree.insert(i, 0).unwrap();
+        }
+        // 写者把所有 key 一起改成下一轮的值, 每个 snapshot 看到的都是同一轮的
+        let writer = {
+            let tree = tree.clone();
+            thread::spawn(move || {
+                for round in 1..=20 {
+                    for i in 0..200 {
+                        assert_eq!(tree.insert(i, round).unwrap(), Some(round - 1));
+                    }
+                    if round % 5 == 0 {
+                        tree.purge().unwrap();
+                    }
+                }
+            })
+        };
+        for _ in 0..50 {
+            let snapshot = tree.begin_snapshot().unwrap();
+            let entries = snapshot.range(..).unwrap();
+            assert_eq!(entries.len(), 200);
+            // 同一个 snapshot 里, 前面的 key 不会比后面的新
+            assert!(entries.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+            assert!(entries.iter().all(|&(key, value)| snapshot.get(&key).unwrap() == Some(value)));
+        }
+        writer.join().unwrap();
+
+        let snapshot = tree.begin_snapshot().unwrap();
+        for i in 0..100 {
+            assert_eq!(tree.delete(&i).unwrap(), Some(20));
+        }
+        assert_eq!(tree.get(&0).unwrap(), None);
+        assert_eq!(tree.range(..).unwrap().len(), 100);
+        assert_eq!(snapshot.get(&0).unwrap(), Some(20));
+        assert_eq!(snapshot.range(..).unwrap().len(), 200);
+        // snapshot 还开着时删除标记要留着
+        assert_eq!(tree.purge().unwrap(), 0);
+        drop(snapshot);
+        assert_eq!(tree.purge().unwrap(), 100);
+        assert_eq!(tree.tree.len(), 100);
+        assert!(tree.tree.range(..).unwrap().iter().all(|(_, chain)| chain.len() == 1));
+    }
+}
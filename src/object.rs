@@ -0,0 +1,525 @@
+use anyhow::{anyhow, Ok, Result};
+use futures::{stream, StreamExt, TryStreamExt};
+use object_store::{path::Path, ObjectStore};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+use tokio::runtime::Runtime;
+
+use crate::{
+    block::{Block, BlockEngine, BlockEngineStats, BlockId, BlockReadGuard, BlockWriteGuard, TreeMeta},
+    codec::{BincodeCodec, NodeCodec},
+    compress::Compression,
+    error::Error,
+    file::{self, ChecksumPolicy, PAGE_HEADER_LEN},
+    pool::{BufferPool, PageStore},
+    replacement::Replacement,
+};
+
+const MAGIC: &[u8; 4] = b"BPTO";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectOptions {
+    // page_size 和 group_size 只在新建时用, 打开时用 manifest 里记着的
+    pub page_size: usize,
+    // 每个对象里放几个 block, 大一点对象少, 但是改一个 block 上传时要带上整组
+    pub group_size: usize,
+    pub pool_size: usize,
+    pub replacement: Replacement,
+    pub checksum: ChecksumPolicy,
+    pub compression: Compression,
+    // 同时进行的上传和下载请求数
+    pub concurrency: usize,
+}
+
+impl Default for ObjectOptions {
+    fn default() -> Self {
+        ObjectOptions {
+            page_size: 4096,
+            group_size: 64,
+            pool_size: 1024,
+            replacement: Replacement::Lru,
+            checksum: ChecksumPolicy::Error,
+            compression: Compression::None,
+            concurrency: 8,
+        }
+    }
+}
+
+// 记着整个 store 状态的对象, 覆盖它就是提交, 对象存储的 put 是原子的
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    page_size: usize,
+    group_size: usize,
+    seq: u64,
+    // way, root, 条目数, 是不是持久化模式, key 顺序的 id, 还没有 flush 过树时是 None
+    meta: Option<(usize, BlockId, usize, bool, u64)>,
+    block_count: usize,
+    // 每组现在是哪一次 flush 上传的, 还没上传过的组是 None
+    groups: Vec<Option<u64>>,
+    free_list: Vec<BlockId>,
+}
+
+impl Manifest {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = MAGIC.to_vec();
+        bincode::serialize_into(&mut buf, self)?;
+        Ok(buf)
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self> {
+        match buf.strip_prefix(MAGIC) {
+            Some(body) => Ok(bincode::deserialize(body)?),
+            None => Err(anyhow!("not a block store manifest.")),
+        }
+    }
+}
+
+// prefix 下面是 manifest 和 groups/{组号}.{seq}, 每个组对象是 group_size 个和 FileBlockEngine 一样格式的页
+// 组对象上传之后不再改, 改过的组在 flush 时整组上传成新的一版, manifest 换过去之后再删旧的
+// 上传到一半失败或者崩溃时 manifest 还指着旧的, 多出来的对象下次用同一个 seq flush 时会被覆盖
+struct ObjectPages<C> {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+    runtime: Runtime,
+    // 写回了但是还没上传的页, 已经补齐并且算过校验和
+    pending: Mutex<HashMap<BlockId, Vec<u8>>>,
+    groups: Vec<Option<u64>>,
+    page_size: usize,
+    group_size: usize,
+    concurrency: usize,
+    codec: C,
+    checksum: ChecksumPolicy,
+    compression: Compression,
+}
+
+impl<C> ObjectPages<C> {
+    fn manifest_path(&self) -> Path {
+        self.prefix.child("manifest")
+    }
+
+    fn group_path(&self, group: usize, seq: u64) -> Path {
+        self.prefix.child("groups").child(format!("{}.{}", group, seq))
+    }
+
+    fn block_path(&self, block_id: BlockId) -> Result<Path> {
+        let group = block_id / self.group_size;
+        match self.groups.get(group).copied().flatten() {
+            Some(seq) => Ok(self.group_path(group, seq)),
+            None => Err(anyhow!("block {} is not in the object store.", block_id)),
+        }
+    }
+
+    fn block_range(&self, block_id: BlockId) -> std::ops::Range<u64> {
+        let offset = (block_id % self.group_size * self.page_size) as u64;
+        offset..offset + self.page_size as u64
+    }
+
+    // 把还没上传的页按组合进新的一版对象, 返回上传了哪些组
+    // 整组都改过时不用先下载旧的那一版
+    fn upload(&self, block_count: usize, seq: u64) -> Result<Vec<usize>> {
+        let pending = self.pending.lock().map_err(|_| Error::LockPoisoned)?;
+        let mut dirty: BTreeMap<usize, Vec<(BlockId, &[u8])>> = BTreeMap::new();
+        for (&block_id, page) in pending.iter() {
+            dirty.entry(block_id / self.group_size).or_default().push((block_id, page));
+        }
+        let uploads = dirty.into_iter().map(|(group, pages)| async move {
+            let len = self.group_size.min(block_count - group * self.group_size);
+            let mut image = match self.groups.get(group).copied().flatten() {
+                Some(old) if pages.len() < len => {
+                    self.store.get(&self.group_path(group, old)).await?.bytes().await?.to_vec()
+                }
+                _ => vec![],
+            };
+            // 分配了还没写过的 block 和已经回收的一样不会被读到, 补 0 就行
+            image.resize(len * self.page_size, 0);
+            for (block_id, page) in pages {
+                let offset = block_id % self.group_size * self.page_size;
+                image[offset..offset + self.page_size].copy_from_slice(page);
+            }
+            self.store.put(&self.group_path(group, seq), image.into()).await?;
+            Ok(group)
+        });
+        self.runtime.block_on(stream::iter(uploads).buffer_unordered(self.concurrency).try_collect())
+    }
+
+    fn decode<B>(&self, block_id: BlockId, page: &[u8]) -> Result<Option<B>>
+    where
+        C: NodeCodec<B>,
+    {
+        file::check_page(block_id, page, self.checksum)?;
+        file::decode_page(&self.codec, block_id, page)
+    }
+}
+
+impl<B, C> PageStore<B> for ObjectPages<C>
+where
+    C: NodeCodec<B>,
+{
+    fn read_page(&self, block_id: BlockId) -> Result<Option<B>> {
+        if let Some(page) = self.pending.lock().map_err(|_| Error::LockPoisoned)?.get(&block_id) {
+            return self.decode(block_id, page);
+        }
+        let path = self.block_path(block_id)?;
+        let page = self.runtime.block_on(self.store.get_range(&path, self.block_range(block_id)))?;
+        self.decode(block_id, &page)
+    }
+
+    fn write_page(&self, block_id: BlockId, content: Option<&B>) -> Result<()> {
+        let page = file::encode_page(&self.codec, self.compression, content)?;
+        let page = file::seal_page(block_id, page, self.page_size)?;
+        self.pending.lock().map_err(|_| Error::LockPoisoned)?.insert(block_id, page);
+        Ok(())
+    }
+
+    // 同一组的页合成一次 get_ranges, 各组一起下载
+    fn read_pages(&self, block_ids: &[BlockId]) -> Result<Vec<Option<B>>> {
+        let pending = self.pending.lock().map_err(|_| Error::LockPoisoned)?;
+        let mut groups: BTreeMap<usize, Vec<BlockId>> = BTreeMap::new();
+        for &block_id in block_ids.iter().filter(|block_id| !pending.contains_key(block_id)) {
+            groups.entry(block_id / self.group_size).or_default().push(block_id);
+        }
+        let downloads = groups.into_values().map(|block_ids| async move {
+            let path = self.block_path(block_ids[0])?;
+            let ranges: Vec<_> = block_ids.iter().map(|&block_id| self.block_range(block_id)).collect();
+            let pages = self.store.get_ranges(&path, &ranges).await?;
+            Ok(block_ids.into_iter().zip(pages).collect::<Vec<_>>())
+        });
+        let fetched: Vec<_> =
+            self.runtime.block_on(stream::iter(downloads).buffer_unordered(self.concurrency).try_collect())?;
+        let fetched: HashMap<_, _> = fetched.into_iter().flatten().collect();
+        block_ids
+            .iter()
+            .map(|block_id| match pending.get(block_id) {
+                Some(page) => self.decode(*block_id, page),
+                None => self.decode(*block_id, &fetched[block_id]),
+            })
+            .collect()
+    }
+}
+
+// 把 block 存在对象存储 (S3 之类) 里的 engine, store 由调用的人用 object_store 建好传进来
+// 读 block 时只按范围下载它所在的那一页, 不用把整个索引下载下来, buffer pool 缓存解码过的 block
+// 写回的页先留在本地, flush 时按组成批上传, 最后换 manifest; 两次 flush 之间改的页都在内存里
+// 内部自己带一个 tokio runtime, 不能在异步任务里直接调用, 要在异步代码里用的话套一层 aio::Offload
+pub struct ObjectBlockEngine<B, C = BincodeCodec> {
+    pages: ObjectPages<C>,
+    pool: BufferPool<B>,
+    // 分配和回收只要 &self, 要改的放在一把锁里
+    space: Mutex<Space>,
+    meta: Option<TreeMeta>,
+    seq: u64,
+    allocations: AtomicU64,
+}
+
+struct Space {
+    block_count: usize,
+    free_list: Vec<BlockId>,
+    // free_list 里的 block, 查一个 block 是不是空闲的不用扫整个 free_list
+    free: HashSet<BlockId>,
+}
+
+impl Space {
+    fn check_block(&self, block_id: BlockId) -> Result<()> {
+        if block_id >= self.block_count || self.free.contains(&block_id) {
+            return Err(Error::InvalidBlock(block_id).into());
+        }
+        Ok(())
+    }
+}
+
+impl<B> ObjectBlockEngine<B>
+where
+    B: Serialize + DeserializeOwned,
+{
+    // 新建时覆盖 prefix 下的 manifest, 不会清理旧的组对象
+    pub fn create(store: Arc<dyn ObjectStore>, prefix: impl Into<Path>, options: ObjectOptions) -> Result<Self> {
+        Self::create_with_codec(store, prefix, options, BincodeCodec)
+    }
+
+    pub fn open(store: Arc<dyn ObjectStore>, prefix: impl Into<Path>, options: ObjectOptions) -> Result<Self> {
+        Self::open_with_codec(store, prefix, options, BincodeCodec)
+    }
+}
+
+impl<B, C> ObjectBlockEngine<B, C>
+where
+    C: NodeCodec<B>,
+{
+    pub fn create_with_codec(
+        store: Arc<dyn ObjectStore>,
+        prefix: impl Into<Path>,
+        options: ObjectOptions,
+        codec: C,
+    ) -> Result<Self> {
+        if options.page_size < PAGE_HEADER_LEN + 8 {
+            return Err(anyhow!("page size {} is too small.", options.page_size));
+        }
+        if options.group_size == 0 || options.concurrency == 0 {
+            return Err(anyhow!("group size and concurrency must be positive."));
+        }
+        let manifest = Manifest {
+            page_size: options.page_size,
+            group_size: options.group_size,
+            seq: 0,
+            meta: None,
+            block_count: 0,
+            groups: vec![],
+            free_list: vec![],
+        };
+        let engine = Self::with_manifest(store, prefix.into(), options, codec, manifest)?;
+        let manifest = engine.pages.manifest_path();
+        engine.pages.runtime.block_on(engine.pages.store.put(&manifest, engine.manifest()?.into()))?;
+        Ok(engine)
+    }
+
+    pub fn open_with_codec(
+        store: Arc<dyn ObjectStore>,
+        prefix: impl Into<Path>,
+        options: ObjectOptions,
+        codec: C,
+    ) -> Result<Self> {
+        let prefix = prefix.into();
+        let runtime = runtime()?;
+        let manifest = runtime.block_on(async { store.get(&prefix.child("manifest")).await?.bytes().await })?;
+        let manifest = Manifest::decode(&manifest)?;
+        Self::with_manifest(store, prefix, options, codec, manifest)
+    }
+
+    fn with_manifest(
+        store: Arc<dyn ObjectStore>,
+        prefix: Path,
+        options: ObjectOptions,
+        codec: C,
+        manifest: Manifest,
+    ) -> Result<Self> {
+        let pages = ObjectPages {
+            store,
+            prefix,
+            runtime: runtime()?,
+            pending: Mutex::new(HashMap::new()),
+            groups: manifest.groups,
+            page_size: manifest.page_size,
+            group_size: manifest.group_size,
+            concurrency: options.concurrency,
+            codec,
+            checksum: options.checksum,
+            compression: options.compression,
+        };
+        Ok(ObjectBlockEngine {
+            pages,
+            pool: BufferPool::new(options.pool_size, options.replacement)?,
+            space: Mutex::new(Space {
+                block_count: manifest.block_count,
+                free: manifest.free_list.iter().copied().collect(),
+                free_list: manifest.free_list,
+            }),
+            meta: manifest.meta.map(|(way, root, len, persistent, order)| TreeMeta { way, root, len, persistent, order }),
+            seq: manifest.seq,
+            allocations: AtomicU64::new(0),
+        })
+    }
+
+    fn manifest(&self) -> Result<Vec<u8>> {
+        let space = self.space()?;
+        Manifest {
+            page_size: self.pages.page_size,
+            group_size: self.pages.group_size,
+            seq: self.seq,
+            meta: self.meta.map(|meta| (meta.way, meta.root, meta.len, meta.persistent, meta.order)),
+            block_count: space.block_count,
+            groups: self.pages.groups.clone(),
+            free_list: space.free_list.clone(),
+        }
+        .encode()
+    }
+
+    fn check_block(&self, block_id: BlockId) -> Result<()> {
+        if block_id >= self.space()?.block_count {
+            return Err(Error::InvalidBlock(block_id).into());
+        }
+        Ok(())
+    }
+
+    fn space(&self) -> Result<MutexGuard<'_, Space>> {
+        Ok(self.space.lock().map_err(|_| Error::LockPoisoned)?)
+    }
+}
+
+impl<B, C> BlockEngine for ObjectBlockEngine<B, C>
+where
+    C: NodeCodec<B>,
+{
+    type Item = B;
+
+    fn alloc_block(&self) -> Result<BlockId> {
+        let mut space = self.space()?;
+        let (block_id, reused) = match space.free_list.last() {
+            Some(&block_id) => (block_id, true),
+            None => (space.block_count, false),
+        };
+        self.pool.insert_new(block_id, &self.pages)?;
+        if reused {
+            space.free_list.pop();
+            space.free.remove(&block_id);
+        } else {
+            space.block_count += 1;
+        }
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        Ok(block_id)
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, B>> {
+        self.check_block(block_id)?;
+        self.pool.fetch_read(block_id, &self.pages)
+    }
+
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, B>> {
+        self.check_block(block_id)?;
+        self.pool.fetch_write(block_id, &self.pages)
+    }
+
+    fn delete(&self, block_id: BlockId) -> Result<Option<B>> {
+        self.space()?.check_block(block_id)?;
+        let content = match self.pool.remove(block_id)? {
+            Some(content) => content,
+            None => self.pages.read_page(block_id)?,
+        };
+        // 回收的 block 不用上传, manifest 里的空闲列表说了算
+        self.pages.pending.lock().map_err(|_| Error::LockPoisoned)?.remove(&block_id);
+        let mut space = self.space()?;
+        space.free_list.push(block_id);
+        space.free.insert(block_id);
+        Ok(content)
+    }
+
+    // 写 guard 释放时还不写回, dirty 的 block 在被淘汰或者 flush 时写进待上传的页
+    fn write_back(_block_id: BlockId, _block: &Block<B>) {}
+
+    fn load_meta(&self) -> Option<TreeMeta> {
+        self.meta
+    }
+
+    // 先上传改过的组, 再换 manifest, 最后删掉被替换的旧版本
+    fn flush(&mut self, meta: TreeMeta) -> Result<()> {
+        self.pool.flush(&self.pages)?;
+        let seq = self.seq + 1;
+        let block_count = self.space()?.block_count;
+        let uploaded = self.pages.upload(block_count, seq)?;
+        let old = self.pages.groups.clone();
+        self.pages.groups.resize(block_count.div_ceil(self.pages.group_size), None);
+        for &group in &uploaded {
+            self.pages.groups[group] = Some(seq);
+        }
+        let (old_seq, old_meta) = (self.seq, self.meta);
+        self.seq = seq;
+        self.meta = Some(meta);
+        let manifest = self.pages.manifest_path();
+        let committed = self.manifest().and_then(|bytes| {
+            self.pages.runtime.block_on(self.pages.store.put(&manifest, bytes.into()))?;
+            Ok(())
+        });
+        if let Err(e) = committed {
+            (self.pages.groups, self.seq, self.meta) = (old, old_seq, old_meta);
+            return Err(e);
+        }
+        self.pages.pending.lock().map_err(|_| Error::LockPoisoned)?.clear();
+        // 旧版本删不掉只是多占地方
+        for group in uploaded {
+            if let Some(Some(old_seq)) = old.get(group) {
+                let path = self.pages.group_path(group, *old_seq);
+                if let Err(e) = self.pages.runtime.block_on(self.pages.store.delete(&path)) {
+                    log::warn!("failed to delete {}: {}.", path, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> BlockEngineStats {
+        BlockEngineStats { allocations: self.allocations.load(Ordering::Relaxed), ..self.pool.stats() }
+    }
+
+    // 同一组的 block 合成一次请求下载, 不存在或者已经回收的 block 跳过
+    fn prefetch(&self, block_ids: &[BlockId]) -> Result<usize> {
+        let block_ids: Vec<_> = {
+            let space = self.space()?;
+            block_ids.iter().copied().filter(|&block_id| space.check_block(block_id).is_ok()).collect()
+        };
+        self.pool.prefetch(&block_ids, &self.pages)
+    }
+
+    fn resident(&self) -> Result<Vec<BlockId>> {
+        self.pool.resident()
+    }
+
+    fn pin(&self, block_id: BlockId) -> Result<()> {
+        self.space()?.check_block(block_id)?;
+        self.pool.pin(block_id, &self.pages)
+    }
+
+    fn unpin(&self, block_id: BlockId) -> Result<()> {
+        self.pool.unpin(block_id)
+    }
+}
+
+fn runtime() -> Result<Runtime> {
+    Ok(tokio::runtime::Builder::new_current_thread().enable_all().build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::memory::InMemory;
+    use std::collections::HashSet;
+
+    use crate::tree::{BPlusTree, BPlusTreeNode};
+
+    use super::*;
+
+    fn objects(store: &InMemory) -> Vec<String> {
+        let objects: Vec<_> = futures::executor::block_on(store.list(None).try_collect()).unwrap();
+        let mut objects: Vec<_> = objects.into_iter().map(|object| object.location.to_string()).collect();
+        objects.sort();
+        objects
+    }
+
+    #[test]
+    fn test_object_engine() {
+        let store = Arc::new(InMemory::new());
+        let options = ObjectOptions { page_size: 512, group_size: 8, pool_size: 16, ..ObjectOptions::default() };
+        let engine = ObjectBlockEngine::<BPlusTreeNode<u32, String>>::create(store.clone(), "index", options).unwrap();
+        let mut tree = BPlusTree::new(8, engine).unwrap();
+        for i in 0..1000 {
+            tree.insert(i, format!("value {}", i)).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+        let first = objects(&store);
+        assert!(first.iter().all(|object| object == "index/manifest" || object.ends_with(".1")));
+
+        // 只改了几个 block, 只有它们所在的组换成新版本, 旧的被删掉
+        let engine = ObjectBlockEngine::<BPlusTreeNode<u32, String>>::open(store.clone(), "index", options).unwrap();
+        assert_eq!(engine.prefetch(&(0..100).collect::<Vec<_>>()).unwrap(), 16);
+        let mut tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.search(&999).unwrap(), Some("value 999".to_string()));
+        tree.insert(1000, "value 1000".to_string()).unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+        let second = objects(&store);
+        let groups: HashSet<_> = second.iter().filter_map(|object| Some(object.split_once('.')?.0)).collect();
+        assert_eq!(groups.len() + 1, second.len());
+        assert!(second.iter().any(|object| object.ends_with(".2")));
+
+        let engine = ObjectBlockEngine::<BPlusTreeNode<u32, String>>::open(store, "index", options).unwrap();
+        let tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.len(), 1001);
+        assert!(ObjectBlockEngine::<u32>::open(Arc::new(InMemory::new()), "index", options).is_err());
+    }
+}
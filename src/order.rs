@@ -0,0 +1,97 @@
+use std::{cmp::Ordering, num::NonZeroU64, ops::Bound, sync::Arc};
+
+type CompareFn<K> = dyn Fn(&K, &K) -> Ordering + Send + Sync;
+
+// 树里 key 的排列顺序, 默认用 K 的 Ord, 也可以在建树时换成自定义的比较函数
+// 结点里所有的查找和范围判断都走这里, 不直接用 K 的 <, == 或 binary_search
+pub struct KeyOrder<K> {
+    compare: Option<Arc<CompareFn<K>>>,
+    id: u64,
+}
+
+impl<K> Clone for KeyOrder<K> {
+    fn clone(&self) -> Self {
+        KeyOrder { compare: self.compare.clone(), id: self.id }
+    }
+}
+
+impl<K> Default for KeyOrder<K> {
+    fn default() -> Self {
+        KeyOrder { compare: None, id: 0 }
+    }
+}
+
+impl<K: Ord> KeyOrder<K> {
+    // 没有起 id 的比较函数都算 1, 只能和 K 的 Ord 区分开
+    pub fn new<F>(compare: F) -> Self
+    where
+        F: Fn(&K, &K) -> Ordering + Send + Sync + 'static,
+    {
+        Self::with_id(NonZeroU64::MIN, compare)
+    }
+
+    // id 由调用方给这个比较函数起, flush 时记进 superblock, 用别的顺序打开时能发现
+    pub fn with_id<F>(id: NonZeroU64, compare: F) -> Self
+    where
+        F: Fn(&K, &K) -> Ordering + Send + Sync + 'static,
+    {
+        KeyOrder { compare: Some(Arc::new(compare)), id: id.get() }
+    }
+
+    // K 的 Ord 是 0
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn cmp(&self, a: &K, b: &K) -> Ordering {
+        match &self.compare {
+            Some(compare) => compare(a, b),
+            None => a.cmp(b),
+        }
+    }
+
+    pub(crate) fn lt(&self, a: &K, b: &K) -> bool {
+        self.cmp(a, b) == Ordering::Less
+    }
+
+    pub(crate) fn eq(&self, a: &K, b: &K) -> bool {
+        self.cmp(a, b) == Ordering::Equal
+    }
+
+    // 和 slice::binary_search 一样, keys 必须按这个顺序排好
+    pub(crate) fn search(&self, keys: &[K], key: &K) -> Result<usize, usize> {
+        keys.binary_search_by(|probe| self.cmp(probe, key))
+    }
+
+    pub(crate) fn after_start(&self, start: Bound<&K>, key: &K) -> bool {
+        match start {
+            Bound::Included(start) => self.cmp(key, start) != Ordering::Less,
+            Bound::Excluded(start) => self.cmp(key, start) == Ordering::Greater,
+            Bound::Unbounded => true,
+        }
+    }
+
+    pub(crate) fn before_end(&self, end: Bound<&K>, key: &K) -> bool {
+        match end {
+            Bound::Included(end) => self.cmp(key, end) != Ordering::Greater,
+            Bound::Excluded(end) => self.cmp(key, end) == Ordering::Less,
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_order() {
+        let natural = KeyOrder::<String>::default();
+        let folded = KeyOrder::new(|a: &String, b: &String| a.to_lowercase().cmp(&b.to_lowercase()));
+        let keys = ["apple".to_string(), "Banana".to_string(), "cherry".to_string()];
+        assert_eq!(folded.search(&keys, &"BANANA".to_string()), Ok(1));
+        assert_eq!(natural.search(&keys, &"BANANA".to_string()), Err(0));
+        assert!(folded.after_start(Bound::Included(&"APPLE".to_string()), &keys[0]));
+        assert!(!folded.before_end(Bound::Excluded(&"banana".to_string()), &keys[1]));
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::{anyhow, Ok, Result};
+use arrow_array::RecordBatch;
+use arrow_schema::{Field, Schema};
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter, ProjectionMask},
+    file::properties::WriterProperties,
+};
+use std::{fs::File, ops::RangeBounds, path::Path, sync::Arc};
+
+use crate::{
+    block::BlockEngine,
+    columns::{ArrowColumn, ArrowRow},
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 导出时一个 row group 的行数, 攒够了就写出去, 内存里最多放这么多行
+const ROW_GROUP: usize = 64 * 1024;
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone + ArrowColumn,
+    V: Clone + ArrowRow,
+{
+    // key_col 做 key, value_cols 按顺序组成 value, 只读这几列, 用 bulk_load 建一棵新树
+    // 整个文件先读进内存, 没按 key 排好序时先排序; key 有重复时返回错误
+    pub fn import_parquet(
+        way: usize,
+        engine: E,
+        fill_factor: f64,
+        path: impl AsRef<Path>,
+        key_col: &str,
+        value_cols: &[&str],
+    ) -> Result<Self> {
+        if value_cols.len() != V::WIDTH {
+            return Err(anyhow!("value needs {} columns, got {}.", V::WIDTH, value_cols.len()));
+        }
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?;
+        let mut roots = vec![];
+        for name in std::iter::once(&key_col).chain(value_cols) {
+            roots.push(builder.schema().index_of(name).map_err(|_| anyhow!("column {} does not exist.", name))?);
+        }
+        let mask = ProjectionMask::roots(builder.parquet_schema(), roots);
+        let mut entries = vec![];
+        for batch in builder.with_projection(mask).build()? {
+            let batch = batch?;
+            let column = |name: &str| batch.column_by_name(name).map(|array| array.as_ref());
+            let key = column(key_col).ok_or_else(|| anyhow!("column {} does not exist.", key_col))?;
+            let values: Option<Vec<_>> = value_cols.iter().map(|name| column(name)).collect();
+            let values = values.ok_or_else(|| anyhow!("value columns {:?} do not exist.", value_cols))?;
+            for row in 0..batch.num_rows() {
+                entries.push((<K as ArrowColumn>::read(key, row)?, V::read(&values, row)?));
+            }
+        }
+        // 已经有序时只比较一遍
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        if entries.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err(anyhow!("column {} has duplicate keys.", key_col));
+        }
+        Self::bulk_load(way, engine, fill_factor, entries)
+    }
+
+    // 按 key 的顺序把 range 里的条目写进 parquet, 列名是 key 和 value, 元组的 value 是 value_0, value_1 ...
+    pub fn export_parquet<R>(&self, range: R, path: impl AsRef<Path>) -> Result<usize>
+    where
+        R: RangeBounds<K>,
+    {
+        let names: Vec<String> = match V::WIDTH {
+            1 => vec!["value".to_string()],
+            width => (0..width).map(|i| format!("value_{}", i)).collect(),
+        };
+        let names: Vec<_> = names.iter().map(String::as_str).collect();
+        self.export_parquet_with(range, path, "key", &names)
+    }
+
+    // 返回写了多少行; 边扫边写, 每 ROW_GROUP 行一个 row group
+    pub fn export_parquet_with<R>(&self, range: R, path: impl AsRef<Path>, key_col: &str, value_cols: &[&str]) -> Result<usize>
+    where
+        R: RangeBounds<K>,
+    {
+        if value_cols.len() != V::WIDTH {
+            return Err(anyhow!("value needs {} columns, got {}.", V::WIDTH, value_cols.len()));
+        }
+        let mut fields = vec![Field::new(key_col, K::data_type(), K::nullable())];
+        fields.extend(V::fields(value_cols));
+        let schema = Arc::new(Schema::new(fields));
+        let props = WriterProperties::builder().set_max_row_group_size(ROW_GROUP).build();
+        let mut writer = ArrowWriter::try_new(File::create(path)?, schema.clone(), Some(props))?;
+        let mut rows = vec![];
+        let mut count = 0;
+        let mut write = |rows: Vec<(K, V)>| -> Result<()> {
+            let (keys, values): (Vec<_>, Vec<_>) = rows.into_iter().unzip();
+            let mut columns = vec![K::array(keys)];
+            columns.extend(V::arrays(values));
+            writer.write(&RecordBatch::try_new(schema.clone(), columns)?)?;
+            writer.flush()?;
+            Ok(())
+        };
+        for entry in self.try_range(range) {
+            rows.push(entry?);
+            count += 1;
+            if rows.len() == ROW_GROUP {
+                write(std::mem::take(&mut rows))?;
+            }
+        }
+        if !rows.is_empty() {
+            write(rows)?;
+        }
+        writer.close()?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_parquet() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-parquet-{}.parquet", std::process::id()));
+        let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        for i in 0..300u64 {
+            let score = (i % 3 != 0).then_some(i as f64 / 2.0);
+            tree.insert(i, (format!("name-{}", i), score)).unwrap();
+        }
+        assert_eq!(tree.export_parquet(100..200, &path).unwrap(), 100);
+
+        let imported = BPlusTree::<u64, (String, Option<f64>), _>::import_parquet(
+            8,
+            MemoryBlockEngine::new(),
+            1.0,
+            &path,
+            "key",
+            &["value_0", "value_1"],
+        )
+        .unwrap();
+        assert_eq!(imported.search(&99).unwrap(), None);
+        assert_eq!(imported.search(&150).unwrap(), Some(("name-150".to_string(), None)));
+        assert_eq!(imported.search(&151).unwrap(), Some(("name-151".to_string(), Some(75.5))));
+        assert_eq!(imported.search(&199).unwrap(), Some(("name-199".to_string(), Some(99.5))));
+        assert_eq!(imported.search(&200).unwrap(), None);
+        // 只取一列, key 换成另一列; 列名或者类型不对时报错
+        let names = BPlusTree::<String, u64, _>::import_parquet(4, MemoryBlockEngine::new(), 1.0, &path, "value_0", &["key"]);
+        assert_eq!(names.unwrap().search(&"name-100".to_string()).unwrap(), Some(100));
+        assert!(BPlusTree::<u64, String, _>::import_parquet(4, MemoryBlockEngine::new(), 1.0, &path, "key", &["nope"]).is_err());
+        assert!(BPlusTree::<u64, u64, _>::import_parquet(4, MemoryBlockEngine::new(), 1.0, &path, "key", &["value_0"]).is_err());
+        assert!(BPlusTree::<u64, f64, _>::import_parquet(4, MemoryBlockEngine::new(), 1.0, &path, "key", &["value_1"]).is_err());
+
+        // 没排序的输入先排序, 重复的 key 报错
+        let mut unsorted = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        for i in 0..50i64 {
+            unsorted.insert(i, (i * 37 % 50) as i32).unwrap();
+        }
+        unsorted.export_parquet_with(.., &path, "id", &["rank"]).unwrap();
+        let by_rank = BPlusTree::<i32, i64, _>::import_parquet(4, MemoryBlockEngine::new(), 1.0, &path, "rank", &["id"]).unwrap();
+        by_rank.verify().unwrap();
+        assert_eq!(by_rank.keys().collect::<Vec<_>>(), (0..50).collect::<Vec<_>>());
+        for rank in 0..50 {
+            assert_eq!(by_rank.search(&rank).unwrap(), Some((0..50).find(|i| i * 37 % 50 == rank as i64).unwrap()));
+        }
+        unsorted.insert(50, 0).unwrap();
+        unsorted.export_parquet_with(.., &path, "id", &["rank"]).unwrap();
+        assert!(BPlusTree::<i32, i64, _>::import_parquet(4, MemoryBlockEngine::new(), 1.0, &path, "rank", &["id"]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
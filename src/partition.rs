@@ -0,0 +1,91 @@
+use anyhow::{anyhow, Ok, Result};
+use std::ops::Bound;
+
+use crate::{
+    block::BlockEngine,
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 把 key 空间切成最多 n 段, 各段的数据量大致相同, 可以直接交给并行的扫描任务
+    // 逐层往下展开, 直到某一层的结点数够多, 再从这一层的分隔 key 里均匀地挑切分点
+    pub fn partition_ranges(&self, n: usize) -> Result<Vec<(Bound<K>, Bound<K>)>> {
+        if n == 0 {
+            return Err(anyhow!("cannot partition into 0 ranges."));
+        }
+        let mut level = vec![self.root];
+        // 相邻两个结点之间的分隔 key
+        let mut separators: Vec<K> = vec![];
+        let mut leaf_keys: Vec<K> = vec![];
+        while level.len() < n {
+            let mut next_level = vec![];
+            let mut next_separators = vec![];
+            for (i, &block_id) in level.iter().enumerate() {
+                let read = self.engine.fetch_read(block_id)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+                if node.is_leaf() {
+                    leaf_keys.extend(node.keys.iter().cloned());
+                    continue;
+                }
+                if i > 0 {
+                    next_separators.push(separators[i - 1].clone());
+                }
+                next_level.extend(node.pointers.iter().copied());
+                next_separators.extend(node.keys.iter().cloned());
+            }
+            if next_level.is_empty() {
+                // 已经到叶子层了, 结点还是不够就直接用叶子里的 key
+                separators = leaf_keys.into_iter().skip(1).collect();
+                break;
+            }
+            level = next_level;
+            separators = next_separators;
+        }
+
+        let splits: Vec<K> = if separators.len() < n {
+            separators
+        } else {
+            (1..n).map(|i| separators[i * (separators.len() + 1) / n - 1].clone()).collect()
+        };
+        let mut ranges = Vec::with_capacity(splits.len() + 1);
+        let mut start = Bound::Unbounded;
+        for split in splits {
+            ranges.push((start, Bound::Excluded(split.clone())));
+            start = Bound::Included(split);
+        }
+        ranges.push((start, Bound::Unbounded));
+        Ok(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_partition_ranges() {
+        let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        assert_eq!(tree.partition_ranges(4).unwrap(), vec![(Bound::Unbounded, Bound::Unbounded)]);
+        for i in 0..1000 {
+            tree.insert(i, i).unwrap();
+        }
+        assert!(tree.partition_ranges(0).is_err());
+
+        for n in [1, 3, 8, 100] {
+            let ranges = tree.partition_ranges(n).unwrap();
+            assert!(ranges.len() <= n);
+            let counts: Vec<usize> = ranges.iter().map(|r| tree.range(*r).count()).collect();
+            assert_eq!(counts.iter().sum::<usize>(), 1000);
+            let expected = 1000 / ranges.len();
+            assert!(counts.iter().all(|&c| c > expected / 3 && c < expected * 3), "{:?}", counts);
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use anyhow::{anyhow, Ok, Result};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError, RwLock, RwLockWriteGuard, TryLockError},
+};
+
+use crate::{
+    block::{Block, BlockEngineStats, BlockId, BlockReadGuard, BlockWriteGuard},
+    error::Error,
+    replacement::{Replacement, ReplacementPolicy},
+};
+
+// 缓存不命中时从这里读, 淘汰 dirty 的 block 时写回这里
+pub(crate) trait PageStore<B> {
+    fn read_page(&self, block_id: BlockId) -> Result<Option<B>>;
+    fn write_page(&self, block_id: BlockId, content: Option<&B>) -> Result<()>;
+
+    // 一次读写好几页, 能把 I/O 合并成一批的 store 可以覆盖
+    fn read_pages(&self, block_ids: &[BlockId]) -> Result<Vec<Option<B>>> {
+        block_ids.iter().map(|&block_id| self.read_page(block_id)).collect()
+    }
+
+    fn write_pages(&self, pages: &[(BlockId, Option<&B>)]) -> Result<()> {
+        pages.iter().try_for_each(|&(block_id, content)| self.write_page(block_id, content))
+    }
+}
+
+// 固定数量的 frame, 每个 frame 放一个 block, 满了之后按 ReplacementPolicy 淘汰
+// 有 guard 借着的 frame 拿不到写锁, 淘汰时会跳过; pin 住的 block 没有 guard 借着也不会被淘汰
+pub(crate) struct BufferPool<B> {
+    frames: Vec<RwLock<Block<B>>>,
+    state: Mutex<PoolState>,
+}
+
+struct PoolState {
+    // block id -> frame 下标
+    table: HashMap<BlockId, usize>,
+    dirty: Vec<bool>,
+    policy: Box<dyn ReplacementPolicy>,
+    // block id -> pin 了几次
+    pins: HashMap<BlockId, usize>,
+    // dirty 和 allocations 不在这里记
+    stats: BlockEngineStats,
+}
+
+impl<B> BufferPool<B> {
+    pub(crate) fn new(size: usize, replacement: Replacement) -> Result<Self> {
+        if size == 0 {
+            return Err(anyhow!("buffer pool needs at least one frame."));
+        }
+        let frames = (0..size).map(|_| RwLock::new(Block { valid: false, id: BlockId::MAX, content: None })).collect();
+        let state = PoolState {
+            table: HashMap::new(),
+            dirty: vec![false; size],
+            policy: replacement.build(size),
+            pins: HashMap::new(),
+            stats: BlockEngineStats::default(),
+        };
+        Ok(BufferPool { frames, state: Mutex::new(state) })
+    }
+
+    pub(crate) fn fetch_read(&self, block_id: BlockId, store: &impl PageStore<B>) -> Result<BlockReadGuard<'_, B>> {
+        loop {
+            let mut state = self.state.lock().map_err(|_| Error::LockPoisoned)?;
+            let frame = match state.table.get(&block_id) {
+                Some(&frame) => {
+                    state.policy.on_hit(frame);
+                    state.stats.hits += 1;
+                    drop(state);
+                    frame
+                }
+                None => {
+                    let (frame, mut write) = evict(&self.frames, &mut state, store)?;
+                    state.stats.misses += 1;
+                    state.stats.reads += 1;
+                    *write = Block { valid: true, id: block_id, content: store.read_page(block_id)? };
+                    state.table.insert(block_id, frame);
+                    state.policy.on_load(frame);
+                    // 还拿着 state 的锁, 这期间别的线程没法淘汰这个 frame
+                    drop(write);
+                    let read = self.frames[frame].read().map_err(|_| Error::LockPoisoned)?;
+                    return Ok(BlockReadGuard { rwlock_guard: read });
+                }
+            };
+            let read = self.frames[frame].read().map_err(|_| Error::LockPoisoned)?;
+            // 放开 state 的锁之后 frame 可能已经换成别的 block 了, 重新找
+            if read.valid && read.id == block_id {
+                return Ok(BlockReadGuard { rwlock_guard: read });
+            }
+        }
+    }
+
+    // 可写的 block 会被标成 dirty, 淘汰或者 flush 时写回
+    // 和 fetch_read 一样, 拿到写锁之后 frame 可能已经换成别的 block 了, 这时 dirty 标记已经跟着淘汰清掉了, 重新找
+    pub(crate) fn fetch_write(&self, block_id: BlockId, store: &impl PageStore<B>) -> Result<BlockWriteGuard<'_, B>> {
+        loop {
+            let mut state = self.state.lock().map_err(|_| Error::LockPoisoned)?;
+            let frame = match state.table.get(&block_id) {
+                Some(&frame) => {
+                    state.policy.on_hit(frame);
+                    state.stats.hits += 1;
+                    state.dirty[frame] = true;
+                    drop(state);
+                    frame
+                }
+                None => {
+                    let (frame, mut write) = evict(&self.frames, &mut state, store)?;
+                    state.stats.misses += 1;
+                    state.stats.reads += 1;
+                    *write = Block { valid: true, id: block_id, content: store.read_page(block_id)? };
+                    state.table.insert(block_id, frame);
+                    state.policy.on_load(frame);
+                    state.dirty[frame] = true;
+                    return Ok(BlockWriteGuard { rwlock_guard: write, write_back: |_, _| {} });
+                }
+            };
+            let write = self.frames[frame].write().map_err(|_| Error::LockPoisoned)?;
+            if write.valid && write.id == block_id {
+                return Ok(BlockWriteGuard { rwlock_guard: write, write_back: |_, _| {} });
+            }
+        }
+    }
+
+    // 新分配的 block, 不用从 store 里读
+    pub(crate) fn insert_new(&self, block_id: BlockId, store: &impl PageStore<B>) -> Result<()> {
+        let mut state = self.state.lock().map_err(|_| Error::LockPoisoned)?;
+        let frame = match state.table.get(&block_id) {
+            Some(&frame) => {
+                state.policy.on_hit(frame);
+                frame
+            }
+            None => {
+                let (frame, mut write) = evict(&self.frames, &mut state, store)?;
+                *write = Block { valid: true, id: block_id, content: None };
+                state.table.insert(block_id, frame);
+                state.policy.on_load(frame);
+                frame
+            }
+        };
+        state.dirty[frame] = true;
+        Ok(())
+    }
+
+    // block 被回收, 从缓存里拿掉, 返回缓存着的内容
+    // 回收的 block 不应该还有 guard 借着; 拿着 state 的锁等 frame 的写锁, 免得 frame 先被别的 block 占了
+    pub(crate) fn remove(&self, block_id: BlockId) -> Result<Option<Option<B>>> {
+        let mut state = self.state.lock().map_err(|_| Error::LockPoisoned)?;
+        if state.pins.contains_key(&block_id) {
+            return Err(anyhow!("block {} is pinned.", block_id));
+        }
+        let Some(frame) = state.table.remove(&block_id) else {
+            return Ok(None);
+        };
+        state.dirty[frame] = false;
+        state.policy.on_remove(frame);
+        let mut block = self.frames[frame].write().map_err(|_| Error::LockPoisoned)?;
+        block.valid = false;
+        Ok(Some(block.content.take()))
+    }
+
+    // 写回所有 dirty 的 block, 一起交给 store; 失败时都还是 dirty, 留到下次
+    // 拿着 state 的锁等 frame 的读锁, 调用时同一个线程不能拿着这个 pool 里的写 guard
+    pub(crate) fn flush(&self, store: &impl PageStore<B>) -> Result<()> {
+        let BufferPool { frames, state } = self;
+        let mut state = state.lock().map_err(|_| Error::LockPoisoned)?;
+        let state = &mut *state;
+        let mut dirty: Vec<_> = state.table.iter().filter(|(_, &frame)| state.dirty[frame]).map(|(&id, &frame)| (id, frame)).collect();
+        dirty.sort();
+        let blocks = dirty
+            .iter()
+            .map(|&(_, frame)| frames[frame].read().map_err(|_| Error::LockPoisoned))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let pages: Vec<_> =
+            dirty.iter().zip(&blocks).map(|(&(block_id, _), block)| (block_id, block.content.as_ref())).collect();
+        store.write_pages(&pages)?;
+        state.stats.writes += pages.len() as u64;
+        for (_, frame) in dirty {
+            state.dirty[frame] = false;
+        }
+        Ok(())
+    }
+
+    // 计数只在 state 的锁里改, 锁被污染了计数也还是对的
+    pub(crate) fn stats(&self) -> BlockEngineStats {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        BlockEngineStats { dirty: state.dirty.iter().filter(|&&dirty| dirty).count(), ..state.stats }
+    }
+
+    // 把还不在缓存里的 block 一批读进来, 返回读进来几个; 最多占满整个 pool, 腾不出 frame 时少读几个
+    pub(crate) fn prefetch(&self, block_ids: &[BlockId], store: &impl PageStore<B>) -> Result<usize> {
+        let mut state = self.state.lock().map_err(|_| Error::LockPoisoned)?;
+        let mut wanted = vec![];
+        for &block_id in block_ids {
+            if wanted.len() < self.frames.len() && !state.table.contains_key(&block_id) && !wanted.contains(&block_id) {
+                wanted.push(block_id);
+            }
+        }
+        // 腾出来的 frame 一直拿着写锁, 后面的 evict 不会再挑到它们
+        let mut taken = vec![];
+        for _ in 0..wanted.len() {
+            match evict(&self.frames, &mut state, store) {
+                Result::Ok(frame) => taken.push(frame),
+                Err(e) if taken.is_empty() => return Err(e),
+                Err(_) => break,
+            }
+        }
+        wanted.truncate(taken.len());
+        let contents = store.read_pages(&wanted)?;
+        state.stats.reads += wanted.len() as u64;
+        for ((block_id, content), (frame, mut write)) in wanted.iter().zip(contents).zip(taken) {
+            *write = Block { valid: true, id: *block_id, content };
+            state.table.insert(*block_id, frame);
+            state.policy.on_load(frame);
+        }
+        Ok(wanted.len())
+    }
+
+    pub(crate) fn resident(&self) -> Result<Vec<BlockId>> {
+        let state = self.state.lock().map_err(|_| Error::LockPoisoned)?;
+        Ok(state.table.keys().copied().collect())
+    }
+
+    // 把 block 读进缓存并留在里面, 直到 unpin 的次数和 pin 的一样多
+    pub(crate) fn pin(&self, block_id: BlockId, store: &impl PageStore<B>) -> Result<()> {
+        let mut state = self.state.lock().map_err(|_| Error::LockPoisoned)?;
+        match state.table.get(&block_id) {
+            Some(&frame) => {
+                state.policy.on_hit(frame);
+                state.stats.hits += 1;
+            }
+            None => {
+                let (frame, mut write) = evict(&self.frames, &mut state, store)?;
+                state.stats.misses += 1;
+                state.stats.reads += 1;
+                *write = Block { valid: true, id: block_id, content: store.read_page(block_id)? };
+                state.table.insert(block_id, frame);
+                state.policy.on_load(frame);
+            }
+        }
+        *state.pins.entry(block_id).or_default() += 1;
+        Ok(())
+    }
+
+    pub(crate) fn unpin(&self, block_id: BlockId) -> Result<()> {
+        let mut state = self.state.lock().map_err(|_| Error::LockPoisoned)?;
+        match state.pins.get_mut(&block_id) {
+            Some(1) => {
+                state.pins.remove(&block_id);
+            }
+            Some(count) => *count -= 1,
+            None => return Err(anyhow!("block {} is not pinned.", block_id)),
+        }
+        Ok(())
+    }
+}
+
+// 找一个没有被借用也没有被 pin 的 frame 腾出来, dirty 的先写回, 返回时 frame 已经不在 table 里了
+fn evict<'a, B>(
+    frames: &'a [RwLock<Block<B>>],
+    state: &mut PoolState,
+    store: &impl PageStore<B>,
+) -> Result<(usize, RwLockWriteGuard<'a, Block<B>>)> {
+    let mut taken = None;
+    let mut poisoned = false;
+    let pins = &state.pins;
+    let victim = state.policy.victim(&mut |frame| match frames[frame].try_write() {
+        Result::Ok(write) if write.valid && pins.contains_key(&write.id) => false,
+        Result::Ok(write) => {
+            taken = Some(write);
+            true
+        }
+        Err(TryLockError::WouldBlock) => false,
+        Err(TryLockError::Poisoned(_)) => {
+            poisoned = true;
+            false
+        }
+    });
+    if poisoned {
+        return Err(Error::LockPoisoned.into());
+    }
+    let (Some(frame), Some(mut write)) = (victim, taken) else {
+        return Err(anyhow!("all {} frames of the buffer pool are in use.", frames.len()));
+    };
+    if write.valid {
+        if state.dirty[frame] {
+            store.write_page(write.id, write.content.as_ref())?;
+            state.dirty[frame] = false;
+            state.stats.writes += 1;
+        }
+        state.stats.evictions += 1;
+        state.table.remove(&write.id);
+        write.valid = false;
+        write.content = None;
+    }
+    Ok((frame, write))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    // 记下每次写回的 block
+    #[derive(Default)]
+    struct MemoryStore {
+        pages: Mutex<HashMap<BlockId, u32>>,
+        writes: Mutex<Vec<BlockId>>,
+        reads: Mutex<usize>,
+    }
+
+    impl PageStore<u32> for MemoryStore {
+        fn read_page(&self, block_id: BlockId) -> Result<Option<u32>> {
+            *self.reads.lock().unwrap() += 1;
+            Ok(self.pages.lock().unwrap().get(&block_id).copied())
+        }
+
+        fn write_page(&self, block_id: BlockId, content: Option<&u32>) -> Result<()> {
+            self.pages.lock().unwrap().insert(block_id, *content.unwrap());
+            self.writes.lock().unwrap().push(block_id);
+            Ok(())
+        }
+    }
+
+    fn resident(pool: &mut BufferPool<u32>) -> HashSet<BlockId> {
+        pool.state.get_mut().unwrap().table.keys().copied().collect()
+    }
+
+    #[test]
+    fn test_buffer_pool_lru() {
+        let store = MemoryStore::default();
+        let mut pool = BufferPool::new(2, Replacement::Lru).unwrap();
+        for block_id in 0..3 {
+            pool.insert_new(block_id, &store).unwrap();
+            **pool.fetch_write(block_id, &store).unwrap() = Some(block_id as u32 * 10);
+        }
+        // 0 最久没用, 被淘汰时写回
+        assert_eq!(*store.writes.lock().unwrap(), vec![0]);
+        assert_eq!(resident(&mut pool), HashSet::from([1, 2]));
+
+        // 读 1 之后 2 变成最久没用的
+        assert_eq!(**pool.fetch_read(1, &store).unwrap(), Some(10));
+        assert_eq!(**pool.fetch_read(0, &store).unwrap(), Some(0));
+        assert_eq!(resident(&mut pool), HashSet::from([0, 1]));
+        assert_eq!(*store.writes.lock().unwrap(), vec![0, 2]);
+
+        // 被 guard 借着的 frame 不能淘汰
+        let first = pool.fetch_read(0, &store).unwrap();
+        let second = pool.fetch_read(2, &store).unwrap();
+        assert!(pool.fetch_read(1, &store).is_err());
+        drop((first, second));
+
+        pool.flush(&store).unwrap();
+        assert_eq!(*store.pages.lock().unwrap(), HashMap::from([(0, 0), (1, 10), (2, 20)]));
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (5, 2, 3));
+        assert_eq!((stats.reads, stats.writes, stats.dirty), (2, 3, 0));
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let store = MemoryStore::default();
+        store.pages.lock().unwrap().extend((0..5).map(|block_id| (block_id, block_id as u32)));
+        let mut pool = BufferPool::new(3, Replacement::Lru).unwrap();
+        pool.insert_new(9, &store).unwrap();
+        **pool.fetch_write(9, &store).unwrap() = Some(90);
+
+        // 最多读满整个 pool, 已经在缓存里的和重复的不读, 被淘汰的 dirty block 先写回
+        assert_eq!(pool.prefetch(&[9, 1, 2, 1, 3, 4], &store).unwrap(), 3);
+        assert_eq!(*store.writes.lock().unwrap(), vec![9]);
+        assert_eq!(resident(&mut pool), HashSet::from([1, 2, 3]));
+        let reads = *store.reads.lock().unwrap();
+        assert_eq!(**pool.fetch_read(2, &store).unwrap(), Some(2));
+        assert_eq!(*store.reads.lock().unwrap(), reads);
+    }
+
+    #[test]
+    fn test_pin() {
+        let store = MemoryStore::default();
+        store.pages.lock().unwrap().extend((0..5).map(|block_id| (block_id, block_id as u32)));
+        let mut pool = BufferPool::new(2, Replacement::Lru).unwrap();
+
+        // pin 住的 block 没有 guard 借着也不会被淘汰, pin 几次就要 unpin 几次
+        pool.pin(0, &store).unwrap();
+        pool.pin(0, &store).unwrap();
+        for block_id in 1..5 {
+            assert_eq!(**pool.fetch_read(block_id, &store).unwrap(), Some(block_id as u32));
+            assert!(resident(&mut pool).contains(&0));
+        }
+        pool.pin(4, &store).unwrap();
+        assert!(pool.fetch_read(1, &store).is_err());
+        assert!(pool.remove(0).is_err());
+
+        pool.unpin(0).unwrap();
+        assert!(pool.fetch_read(1, &store).is_err());
+        pool.unpin(0).unwrap();
+        assert!(pool.unpin(0).is_err());
+        assert_eq!(**pool.fetch_read(1, &store).unwrap(), Some(1));
+        assert_eq!(resident(&mut pool), HashSet::from([1, 4]));
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::{Ok, Result};
+use prost::Message;
+use std::{fmt, marker::PhantomData};
+
+use crate::{
+    block::BlockEngine,
+    codec::ValueCodec,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// protobuf 的 value, 和 CompactWith 一起用: CompactWith(ProstCodec)
+// value 是 M 时读页就解码整个 message; 是 Lazy<M> 时读页只拷字节, 用到时才解码
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProstCodec;
+
+impl<M: Message + Default> ValueCodec<M> for ProstCodec {
+    fn encode_value(&self, value: &M, buf: &mut Vec<u8>) -> Result<()> {
+        value.encode(buf)?;
+        Ok(())
+    }
+
+    fn decode_value(&self, bytes: &[u8]) -> Result<M> {
+        Ok(M::decode(bytes)?)
+    }
+}
+
+impl<M> ValueCodec<Lazy<M>> for ProstCodec {
+    fn encode_value(&self, value: &Lazy<M>, buf: &mut Vec<u8>) -> Result<()> {
+        buf.extend_from_slice(&value.bytes);
+        Ok(())
+    }
+
+    fn decode_value(&self, bytes: &[u8]) -> Result<Lazy<M>> {
+        Ok(Lazy { bytes: bytes.to_vec(), _marker: PhantomData })
+    }
+}
+
+// 编码好的 M, 存的就是 protobuf 的字节
+pub struct Lazy<M> {
+    bytes: Vec<u8>,
+    _marker: PhantomData<fn() -> M>,
+}
+
+impl<M: Message + Default> Lazy<M> {
+    pub fn new(message: &M) -> Self {
+        Lazy { bytes: message.encode_to_vec(), _marker: PhantomData }
+    }
+
+    pub fn decode(&self) -> Result<M> {
+        Ok(M::decode(self.bytes.as_slice())?)
+    }
+}
+
+impl<M> Lazy<M> {
+    // P 只声明 M 里的一部分字段 (同样的 tag 和类型), 别的字段解码时直接跳过
+    pub fn decode_as<P: Message + Default>(&self) -> Result<P> {
+        Ok(P::decode(self.bytes.as_slice())?)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl<M> Clone for Lazy<M> {
+    fn clone(&self) -> Self {
+        Lazy { bytes: self.bytes.clone(), _marker: PhantomData }
+    }
+}
+
+impl<M> PartialEq for Lazy<M> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl<M> fmt::Debug for Lazy<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Lazy({} bytes)", self.bytes.len())
+    }
+}
+
+impl<K, M, E> BPlusTree<K, Lazy<M>, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, Lazy<M>>>,
+    K: Ord,
+{
+    // 只解码 P 里声明的字段, value 的字节不 clone, 在叶子的读锁下直接解码
+    pub fn get_with<P: Message + Default>(&self, key: &K) -> Result<Option<P>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(value.decode_as()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        codec::CompactWith,
+        file::{FileBlockEngine, FileOptions},
+    };
+
+    use super::*;
+
+    #[derive(Clone, PartialEq, Message)]
+    struct User {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(uint32, tag = "2")]
+        age: u32,
+        #[prost(bytes = "vec", tag = "3")]
+        avatar: Vec<u8>,
+    }
+
+    // User 里的一部分字段
+    #[derive(Clone, PartialEq, Message)]
+    struct UserName {
+        #[prost(string, tag = "1")]
+        name: String,
+    }
+
+    fn user(i: u32) -> User {
+        User { name: format!("user-{}", i), age: i % 90, avatar: vec![i as u8; 16] }
+    }
+
+    #[test]
+    fn test_prost_codec() {
+        // M 和 Lazy<M> 的编码是同样的字节
+        let mut buf = vec![];
+        ProstCodec.encode_value(&user(7), &mut buf).unwrap();
+        let lazy: Lazy<User> = ProstCodec.decode_value(&buf).unwrap();
+        assert_eq!(lazy, Lazy::new(&user(7)));
+        assert!(ValueCodec::<User>::decode_value(&ProstCodec, &[0xff]).is_err());
+
+        let path = std::env::temp_dir().join(format!("bplus-tree-prost-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 1024, ..FileOptions::default() };
+        let engine = FileBlockEngine::create_with_codec(&path, options, CompactWith(ProstCodec)).unwrap();
+        let mut tree = BPlusTree::new(16, engine).unwrap();
+        for i in 0..500 {
+            tree.insert(i, user(i)).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+        let engine = FileBlockEngine::open_with_codec(&path, options, CompactWith(ProstCodec)).unwrap();
+        let tree: BPlusTree<u32, User, _> = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.search(&123).unwrap(), Some(user(123)));
+        drop(tree);
+
+        // 同一个文件按 Lazy 打开, 页里的字节不用重新编码
+        let engine = FileBlockEngine::open_with_codec(&path, options, CompactWith(ProstCodec)).unwrap();
+        let mut tree: BPlusTree<u32, Lazy<User>, _> = BPlusTree::open(engine).unwrap();
+        assert_eq!(tree.search(&7).unwrap().unwrap().decode().unwrap(), user(7));
+        assert_eq!(tree.get_with::<UserName>(&42).unwrap(), Some(UserName { name: "user-42".to_string() }));
+        assert_eq!(tree.get_with::<UserName>(&500).unwrap(), None);
+        tree.insert(500, Lazy::new(&user(500))).unwrap();
+        assert_eq!(tree.get_with::<User>(&500).unwrap(), Some(user(500)));
+        tree.flush().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,136 @@
+use anyhow::{Ok, Result};
+use std::{
+    cmp::Ordering,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{
+    block::BlockEngine,
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 靠内部结点里每个子树的条目数 (counts), 两个操作都只走一条 root 到叶子的路径
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 小于 key 的条目数
+    pub fn rank(&self, key: &K) -> Result<usize> {
+        self.count_before(key, false)
+    }
+
+    // range 里的条目数, 只走两端的两条路径, 不读中间的叶子
+    pub fn count_range<R>(&self, range: R) -> Result<usize>
+    where
+        R: RangeBounds<K>,
+    {
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.count_before(key, false)?,
+            Bound::Excluded(key) => self.count_before(key, true)?,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.count_before(key, true)?,
+            Bound::Excluded(key) => self.count_before(key, false)?,
+            Bound::Unbounded => self.len,
+        };
+        Ok(end.saturating_sub(start))
+    }
+
+    // 小于 (inclusive 时小于等于) key 的条目数
+    fn count_before(&self, key: &K, inclusive: bool) -> Result<usize> {
+        let mut block_id = self.root;
+        let mut rank = 0;
+        loop {
+            let read = self.engine.fetch_read(block_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                return Ok(rank + node.keys.partition_point(|k| match self.order.cmp(k, key) {
+                    Ordering::Less => true,
+                    Ordering::Equal => inclusive,
+                    Ordering::Greater => false,
+                }));
+            }
+            let pos = node.child_index(key, &self.order);
+            rank += node.counts[..pos].iter().sum::<usize>();
+            block_id = node.pointers[pos];
+        }
+    }
+
+    // 第 n 小的条目 (从 0 开始), n 超出范围时返回 None
+    pub fn select(&self, mut n: usize) -> Result<Option<(K, V)>> {
+        if n >= self.len {
+            return Ok(None);
+        }
+        let mut block_id = self.root;
+        loop {
+            let read = self.engine.fetch_read(block_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                return Ok(node.keys.get(n).map(|key| (key.clone(), node.values[n].clone())));
+            }
+            let mut pos = 0;
+            while pos + 1 < node.counts.len() && n >= node.counts[pos] {
+                n -= node.counts[pos];
+                pos += 1;
+            }
+            block_id = node.pointers[pos];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{block::MemoryBlockEngine, build::RebuildOptions};
+
+    use super::*;
+
+    #[test]
+    fn test_rank_select() {
+        for way in [2, 3, 5] {
+            let mut tree = BPlusTree::new(way, MemoryBlockEngine::new()).unwrap();
+            for i in 0..200 {
+                tree.insert(i * 2, i).unwrap();
+            }
+            for i in (0..200).step_by(3) {
+                tree.delete(&(i * 2)).unwrap();
+            }
+            tree.freeze();
+            tree.remove_range(100..150).unwrap();
+            let keys: Vec<_> = tree.keys().collect();
+            for (n, key) in keys.iter().enumerate() {
+                assert_eq!(tree.rank(key).unwrap(), n);
+                assert_eq!(tree.rank(&(key + 1)).unwrap(), n + 1);
+                assert_eq!(tree.select(n).unwrap().map(|(k, _)| k), Some(*key));
+            }
+            assert_eq!(tree.select(keys.len()).unwrap(), None);
+
+            tree.rebuild(RebuildOptions { way, fill_factor: 0.7 }).unwrap();
+            assert_eq!(tree.select(keys.len() / 2).unwrap().map(|(k, _)| k), Some(keys[keys.len() / 2]));
+            assert_eq!(tree.rank(&1000).unwrap(), keys.len());
+        }
+    }
+
+    #[test]
+    fn test_count_range() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        let mut expected = std::collections::BTreeSet::new();
+        for i in 0..300 {
+            let key = (i * 37) % 500;
+            tree.insert(key, i).unwrap();
+            expected.insert(key);
+        }
+        for (start, end) in [(0, 500), (10, 20), (123, 321), (499, 499), (300, 100)] {
+            assert_eq!(tree.count_range(start..end).unwrap(), expected.range(start..end.max(start)).count());
+            assert_eq!(tree.count_range(start..=end).unwrap(), expected.range(start..=end.max(start)).count());
+            assert_eq!(
+                tree.count_range((Bound::Excluded(start), Bound::Unbounded)).unwrap(),
+                expected.range((Bound::Excluded(start), Bound::Unbounded)).count()
+            );
+        }
+        assert_eq!(tree.count_range(..).unwrap(), 300);
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+// 令牌桶, 允许透支: 透支的部分由调用方睡够时间补上, 后来的请求排在后面
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Bucket {
+        let rate = rate.max(1) as f64;
+        // 最多攒 100ms 的量, 避免空闲之后一下子放出一大波
+        let capacity = (rate / 10.0).max(1.0);
+        Bucket { rate, capacity, tokens: capacity, last: Instant::now() }
+    }
+
+    // 扣掉 n 个 token, 返回需要等待的时间
+    fn take(&mut self, n: u64) -> Duration {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.capacity);
+        self.last = now;
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+// 给后台任务 (scrub 等) 限速, 可以放在 Arc 里让多个任务共享同一个额度
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes: Option<Mutex<Bucket>>,
+    ops: Option<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    // None 表示这一项不限
+    pub fn new(bytes_per_sec: Option<u64>, ops_per_sec: Option<u64>) -> RateLimiter {
+        RateLimiter {
+            bytes: bytes_per_sec.map(|rate| Mutex::new(Bucket::new(rate))),
+            ops: ops_per_sec.map(|rate| Mutex::new(Bucket::new(rate))),
+        }
+    }
+
+    // 不会阻塞, 返回按当前额度需要等待的时间
+    pub fn reserve(&self, ops: u64, bytes: u64) -> Duration {
+        let take = |bucket: &Option<Mutex<Bucket>>, n: u64| match bucket {
+            Some(bucket) if n > 0 => bucket.lock().unwrap_or_else(|e| e.into_inner()).take(n),
+            _ => Duration::ZERO,
+        };
+        take(&self.ops, ops).max(take(&self.bytes, bytes))
+    }
+
+    // 阻塞直到额度够做 ops 次共 bytes 字节的 I/O
+    pub fn acquire(&self, ops: u64, bytes: u64) {
+        let wait = self.reserve(ops, bytes);
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(None, Some(100));
+        let start = Instant::now();
+        // 先用掉 10 个攒着的, 剩下 20 个要 200ms
+        for _ in 0..3 {
+            limiter.acquire(10, 1 << 20);
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+        let unlimited = RateLimiter::new(None, None);
+        assert_eq!(unlimited.reserve(1 << 30, 1 << 30), Duration::ZERO);
+        let bytes = RateLimiter::new(Some(1000), None);
+        assert!(bytes.reserve(0, 1100) >= Duration::from_millis(900));
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::{Ok, Result};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{
+    block::{BlockEngine, BlockId},
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode, Version},
+};
+
+// 冻结了的 block 的引用计数: 指向它的结点, 当前 root 和拿着它当 root 的版本 (snapshot, 历史版本, 提交的版本) 各算一个
+// 只记大于 1 的, 没记的是 1; 当前 root 独占的 block (owned) 不记, 它们正好被引用一次
+// 冻结了的 block 不会再被修改, 所以结点里的指针算的引用一直有效, 只有复制和回收结点时要改计数
+// 只在内存里, 重新打开时按当前 root 和保存下来的 snapshot 重新数一遍, 见 recount
+// clone 出来的和原来的是同一份计数, split_off 切出来的树和原来的树共用结点, 也共用计数
+#[derive(Default, Clone)]
+pub(crate) struct RefCounts(Arc<Mutex<HashMap<BlockId, usize>>>);
+
+impl RefCounts {
+    // 每次只改一个计数, 中途 panic 也不会留下改了一半的状态
+    fn counts(&self) -> MutexGuard<'_, HashMap<BlockId, usize>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn get(&self, block_id: BlockId) -> usize {
+        self.counts().get(&block_id).copied().unwrap_or(1)
+    }
+
+    pub(crate) fn retain(&mut self, block_id: BlockId) {
+        *self.counts().entry(block_id).or_insert(1) += 1;
+    }
+
+    // 去掉一个引用, 返回剩下的个数
+    pub(crate) fn dec(&mut self, block_id: BlockId) -> usize {
+        let mut counts = self.counts();
+        let Some(count) = counts.get_mut(&block_id) else {
+            return 0;
+        };
+        *count -= 1;
+        let count = *count;
+        if count == 1 {
+            counts.remove(&block_id);
+        }
+        count
+    }
+
+    // 把另一棵树的计数并进来, 两棵树的结点不相交; 本来就是同一份计数时什么都不做
+    pub(crate) fn absorb(&mut self, other: &RefCounts) {
+        if !Arc::ptr_eq(&self.0, &other.0) {
+            let other = other.counts().clone();
+            self.counts().extend(other);
+        }
+    }
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 不再用 freeze, snapshot 或者 modify 返回的版本了, 只有它用到的 block 会被回收
+    // 同一个版本只能还一次, 还了之后不能再读
+    pub fn drop_version(&mut self, version: Version) -> Result<()> {
+        self.release(version.root)
+    }
+
+    // 去掉一个指向 block_id 的引用, 没有引用了就回收它, 再对它指向的 block 各去掉一个引用
+    pub(crate) fn release(&mut self, block_id: BlockId) -> Result<()> {
+        if !self.owned.remove(&block_id) && self.refs.dec(block_id) > 0 {
+            return Ok(());
+        }
+        let Some(node) = self.engine.delete(block_id)? else {
+            return Ok(());
+        };
+        for child in node.pointers {
+            self.release(child)?;
+        }
+        Ok(())
+    }
+
+    // 从当前 root 和所有 snapshot 的 root 往下走一遍, 每个结点只读一次, 按指向它的结点和 root 重新数引用
+    // 打开时用, 要读遍所有版本里的结点; 当前 root 不再独占任何 block, 写的时候才按计数认领
+    pub(crate) fn recount(&mut self) -> Result<()> {
+        let mut counts: HashMap<BlockId, usize> = HashMap::new();
+        let mut stack = vec![];
+        for root in std::iter::once(self.root).chain(self.snapshots.values().map(|version| version.root)) {
+            let count = counts.entry(root).or_insert(0);
+            *count += 1;
+            if *count == 1 {
+                stack.push(root);
+            }
+        }
+        while let Some(block_id) = stack.pop() {
+            let read = self.engine.fetch_read(block_id)?;
+            for &child in &read.as_ref().ok_or(Error::EmptyBlock(block_id))?.pointers {
+                let count = counts.entry(child).or_insert(0);
+                *count += 1;
+                if *count == 1 {
+                    stack.push(child);
+                }
+            }
+        }
+        counts.retain(|_, count| *count > 1);
+        self.refs = RefCounts(Arc::new(Mutex::new(counts)));
+        self.owned.clear();
+        self.persistent = true;
+        Ok(())
+    }
+
+    // 调用的人已经把 block_id 的内容 (包括指针) 搬走了, 指向它的那个引用也去掉了
+    // 没有别的引用时直接回收; 否则它还留着, 搬走的那份指针是新增的引用
+    pub(crate) fn free_node(&mut self, block_id: BlockId) -> Result<()> {
+        if !self.persistent || self.owned.remove(&block_id) || self.refs.dec(block_id) == 0 {
+            self.engine.delete(block_id)?;
+            return Ok(());
+        }
+        let pointers = {
+            let read = self.engine.fetch_read(block_id)?;
+            read.as_ref().ok_or(Error::EmptyBlock(block_id))?.pointers.clone()
+        };
+        for child in pointers {
+            self.refs.retain(child);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    fn root_refcount(tree: &BPlusTree<i32, i32, Arc<MemoryBlockEngine<BPlusTreeNode<i32, i32>>>>) -> usize {
+        if tree.owned.contains(&tree.root) {
+            1
+        } else {
+            tree.refs.get(tree.root)
+        }
+    }
+
+    #[test]
+    fn test_release_versions() {
+        // block 不够多, 旧版本独占的 block 不回收的话很快就会用完
+        let mut tree = BPlusTree::new(4, Arc::new(MemoryBlockEngine::with_capacity(200))).unwrap();
+        for i in 0..100 {
+            tree.insert(i, i).unwrap();
+        }
+        for round in 0..50 {
+            let version = tree.snapshot();
+            tree.create_snapshot("round").unwrap();
+            for i in 0..100 {
+                tree.insert(i, round).unwrap();
+            }
+            tree.delete(&round).unwrap();
+            tree.insert(round, round).unwrap();
+            assert_eq!(tree.at(version).search(&10).unwrap(), Some(if round == 0 { 10 } else { round - 1 }));
+            assert_eq!(tree.open_snapshot("round").unwrap().iter().count(), 100);
+            tree.drop_version(version).unwrap();
+            tree.drop_snapshot("round").unwrap();
+            tree.verify().unwrap();
+        }
+
+        // 共享的子树只在最后一个引用去掉时回收
+        let version = tree.snapshot();
+        assert_eq!(root_refcount(&tree), 2);
+        let (first, second) = (tree.split_off(&50).unwrap(), tree.snapshot());
+        assert_eq!(first.len(), 50);
+        tree.clear().unwrap();
+        assert!((0..100).all(|i| tree.at(version).search(&i).unwrap().is_some()));
+        assert_eq!(tree.at(second).iter().count(), 50);
+        tree.drop_version(version).unwrap();
+        assert_eq!(tree.at(second).iter().count(), 50);
+        tree.drop_version(second).unwrap();
+        assert_eq!(root_refcount(&tree), 1);
+        // 切下来的树和旧版本共用结点, 旧版本都还掉之后它还在
+        assert_eq!(first.keys().collect::<Vec<_>>(), (50..100).collect::<Vec<_>>());
+        first.verify().unwrap();
+    }
+}
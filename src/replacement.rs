@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+
+// buffer pool 的淘汰策略, frame 用下标表示
+pub trait ReplacementPolicy: Send {
+    // frame 里换成了新读入的 block
+    fn on_load(&mut self, frame: usize);
+    // 命中了 frame 里的 block
+    fn on_hit(&mut self, frame: usize);
+    // frame 里的 block 被回收, frame 空了出来
+    fn on_remove(&mut self, frame: usize);
+    // 选一个 frame 淘汰, evictable 返回 false 的 frame 正在被借用, 不能选
+    fn victim(&mut self, evictable: &mut dyn FnMut(usize) -> bool) -> Option<usize>;
+}
+
+// 建 engine 时选用的淘汰策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Replacement {
+    #[default]
+    Lru,
+    Clock,
+    // 按倒数第 k 次访问的时间淘汰, 访问不到 k 次的先淘汰, 一次性的扫描不会把常用的 block 挤出去
+    LruK(usize),
+}
+
+impl Replacement {
+    pub fn build(self, frames: usize) -> Box<dyn ReplacementPolicy> {
+        match self {
+            Replacement::Lru => Box::new(LruK::new(frames, 1)),
+            Replacement::Clock => Box::new(Clock::new(frames)),
+            Replacement::LruK(k) => Box::new(LruK::new(frames, k.max(1))),
+        }
+    }
+}
+
+// 每个 frame 一个引用位, 指针转一圈, 引用位是 1 的清成 0 放过, 是 0 的淘汰
+pub struct Clock {
+    referenced: Vec<bool>,
+    hand: usize,
+}
+
+impl Clock {
+    pub fn new(frames: usize) -> Self {
+        Clock { referenced: vec![false; frames], hand: 0 }
+    }
+}
+
+impl ReplacementPolicy for Clock {
+    fn on_load(&mut self, frame: usize) {
+        self.referenced[frame] = true;
+    }
+
+    fn on_hit(&mut self, frame: usize) {
+        self.referenced[frame] = true;
+    }
+
+    fn on_remove(&mut self, frame: usize) {
+        self.referenced[frame] = false;
+    }
+
+    fn victim(&mut self, evictable: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        // 第一圈清掉所有引用位, 第二圈一定能找到, 除非全部被借用
+        for _ in 0..self.referenced.len() * 2 {
+            let frame = self.hand;
+            self.hand = (self.hand + 1) % self.referenced.len();
+            if self.referenced[frame] {
+                self.referenced[frame] = false;
+            } else if evictable(frame) {
+                return Some(frame);
+            }
+        }
+        None
+    }
+}
+
+// 记每个 frame 最近 k 次访问的时间, k = 1 就是普通的 LRU
+pub struct LruK {
+    k: usize,
+    history: Vec<VecDeque<u64>>,
+    tick: u64,
+}
+
+impl LruK {
+    pub fn new(frames: usize, k: usize) -> Self {
+        LruK { k, history: vec![VecDeque::new(); frames], tick: 0 }
+    }
+}
+
+impl ReplacementPolicy for LruK {
+    fn on_load(&mut self, frame: usize) {
+        self.history[frame].clear();
+        self.on_hit(frame);
+    }
+
+    fn on_hit(&mut self, frame: usize) {
+        self.tick += 1;
+        let history = &mut self.history[frame];
+        if history.len() == self.k {
+            history.pop_front();
+        }
+        history.push_back(self.tick);
+    }
+
+    fn on_remove(&mut self, frame: usize) {
+        self.history[frame].clear();
+    }
+
+    fn victim(&mut self, evictable: &mut dyn FnMut(usize) -> bool) -> Option<usize> {
+        // 空的 frame 最先, 然后是访问不到 k 次的 (按最近一次访问), 最后按倒数第 k 次访问
+        let mut frames: Vec<usize> = (0..self.history.len()).collect();
+        frames.sort_by_key(|&frame| {
+            let history = &self.history[frame];
+            match (history.front(), history.back()) {
+                (Some(&kth), _) if history.len() == self.k => (2, kth),
+                (_, Some(&last)) => (1, last),
+                _ => (0, 0),
+            }
+        });
+        frames.into_iter().find(|&frame| evictable(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 依次访问 accesses 里的 frame, 返回淘汰的那个
+    fn run(mut policy: Box<dyn ReplacementPolicy>, accesses: &[usize]) -> Option<usize> {
+        let mut loaded = [false; 4];
+        for &frame in accesses {
+            if std::mem::replace(&mut loaded[frame], true) {
+                policy.on_hit(frame);
+            } else {
+                policy.on_load(frame);
+            }
+        }
+        policy.victim(&mut |frame| frame != 3)
+    }
+
+    #[test]
+    fn test_policies() {
+        let accesses = [0, 0, 1, 2, 3, 1, 2];
+        assert_eq!(run(Replacement::Lru.build(4), &accesses), Some(0));
+        // 只有 3 访问不到两次, 但 3 被借用着, 就在其余的里面按倒数第二次访问淘汰
+        assert_eq!(run(Replacement::LruK(2).build(4), &accesses), Some(0));
+        // 0 1 反复访问之后扫过 2 3, LRU 淘汰 0, LRU-2 淘汰只访问过一次的 2
+        let scan = [0, 1, 0, 1, 2, 3];
+        assert_eq!(run(Replacement::Lru.build(4), &scan), Some(0));
+        assert_eq!(run(Replacement::LruK(2).build(4), &scan), Some(2));
+        // 指针从 0 开始, 第一圈清掉引用位, 第二圈淘汰 0
+        assert_eq!(run(Replacement::Clock.build(4), &accesses), Some(0));
+        let mut clock = Clock::new(4);
+        clock.on_load(0);
+        clock.on_load(1);
+        assert_eq!(clock.victim(&mut |_| true), Some(2));
+        assert!(clock.victim(&mut |_| false).is_none());
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    ops::Bound,
+    thread,
+};
+
+use crate::{
+    block::BlockEngine,
+    latch::Latch,
+    server::{poisoned, KvTree, Server, MAX_FRAME},
+    tree::BPlusTreeNode,
+};
+
+// Redis 协议 (RESP2) 的前端, 支持的命令:
+//   PING, GET, SET, DEL, EXISTS, SCAN cursor [MATCH pattern] [COUNT n],
+//   ZRANGEBYLEX key min max [LIMIT offset count]
+// 只有一个 key 空间, ZRANGEBYLEX 的 key 参数会被忽略, 返回范围内的 key
+// SCAN 的 cursor 是上次返回的最后一个 key, 下次从它后面接着扫, 中间删掉的 key 不会让别的 key 被跳过或者重复
+// 一行最长 MAX_LINE, 一个命令最多 MAX_ARGS 个参数, 每个参数最长 MAX_FRAME, 所有参数加起来也最多 MAX_FRAME
+// 超出的话回一个错误然后断开
+const MAX_LINE: usize = 64 << 10;
+const MAX_ARGS: usize = 1 << 20;
+const MAX_COMMAND: usize = MAX_FRAME;
+
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn write(&self, w: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(w, "+{}\r\n", s),
+            Reply::Error(e) => write!(w, "-ERR {}\r\n", e),
+            Reply::Integer(n) => write!(w, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(w, "$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(w, "${}\r\n", bytes.len())?;
+                w.write_all(bytes)?;
+                w.write_all(b"\r\n")
+            }
+            Reply::Array(items) => {
+                write!(w, "*{}\r\n", items.len())?;
+                items.iter().try_for_each(|item| item.write(w))
+            }
+        }
+    }
+}
+
+impl<E> Server<E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<Vec<u8>, Vec<u8>>> + Send + Sync + 'static,
+{
+    pub fn serve_resp(&self, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let tree = self.tree();
+            thread::spawn(move || {
+                let _ = handle_connection(&tree, stream);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection<E>(tree: &Latch<KvTree<E>>, stream: TcpStream) -> io::Result<()>
+where
+    E: BlockEngine<Item = BPlusTreeNode<Vec<u8>, Vec<u8>>>,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let command = match read_command(&mut reader) {
+            Ok(Some(command)) => command,
+            Ok(None) => break,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Reply::Error(format!("Protocol error: {}", e)).write(&mut writer)?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+        if command.is_empty() {
+            continue;
+        }
+        let reply = execute(tree, &command).unwrap_or_else(|e| Reply::Error(e.to_string()));
+        reply.write(&mut writer)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    if reader.by_ref().take(MAX_LINE as u64).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.len() == MAX_LINE && line.last() != Some(&b'\n') {
+        return Err(invalid("line too long"));
+    }
+    while line.last().is_some_and(|&b| b == b'\n' || b == b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn parse_len(bytes: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+// 客户端发的是 bulk string 数组, 也兼容 telnet 式的 inline 命令
+fn read_command(reader: &mut impl BufRead) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    if line.first() != Some(&b'*') {
+        return Ok(Some(line.split(|b| b.is_ascii_whitespace()).filter(|s| !s.is_empty()).map(|s| s.to_vec()).collect()));
+    }
+    let count = parse_len(&line[1..])?;
+    if count > MAX_ARGS as i64 {
+        return Err(invalid("invalid multibulk length"));
+    }
+    let mut args = vec![];
+    // 读每个参数之前先检查, 不会为超出的命令分配内存
+    let mut total = 0;
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or_else(|| invalid("unexpected eof"))?;
+        if header.first() != Some(&b'$') {
+            return Err(invalid("expected bulk string"));
+        }
+        let len = usize::try_from(parse_len(&header[1..])?)
+            .ok()
+            .filter(|&len| len <= MAX_FRAME)
+            .ok_or_else(|| invalid("invalid bulk length"))?;
+        total += len;
+        if total > MAX_COMMAND {
+            return Err(invalid("command too large"));
+        }
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn execute<E>(tree: &Latch<KvTree<E>>, command: &[Vec<u8>]) -> anyhow::Result<Reply>
+where
+    E: BlockEngine<Item = BPlusTreeNode<Vec<u8>, Vec<u8>>>,
+{
+    let name = String::from_utf8_lossy(&command[0]).to_ascii_uppercase();
+    let args = &command[1..];
+    let arity = |n: usize| {
+        if args.len() < n {
+            Err(anyhow::anyhow!("wrong number of arguments for '{}' command", name.to_lowercase()))
+        } else {
+            Ok(())
+        }
+    };
+    let reply = match name.as_str() {
+        "PING" => match args.first() {
+            Some(msg) => Reply::Bulk(Some(msg.clone())),
+            None => Reply::Simple("PONG"),
+        },
+        "COMMAND" => Reply::Array(vec![]),
+        "GET" => {
+            arity(1)?;
+            Reply::Bulk(tree.read().map_err(poisoned)?.search(&args[0])?)
+        }
+        "SET" => {
+            arity(2)?;
+            tree.write().map_err(poisoned)?.insert(args[0].clone(), args[1].clone())?;
+            Reply::Simple("OK")
+        }
+        "DEL" => {
+            arity(1)?;
+            let mut tree = tree.write().map_err(poisoned)?;
+            let mut deleted = 0;
+            for key in args {
+                deleted += i64::from(tree.delete(key)?.is_some());
+            }
+            Reply::Integer(deleted)
+        }
+        "EXISTS" => {
+            arity(1)?;
+            let tree = tree.read().map_err(poisoned)?;
+            let mut found = 0;
+            for key in args {
+                found += i64::from(tree.contains_key(key)?);
+            }
+            Reply::Integer(found)
+        }
+        "SCAN" => {
+            arity(1)?;
+            let start = match decode_cursor(&args[0])? {
+                Some(last) => Bound::Excluded(last),
+                None => Bound::Unbounded,
+            };
+            let mut pattern = None;
+            let mut count = 10;
+            for option in args[1..].chunks(2) {
+                let [name, value] = option else {
+                    return Err(anyhow::anyhow!("syntax error"));
+                };
+                match String::from_utf8_lossy(name).to_ascii_uppercase().as_str() {
+                    "MATCH" => pattern = Some(value.clone()),
+                    "COUNT" => count = String::from_utf8_lossy(value).parse::<usize>()?.max(1),
+                    _ => return Err(anyhow::anyhow!("syntax error")),
+                }
+            }
+            let tree = tree.read().map_err(poisoned)?;
+            let scanned = tree
+                .try_range((start, Bound::Unbounded))
+                .take(count)
+                .map(|entry| entry.map(|(key, _)| key))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let next = match scanned.last() {
+                Some(last) if scanned.len() == count => encode_cursor(last),
+                _ => b"0".to_vec(),
+            };
+            let keys = scanned
+                .into_iter()
+                .filter(|key| pattern.as_ref().is_none_or(|p| glob_match(p, key)))
+                .map(|key| Reply::Bulk(Some(key)))
+                .collect();
+            Reply::Array(vec![Reply::Bulk(Some(next)), Reply::Array(keys)])
+        }
+        "ZRANGEBYLEX" => {
+            arity(3)?;
+            let (min, max) = (lex_bound(&args[1], true)?, lex_bound(&args[2], false)?);
+            // 和 Redis 一样, offset 是负数时返回空的, count 是负数时返回 offset 之后所有的
+            let (offset, limit) = match &args[3..] {
+                [] => (0, usize::MAX),
+                [limit, offset, count] if limit.eq_ignore_ascii_case(b"LIMIT") => {
+                    let offset: i64 = String::from_utf8_lossy(offset).parse()?;
+                    let count: i64 = String::from_utf8_lossy(count).parse()?;
+                    (offset, usize::try_from(count).unwrap_or(usize::MAX))
+                }
+                _ => return Err(anyhow::anyhow!("syntax error")),
+            };
+            let (Some(min), Some(max), Result::Ok(offset)) = (min, max, usize::try_from(offset)) else {
+                return Ok(Reply::Array(vec![]));
+            };
+            let tree = tree.read().map_err(poisoned)?;
+            let keys = tree
+                .try_range((min, max))
+                .skip(offset)
+                .take(limit)
+                .map(|entry| entry.map(|(key, _)| Reply::Bulk(Some(key))))
+                .collect::<anyhow::Result<_>>()?;
+            Reply::Array(keys)
+        }
+        _ => return Err(anyhow::anyhow!("unknown command '{}'", name.to_lowercase())),
+    };
+    Ok(reply)
+}
+
+// "[x" 包含, "(x" 不包含; min 是 "-" 或者 max 是 "+" 时不限
+// min 是 "+" 或者 max 是 "-" 时范围一定是空的, 返回 None
+fn lex_bound(arg: &[u8], is_min: bool) -> anyhow::Result<Option<Bound<Vec<u8>>>> {
+    match (arg.split_first(), is_min) {
+        (Some((b'-', [])), true) | (Some((b'+', [])), false) => Ok(Some(Bound::Unbounded)),
+        (Some((b'+', [])), true) | (Some((b'-', [])), false) => Ok(None),
+        (Some((b'[', rest)), _) => Ok(Some(Bound::Included(rest.to_vec()))),
+        (Some((b'(', rest)), _) => Ok(Some(Bound::Excluded(rest.to_vec()))),
+        _ => Err(anyhow::anyhow!("min or max not valid string range item")),
+    }
+}
+
+// 客户端大多把 cursor 当成整数解析, 所以 key 的每个字节写成三位十进制数, 前面加一个 1; "0" 是开头也是结尾
+fn encode_cursor(key: &[u8]) -> Vec<u8> {
+    let mut cursor = b"1".to_vec();
+    for byte in key {
+        cursor.extend_from_slice(format!("{:03}", byte).as_bytes());
+    }
+    cursor
+}
+
+fn decode_cursor(cursor: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let invalid = || anyhow::anyhow!("invalid cursor");
+    match cursor.split_first() {
+        Some((b'0', [])) => Ok(None),
+        Some((b'1', digits)) if digits.len() % 3 == 0 => digits
+            .chunks(3)
+            .map(|digits| std::str::from_utf8(digits).ok().and_then(|digits| digits.parse().ok()).ok_or_else(invalid))
+            .collect::<anyhow::Result<_>>()
+            .map(Some),
+        _ => Err(invalid()),
+    }
+}
+
+// 只支持 * 和 ?; 对不上时只退回到最近的一个 *, 让它多吃一个字节, 最多 O(len(pattern) * len(key))
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // 最近的一个 * 的位置, 和它之后的部分从 key 的哪里开始对
+    let mut star = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, k));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match star {
+                Some((star_p, star_k)) => {
+                    star = Some((star_p, star_k + 1));
+                    p = star_p + 1;
+                    k = star_k + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{block::MemoryBlockEngine, tree::BPlusTree};
+
+    use super::*;
+
+    fn call(stream: &mut BufReader<TcpStream>, args: &[&str]) -> String {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request += &format!("${}\r\n{}\r\n", arg.len(), arg);
+        }
+        stream.get_mut().write_all(request.as_bytes()).unwrap();
+        // 测试里的回复都足够短, 读到没有更多数据为止
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            reply += &line;
+            if stream.buffer().is_empty() {
+                break;
+            }
+        }
+        reply
+    }
+
+    #[test]
+    fn test_resp() {
+        let server = Server::new(BPlusTree::new(4, MemoryBlockEngine::new()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve_resp(listener));
+        let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+
+        assert_eq!(call(&mut stream, &["PING"]), "+PONG\r\n");
+        assert_eq!(call(&mut stream, &["SET", "a", "1"]), "+OK\r\n");
+        assert_eq!(call(&mut stream, &["SET", "b", "2"]), "+OK\r\n");
+        assert_eq!(call(&mut stream, &["SET", "c", "3"]), "+OK\r\n");
+        assert_eq!(call(&mut stream, &["GET", "b"]), "$1\r\n2\r\n");
+        assert_eq!(call(&mut stream, &["GET", "z"]), "$-1\r\n");
+        assert_eq!(call(&mut stream, &["EXISTS", "a", "z"]), ":1\r\n");
+        assert_eq!(call(&mut stream, &["ZRANGEBYLEX", "k", "(a", "+"]), "*2\r\n$1\r\nb\r\n$1\r\nc\r\n");
+        assert_eq!(call(&mut stream, &["ZRANGEBYLEX", "k", "-", "+", "LIMIT", "1", "1"]), "*1\r\n$1\r\nb\r\n");
+        // min 是 + 或者 max 是 -, offset 是负数时都是空的
+        assert_eq!(call(&mut stream, &["ZRANGEBYLEX", "k", "+", "+"]), "*0\r\n");
+        assert_eq!(call(&mut stream, &["ZRANGEBYLEX", "k", "-", "-"]), "*0\r\n");
+        assert_eq!(call(&mut stream, &["ZRANGEBYLEX", "k", "[c", "[a"]), "*0\r\n");
+        assert_eq!(call(&mut stream, &["ZRANGEBYLEX", "k", "-", "+", "LIMIT", "-1", "1"]), "*0\r\n");
+        assert_eq!(call(&mut stream, &["ZRANGEBYLEX", "k", "-", "+", "LIMIT", "1", "-1"]), "*2\r\n$1\r\nb\r\n$1\r\nc\r\n");
+        // cursor 是 "b" 编码出来的
+        assert_eq!(call(&mut stream, &["SCAN", "0", "COUNT", "2"]), "*2\r\n$4\r\n1098\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(call(&mut stream, &["SCAN", "1098", "MATCH", "?"]), "*2\r\n$1\r\n0\r\n*1\r\n$1\r\nc\r\n");
+        assert!(call(&mut stream, &["SCAN", "2"]).starts_with("-ERR invalid cursor"));
+        // 两次 SCAN 之间删掉已经扫过的 key, 后面的 key 一个都不少
+        assert_eq!(call(&mut stream, &["SET", "d", "4"]), "+OK\r\n");
+        assert_eq!(call(&mut stream, &["SCAN", "0", "COUNT", "2"]), "*2\r\n$4\r\n1098\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(call(&mut stream, &["DEL", "a"]), ":1\r\n");
+        assert_eq!(call(&mut stream, &["SCAN", "1098", "COUNT", "2"]), "*2\r\n$4\r\n1100\r\n*2\r\n$1\r\nc\r\n$1\r\nd\r\n");
+        assert_eq!(call(&mut stream, &["SCAN", "1100", "COUNT", "2"]), "*2\r\n$1\r\n0\r\n*0\r\n");
+        assert_eq!(call(&mut stream, &["SET", "a", "1"]), "+OK\r\n");
+        assert_eq!(call(&mut stream, &["DEL", "a", "b", "z"]), ":2\r\n");
+        assert!(call(&mut stream, &["NOPE"]).starts_with("-ERR unknown command"));
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let server = Server::new(BPlusTree::new(4, MemoryBlockEngine::new()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve_resp(listener));
+
+        for header in ["*1\r\n$99999999999999999\r\n", "*1\r\n$-1\r\n", "*99999999999999999\r\n"] {
+            let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+            stream.get_mut().write_all(header.as_bytes()).unwrap();
+            let mut reply = String::new();
+            stream.read_line(&mut reply).unwrap();
+            assert!(reply.starts_with("-ERR Protocol error"));
+        }
+        let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+        stream.get_mut().write_all(&vec![b'x'; MAX_LINE]).unwrap();
+        let mut reply = String::new();
+        stream.read_line(&mut reply).unwrap();
+        assert!(reply.starts_with("-ERR Protocol error"));
+
+        // 每个参数都没超过 MAX_FRAME, 加起来超过了
+        let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+        let mut request = format!("*2\r\n${}\r\n", MAX_FRAME).into_bytes();
+        request.resize(request.len() + MAX_FRAME, b'v');
+        request.extend_from_slice(b"\r\n$1\r\n");
+        stream.get_mut().write_all(&request).unwrap();
+        let mut reply = String::new();
+        stream.read_line(&mut reply).unwrap();
+        assert_eq!(reply, "-ERR Protocol error: command too large\r\n");
+
+        let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+        assert_eq!(call(&mut stream, &["PING"]), "+PONG\r\n");
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(glob_match(b"a?c", b"abc"));
+        assert!(!glob_match(b"a?c", b"ac"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*a*b", b"xaybzb"));
+        assert!(!glob_match(b"*a*b", b"xaybzc"));
+        assert!(glob_match(b"a**", b"a"));
+        // 回溯是指数级的话这里要算很久
+        let key = vec![b'a'; 200];
+        assert!(!glob_match(&b"*a".repeat(30).into_iter().chain(*b"*b").collect::<Vec<_>>(), &key));
+        assert_eq!(decode_cursor(&encode_cursor(&[0, 7, 255])).unwrap(), Some(vec![0, 7, 255]));
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::Result;
+use std::{future::Future, thread};
+
+use crate::aio;
+
+// 异步的接口对 runtime 只要求一件事: 把会阻塞的文件读写交给别的线程, 拿到一个等它结果的 future
+// 这样不绑死在某一个 runtime 上, 用哪个由类型参数决定
+// f panic 时 future 返回错误, 不会一直挂着
+pub trait Runtime: 'static {
+    fn unblock<T, F>(f: F) -> impl Future<Output = Result<T>> + Send + 'static
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static;
+}
+
+// 不依赖任何 runtime, 每个操作起一个线程, 哪个 executor 都能 poll
+pub struct Threads;
+
+impl Runtime for Threads {
+    fn unblock<T, F>(f: F) -> impl Future<Output = Result<T>> + Send + 'static
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (mut done, reply) = aio::reply();
+        thread::spawn(move || done.complete(Ok(f())));
+        reply
+    }
+}
+
+// tokio 的阻塞线程池, future 要在 tokio runtime 里 poll
+#[cfg(feature = "tokio")]
+pub struct Tokio;
+
+#[cfg(feature = "tokio")]
+impl Runtime for Tokio {
+    async fn unblock<T, F>(f: F) -> Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        tokio::task::spawn_blocking(f).await.map_err(|e| anyhow::anyhow!("blocking task failed: {}.", e))
+    }
+}
+
+// smol 和 async-std 共用的 blocking 线程池, 不要求在哪个 runtime 里 poll
+#[cfg(feature = "smol")]
+pub struct Smol;
+
+#[cfg(feature = "smol")]
+impl Runtime for Smol {
+    fn unblock<T, F>(f: F) -> impl Future<Output = Result<T>> + Send + 'static
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // blocking 在 await 的地方重新抛出 f 的 panic, 先在线程里接住
+        let task = blocking::unblock(move || std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)));
+        async move { task.await.map_err(|_| anyhow::anyhow!("blocking task panicked.")) }
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    block::{BlockEngine, BlockId},
+    ratelimit::RateLimiter,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// scrub 发现的问题
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScrubProblem {
+    // block 读不出来或者是空的
+    Unreadable { block_id: BlockId, error: String },
+    // 结点内 key 不是严格递增的
+    UnsortedKeys { block_id: BlockId },
+    // key 不在父结点分隔 key 划定的范围内
+    OutOfRange { block_id: BlockId },
+    // keys / values / pointers 数量对不上
+    BadShape { block_id: BlockId },
+    // 叶子的 next 指向的叶子的 prev 没有指回来
+    BrokenLink { block_id: BlockId, next: BlockId },
+}
+
+#[derive(Debug, Clone)]
+pub struct ScrubOptions {
+    // 每秒最多检查多少个 block
+    pub blocks_per_sec: u32,
+    // 和其他后台任务共享的限速, 每检查一个 block 算一次 I/O
+    pub limiter: Option<Arc<RateLimiter>>,
+}
+
+#[derive(Debug, Default)]
+pub struct ScrubStats {
+    pub passes: AtomicU64,
+    pub blocks: AtomicU64,
+    pub problems: AtomicU64,
+    // 树被修改导致重新开始的次数
+    pub restarts: AtomicU64,
+}
+
+// 后台线程, 按限定的速率不停地从 root 开始检查整棵树
+// 树被修改之后当前这一遍作废, 从头再来
+pub struct Scrubber {
+    stop: Arc<AtomicBool>,
+    stats: Arc<ScrubStats>,
+    handle: JoinHandle<()>,
+}
+
+impl Scrubber {
+    pub fn spawn<K, V, E, F>(tree: Arc<RwLock<BPlusTree<K, V, E>>>, options: ScrubOptions, mut on_problem: F) -> Scrubber
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>> + Send + Sync + 'static,
+        K: Ord + Clone + Send + Sync + 'static,
+        V: Clone + Send + Sync + 'static,
+        F: FnMut(ScrubProblem) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stats = Arc::new(ScrubStats::default());
+        // 一次拿锁检查一批, 大约 100ms 的量
+        let batch = (options.blocks_per_sec / 10).max(1) as usize;
+        let limiter = RateLimiter::new(None, Some(options.blocks_per_sec.into()));
+        let handle = {
+            let stop = stop.clone();
+            let stats = stats.clone();
+            thread::spawn(move || {
+                // (block id, 下界, 上界)
+                let mut stack: Vec<(BlockId, Option<K>, Option<K>)> = vec![];
+                let mut seq = None;
+                while !stop.load(Ordering::Relaxed) {
+                    limiter.acquire(batch as u64, 0);
+                    if let Some(shared) = &options.limiter {
+                        shared.acquire(batch as u64, 0);
+                    }
+                    {
+                        let Result::Ok(tree) = tree.read() else {
+                            return;
+                        };
+                        if seq != Some(tree.seq()) {
+                            if seq.is_some() {
+                                stats.restarts.fetch_add(1, Ordering::Relaxed);
+                            }
+                            seq = Some(tree.seq());
+                            stack = vec![(tree.root, None, None)];
+                        }
+                        for _ in 0..batch {
+                            let Some((block_id, lower, upper)) = stack.pop() else {
+                                break;
+                            };
+                            stats.blocks.fetch_add(1, Ordering::Relaxed);
+                            for problem in tree.scrub_block(block_id, lower.as_ref(), upper.as_ref(), &mut stack) {
+                                stats.problems.fetch_add(1, Ordering::Relaxed);
+                                on_problem(problem);
+                            }
+                        }
+                        if stack.is_empty() {
+                            stats.passes.fetch_add(1, Ordering::Relaxed);
+                            seq = None;
+                        }
+                    }
+                }
+            })
+        };
+        Scrubber { stop, stats, handle }
+    }
+
+    pub fn stats(&self) -> &ScrubStats {
+        &self.stats
+    }
+
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+    }
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 检查单个 block, 子结点连同它们的 key 范围压进 stack
+    fn scrub_block(
+        &self,
+        block_id: BlockId,
+        lower: Option<&K>,
+        upper: Option<&K>,
+        stack: &mut Vec<(BlockId, Option<K>, Option<K>)>,
+    ) -> Vec<ScrubProblem> {
+        let mut problems = vec![];
+        let read = match self.engine.fetch_read(block_id) {
+            Result::Ok(read) => read,
+            Err(e) => return vec![ScrubProblem::Unreadable { block_id, error: e.to_string() }],
+        };
+        let Some(node) = read.as_ref() else {
+            return vec![ScrubProblem::Unreadable { block_id, error: "empty block".to_string() }];
+        };
+
+        if node.keys.windows(2).any(|w| !self.order.lt(&w[0], &w[1])) {
+            problems.push(ScrubProblem::UnsortedKeys { block_id });
+        }
+        let in_range = |key: &K| {
+            lower.is_none_or(|lower| !self.order.lt(key, lower))
+                && upper.is_none_or(|upper| self.order.lt(key, upper))
+        };
+        if !node.keys.iter().all(in_range) {
+            problems.push(ScrubProblem::OutOfRange { block_id });
+        }
+
+        if node.is_leaf() {
+            if node.keys.len() != node.values.len() || !node.pointers.is_empty() || !node.counts.is_empty() {
+                problems.push(ScrubProblem::BadShape { block_id });
+            }
+            // 持久化模式下叶子链不可信, 不检查
+            if let (Some(next), false) = (node.next, self.persistent) {
+                let linked = self
+                    .engine
+                    .fetch_read(next)
+                    .is_ok_and(|read| read.as_ref().is_some_and(|next| next.prev == Some(block_id)));
+                if !linked {
+                    problems.push(ScrubProblem::BrokenLink { block_id, next });
+                }
+            }
+        } else if node.pointers.len() != node.keys.len() + 1
+            || node.counts.len() != node.pointers.len()
+            || node.maxes.len() != node.pointers.len()
+            || !node.values.is_empty()
+        {
+            problems.push(ScrubProblem::BadShape { block_id });
+        } else {
+            for (i, &child) in node.pointers.iter().enumerate() {
+                let lower = if i == 0 { lower.cloned() } else { Some(node.keys[i - 1].clone()) };
+                let upper = if i == node.keys.len() { upper.cloned() } else { Some(node.keys[i].clone()) };
+                stack.push((child, lower, upper));
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    fn wait_for(mut f: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !f() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_scrubber() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..100 {
+            tree.insert(i, i).unwrap();
+        }
+        let tree = Arc::new(RwLock::new(tree));
+        let problems = Arc::new(Mutex::new(vec![]));
+        let scrubber = {
+            let problems = problems.clone();
+            Scrubber::spawn(tree.clone(), ScrubOptions { blocks_per_sec: 10000, limiter: Some(Arc::new(RateLimiter::new(None, None))) }, move |p| {
+                problems.lock().unwrap().push(p)
+            })
+        };
+        wait_for(|| scrubber.stats().passes.load(Ordering::Relaxed) > 0);
+        assert!(problems.lock().unwrap().is_empty());
+
+        // 直接改坏一个叶子
+        let leaf = {
+            let tree = tree.write().unwrap();
+            let leaf = tree.find_leaf(tree.root, &50).unwrap();
+            tree.engine.fetch_write(leaf).unwrap().as_mut().unwrap().keys.reverse();
+            leaf
+        };
+        wait_for(|| !problems.lock().unwrap().is_empty());
+        scrubber.stop();
+        assert!(problems.lock().unwrap().contains(&ScrubProblem::UnsortedKeys { block_id: leaf }));
+    }
+}
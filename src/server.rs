@@ -0,0 +1,374 @@
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    ops::Bound,
+    sync::Arc,
+    thread,
+};
+
+use crate::{
+    block::BlockEngine,
+    latch::{Latch, LatchStats},
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 协议: 每个请求 / 响应都是一帧, 4 字节大端长度 + payload
+// 请求 payload: op (1 字节) + 参数, 每个参数都是 4 字节长度 + 内容
+//   GET key / PUT key value / DEL key / SCAN start end limit (start / end 为空表示不限, limit 为 0 表示不限)
+// 响应 payload: status (1 字节) + body
+//   GET 返回 value, DEL 返回被删掉的 value, SCAN 返回条目数 + 每条的 key / value + 1 字节 more
+//   SCAN 的响应不超过 MAX_FRAME, 放不下的条目不返回, more 为 1, 客户端从最后一个 key 之后接着 SCAN
+pub const OP_GET: u8 = 1;
+pub const OP_PUT: u8 = 2;
+pub const OP_DEL: u8 = 3;
+pub const OP_SCAN: u8 = 4;
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_NOT_FOUND: u8 = 1;
+pub const STATUS_ERROR: u8 = 2;
+
+pub(crate) const MAX_FRAME: usize = 64 << 20;
+
+pub type KvTree<E> = BPlusTree<Vec<u8>, Vec<u8>, E>;
+
+pub struct Server<E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<Vec<u8>, Vec<u8>>>,
+{
+    tree: Arc<Latch<KvTree<E>>>,
+}
+
+impl<E> Server<E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<Vec<u8>, Vec<u8>>> + Send + Sync + 'static,
+{
+    pub fn new(tree: KvTree<E>) -> Self {
+        Server { tree: Arc::new(Latch::new(tree)) }
+    }
+
+    pub fn tree(&self) -> Arc<Latch<KvTree<E>>> {
+        self.tree.clone()
+    }
+
+    // 整棵树一把锁, 都算在 root 上; 从创建或者上次 reset_latch_stats 起的
+    pub fn latch_stats(&self) -> LatchStats {
+        LatchStats { root: self.tree.stats(), ..LatchStats::default() }
+    }
+
+    pub fn reset_latch_stats(&self) {
+        self.tree.reset_stats();
+    }
+
+    // 每个连接一个线程, 读请求共享读锁, 写请求独占
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let tree = self.tree.clone();
+            thread::spawn(move || {
+                let _ = handle_connection(&tree, stream);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle_connection<E>(tree: &Latch<KvTree<E>>, stream: TcpStream) -> io::Result<()>
+where
+    E: BlockEngine<Item = BPlusTreeNode<Vec<u8>, Vec<u8>>>,
+{
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while let Some(request) = read_frame(&mut reader)? {
+        let response = match handle_request(tree, &request) {
+            Ok(response) => response,
+            Err(e) => {
+                let mut response = vec![STATUS_ERROR];
+                response.extend_from_slice(e.to_string().as_bytes());
+                response
+            }
+        };
+        write_frame(&mut writer, &response)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn handle_request<E>(tree: &Latch<KvTree<E>>, request: &[u8]) -> anyhow::Result<Vec<u8>>
+where
+    E: BlockEngine<Item = BPlusTreeNode<Vec<u8>, Vec<u8>>>,
+{
+    let mut args = Args { buf: request, pos: 0 };
+    let op = args.u8()?;
+    let mut response = vec![STATUS_OK];
+    match op {
+        OP_GET => {
+            let key = args.bytes()?;
+            match tree.read().map_err(poisoned)?.search(&key.to_vec())? {
+                Some(value) => response.extend_from_slice(&value),
+                None => response[0] = STATUS_NOT_FOUND,
+            }
+        }
+        OP_PUT => {
+            let key = args.bytes()?.to_vec();
+            let value = args.bytes()?.to_vec();
+            tree.write().map_err(poisoned)?.insert(key, value)?;
+        }
+        OP_DEL => {
+            let key = args.bytes()?;
+            match tree.write().map_err(poisoned)?.delete(&key.to_vec())? {
+                Some(value) => response.extend_from_slice(&value),
+                None => response[0] = STATUS_NOT_FOUND,
+            }
+        }
+        OP_SCAN => {
+            let start = args.bytes()?;
+            let end = args.bytes()?;
+            let limit = match args.u32()? {
+                0 => usize::MAX,
+                limit => limit as usize,
+            };
+            let bounds = (
+                if start.is_empty() { Bound::Unbounded } else { Bound::Included(start.to_vec()) },
+                if end.is_empty() { Bound::Unbounded } else { Bound::Excluded(end.to_vec()) },
+            );
+            let tree = tree.read().map_err(poisoned)?;
+            encode_scan(&mut response, tree.try_range(bounds).take(limit), MAX_FRAME)?;
+        }
+        op => return Err(anyhow::anyhow!("unknown op: {}.", op)),
+    }
+    Ok(response)
+}
+
+// 读结点出错时整个请求返回 STATUS_ERROR, 不会回一个少了条目的 STATUS_OK
+fn encode_scan(
+    response: &mut Vec<u8>,
+    entries: impl Iterator<Item = anyhow::Result<(Vec<u8>, Vec<u8>)>>,
+    max_len: usize,
+) -> anyhow::Result<()> {
+    let count_pos = response.len();
+    response.extend_from_slice(&[0; 4]);
+    let mut count = 0u32;
+    let mut more = false;
+    for entry in entries {
+        let (key, value) = entry?;
+        if response.len() + 8 + key.len() + value.len() + 1 > max_len {
+            if count == 0 {
+                return Err(anyhow::anyhow!("entry too large for a response."));
+            }
+            more = true;
+            break;
+        }
+        put_bytes(response, &key);
+        put_bytes(response, &value);
+        count += 1;
+    }
+    response[count_pos..count_pos + 4].copy_from_slice(&count.to_be_bytes());
+    response.push(more as u8);
+    Ok(())
+}
+
+pub(crate) fn poisoned<T>(_: T) -> anyhow::Error {
+    anyhow::anyhow!("tree lock poisoned.")
+}
+
+struct Args<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Args<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.buf.len() - self.pos < len {
+            return Err(anyhow::anyhow!("truncated request."));
+        }
+        self.pos += len;
+        Ok(&self.buf[self.pos - len..self.pos])
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+// 连接正常关闭时返回 None
+fn read_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+// 长度和 payload 一次写出去, 避免被 Nagle 拆成两个包
+fn write_frame(writer: &mut impl Write, frame: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(frame.len() + 4);
+    put_bytes(&mut buf, frame);
+    writer.write_all(&buf)
+}
+
+// 同步的简单客户端
+pub struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Client> {
+        Ok(Client { stream: TcpStream::connect(addr)? })
+    }
+
+    fn call(&mut self, request: &[u8]) -> anyhow::Result<(u8, Vec<u8>)> {
+        write_frame(&mut self.stream, request)?;
+        let response = read_frame(&mut self.stream)?.ok_or_else(|| anyhow::anyhow!("connection closed."))?;
+        let (&status, body) = response.split_first().ok_or_else(|| anyhow::anyhow!("empty response."))?;
+        if status == STATUS_ERROR {
+            return Err(anyhow::anyhow!("server error: {}", String::from_utf8_lossy(body)));
+        }
+        Ok((status, body.to_vec()))
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut request = vec![OP_GET];
+        put_bytes(&mut request, key);
+        let (status, body) = self.call(&request)?;
+        Ok((status == STATUS_OK).then_some(body))
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
+        let mut request = vec![OP_PUT];
+        put_bytes(&mut request, key);
+        put_bytes(&mut request, value);
+        self.call(&request).map(|_| ())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut request = vec![OP_DEL];
+        put_bytes(&mut request, key);
+        let (status, body) = self.call(&request)?;
+        Ok((status == STATUS_OK).then_some(body))
+    }
+
+    // 一次响应放不下时自动翻页, 返回的是完整的结果
+    pub fn scan(&mut self, start: &[u8], end: &[u8], limit: u32) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        let mut start = start.to_vec();
+        loop {
+            let mut request = vec![OP_SCAN];
+            put_bytes(&mut request, &start);
+            put_bytes(&mut request, end);
+            let remaining = if limit == 0 { 0 } else { limit - entries.len() as u32 };
+            request.extend_from_slice(&remaining.to_be_bytes());
+            let (_, body) = self.call(&request)?;
+            let mut args = Args { buf: &body, pos: 0 };
+            for _ in 0..args.u32()? {
+                entries.push((args.bytes()?.to_vec(), args.bytes()?.to_vec()));
+            }
+            if args.u8()? == 0 {
+                return Ok(entries);
+            }
+            // 比 last 大的最小 key 是 last 后面补一个 0
+            let (last, _) = entries.last().ok_or_else(|| anyhow::anyhow!("empty scan page."))?;
+            start = last.clone();
+            start.push(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::MemoryBlockEngine,
+        fault::{FaultOptions, FaultyBlockEngine},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_server() {
+        let server = Server::new(BPlusTree::new(4, MemoryBlockEngine::new()).unwrap());
+        let stats = server.tree();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve(listener));
+
+        let handles: Vec<_> = (0..4u8)
+            .map(|t| {
+                thread::spawn(move || {
+                    let mut client = Client::connect(addr).unwrap();
+                    for i in 0..50u8 {
+                        client.put(&[t, i], &[i]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let mut client = Client::connect(addr).unwrap();
+        assert_eq!(client.get(&[1, 7]).unwrap(), Some(vec![7]));
+        client.put(&[1, 7], b"x").unwrap();
+        assert_eq!(client.get(&[1, 7]).unwrap(), Some(b"x".to_vec()));
+        assert_eq!(client.delete(&[1, 7]).unwrap(), Some(b"x".to_vec()));
+        assert_eq!(client.get(&[1, 7]).unwrap(), None);
+
+        let entries = client.scan(&[2], &[3], 0).unwrap();
+        assert_eq!(entries.len(), 50);
+        assert_eq!(entries[0], (vec![2, 0], vec![0]));
+        assert_eq!(client.scan(&[], &[], 10).unwrap().len(), 10);
+        assert_eq!(client.scan(&[], &[], 0).unwrap().len(), 199);
+        // 每个请求拿一次锁
+        assert_eq!(stats.stats().acquisitions, 200 + 8);
+    }
+
+    #[test]
+    fn test_scan_errors_and_pages() {
+        let engine = FaultyBlockEngine::new(MemoryBlockEngine::new(), FaultOptions::default());
+        let server = Server::new(BPlusTree::new(3, engine).unwrap());
+        let tree = server.tree();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve(listener));
+
+        let mut client = Client::connect(addr).unwrap();
+        for i in 0..100u8 {
+            client.put(&[i], &[i]).unwrap();
+        }
+        let options = FaultOptions { read_error: 0.2, seed: 1, ..FaultOptions::default() };
+        tree.write().unwrap().engine.set_options(options);
+        assert!(client.scan(&[], &[], 0).unwrap_err().to_string().starts_with("server error"));
+        tree.write().unwrap().engine.set_options(FaultOptions::default());
+        assert_eq!(client.scan(&[], &[], 0).unwrap().len(), 100);
+
+        // 放不下的条目留给下一页
+        let entries = (0..10u8).map(|i| anyhow::Ok((vec![i], vec![0; 10])));
+        let mut response = vec![STATUS_OK];
+        encode_scan(&mut response, entries, 100).unwrap();
+        assert_eq!(response.len(), 1 + 4 + 4 * 19 + 1);
+        assert_eq!(response[1..5], 4u32.to_be_bytes());
+        assert_eq!(response.last(), Some(&1));
+        let entries = (0..1u8).map(|i| anyhow::Ok((vec![i], vec![0; 100])));
+        assert!(encode_scan(&mut vec![STATUS_OK], entries, 100).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use anyhow::{Ok, Result};
+use std::{
+    cmp::Ordering,
+    iter::Peekable,
+    ops::RangeBounds,
+};
+
+use crate::{
+    block::BlockEngine,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 只有 key 的集合, value 是 (), 叶子里的 Vec<()> 不占空间
+pub struct BPlusTreeSet<K, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, ()>>,
+    K: Ord,
+{
+    tree: BPlusTree<K, (), E>,
+}
+
+impl<K, E> BPlusTreeSet<K, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, ()>>,
+    K: Ord + Clone,
+{
+    pub fn new(way: usize, engine: E) -> Result<Self> {
+        Ok(BPlusTreeSet { tree: BPlusTree::new(way, engine)? })
+    }
+
+    pub fn tree(&self) -> &BPlusTree<K, (), E> {
+        &self.tree
+    }
+
+    // 已经存在时返回 false
+    pub fn insert(&mut self, key: K) -> Result<bool> {
+        Ok(self.tree.insert(key, ())?.is_none())
+    }
+
+    pub fn contains(&self, key: &K) -> Result<bool> {
+        self.tree.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<bool> {
+        Ok(self.tree.delete(key)?.is_some())
+    }
+
+    // 读结点出错时先返回一个 Err 再结束
+    pub fn range<R>(&self, range: R) -> impl Iterator<Item = Result<K>> + '_
+    where
+        R: RangeBounds<K>,
+    {
+        self.tree.try_range(range).map(|entry| entry.map(|(key, _)| key))
+    }
+
+    // 下面几个都是两边有序流的归并, 不会把整个集合读进内存, 任何一边出错都返回 Err 并结束
+    pub fn union<'a, F>(&'a self, other: &'a BPlusTreeSet<K, F>) -> impl Iterator<Item = Result<K>> + 'a
+    where
+        F: BlockEngine<Item = BPlusTreeNode<K, ()>>,
+    {
+        Merge::new(self.range(..), other.range(..), SetOp::Union)
+    }
+
+    pub fn intersection<'a, F>(&'a self, other: &'a BPlusTreeSet<K, F>) -> impl Iterator<Item = Result<K>> + 'a
+    where
+        F: BlockEngine<Item = BPlusTreeNode<K, ()>>,
+    {
+        Merge::new(self.range(..), other.range(..), SetOp::Intersection)
+    }
+
+    pub fn difference<'a, F>(&'a self, other: &'a BPlusTreeSet<K, F>) -> impl Iterator<Item = Result<K>> + 'a
+    where
+        F: BlockEngine<Item = BPlusTreeNode<K, ()>>,
+    {
+        Merge::new(self.range(..), other.range(..), SetOp::Difference)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SetOp {
+    Union,
+    Intersection,
+    Difference,
+}
+
+struct Merge<K, A: Iterator<Item = Result<K>>, B: Iterator<Item = Result<K>>> {
+    left: Peekable<A>,
+    right: Peekable<B>,
+    op: SetOp,
+    failed: bool,
+}
+
+impl<K, A, B> Merge<K, A, B>
+where
+    A: Iterator<Item = Result<K>>,
+    B: Iterator<Item = Result<K>>,
+{
+    fn new(left: A, right: B, op: SetOp) -> Self {
+        Merge { left: left.peekable(), right: right.peekable(), op, failed: false }
+    }
+}
+
+impl<K, A, B> Iterator for Merge<K, A, B>
+where
+    K: Ord,
+    A: Iterator<Item = Result<K>>,
+    B: Iterator<Item = Result<K>>,
+{
+    type Item = Result<K>;
+
+    fn next(&mut self) -> Option<Result<K>> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            if self.left.peek().is_some_and(Result::is_err) {
+                self.failed = true;
+                return self.left.next();
+            }
+            if self.right.peek().is_some_and(Result::is_err) {
+                self.failed = true;
+                return self.right.next();
+            }
+            // 走到这里两边 peek 到的都是 Ok
+            let order = match (self.left.peek(), self.right.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(left), Some(right)) => left.as_ref().ok().cmp(&right.as_ref().ok()),
+            };
+            match (order, self.op) {
+                (Ordering::Less, SetOp::Intersection) => {
+                    // 右边已经没了, 不会再有交集
+                    self.right.peek()?;
+                    self.left.next();
+                }
+                (Ordering::Less, _) => return self.left.next(),
+                (Ordering::Greater, SetOp::Union) => return self.right.next(),
+                (Ordering::Greater, _) => {
+                    // 左边已经没了, 交集和差集都不会再有结果
+                    self.left.peek()?;
+                    self.right.next();
+                }
+                (Ordering::Equal, SetOp::Difference) => {
+                    self.left.next();
+                    self.right.next();
+                }
+                (Ordering::Equal, _) => {
+                    self.right.next();
+                    return self.left.next();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        block::MemoryBlockEngine,
+        fault::{FaultOptions, FaultyBlockEngine},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_set_ops() {
+        let mut a = BPlusTreeSet::new(3, MemoryBlockEngine::new()).unwrap();
+        let mut b = BPlusTreeSet::new(4, MemoryBlockEngine::new()).unwrap();
+        for i in 0..30 {
+            assert!(a.insert(i * 2).unwrap());
+            assert!(b.insert(i * 3).unwrap());
+        }
+        assert!(!a.insert(0).unwrap());
+        assert!(a.contains(&10).unwrap() && !a.contains(&11).unwrap());
+        assert!(a.remove(&10).unwrap());
+        assert!(!a.remove(&10).unwrap());
+        assert_eq!(a.range(4..=12).collect::<Result<Vec<_>>>().unwrap(), vec![4, 6, 8, 12]);
+
+        let union: Vec<_> = a.union(&b).take(8).collect::<Result<_>>().unwrap();
+        assert_eq!(union, vec![0, 2, 3, 4, 6, 8, 9, 12]);
+        let intersection: Vec<_> = a.intersection(&b).collect::<Result<_>>().unwrap();
+        assert_eq!(intersection, vec![0, 6, 12, 18, 24, 30, 36, 42, 48, 54]);
+        let difference: Vec<_> = b.difference(&a).take(5).collect::<Result<_>>().unwrap();
+        assert_eq!(difference, vec![3, 9, 15, 21, 27]);
+    }
+
+    #[test]
+    fn test_set_ops_read_errors() {
+        let engine = FaultyBlockEngine::new(MemoryBlockEngine::new(), FaultOptions::default());
+        let mut a = BPlusTreeSet::new(3, engine).unwrap();
+        let mut b = BPlusTreeSet::new(4, MemoryBlockEngine::new()).unwrap();
+        for i in 0..100 {
+            a.insert(i * 2).unwrap();
+            b.insert(i * 3).unwrap();
+        }
+        a.tree.engine.set_options(FaultOptions { read_error: 0.2, seed: 5, ..FaultOptions::default() });
+        // 出错之后只返回一个 Err, 不会悄悄地少返回 key
+        for items in [
+            a.range(..).collect::<Vec<_>>(),
+            a.union(&b).collect(),
+            b.intersection(&a).collect(),
+            b.difference(&a).collect(),
+        ] {
+            let (last, ok) = items.split_last().unwrap();
+            assert!(last.is_err() && ok.iter().all(|item| item.is_ok()));
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Ok, Result};
+
+use crate::{
+    block::BlockEngine,
+    tree::{BPlusTree, BPlusTreeNode, Version},
+};
+
+pub(crate) struct Shadow {
+    // 上一次提交的版本, 存储上的 superblock 指着它, 拿着一个引用
+    committed: Version,
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 先 flush 一次作为第一个提交的版本, 之后和 freeze 之后一样, 修改都复制到新分配的 block 上再改
+    // 提交的版本在存储上一直是完整的: commit 时 flush 把 superblock 换到新的 root, rollback 时扔掉影子页
+    // 文件 engine 要开着 FileOptions::shadow, 两次 commit 之间崩溃的话打开时是上一次 commit 的样子
+    // 重新打开之后要再调用一次
+    pub fn enable_shadow_paging(&mut self) -> Result<()> {
+        if self.shadow.is_some() {
+            return Ok(());
+        }
+        self.flush()?;
+        let committed = self.freeze();
+        self.shadow = Some(Shadow { committed });
+        Ok(())
+    }
+
+    pub fn is_shadow_paging(&self) -> bool {
+        self.shadow.is_some()
+    }
+
+    // flush 成功之后才还上一个提交的版本的引用, 只有它用到的 block 被回收, snapshot 和历史版本还在用的留着
+    pub fn commit(&mut self) -> Result<()> {
+        let Some(shadow) = &self.shadow else {
+            return Err(anyhow!("shadow paging is not enabled."));
+        };
+        let prev = shadow.committed;
+        self.flush()?;
+        let committed = self.freeze();
+        self.shadow = Some(Shadow { committed });
+        self.release(prev.root)
+    }
+
+    // 扔掉上一次 commit 之后的修改, 回到提交的版本, 存储上什么都不用改
+    pub fn rollback(&mut self) -> Result<()> {
+        let Some(shadow) = &self.shadow else {
+            return Err(anyhow!("shadow paging is not enabled."));
+        };
+        let committed = shadow.committed;
+        self.refs.retain(committed.root);
+        let root = std::mem::replace(&mut self.root, committed.root);
+        self.len = committed.len;
+        self.release(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_rollback() {
+        let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        assert!(tree.commit().is_err());
+        for i in 0..200 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.enable_shadow_paging().unwrap();
+        for i in 0..100 {
+            tree.insert(i + 200, i).unwrap();
+            tree.delete(&i).unwrap();
+        }
+        tree.rollback().unwrap();
+        assert_eq!(tree.len(), 200);
+        assert!((0..200).all(|i| tree.search(&i).unwrap() == Some(i)));
+        assert_eq!(tree.search(&250).unwrap(), None);
+        tree.verify().unwrap();
+
+        tree.insert(1000, 0).unwrap();
+        tree.commit().unwrap();
+        tree.delete(&1000).unwrap();
+        tree.rollback().unwrap();
+        assert_eq!(tree.search(&1000).unwrap(), Some(0));
+        tree.verify().unwrap();
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_shadow_paging_crash() {
+        use std::collections::BTreeMap;
+
+        use crate::file::{FileBlockEngine, FileOptions};
+
+        let path = std::env::temp_dir().join(format!("bplus-tree-shadow-{}.db", std::process::id()));
+        // pool 很小, 没提交的影子页会被淘汰写进文件, 复用的也有上次提交时空闲链表里的页
+        let options = FileOptions { page_size: 512, pool_size: 4, shadow: true, ..FileOptions::default() };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap();
+        let mut tree = BPlusTree::new(8, engine).unwrap();
+        let mut expected = BTreeMap::new();
+        for i in 0..500 {
+            tree.insert(i, i).unwrap();
+            expected.insert(i, i);
+        }
+        tree.enable_shadow_paging().unwrap();
+        let mut sizes = vec![];
+        for round in 0..10 {
+            for i in 0..50 {
+                let key = (round * 37 + i * 7) % 600;
+                tree.insert(key, round).unwrap();
+                expected.insert(key, round);
+                tree.delete(&((key + 300) % 600)).unwrap();
+                expected.remove(&((key + 300) % 600));
+            }
+            tree.commit().unwrap();
+            sizes.push(std::fs::metadata(&path).unwrap().len());
+        }
+        // 旧版本的 block 回收之后会被复用, 文件不会一直变大
+        assert_eq!(sizes[9], sizes[5]);
+
+        // 没有 commit 就崩溃
+        for i in 0..300 {
+            tree.insert(i, 1000).unwrap();
+        }
+        drop(tree);
+
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        let tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.iter().collect::<BTreeMap<_, _>>(), expected);
+        std::fs::remove_file(&path).unwrap();
+
+        let options = FileOptions { wal: true, ..options };
+        assert!(FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::{anyhow, Ok, Result};
+use std::{
+    collections::VecDeque,
+    ops::{Bound, RangeBounds},
+    sync::RwLock,
+};
+
+use crate::{
+    block::BlockEngine,
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// 范围查询时每个 shard 一次最多读出来的条目数, 读完了再拿一次读锁接着读
+const CHUNK: usize = 256;
+
+// 把 key 空间分给几棵独立的树, 每棵各自一把读写锁, 写不同 shard 的线程互不阻塞
+// 按 hash 分时数据均匀, 范围查询要读所有 shard 再归并; 按范围分时范围查询只读相关的几个 shard
+// 每个 shard 用自己的 engine; 范围查询时 shard 是一段段读的, 整个结果不是同一时刻的样子
+pub struct ShardedBPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    shards: Vec<RwLock<BPlusTree<K, V, E>>>,
+    sharding: Sharding<K>,
+}
+
+enum Sharding<K> {
+    Hash(fn(&K) -> u64),
+    // splits[i] 是第 i + 1 个 shard 的下界, 严格升序
+    Range(Vec<K>),
+}
+
+// 范围查询的结果, 每个 shard 按需一段段地读, 按 key 归并
+pub struct ShardedRange<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    tree: &'a ShardedBPlusTree<K, V, E>,
+    end: Bound<K>,
+    parts: Vec<Part<K, V>>,
+    // 读 shard 出错时迭代提前结束, 错误留在这里
+    error: Option<anyhow::Error>,
+}
+
+// 一个 shard 里读出来还没返回的条目, 还没读的部分从 start 开始
+struct Part<K, V> {
+    shard: usize,
+    start: Bound<K>,
+    buffer: VecDeque<(K, V)>,
+    done: bool,
+}
+
+// FNV-1a, 结果只由输入的字节决定, 不随 Rust 版本和平台变化
+// 存储上的数据是按 hash 分的, key 要先编码成固定的字节 (比如 to_le_bytes, 不要用 usize) 再算
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+impl<K, V, E> ShardedBPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 每个 engine 一个 shard, key 按 hash 分; 重新打开已有的数据时要用同一个 hash
+    pub fn with_hash(way: usize, engines: Vec<E>, hash: fn(&K) -> u64) -> Result<Self> {
+        Self::with_sharding(way, engines, Sharding::Hash(hash))
+    }
+
+    // splits 把 key 空间切成 splits.len() + 1 段, 要严格升序, engine 的个数要和段数一样
+    pub fn with_ranges(way: usize, splits: Vec<K>, engines: Vec<E>) -> Result<Self> {
+        if engines.len() != splits.len() + 1 {
+            return Err(anyhow!("{} splits need {} engines, got {}.", splits.len(), splits.len() + 1, engines.len()));
+        }
+        if splits.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(anyhow!("splits must be strictly ascending."));
+        }
+        Self::with_sharding(way, engines, Sharding::Range(splits))
+    }
+
+    fn with_sharding(way: usize, engines: Vec<E>, sharding: Sharding<K>) -> Result<Self> {
+        if engines.is_empty() {
+            return Err(anyhow!("sharded tree needs at least one engine."));
+        }
+        let shards = engines.into_iter().map(|engine| BPlusTree::new(way, engine).map(RwLock::new)).collect::<Result<_>>()?;
+        Ok(ShardedBPlusTree { shards, sharding })
+    }
+
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_of(&self, key: &K) -> usize {
+        match &self.sharding {
+            Sharding::Hash(hash) => (hash(key) % self.shards.len() as u64) as usize,
+            Sharding::Range(splits) => splits.partition_point(|split| split <= key),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().map_or(0, |tree| tree.len())).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn search(&self, key: &K) -> Result<Option<V>> {
+        self.shards[self.shard_of(key)].read().map_err(|_| Error::LockPoisoned)?.search(key)
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>> {
+        let mut tree = self.shards[self.shard_of(&key)].write().map_err(|_| Error::LockPoisoned)?;
+        tree.insert(key, value)
+    }
+
+    pub fn delete(&self, key: &K) -> Result<Option<V>> {
+        let mut tree = self.shards[self.shard_of(key)].write().map_err(|_| Error::LockPoisoned)?;
+        tree.delete(key)
+    }
+
+    // 按范围分时只读和 range 有交集的 shard; 每个 shard 每次只读 CHUNK 条, 不会把整个范围放进内存
+    pub fn range<R>(&self, range: R) -> ShardedRange<'_, K, V, E>
+    where
+        R: RangeBounds<K>,
+    {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        let (first, last) = match &self.sharding {
+            Sharding::Range(_) => (
+                match &start {
+                    Bound::Included(key) | Bound::Excluded(key) => self.shard_of(key),
+                    Bound::Unbounded => 0,
+                },
+                match &end {
+                    Bound::Included(key) | Bound::Excluded(key) => self.shard_of(key),
+                    Bound::Unbounded => self.shards.len() - 1,
+                },
+            ),
+            Sharding::Hash(_) => (0, self.shards.len() - 1),
+        };
+        let parts = (first..=last)
+            .map(|shard| Part { shard, start: start.clone(), buffer: VecDeque::new(), done: false })
+            .collect();
+        ShardedRange { tree: self, end, parts, error: None }
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        for shard in &mut self.shards {
+            shard.get_mut().map_err(|_| Error::LockPoisoned)?.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> Result<Vec<BPlusTree<K, V, E>>> {
+        self.shards.into_iter().map(|shard| shard.into_inner().map_err(|_| Error::LockPoisoned.into())).collect()
+    }
+}
+
+impl<K, V> Part<K, V>
+where
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 从 start 开始读下一段, 读到的不满 CHUNK 条说明这个 shard 读完了
+    fn fill<E>(&mut self, tree: &ShardedBPlusTree<K, V, E>, end: &Bound<K>) -> Result<()>
+    where
+        E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    {
+        let shard = tree.shards[self.shard].read().map_err(|_| Error::LockPoisoned)?;
+        for item in shard.try_range((self.start.clone(), end.clone())).take(CHUNK) {
+            self.buffer.push_back(item?);
+        }
+        self.done = self.buffer.len() < CHUNK;
+        if let Some((key, _)) = self.buffer.back() {
+            self.start = Bound::Excluded(key.clone());
+        }
+        Ok(())
+    }
+}
+
+impl<'a, K, V, E> ShardedRange<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    // 迭代因为出错而提前结束时返回那个错误, 没出错时是 None
+    pub fn error(&self) -> Option<&anyhow::Error> {
+        self.error.as_ref()
+    }
+}
+
+impl<'a, K, V, E> Iterator for ShardedRange<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        for part in self.parts.iter_mut().filter(|part| part.buffer.is_empty() && !part.done) {
+            if let Err(e) = part.fill(self.tree, &self.end) {
+                self.error = Some(e);
+                self.parts.clear();
+                return None;
+            }
+        }
+        let (i, _) = self
+            .parts
+            .iter()
+            .enumerate()
+            .filter_map(|(i, part)| part.buffer.front().map(|(key, _)| (i, key)))
+            .min_by(|a, b| a.1.cmp(b.1))?;
+        self.parts[i].buffer.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    type Tree = ShardedBPlusTree<u32, u32, MemoryBlockEngine<BPlusTreeNode<u32, u32>>>;
+
+    #[test]
+    fn test_sharded_tree() {
+        assert_eq!((fnv1a(b""), fnv1a(b"a")), (0xcbf2_9ce4_8422_2325, 0xaf63_dc4c_8601_ec8c));
+        let engines = (0..4).map(|_| MemoryBlockEngine::new()).collect();
+        let hash = |key: &u32| fnv1a(&key.to_le_bytes());
+        let tree: Arc<Tree> = Arc::new(ShardedBPlusTree::with_hash(4, engines, hash).unwrap());
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let tree = tree.clone();
+                thread::spawn(move || {
+                    for i in (0..1000).filter(|i| i % 4 == writer) {
+                        tree.insert(i, i * 2).unwrap();
+                    }
+                })
+            })
+            .collect();
+        writers.into_iter().for_each(|writer| writer.join().unwrap());
+        assert_eq!(tree.len(), 1000);
+        assert!(tree.range(..).eq((0..1000).map(|i| (i, i * 2))));
+        assert!(tree.range(100..=200).map(|(key, _)| key).eq(100..=200));
+        assert_eq!(tree.delete(&7).unwrap(), Some(14));
+        assert_eq!(tree.search(&7).unwrap(), None);
+        let tree = Arc::into_inner(tree).unwrap();
+        assert!(tree.into_inner().unwrap().iter().all(|shard| shard.len() > 150));
+
+        let engines = (0..4).map(|_| MemoryBlockEngine::new()).collect();
+        let tree: Tree = ShardedBPlusTree::with_ranges(4, vec![1000, 2000, 3000], engines).unwrap();
+        for i in 0..4000 {
+            tree.insert(i, i).unwrap();
+        }
+        // 每个 shard 要读好几段
+        let mut range = tree.range(500..2600);
+        assert!(range.by_ref().map(|(key, _)| key).eq(500..2600) && range.error().is_none());
+        assert!(tree.range(..=1000).map(|(key, _)| key).eq(0..=1000));
+        assert!(tree.into_inner().unwrap().iter().all(|shard| shard.len() == 1000));
+        let engines: Vec<MemoryBlockEngine<BPlusTreeNode<u32, u32>>> = (0..3).map(|_| MemoryBlockEngine::new()).collect();
+        assert!(ShardedBPlusTree::with_ranges(4, vec![2, 1], engines).is_err());
+    }
+}
@@ -0,0 +1,391 @@
+use anyhow::{anyhow, Ok, Result};
+
+use crate::{block::BlockId, capacity::NodeCapacity, codec::NodeCodec, tree::BPlusTreeNode};
+
+// 页头:
+// 0       flag, 是否叶子
+// 2..4    slot 个数
+// 4..6    cell 区的起点, cell 从页尾往前长
+// 6..8    删掉的 cell 留下的碎片字节数, compact 之后归零
+// 8..12   way
+// 12..20  叶子是 prev, 内部结点是 pointers[0]
+// 20..28  叶子是 next, 内部结点是 counts[0]
+// 之后是 slot 数组, 每个 slot 是 cell 的偏移和长度, 各 2 字节
+pub const HEADER_LEN: usize = 28;
+pub const SLOT_LEN: usize = 4;
+const LEAF: u8 = 1;
+const NONE: u64 = u64::MAX;
+
+// 定长的页, slot 数组从页头往后长, cell 从页尾往前长, 中间是空闲空间
+// slot 按 key 的顺序排, cell 在页里的位置是任意的, 插入和删除只移动 slot
+pub struct SlottedPage {
+    buf: Vec<u8>,
+}
+
+impl SlottedPage {
+    pub fn new(page_size: usize) -> Result<Self> {
+        check_page_size(page_size)?;
+        let mut page = SlottedPage { buf: vec![0; page_size] };
+        page.put_u16(4, page_size as u16);
+        Ok(page)
+    }
+
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self> {
+        check_page_size(buf.len())?;
+        let page = SlottedPage { buf };
+        let slots_end = HEADER_LEN + page.len() * SLOT_LEN;
+        let cell_start = page.get_u16(4) as usize;
+        if slots_end > cell_start || cell_start > page.buf.len() {
+            return Err(anyhow!("slotted page has {} slots but cells start at {}.", page.len(), cell_start));
+        }
+        for index in 0..page.len() {
+            let (offset, len) = page.slot(index);
+            if offset < cell_start || offset + len > page.buf.len() {
+                return Err(anyhow!("slot {} of the slotted page points outside the cells.", index));
+            }
+        }
+        Ok(page)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn len(&self) -> usize {
+        self.get_u16(2) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn cell(&self, index: usize) -> &[u8] {
+        let (offset, len) = self.slot(index);
+        &self.buf[offset..offset + len]
+    }
+
+    // 还能放下多少字节, 一个 cell 要占 cell 本身加一个 slot, 碎片要 compact 之后才能用
+    pub fn free_space(&self) -> usize {
+        self.get_u16(4) as usize - (HEADER_LEN + self.len() * SLOT_LEN) + self.get_u16(6) as usize
+    }
+
+    // 放不下时返回 false, 页不变
+    pub fn insert(&mut self, index: usize, cell: &[u8]) -> bool {
+        let len = self.len();
+        assert!(index <= len, "slot index {} out of range {}", index, len);
+        if cell.len() + SLOT_LEN > self.free_space() {
+            return false;
+        }
+        let slots_end = HEADER_LEN + len * SLOT_LEN;
+        if (self.get_u16(4) as usize) < slots_end + SLOT_LEN + cell.len() {
+            self.compact();
+        }
+        let offset = self.get_u16(4) as usize - cell.len();
+        self.buf[offset..offset + cell.len()].copy_from_slice(cell);
+        self.put_u16(4, offset as u16);
+        let at = HEADER_LEN + index * SLOT_LEN;
+        self.buf.copy_within(at..slots_end, at + SLOT_LEN);
+        self.put_u16(at, offset as u16);
+        self.put_u16(at + 2, cell.len() as u16);
+        self.put_u16(2, len as u16 + 1);
+        true
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        let len = self.len();
+        assert!(index < len, "slot index {} out of range {}", index, len);
+        let garbage = self.get_u16(6) + self.slot(index).1 as u16;
+        self.put_u16(6, garbage);
+        let at = HEADER_LEN + index * SLOT_LEN;
+        self.buf.copy_within(at + SLOT_LEN..HEADER_LEN + len * SLOT_LEN, at);
+        self.put_u16(2, len as u16 - 1);
+    }
+
+    // 把 cell 按 slot 的顺序重新紧挨着排到页尾, 回收碎片
+    fn compact(&mut self) {
+        let cells: Vec<Vec<u8>> = (0..self.len()).map(|index| self.cell(index).to_vec()).collect();
+        let mut offset = self.buf.len();
+        for (index, cell) in cells.iter().enumerate() {
+            offset -= cell.len();
+            self.buf[offset..offset + cell.len()].copy_from_slice(cell);
+            self.put_u16(HEADER_LEN + index * SLOT_LEN, offset as u16);
+        }
+        self.put_u16(4, offset as u16);
+        self.put_u16(6, 0);
+    }
+
+    fn slot(&self, index: usize) -> (usize, usize) {
+        let at = HEADER_LEN + index * SLOT_LEN;
+        (self.get_u16(at) as usize, self.get_u16(at + 2) as usize)
+    }
+
+    fn get_u16(&self, at: usize) -> u16 {
+        u16::from_le_bytes([self.buf[at], self.buf[at + 1]])
+    }
+
+    fn put_u16(&mut self, at: usize, n: u16) {
+        self.buf[at..at + 2].copy_from_slice(&n.to_le_bytes());
+    }
+
+    fn get_u64(&self, at: usize) -> u64 {
+        u64::from_le_bytes(self.buf[at..at + 8].try_into().unwrap())
+    }
+
+    fn put_u64(&mut self, at: usize, n: u64) {
+        self.buf[at..at + 8].copy_from_slice(&n.to_le_bytes());
+    }
+}
+
+fn check_page_size(page_size: usize) -> Result<()> {
+    if page_size <= HEADER_LEN || page_size > u16::MAX as usize {
+        return Err(anyhow!("slotted page size must be between {} and {}, got {}.", HEADER_LEN + 1, u16::MAX, page_size));
+    }
+    Ok(())
+}
+
+// 变长字节 key / value 的结点编码成一个 slotted page, 每个 key 一个 cell:
+// 叶子的 cell 是 2 字节 key 长度, key, value; 内部结点的 cell 是 8 字节 pointer, 8 字节 count, key
+// 编码结果正好是 page_size 字节, 所以 FileOptions 的 page_size 要再加上 file::PAGE_HEADER_LEN
+#[derive(Debug, Clone, Copy)]
+pub struct SlottedCodec {
+    page_size: usize,
+}
+
+impl SlottedCodec {
+    pub fn new(page_size: usize) -> Result<Self> {
+        check_page_size(page_size)?;
+        Ok(SlottedCodec { page_size })
+    }
+
+    // cell 连同 slot 占的字节数
+    pub fn cell_size(key: &[u8], value: Option<&[u8]>) -> usize {
+        let cell = match value {
+            Some(value) => 2 + key.len() + value.len(),
+            None => 16 + key.len(),
+        };
+        SLOT_LEN + cell
+    }
+
+    // 和页面布局对应的容量, 树按它分裂出来的结点一定能编码进一页
+    pub fn capacity(&self) -> NodeCapacity<Vec<u8>, Vec<u8>> {
+        NodeCapacity::bytes(self.page_size - HEADER_LEN, |key: &Vec<u8>, value: Option<&Vec<u8>>| {
+            Self::cell_size(key, value.map(Vec::as_slice))
+        })
+    }
+}
+
+impl NodeCodec<BPlusTreeNode<Vec<u8>, Vec<u8>>> for SlottedCodec {
+    fn encode(&self, node: &BPlusTreeNode<Vec<u8>, Vec<u8>>, buf: &mut Vec<u8>) -> Result<()> {
+        let mut page = SlottedPage::new(self.page_size)?;
+        page.buf[8..12].copy_from_slice(&(node.way as u32).to_le_bytes());
+        let mut cell = vec![];
+        if node.is_leaf {
+            page.buf[0] = LEAF;
+            page.put_u64(12, node.prev.map_or(NONE, |id| id as u64));
+            page.put_u64(20, node.next.map_or(NONE, |id| id as u64));
+        } else {
+            if node.pointers.len() != node.keys.len() + 1 || node.counts.len() != node.pointers.len() {
+                return Err(anyhow!("inner node has {} keys but {} pointers.", node.keys.len(), node.pointers.len()));
+            }
+            if node.maxes.iter().any(Option::is_some) {
+                return Err(anyhow!("slotted pages can not store the maxes of an augmented tree."));
+            }
+            page.put_u64(12, node.pointers[0] as u64);
+            page.put_u64(20, node.counts[0] as u64);
+        }
+        for (i, key) in node.keys.iter().enumerate() {
+            cell.clear();
+            if node.is_leaf {
+                let key_len = u16::try_from(key.len()).map_err(|_| anyhow!("key of {} bytes is too long.", key.len()))?;
+                cell.extend_from_slice(&key_len.to_le_bytes());
+                cell.extend_from_slice(key);
+                cell.extend_from_slice(&node.values[i]);
+            } else {
+                cell.extend_from_slice(&(node.pointers[i + 1] as u64).to_le_bytes());
+                cell.extend_from_slice(&(node.counts[i + 1] as u64).to_le_bytes());
+                cell.extend_from_slice(key);
+            }
+            if !page.insert(i, &cell) {
+                return Err(anyhow!("node does not fit into a page of {} bytes.", self.page_size));
+            }
+        }
+        buf.extend_from_slice(&page.into_bytes());
+        Ok(())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<BPlusTreeNode<Vec<u8>, Vec<u8>>> {
+        let page = SlottedPage::from_bytes(bytes.to_vec())?;
+        let way = u32::from_le_bytes(page.buf[8..12].try_into()?) as usize;
+        let (a, b) = (page.get_u64(12), page.get_u64(20));
+        let mut node = if page.buf[0] & LEAF != 0 {
+            let mut node = BPlusTreeNode::new_leaf(way);
+            node.prev = (a != NONE).then_some(a as BlockId);
+            node.next = (b != NONE).then_some(b as BlockId);
+            node
+        } else {
+            let mut node = BPlusTreeNode::new_inner(way);
+            node.pointers.push(a as BlockId);
+            node.counts.push(b as usize);
+            node
+        };
+        for index in 0..page.len() {
+            let cell = page.cell(index);
+            if node.is_leaf {
+                let key_len = cell.get(..2).map(|len| u16::from_le_bytes([len[0], len[1]]) as usize);
+                let key = key_len.and_then(|len| cell.get(2..2 + len)).ok_or_else(|| anyhow!("cell {} is truncated.", index))?;
+                node.keys.push(key.to_vec());
+                node.values.push(cell[2 + key.len()..].to_vec());
+            } else {
+                if cell.len() < 16 {
+                    return Err(anyhow!("cell {} is truncated.", index));
+                }
+                node.pointers.push(u64::from_le_bytes(cell[0..8].try_into()?) as BlockId);
+                node.counts.push(u64::from_le_bytes(cell[8..16].try_into()?) as usize);
+                node.keys.push(cell[16..].to_vec());
+            }
+        }
+        if !node.is_leaf {
+            node.maxes = vec![None; node.pointers.len()];
+        }
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use crate::{
+        block::MemoryBlockEngine,
+        build::RebuildOptions,
+        file::{FileBlockEngine, FileOptions, PAGE_HEADER_LEN},
+        tree::BPlusTree,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_slotted_page() {
+        let mut page = SlottedPage::new(64).unwrap();
+        assert_eq!(page.free_space(), 64 - HEADER_LEN);
+        assert!(page.insert(0, b"cccc") && page.insert(0, b"aaaa") && page.insert(1, b"bbbbbbbb"));
+        assert_eq!((0..3).map(|i| page.cell(i)).collect::<Vec<_>>(), vec![&b"aaaa"[..], b"bbbbbbbb", b"cccc"]);
+        assert_eq!(page.free_space(), 64 - HEADER_LEN - 16 - 3 * SLOT_LEN);
+        assert!(!page.insert(3, &[0; 8]));
+
+        // 删掉的 cell 变成碎片, 连续的空间不够时 compact 之后再放
+        page.remove(1);
+        assert!(page.insert(2, b"dddddddddddd"));
+        assert_eq!(page.free_space(), 4);
+        let page = SlottedPage::from_bytes(page.into_bytes()).unwrap();
+        assert_eq!((0..3).map(|i| page.cell(i)).collect::<Vec<_>>(), vec![&b"aaaa"[..], b"cccc", b"dddddddddddd"]);
+        assert!(SlottedPage::from_bytes(vec![0xff; 64]).is_err());
+    }
+
+    #[test]
+    fn test_split_by_bytes() {
+        let codec = SlottedCodec::new(512 - PAGE_HEADER_LEN).unwrap();
+        let mut seed = 7u64;
+        let mut random = move |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % n
+        };
+        // key 是 4 字节随机前缀加 0 到 79 字节, value 是 0 到 24 字节
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..3000)
+            .map(|_| {
+                let key = [(random(1 << 30) as u32).to_be_bytes().to_vec(), vec![b'k'; random(80)]].concat();
+                (key, vec![b'v'; random(25)])
+            })
+            .collect();
+
+        for persistent in [false, true] {
+            let mut tree = BPlusTree::with_node_capacity(1000, MemoryBlockEngine::new(), codec.capacity()).unwrap();
+            let mut expected = BTreeMap::new();
+            for (i, (key, value)) in entries.iter().enumerate() {
+                if persistent && i % 300 == 0 {
+                    tree.freeze();
+                }
+                tree.insert(key.clone(), value.clone()).unwrap();
+                expected.insert(key.clone(), value.clone());
+            }
+            // 删掉大部分, 太空的结点和兄弟合并或者借用
+            for i in 0..2400 {
+                if persistent && i % 300 == 0 {
+                    tree.freeze();
+                }
+                let (key, _) = &entries[random(entries.len())];
+                assert_eq!(tree.delete(key).unwrap(), expected.remove(key));
+            }
+            // 每个结点都能编码进一页
+            let stats = tree.verify().unwrap();
+            assert!(stats.height >= 3);
+            assert!(tree.iter().eq(expected.clone()));
+        }
+
+        // 放不进 1/4 页的条目直接拒绝
+        let mut tree = BPlusTree::with_node_capacity(1000, MemoryBlockEngine::new(), codec.capacity()).unwrap();
+        assert!(tree.insert(vec![0; 100], vec![0; 100]).is_err());
+        assert!(tree.is_empty());
+
+        let path = std::env::temp_dir().join(format!("bplus-tree-slotted-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, pool_size: 8, ..FileOptions::default() };
+        let engine = FileBlockEngine::create_with_codec(&path, options, codec).unwrap();
+        let mut tree = BPlusTree::with_node_capacity(1000, engine, codec.capacity()).unwrap();
+        for (key, value) in &entries {
+            tree.insert(key.clone(), value.clone()).unwrap();
+        }
+        tree.flush().unwrap();
+        let engine = FileBlockEngine::open_with_codec(&path, options, codec).unwrap();
+        let tree = BPlusTree::open_with_node_capacity(engine, codec.capacity()).unwrap();
+        tree.verify().unwrap();
+        assert!(tree.iter().eq(entries.into_iter().collect::<BTreeMap<_, _>>()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_paths_by_bytes() {
+        let codec = SlottedCodec::new(512 - PAGE_HEADER_LEN).unwrap();
+        let entries: BTreeMap<Vec<u8>, Vec<u8>> = (0..2000u32)
+            .map(|i| ([i.to_be_bytes().to_vec(), vec![b'k'; (i * 37 % 80) as usize]].concat(), vec![b'v'; (i % 25) as usize]))
+            .collect();
+        let (low, high): (Vec<_>, Vec<_>) =
+            entries.clone().into_iter().partition(|(key, _)| key[..4] < 1000u32.to_be_bytes()[..]);
+
+        let mut tree = BPlusTree::with_node_capacity(1000, Arc::new(MemoryBlockEngine::new()), codec.capacity()).unwrap();
+        assert_eq!(tree.insert_batch(low.clone()).unwrap(), 1000);
+        let right = tree.split_off(&500u32.to_be_bytes().to_vec()).unwrap();
+        right.verify().unwrap();
+        tree.append(right).unwrap();
+        let mut other = BPlusTree::with_node_capacity(1000, tree.engine.clone(), codec.capacity()).unwrap();
+        other.insert_batch(high.clone()).unwrap();
+        tree.append(other).unwrap();
+        tree.verify().unwrap();
+        assert!(tree.iter().eq(entries.clone()));
+        tree.rebuild(RebuildOptions { way: 1000, fill_factor: 1.0 }).unwrap();
+        tree.verify().unwrap();
+        assert!(tree.iter().eq(entries.clone()));
+        // 只按个数切分的树放不进这棵树的结点, 拒绝接上来
+        let mut plain = BPlusTree::new(1000, tree.engine.clone()).unwrap();
+        let shifted = high.iter().map(|(key, value)| ([&[0xff], &key[..]].concat(), value.clone()));
+        plain.insert_batch(shifted.collect()).unwrap();
+        assert!(tree.append(plain).is_err());
+        assert_eq!(tree.len(), 2000);
+
+        // 批量写入和拼接之后每个结点都能编码进一页
+        let path = std::env::temp_dir().join(format!("bplus-tree-slotted-bulk-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, pool_size: 8, ..FileOptions::default() };
+        let engine = Arc::new(FileBlockEngine::create_with_codec(&path, options, codec).unwrap());
+        let mut tree = BPlusTree::with_node_capacity(1000, engine.clone(), codec.capacity()).unwrap();
+        tree.insert_batch(low).unwrap();
+        let mut other = BPlusTree::with_node_capacity(1000, engine, codec.capacity()).unwrap();
+        other.insert_batch(high).unwrap();
+        tree.append(other).unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+        let engine = FileBlockEngine::open_with_codec(&path, options, codec).unwrap();
+        let tree = BPlusTree::open_with_node_capacity(engine, codec.capacity()).unwrap();
+        tree.verify().unwrap();
+        assert!(tree.iter().eq(entries));
+        std::fs::remove_file(path).unwrap();
+    }
+}
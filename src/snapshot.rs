@@ -0,0 +1,407 @@
+use anyhow::{anyhow, Ok, Result};
+use std::{
+    collections::{HashSet, VecDeque},
+    ops::RangeBounds,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    block::{BlockEngine, BlockId},
+    iter::{Range, TryRange},
+    refcount::RefCounts,
+    tree::{BPlusTree, BPlusTreeNode, Version},
+};
+
+// 历史版本保留多久
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    // 最近的 n 次修改
+    Versions(usize),
+    // 最近一段时间内的修改
+    Age(Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    // 第 n 次修改之后
+    Seq(u64),
+    Time(SystemTime),
+}
+
+#[derive(Default)]
+pub(crate) struct History {
+    // 已经完成的修改次数
+    seq: u64,
+    retention: Option<Retention>,
+    // (seq, 时间, 版本), seq 递增
+    versions: VecDeque<(u64, SystemTime, Version)>,
+    // 丢掉了但是还没有还引用的版本的 root, 记录时拿不到 engine, 由 record_history 还
+    expired: Vec<BlockId>,
+}
+
+impl History {
+    // 一次修改完成, 设置了保留策略时把当前 root 冻结成一个历史版本
+    // 只借用需要的几个字段, 这样拿着叶子 guard 的时候也能记
+    pub(crate) fn record(&mut self, version: Version, frozen: Frozen<'_>) {
+        self.seq += 1;
+        if self.retention.is_some() {
+            self.push_version(version, frozen);
+        }
+    }
+
+    // 不改 seq, 直接把当前 root 冻结成一个版本
+    fn push_version(&mut self, version: Version, frozen: Frozen<'_>) {
+        // 和 BPlusTree::freeze 一样
+        *frozen.persistent = true;
+        frozen.owned.clear();
+        frozen.refs.retain(version.root);
+        let now = SystemTime::now();
+        self.versions.push_back((self.seq, now, version));
+        // 最新的版本总是保留
+        while self.versions.len() > 1 {
+            let expired = match self.retention {
+                Some(Retention::Versions(n)) => self.versions.len() > n.max(1),
+                Some(Retention::Age(age)) => self.versions[0]
+                    .1
+                    .checked_add(age)
+                    .is_some_and(|deadline| deadline < now),
+                None => true,
+            };
+            if !expired {
+                break;
+            }
+            let (_, _, version) = self.versions.pop_front().unwrap();
+            self.expired.push(version.root);
+        }
+    }
+}
+
+// freeze 要改的几个字段, 拿着叶子 guard 时借不到整棵树
+pub(crate) struct Frozen<'a> {
+    pub(crate) persistent: &'a mut bool,
+    pub(crate) owned: &'a mut HashSet<BlockId>,
+    pub(crate) refs: &'a mut RefCounts,
+}
+
+// 只读的树句柄, 看到的是创建 snapshot 那一刻的数据
+pub struct Snapshot<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord,
+{
+    tree: &'a BPlusTree<K, V, E>,
+    version: Version,
+}
+
+impl<'a, K, V, E> Snapshot<'a, K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn search(&self, key: &K) -> Result<Option<V>> {
+        self.tree.search_helper(self.version.root, key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.version.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.version.len == 0
+    }
+
+    pub fn iter(&self) -> Range<'a, K, V, E> {
+        self.range(..)
+    }
+
+    pub fn range<R>(&self, range: R) -> Range<'a, K, V, E>
+    where
+        R: RangeBounds<K>,
+    {
+        self.tree.range_filtered_at(self.version.root, range, |_, _| true)
+    }
+
+    pub fn try_range<R>(&self, range: R) -> TryRange<'a, K, V, E>
+    where
+        R: RangeBounds<K>,
+    {
+        TryRange { inner: self.range(range) }
+    }
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 冻结当前版本, 之后的写操作会把共享的结点连同到 root 的路径复制一份再改, 旧的 root 下什么都不变
+    // 返回的 Version 不借用树, 拿着它可以接着写, 要读的时候用 at 拿一个只读视图
+    // 长时间的导出可以每次从上次读到的 key 之后读一段, 中间穿插写操作, 读到的都是这一刻的数据
+    pub fn snapshot(&mut self) -> Version {
+        self.freeze()
+    }
+
+    pub fn at(&self, version: Version) -> Snapshot<'_, K, V, E> {
+        Snapshot { tree: self, version }
+    }
+
+    // 冻结当前版本并给它起个名字
+    pub fn create_snapshot(&mut self, name: &str) -> Result<Version> {
+        if self.snapshots.contains_key(name) {
+            return Err(anyhow!("snapshot already exists: {}.", name));
+        }
+        // 先让 engine 看看放不放得下, 放不下时不冻结, 之后的 flush 也不会因为它失败
+        let mut snapshots = self.snapshot_metas();
+        snapshots.insert(name.to_string(), self.meta());
+        self.engine.stage_snapshots(&snapshots)?;
+        let version = self.freeze();
+        self.snapshots.insert(name.to_string(), version);
+        Ok(version)
+    }
+
+    pub fn open_snapshot(&self, name: &str) -> Result<Snapshot<'_, K, V, E>> {
+        let version = *self
+            .snapshots
+            .get(name)
+            .ok_or_else(|| anyhow!("no such snapshot: {}.", name))?;
+        Ok(Snapshot { tree: self, version })
+    }
+
+    // snapshot 独占的 block 在这时回收
+    pub fn drop_snapshot(&mut self, name: &str) -> Result<()> {
+        let version = self.snapshots.remove(name).ok_or_else(|| anyhow!("no such snapshot: {}.", name))?;
+        self.release(version.root)
+    }
+
+    // 打开之后每次修改都会冻结并记录一个版本, 超出 retention 的版本会被丢掉
+    pub fn set_retention(&mut self, retention: Option<Retention>) {
+        self.history.retention = retention;
+        if retention.is_none() {
+            let expired = self.history.versions.drain(..).map(|(_, _, version)| version.root);
+            self.history.expired.extend(expired);
+        } else {
+            let version = self.version();
+            let BPlusTree { history, persistent, owned, refs, .. } = self;
+            history.push_version(version, Frozen { persistent, owned, refs });
+        }
+        self.release_expired();
+    }
+
+    pub fn seq(&self) -> u64 {
+        self.history.seq
+    }
+
+    // 返回 as_of 时刻的只读句柄, 比保留的最早版本还早会报错
+    pub fn as_of(&self, as_of: AsOf) -> Result<Snapshot<'_, K, V, E>> {
+        let versions = &self.history.versions;
+        let pos = match as_of {
+            AsOf::Seq(seq) => versions.partition_point(|(s, _, _)| *s <= seq),
+            AsOf::Time(time) => versions.partition_point(|(_, t, _)| *t <= time),
+        };
+        if pos == 0 {
+            return Err(anyhow!("{:?} is out of the retention horizon.", as_of));
+        }
+        Ok(Snapshot { tree: self, version: versions[pos - 1].2 })
+    }
+
+    pub(crate) fn record_history(&mut self) {
+        let version = self.version();
+        let BPlusTree { history, persistent, owned, refs, .. } = self;
+        history.record(version, Frozen { persistent, owned, refs });
+        self.release_expired();
+    }
+
+    // 回收失败只是漏掉几个 block
+    fn release_expired(&mut self) {
+        for root in std::mem::take(&mut self.history.expired) {
+            let _ = self.release(root);
+        }
+    }
+
+    // 有名字的 snapshot 和保留的历史版本, 它们的 block 都不能回收
+    pub(crate) fn retained_versions(&self) -> impl Iterator<Item = Version> + '_ {
+        self.snapshots.values().copied().chain(self.history.versions.iter().map(|(_, _, version)| *version))
+    }
+
+    pub fn snapshot_names(&self) -> impl Iterator<Item = &str> {
+        self.snapshots.keys().map(|name| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_named_snapshot() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..20 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.create_snapshot("deploy").unwrap();
+        assert!(tree.create_snapshot("deploy").is_err());
+
+        for i in 0..20 {
+            tree.insert(i + 100, i).unwrap();
+            tree.delete(&i).unwrap();
+        }
+
+        let snapshot = tree.open_snapshot("deploy").unwrap();
+        for i in 0..20 {
+            assert_eq!(snapshot.search(&i).unwrap(), Some(i));
+            assert_eq!(snapshot.search(&(i + 100)).unwrap(), None);
+            assert_eq!(tree.search(&i).unwrap(), None);
+        }
+
+        tree.drop_snapshot("deploy").unwrap();
+        assert!(tree.open_snapshot("deploy").is_err());
+        assert!(tree.drop_snapshot("deploy").is_err());
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_reopen_snapshot() {
+        use crate::{
+            file::{FileBlockEngine, FileOptions},
+            fsck::{self, VerifyMode},
+        };
+
+        let path = std::env::temp_dir().join(format!("bplus-tree-snapshot-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, ..FileOptions::default() };
+        let mut tree = BPlusTree::new(4, FileBlockEngine::create(&path, options).unwrap()).unwrap();
+        for i in 0..200u32 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.create_snapshot("deploy").unwrap();
+        for i in 0..100 {
+            tree.delete(&i).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+        let open = || BPlusTree::<u32, u32, _>::open(FileBlockEngine::open(&path, options).unwrap()).unwrap();
+        let check = || assert!(fsck::verify::<u32, u32>(&path, VerifyMode::Full).unwrap().is_ok());
+        check();
+
+        // 重新打开之后还能读, 和它共用的结点写的时候先复制, 不会原地改掉, 也不会被回收
+        let mut tree = open();
+        assert_eq!(tree.snapshot_names().collect::<Vec<_>>(), vec!["deploy"]);
+        for i in 100..200 {
+            tree.delete(&i).unwrap();
+        }
+        for i in 0..50 {
+            tree.insert(1000 + i, i).unwrap();
+        }
+        tree.vacuum().unwrap();
+        let keys: Vec<_> = tree.open_snapshot("deploy").unwrap().iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, (0..200).collect::<Vec<_>>());
+        tree.verify().unwrap();
+        drop(tree);
+        check();
+
+        // drop 之后它独占的 block 回收掉, 下次打开就没有了
+        let mut tree = open();
+        assert_eq!(tree.open_snapshot("deploy").unwrap().len(), 200);
+        tree.drop_snapshot("deploy").unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+        check();
+        let tree = open();
+        assert!(tree.open_snapshot("deploy").is_err());
+        assert_eq!(tree.len(), 50);
+        tree.verify().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_snapshot_catalog_full() {
+        use crate::file::{FileBlockEngine, FileOptions};
+
+        let path = std::env::temp_dir().join(format!("bplus-tree-snapshot-full-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, ..FileOptions::default() };
+        let mut tree = BPlusTree::new(4, FileBlockEngine::create(&path, options).unwrap()).unwrap();
+        // superblock 里放不下的那个 snapshot 直接报错, 树不冻结, flush 照常
+        let mut count = 0;
+        loop {
+            tree.insert(count, count).unwrap();
+            if tree.create_snapshot(&format!("snapshot-{}", count)).is_err() {
+                break;
+            }
+            count += 1;
+        }
+        assert!(count > 0);
+        assert_eq!(tree.snapshot_names().count(), count as usize);
+        tree.insert(count + 1, 0).unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+
+        let tree = BPlusTree::<u32, u32, _>::open(FileBlockEngine::open(&path, options).unwrap()).unwrap();
+        assert_eq!(tree.snapshot_names().count(), count as usize);
+        assert_eq!(tree.open_snapshot("snapshot-0").unwrap().len(), 1);
+        tree.verify().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_as_of() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        tree.insert(100, 0).unwrap();
+        assert!(tree.as_of(AsOf::Seq(1)).is_err());
+
+        tree.set_retention(Some(Retention::Versions(10)));
+        let start = SystemTime::now();
+        for i in 1..30 {
+            tree.insert(0, i).unwrap();
+            tree.delete(&0).unwrap();
+        }
+        // 每轮两次修改, 只保留最近 10 个版本
+        assert!(tree.as_of(AsOf::Seq(tree.seq() - 10)).is_err());
+        let snapshot = tree.as_of(AsOf::Seq(tree.seq() - 1)).unwrap();
+        assert_eq!(snapshot.search(&0).unwrap(), Some(29));
+        assert_eq!(tree.as_of(AsOf::Seq(tree.seq())).unwrap().search(&0).unwrap(), None);
+        assert!(tree.as_of(AsOf::Time(start)).is_err());
+        assert_eq!(tree.as_of(AsOf::Time(SystemTime::now())).unwrap().search(&0).unwrap(), None);
+    }
+
+    #[test]
+    fn test_export_while_writing() {
+        let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        for i in 0..500 {
+            tree.insert(i, i).unwrap();
+        }
+        let version = tree.snapshot();
+
+        // 每读 50 条穿插一批写, 导出的还是 snapshot 那一刻的数据
+        let mut exported = vec![];
+        let mut last = None;
+        loop {
+            let start = last.map_or(Bound::Unbounded, Bound::Excluded);
+            let chunk: Vec<_> = tree.at(version).range((start, Bound::Unbounded)).take(50).collect();
+            let Some(&(key, _)) = chunk.last() else {
+                break;
+            };
+            last = Some(key);
+            exported.extend(chunk);
+            for i in 0..20 {
+                tree.insert(1000 + exported.len() as u32 + i, 0).unwrap();
+                tree.delete(&(exported.len() as u32 + i)).unwrap();
+                tree.insert(i, 1).unwrap();
+            }
+        }
+        assert_eq!(exported, (0..500).map(|i| (i, i)).collect::<Vec<_>>());
+        assert_eq!(tree.at(version).len(), 500);
+        assert_eq!(tree.at(version).iter().next_back(), Some((499, 499)));
+        assert_eq!(tree.search(&0).unwrap(), Some(1));
+        tree.verify().unwrap();
+    }
+}
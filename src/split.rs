@@ -0,0 +1,737 @@
+use anyhow::{anyhow, Ok, Result};
+use std::{
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use crate::{
+    block::{BlockEngine, BlockId},
+    capacity::NodeCapacity,
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode, Split},
+};
+
+// 整棵树按 key 切开 / 拼起来, 只动 root 到叶子的两条边, 中间的子树整块搬走
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 删除 range 里的所有条目, 返回删掉的个数
+    // 先在两端切开, 中间那棵树整个回收, 再把两边拼回去
+    pub fn remove_range<R>(&mut self, range: R) -> Result<usize>
+    where
+        R: RangeBounds<K>,
+    {
+        if self.is_empty() {
+            return Ok(0);
+        }
+        // 每次切开最多复制并分裂路径上的每个结点, 拼接时还可能分裂一条边再加一个新 root
+        // 修边界时借用的兄弟也要复制, 超出预留的部分直接向 engine 分配
+        let height = self.height(self.root)? + 1;
+        self.reserve(height * if self.persistent { 6 } else { 3 } + 1)?;
+        let ret = self.remove_range_root(range.start_bound(), range.end_bound());
+        self.release_reserved();
+        let removed = ret?;
+        self.len -= removed;
+        self.record_history();
+        Ok(removed)
+    }
+
+    // 删掉 f 返回 false 的条目, 返回删掉的个数
+    // 先读一遍所有叶子挑出要删的 key, 再逐个删除, 删除时照常借用 / 合并兄弟
+    pub fn retain<F>(&mut self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        // 读叶子出错时直接返回, 什么都不删, 不会只按读到的那一部分删
+        let mut doomed = vec![];
+        for entry in self.try_iter() {
+            let (key, value) = entry?;
+            if !f(&key, &value) {
+                doomed.push(key);
+            }
+        }
+        for key in &doomed {
+            self.delete(key)?;
+        }
+        Ok(doomed.len())
+    }
+
+    // 回收所有结点, root 换成一个新的空叶子
+    // 持久化模式下旧版本还在用的结点不会被回收
+    pub fn clear(&mut self) -> Result<()> {
+        let root = self.alloc_node(BPlusTreeNode::new_leaf(self.way))?;
+        let old = std::mem::replace(&mut self.root, root);
+        self.release(old)?;
+        self.len = 0;
+        self.record_history();
+        Ok(())
+    }
+
+    fn remove_range_root(&mut self, start: Bound<&K>, end: Bound<&K>) -> Result<usize> {
+        let (left, middle) = match start {
+            Bound::Unbounded => (None, self.root),
+            start => {
+                let (left, right) = self.split_tree(self.root, start)?;
+                (Some(left), right)
+            }
+        };
+        // 右边从 end 之后开始
+        let (middle, right) = match end {
+            Bound::Unbounded => (middle, None),
+            Bound::Included(end) => {
+                let (middle, right) = self.split_tree(middle, Bound::Excluded(end))?;
+                (middle, Some(right))
+            }
+            Bound::Excluded(end) => {
+                let (middle, right) = self.split_tree(middle, Bound::Included(end))?;
+                (middle, Some(right))
+            }
+        };
+        let removed = self.count_entries(middle)?;
+        self.release(middle)?;
+        self.root = match (left, right) {
+            (Some(left), Some(right)) => self.join(left, right)?,
+            (Some(root), None) | (None, Some(root)) => root,
+            (None, None) => self.alloc_node(BPlusTreeNode::new_leaf(self.way))?,
+        };
+        Ok(removed)
+    }
+
+    fn check_capacity(&self, block_id: BlockId, capacity: &NodeCapacity<K, V>) -> Result<()> {
+        let read = self.engine.fetch_read(block_id)?;
+        let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+        if capacity.is_overflow(node) {
+            return Err(anyhow!("node {} of the appended tree exceeds the node capacity.", block_id));
+        }
+        if node.is_leaf() {
+            return node.keys.iter().zip(&node.values).try_for_each(|(key, value)| capacity.check_entry(key, value));
+        }
+        node.pointers.iter().try_for_each(|&child| self.check_capacity(child, capacity))
+    }
+
+    // 把 root 下的树切成两棵: 右边是满足 after_start(start) 的条目, 左边是剩下的
+    // 两棵树都满足结点的最少 key 数, 返回 (左 root, 右 root)
+    fn split_tree(&mut self, root: BlockId, start: Bound<&K>) -> Result<(BlockId, BlockId)> {
+        let (left, right) = self.split_node(root, start)?;
+        Ok((self.fix_border(left, true)?, self.fix_border(right, false)?))
+    }
+
+    // 沿着 start 所在的路径把每个结点一分为二, 切口两侧的结点可能很空, 由 fix_border 修
+    fn split_node(&mut self, block_id: BlockId, start: Bound<&K>) -> Result<(BlockId, BlockId)> {
+        let block_id = self.own(block_id)?;
+        let mut node = self.take_node(block_id)?;
+        if node.is_leaf() {
+            let pos = node.keys.partition_point(|key| !self.order.after_start(start, key));
+            let mut right = BPlusTreeNode::new_leaf(self.way);
+            right.keys = node.keys.split_off(pos);
+            right.values = node.values.split_off(pos);
+            right.next = node.next.take();
+            let next = right.next;
+            self.put_node(block_id, node)?;
+            let right_id = self.alloc_node(right)?;
+            // 持久化模式下右边的邻居可能被旧版本共享, 不去动它
+            if let (Some(next), false) = (next, self.persistent) {
+                if let Some(node) = self.engine.fetch_write(next)?.as_mut() {
+                    node.prev = Some(right_id);
+                }
+            }
+            return Ok((block_id, right_id));
+        }
+        let pos = match start {
+            Bound::Included(key) | Bound::Excluded(key) => node.child_index(key, &self.order),
+            Bound::Unbounded => 0,
+        };
+        let child = node.pointers[pos];
+        self.put_node(block_id, node)?;
+
+        let (child_left, child_right) = self.split_node(child, start)?;
+        let (left_count, right_count) = (self.subtree_count(child_left)?, self.subtree_count(child_right)?);
+        let (left_max, right_max) = (self.subtree_max(child_left)?, self.subtree_max(child_right)?);
+        let mut node = self.take_node(block_id)?;
+        let mut right = BPlusTreeNode::new_inner(self.way);
+        right.keys = node.keys.split_off(pos);
+        right.pointers = vec![child_right];
+        right.pointers.extend(node.pointers.split_off(pos + 1));
+        right.counts = vec![right_count];
+        right.counts.extend(node.counts.split_off(pos + 1));
+        right.maxes = vec![right_max];
+        right.maxes.extend(node.maxes.split_off(pos + 1));
+        node.pointers[pos] = child_left;
+        node.counts[pos] = left_count;
+        node.maxes[pos] = left_max;
+        self.put_node(block_id, node)?;
+        Ok((block_id, self.alloc_node(right)?))
+    }
+
+    // 修一棵树最右 (last) 或最左的边: 从上往下, 边上的结点少于 min_keys 时和旁边的兄弟合并或者平分
+    // 合并会让上一层少一个 key, 所以有合并时从 root 再来一遍, 结点数每次都在减少, 一定会停
+    fn fix_border(&mut self, mut root: BlockId, last: bool) -> Result<BlockId> {
+        loop {
+            root = self.collapse_root(root)?;
+            root = self.own(root)?;
+            let mut merged = false;
+            let mut block_id = root;
+            loop {
+                let (count, underfull) = {
+                    let read = self.engine.fetch_read(block_id)?;
+                    let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+                    if node.is_leaf() {
+                        break;
+                    }
+                    let child = node.pointers[if last { node.pointers.len() - 1 } else { 0 }];
+                    let read = self.engine.fetch_read(child)?;
+                    let child = read.as_ref().ok_or(Error::EmptyBlock(child))?;
+                    (node.pointers.len(), self.capacity.is_underflow(child))
+                };
+                if count >= 2 && underfull {
+                    merged |= self.fix_pair(block_id, if last { count - 2 } else { 0 })?;
+                }
+                block_id = self.own_child(block_id, last)?;
+            }
+            if !merged {
+                return Ok(root);
+            }
+        }
+    }
+
+    // 只有一个孩子的内部 root 去掉, 树变矮
+    fn collapse_root(&mut self, mut root: BlockId) -> Result<BlockId> {
+        loop {
+            let only_child = {
+                let read = self.engine.fetch_read(root)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(root))?;
+                (!node.is_leaf() && node.keys.is_empty()).then(|| node.pointers[0])
+            };
+            let Some(child) = only_child else {
+                return Ok(root);
+            };
+            self.free_node(root)?;
+            root = child;
+        }
+    }
+
+    // 让 parent 最右 (last) 或最左的孩子可写, 返回它的 block id
+    fn own_child(&mut self, parent_id: BlockId, last: bool) -> Result<BlockId> {
+        let (pos, child) = {
+            let read = self.engine.fetch_read(parent_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(parent_id))?;
+            let pos = if last { node.pointers.len() - 1 } else { 0 };
+            (pos, node.pointers[pos])
+        };
+        let new_child = self.own(child)?;
+        if new_child != child {
+            if let Some(node) = self.engine.fetch_write(parent_id)?.as_mut() {
+                node.pointers[pos] = new_child;
+            }
+        }
+        Ok(new_child)
+    }
+
+    // parent 已经是可写的, pointers[li] 和 pointers[li + 1] 放得进一个结点就合并, 否则按 capacity 的分裂点重新分到两边
+    // 和 rebalance_child 不同, 两边可以差任意多个 key, 两边都不太空时不动; 返回是否合并了
+    fn fix_pair(&mut self, parent_id: BlockId, li: usize) -> Result<bool> {
+        let (left_id, right_id, merge) = {
+            let read = self.engine.fetch_read(parent_id)?;
+            let parent = read.as_ref().ok_or(Error::EmptyBlock(parent_id))?;
+            let (left_id, right_id) = (parent.pointers[li], parent.pointers[li + 1]);
+            let left_read = self.engine.fetch_read(left_id)?;
+            let left = left_read.as_ref().ok_or(Error::EmptyBlock(left_id))?;
+            let right_read = self.engine.fetch_read(right_id)?;
+            let right = right_read.as_ref().ok_or(Error::EmptyBlock(right_id))?;
+            let merge = self.capacity.can_merge(left, right, &parent.keys[li]);
+            if !merge && !self.capacity.is_underflow(left) && !self.capacity.is_underflow(right) {
+                return Ok(false);
+            }
+            (left_id, right_id, merge)
+        };
+        let left_id = self.own(left_id)?;
+        let mut parent = self.take_node(parent_id)?;
+        parent.pointers[li] = left_id;
+        let mut left = self.take_node(left_id)?;
+
+        if merge {
+            let right = self.engine.fetch_read(right_id)?.as_ref().cloned().ok_or(Error::EmptyBlock(right_id))?;
+            let separator = parent.keys.remove(li);
+            parent.pointers.remove(li + 1);
+            parent.counts.remove(li + 1);
+            parent.maxes.remove(li + 1);
+            if left.is_leaf() {
+                left.keys.extend(right.keys);
+                left.values.extend(right.values);
+                left.next = right.next;
+                if let (Some(next), false) = (right.next, self.persistent) {
+                    if let Some(node) = self.engine.fetch_write(next)?.as_mut() {
+                        node.prev = Some(left_id);
+                    }
+                }
+            } else {
+                left.keys.push(separator);
+                left.keys.extend(right.keys);
+                left.pointers.extend(right.pointers);
+                left.counts.extend(right.counts);
+                left.maxes.extend(right.maxes);
+            }
+            self.free_node(right_id)?;
+        } else {
+            let right_id = self.own(right_id)?;
+            parent.pointers[li + 1] = right_id;
+            let mut right = self.take_node(right_id)?;
+            // 先都放进 left, 再从分裂点分开, 只按个数算时就是平分
+            if left.is_leaf() {
+                left.keys.append(&mut right.keys);
+                left.values.append(&mut right.values);
+                let mid = self.capacity.split_point(&left);
+                right.keys = left.keys.split_off(mid);
+                right.values = left.values.split_off(mid);
+                parent.keys[li] = right.keys[0].clone();
+            } else {
+                // 分隔 key 先放回中间, 分裂点上的那个再回到 parent
+                left.keys.push(parent.keys[li].clone());
+                left.keys.append(&mut right.keys);
+                left.pointers.append(&mut right.pointers);
+                left.counts.append(&mut right.counts);
+                left.maxes.append(&mut right.maxes);
+                let (separator, piece) = left.split_inner(self.capacity.split_point(&left));
+                parent.keys[li] = separator;
+                (right.keys, right.pointers) = (piece.keys, piece.pointers);
+                (right.counts, right.maxes) = (piece.counts, piece.maxes);
+            }
+            parent.counts[li + 1] = right.entry_count();
+            parent.maxes[li + 1] = right.max_key(self.augment);
+            self.put_node(right_id, right)?;
+        }
+        parent.counts[li] = left.entry_count();
+        parent.maxes[li] = left.max_key(self.augment);
+        self.put_node(left_id, left)?;
+        self.put_node(parent_id, parent)?;
+        Ok(merge)
+    }
+
+    // left 的所有 key 都小于 right 的, 拼成一棵树返回新的 root
+    // 矮的那棵挂到高的那棵边上同样高度的位置, 像插入一样往上分裂
+    fn join(&mut self, left: BlockId, right: BlockId) -> Result<BlockId> {
+        let Some((separator, _)) = self.edge_entry(right, false)? else {
+            self.free_node(right)?;
+            return Ok(left);
+        };
+        if self.edge_entry(left, false)?.is_none() {
+            self.free_node(left)?;
+            return Ok(right);
+        }
+        if !self.persistent {
+            let (last, first) = (self.edge_leaf(left, true)?, self.edge_leaf(right, false)?);
+            if let Some(node) = self.engine.fetch_write(last)?.as_mut() {
+                node.next = Some(first);
+            }
+            if let Some(node) = self.engine.fetch_write(first)?.as_mut() {
+                node.prev = Some(last);
+            }
+        }
+        let (left_height, right_height) = (self.height(left)?, self.height(right)?);
+        let (root, split) = if left_height > right_height {
+            self.graft(left, left_height - right_height, separator, right, true)?
+        } else if left_height < right_height {
+            self.graft(right, right_height - left_height, separator, left, false)?
+        } else {
+            let mut node = BPlusTreeNode::new_inner(self.way);
+            node.keys = vec![separator];
+            node.pointers = vec![left, right];
+            node.counts = vec![self.subtree_count(left)?, self.subtree_count(right)?];
+            node.maxes = vec![self.subtree_max(left)?, self.subtree_max(right)?];
+            let root = self.alloc_node(node)?;
+            // 两个 root 都可能比 min_keys 少
+            self.fix_pair(root, 0)?;
+            return self.collapse_root(root);
+        };
+        let Some((mid, right)) = split else {
+            return Ok(root);
+        };
+        let mut node = BPlusTreeNode::new_inner(self.way);
+        node.keys = vec![mid];
+        node.pointers = vec![root, right];
+        node.counts = vec![self.subtree_count(root)?, self.subtree_count(right)?];
+        node.maxes = vec![self.subtree_max(root)?, self.subtree_max(right)?];
+        self.alloc_node(node)
+    }
+
+    // 沿着 block_id 最右 (last) 或最左的边往下走 depth 层, 把 subtree 挂在那里
+    fn graft(
+        &mut self,
+        block_id: BlockId,
+        depth: usize,
+        separator: K,
+        subtree: BlockId,
+        last: bool,
+    ) -> Result<(BlockId, Split<K>)> {
+        let block_id = self.own(block_id)?;
+        if depth == 1 {
+            let (count, max) = (self.subtree_count(subtree)?, self.subtree_max(subtree)?);
+            let mut node = self.take_node(block_id)?;
+            if last {
+                node.keys.push(separator);
+                node.pointers.push(subtree);
+                node.counts.push(count);
+                node.maxes.push(max);
+            } else {
+                node.keys.insert(0, separator);
+                node.pointers.insert(0, subtree);
+                node.counts.insert(0, count);
+                node.maxes.insert(0, max);
+            }
+            let li = if last { node.pointers.len() - 2 } else { 0 };
+            self.put_node(block_id, node)?;
+            let underfull = {
+                let read = self.engine.fetch_read(subtree)?;
+                self.capacity.is_underflow(read.as_ref().ok_or(Error::EmptyBlock(subtree))?)
+            };
+            if underfull {
+                self.fix_pair(block_id, li)?;
+            }
+        } else {
+            let child = self.own_child(block_id, last)?;
+            let (new_child, split) = self.graft(child, depth - 1, separator, subtree, last)?;
+            let (child_count, child_max) = (self.subtree_count(new_child)?, self.subtree_max(new_child)?);
+            let right_entry = match &split {
+                Some((_, right)) => Some((self.subtree_count(*right)?, self.subtree_max(*right)?)),
+                None => None,
+            };
+            let mut node = self.take_node(block_id)?;
+            let pos = if last { node.pointers.len() - 1 } else { 0 };
+            node.pointers[pos] = new_child;
+            node.counts[pos] = child_count;
+            node.maxes[pos] = child_max;
+            if let (Some((mid, right)), Some((right_count, right_max))) = (split, right_entry) {
+                node.keys.insert(pos, mid);
+                node.pointers.insert(pos + 1, right);
+                node.counts.insert(pos + 1, right_count);
+                node.maxes.insert(pos + 1, right_max);
+            }
+            self.put_node(block_id, node)?;
+        }
+        let mut node = self.take_node(block_id)?;
+        if !self.capacity.is_overflow(&node) {
+            self.put_node(block_id, node)?;
+            return Ok((block_id, None));
+        }
+        let (mid, right) = node.split_inner(self.capacity.split_point(&node));
+        self.put_node(block_id, node)?;
+        Ok((block_id, Some((mid, self.alloc_node(right)?))))
+    }
+
+    // 叶子在第 0 层
+    pub(crate) fn height(&self, mut block_id: BlockId) -> Result<usize> {
+        let mut height = 0;
+        loop {
+            let read = self.engine.fetch_read(block_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                return Ok(height);
+            }
+            block_id = node.pointers[0];
+            height += 1;
+        }
+    }
+
+    fn edge_leaf(&self, mut block_id: BlockId, last: bool) -> Result<BlockId> {
+        loop {
+            let read = self.engine.fetch_read(block_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                return Ok(block_id);
+            }
+            block_id = node.pointers[if last { node.pointers.len() - 1 } else { 0 }];
+        }
+    }
+
+    fn count_entries(&self, root: BlockId) -> Result<usize> {
+        let mut leaves = vec![];
+        self.leaf_ids(root, &mut leaves)?;
+        let mut count = 0;
+        for leaf in leaves {
+            let read = self.engine.fetch_read(leaf)?;
+            count += read.as_ref().ok_or(Error::EmptyBlock(leaf))?.keys.len();
+        }
+        Ok(count)
+    }
+}
+
+// 切开之后两棵树共用 engine, 拼起来的两棵树也要在同一个 engine 上, 所以 split_off / append 只能用在放在 Arc 里的 engine 上
+impl<K, V, E> BPlusTree<K, V, Arc<E>>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 把 >= key 的条目切下来作为一棵新树返回, 和 BTreeMap::split_off 一样
+    // 只沿着 key 的路径把结点一分为二, 两边的子树原地不动, 挂到新树下面, 所以是 O(高度) 的
+    // 持久化模式下两棵树共用引用计数, 和旧版本共享的子树谁先写谁复制, 最后一个引用去掉时才回收
+    pub fn split_off(&mut self, key: &K) -> Result<Self> {
+        if self.is_empty() {
+            let mut other = BPlusTree::with_order(self.way, self.engine.clone(), self.order.clone())?;
+            other.capacity = self.capacity.clone();
+            other.augment = self.augment;
+            other.entry_size = self.entry_size.clone();
+            return Ok(other);
+        }
+        let height = self.height(self.root)? + 1;
+        self.reserve(height * if self.persistent { 4 } else { 2 })?;
+        let ret = self.split_tree(self.root, Bound::Included(key));
+        self.release_reserved();
+        let (left, right) = ret?;
+        let moved = self.subtree_count(right)?;
+        self.root = left;
+        self.len -= moved;
+        // 切下来的结点可能还记在 owned 里, 它们现在归新树; 不记的 block 计数是 1, 谁写谁认领
+        self.owned.clear();
+        self.record_history();
+
+        let mut other = BPlusTree::from_root(self.way, self.engine.clone(), right, moved, self.order.clone());
+        other.capacity = self.capacity.clone();
+        other.augment = self.augment;
+        other.entry_size = self.entry_size.clone();
+        other.persistent = self.persistent;
+        other.refs = self.refs.clone();
+        Ok(other)
+    }
+
+    // 把 other 接到后面, other 的 key 必须都大于当前最大的 key, 和 BTreeMap::append 一样之后 other 就没有了
+    // other 要和这棵树用同一个 engine, 它的结点原地不动, 矮的那棵按高度差挂到高的那棵的边上
+    // 和 remove_range 里拼回去一样只修接缝那条边, 是 O(高度) 的; other 的 snapshot 和历史版本跟着还掉
+    pub fn append(&mut self, mut other: Self) -> Result<()> {
+        if !Arc::ptr_eq(&self.engine, &other.engine) {
+            return Err(anyhow!("appended tree must share the engine of the tree."));
+        }
+        if other.is_shadow_paging() {
+            return Err(anyhow!("appended tree must not use shadow paging."));
+        }
+        if let (Some((last, _)), Some((first, _))) = (self.last_key_value()?, other.first_key_value()?) {
+            if !self.order.lt(&last, &first) {
+                return Err(anyhow!("appended keys must be greater than the last key of the tree."));
+            }
+        }
+        // 按别的 capacity 建的树要先确认它的结点在这棵树里也放得下, 这时要读遍 other
+        if self.capacity.is_bytes() && !self.capacity.same(&other.capacity) {
+            other.check_capacity(other.root, &self.capacity)?;
+        }
+        let height = self.height(self.root)?.max(other.height(other.root)?) + 1;
+        self.reserve(height * if self.persistent || other.persistent { 3 } else { 1 } + 1)?;
+
+        // other 的旧版本用它自己的计数还, 剩下的计数并过来; 共用计数 (从这棵树切出去的) 时不用并
+        for version in std::mem::take(&mut other.snapshots).into_values() {
+            other.release(version.root)?;
+        }
+        other.set_retention(None);
+        self.refs.absorb(&other.refs);
+        // other 的叶子链接不可信时拼起来的树也不可信; 没记在 owned 里的 block 计数是 1, 写的时候再认领
+        self.persistent |= other.persistent;
+        // other 的 maxes 是按它自己的 augment 算的
+        if self.augment.is_some() && other.augment.is_none() {
+            self.refresh_maxes(other.root)?;
+        }
+        let ret = self.join(self.root, other.root);
+        self.release_reserved();
+        self.root = ret?;
+        self.len += other.len;
+        // other 的数据是写进它的时候记的, 写的字节也在共用的 engine 的统计里
+        self.stats.logical_bytes += other.stats.logical_bytes;
+        self.record_history();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        block::MemoryBlockEngine,
+        fault::{FaultOptions, FaultyBlockEngine},
+        fsck::{self, VerifyProblem},
+        tree::tests::check_shape,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_remove_range() {
+        for way in [2, 3, 4, 7] {
+            for persistent in [false, true] {
+                let mut tree = BPlusTree::new(way, MemoryBlockEngine::new()).unwrap();
+                let mut expected = BTreeMap::new();
+                for i in 0..400u32 {
+                    tree.insert(i, i).unwrap();
+                    expected.insert(i, i);
+                }
+                let mut seed = 7u32;
+                for round in 0..40 {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    let start = (seed >> 16) % 420;
+                    let end = start + (seed >> 8) % 60;
+                    let frozen = persistent.then(|| (tree.freeze(), expected.clone()));
+                    let removed = match round % 4 {
+                        0 => tree.remove_range(start..end).unwrap(),
+                        1 => tree.remove_range(start..=end).unwrap(),
+                        2 => tree.remove_range((Bound::Excluded(start), Bound::Included(end))).unwrap(),
+                        _ => tree.remove_range(..start / 8).unwrap(),
+                    };
+                    let gone: Vec<_> = match round % 4 {
+                        0 => expected.range(start..end).map(|(&k, _)| k).collect(),
+                        1 => expected.range(start..=end).map(|(&k, _)| k).collect(),
+                        2 => expected.range((Bound::Excluded(start), Bound::Included(end))).map(|(&k, _)| k).collect(),
+                        _ => expected.range(..start / 8).map(|(&k, _)| k).collect(),
+                    };
+                    for key in &gone {
+                        expected.remove(key);
+                    }
+                    assert_eq!(removed, gone.len());
+                    assert_eq!(tree.len(), expected.len());
+                    check_shape(&tree, tree.root, true);
+                    assert_eq!(tree.iter().collect::<Vec<_>>(), expected.clone().into_iter().collect::<Vec<_>>());
+                    assert_eq!(tree.iter().rev().count(), expected.len());
+                    if let Some((version, before)) = frozen {
+                        for (key, value) in before {
+                            assert_eq!(tree.search_at(version, &key).unwrap(), Some(value));
+                        }
+                    }
+                    // 删完之后还能正常插入
+                    tree.insert(start, start).unwrap();
+                    expected.insert(start, start);
+                }
+                assert_eq!(tree.remove_range(..).unwrap(), expected.len());
+                assert!(tree.is_empty() && tree.iter().next().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_append() {
+        for way in [2, 3, 5] {
+            for persistent in [false, true] {
+                let engine = Arc::new(MemoryBlockEngine::new());
+                let mut tree = BPlusTree::new(way, engine.clone()).unwrap();
+                let frozen = persistent.then(|| tree.freeze());
+                // 高度不同的几棵树依次接上去, 只分配修接缝要的 block
+                for (start, count) in [(0u32, 3u32), (3, 200), (203, 1), (204, 40), (244, 0)] {
+                    let mut other = BPlusTree::new(way, engine.clone()).unwrap();
+                    for i in start..start + count {
+                        other.insert(i, i).unwrap();
+                    }
+                    if persistent {
+                        other.create_snapshot("other").unwrap();
+                        other.insert(start + count + 1000, 0).unwrap();
+                        other.delete(&(start + count + 1000)).unwrap();
+                    }
+                    let height = tree.height(tree.root).unwrap().max(other.height(other.root).unwrap()) + 1;
+                    let allocations = engine.stats().allocations;
+                    tree.append(other).unwrap();
+                    assert!(engine.stats().allocations - allocations <= 3 * height as u64 + 1);
+                    check_shape(&tree, tree.root, true);
+                    assert_eq!(tree.len(), (start + count) as usize);
+                    assert_eq!(tree.keys().collect::<Vec<_>>(), (0..start + count).collect::<Vec<_>>());
+                    assert_eq!(tree.keys().rev().count(), tree.len());
+                }
+                let mut other = BPlusTree::new(way, engine.clone()).unwrap();
+                other.insert(100, 0).unwrap();
+                let rejected = other.root;
+                assert!(tree.append(other).is_err());
+                let mut other = BPlusTree::new(way, Arc::new(MemoryBlockEngine::new())).unwrap();
+                other.insert(1000, 0).unwrap();
+                assert!(tree.append(other).is_err());
+                assert_eq!(tree.len(), 244);
+                if let Some(version) = frozen {
+                    assert_eq!(tree.search_at(version, &10).unwrap(), None);
+                    tree.drop_version(version).unwrap();
+                }
+                // 接上来的树的 snapshot 都还掉了, 只有同一个 engine 上被拒绝的那棵树的叶子没有回收
+                tree.verify().unwrap();
+                let report = fsck::verify_tree(&tree, fsck::VerifyMode::Full).unwrap();
+                assert!(report.problems.iter().all(|problem| *problem == VerifyProblem::Unreachable { block_id: rejected }));
+                assert_eq!(report.reachable + report.free + 1, report.blocks);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_off() {
+        for way in [2, 3, 5] {
+            for persistent in [false, true] {
+                let mut tree = BPlusTree::new(way, Arc::new(MemoryBlockEngine::new())).unwrap();
+                for i in 0..300u32 {
+                    tree.insert(i, i).unwrap();
+                }
+                let frozen = persistent.then(|| tree.freeze());
+                // 只切开一条路径, 分配的 block 和高度成正比, 不随切下来的条目数增长
+                let (allocations, height) = (tree.engine_stats().allocations, tree.height(tree.root).unwrap() + 1);
+                let mut right = tree.split_off(&120).unwrap();
+                assert!(tree.engine_stats().allocations - allocations <= 4 * height as u64);
+                let mut rest = right.split_off(&250).unwrap();
+                for (part, range) in [(&tree, 0..120), (&right, 120..250), (&rest, 250..300)] {
+                    check_shape(part, part.root, true);
+                    assert_eq!(part.len(), range.len());
+                    assert_eq!(part.keys().collect::<Vec<_>>(), range.clone().collect::<Vec<_>>());
+                    assert_eq!(part.keys().rev().collect::<Vec<_>>(), range.rev().collect::<Vec<_>>());
+                }
+                assert!(tree.split_off(&1000).unwrap().is_empty());
+                assert_eq!(rest.split_off(&0).unwrap().len(), 50);
+                assert!(rest.is_empty());
+                // 切下来的树是独立的, 可以继续写, 冻结的版本还是原来的样子
+                for i in 120..200 {
+                    right.delete(&i).unwrap();
+                    tree.insert(i + 1000, i).unwrap();
+                }
+                right.insert(1000, 0).unwrap();
+                assert_eq!(right.len(), 51);
+                assert_eq!(tree.len(), 200);
+                right.verify().unwrap();
+                tree.verify().unwrap();
+                if let Some(version) = frozen {
+                    assert_eq!(tree.at(version).iter().map(|(key, _)| key).collect::<Vec<_>>(), (0..300).collect::<Vec<_>>());
+                    tree.drop_version(version).unwrap();
+                    assert_eq!(right.keys().next(), Some(200));
+                    right.verify().unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_retain_clear() {
+        for persistent in [false, true] {
+            // 容量只够一棵树 (持久化模式下再加上冻结的那棵), clear 没有回收的话后面几轮会 StorageFull
+            let capacity = if persistent { 350 } else { 200 };
+            let mut tree = BPlusTree::new(3, MemoryBlockEngine::with_capacity(capacity)).unwrap();
+            for round in 0..8 {
+                for i in 0..150u32 {
+                    tree.insert(i, i).unwrap();
+                }
+                let frozen = (persistent && round == 0).then(|| tree.freeze());
+                assert_eq!(tree.retain(|key, _| key % 3 == 0).unwrap(), 100);
+                check_shape(&tree, tree.root, true);
+                assert_eq!(tree.keys().collect::<Vec<_>>(), (0..150).step_by(3).collect::<Vec<_>>());
+                assert_eq!(tree.keys().rev().count(), 50);
+                tree.clear().unwrap();
+                assert!(tree.is_empty() && tree.iter().next().is_none());
+                if let Some(version) = frozen {
+                    assert_eq!(tree.search_at(version, &1).unwrap(), Some(1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_retain_read_error() {
+        let engine = FaultyBlockEngine::new(MemoryBlockEngine::new(), FaultOptions::default());
+        let mut tree = BPlusTree::new(3, engine).unwrap();
+        for i in 0..200u32 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.engine.set_options(FaultOptions { read_error: 0.05, seed: 3, ..FaultOptions::default() });
+        assert!(tree.retain(|key, _| key % 2 == 0).is_err());
+        tree.engine.set_options(FaultOptions::default());
+        assert_eq!(tree.len(), 200);
+        assert_eq!(tree.retain(|key, _| key % 2 == 0).unwrap(), 100);
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{anyhow, Ok, Result};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use crate::block::{BlockId, TreeMeta};
+
+const MAGIC: &[u8; 4] = b"BPTF";
+const FORMAT_VERSION: u32 = 12;
+// magic, format, page size, seq, way, root, 条目数, block 数, free list 头, checkpoint 时 wal 的 lsn, 树的标记, key 顺序的 id,
+// 用户数据的长度和内容, catalog 的长度和内容, snapshot 的长度和内容, crc32; SUPERBLOCK_LEN 是用户数据最长, catalog 和 snapshot 都为空时的长度
+const FIXED_LEN: usize = 4 + 4 + 4 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8 + 8;
+pub const MAX_USER_METADATA: usize = 128;
+pub(crate) const SUPERBLOCK_LEN: usize = FIXED_LEN + 4 + MAX_USER_METADATA + 4 + 4 + 4;
+// catalog 里每棵树: 名字的长度和内容, root, way, 条目数, 树的标记, key 顺序的 id; snapshot 也一样
+// catalog 和 snapshot 加起来不能超过 MAX_CATALOG
+pub(crate) const MAX_CATALOG: usize = 256;
+const CATALOG_ENTRY_LEN: usize = 1 + 8 * 5;
+// 树的标记里的位
+const PERSISTENT: u64 = 1;
+// 两份 superblock 各占 512 字节, 和页大小无关, 这样第一份坏了也能找到第二份
+const SLOT_SIZE: usize = 512;
+const _: () = assert!(SUPERBLOCK_LEN + MAX_CATALOG <= SLOT_SIZE);
+const NONE: u64 = u64::MAX;
+
+// 文件开头记着整个文件状态的元数据, 有两份, 轮流写, seq 大的那份是新的
+// 写到一半崩溃时那一份的校验和对不上, 打开时用另一份, 所以换 root 是原子的
+// 两份后面从下一个整页开始放 block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Superblock {
+    pub(crate) page_size: usize,
+    pub(crate) seq: u64,
+    // 还没有 flush 过树时是 None
+    pub(crate) meta: Option<TreeMeta>,
+    pub(crate) block_count: usize,
+    pub(crate) free_head: Option<BlockId>,
+    pub(crate) checkpoint_lsn: u64,
+    // 用户自己的一小段数据, 和 root 一起换
+    pub(crate) user_metadata: Vec<u8>,
+    // TreeGroup 里按名字记的树, 和 meta 里的树互不相干
+    pub(crate) trees: BTreeMap<String, TreeMeta>,
+    // 主树的有名字的 snapshot, 见 BPlusTree::create_snapshot
+    pub(crate) snapshots: BTreeMap<String, TreeMeta>,
+}
+
+impl Superblock {
+    pub(crate) fn new(page_size: usize) -> Self {
+        Superblock {
+            page_size,
+            seq: 0,
+            meta: None,
+            block_count: 0,
+            free_head: None,
+            checkpoint_lsn: 0,
+            user_metadata: vec![],
+            trees: BTreeMap::new(),
+            snapshots: BTreeMap::new(),
+        }
+    }
+
+    // 名字不能超过 255 字节
+    pub(crate) fn catalog_len(trees: &BTreeMap<String, TreeMeta>) -> usize {
+        trees.keys().map(|name| Self::entry_len(name)).sum()
+    }
+
+    pub(crate) fn entry_len(name: &str) -> usize {
+        CATALOG_ENTRY_LEN + name.len()
+    }
+
+    // block 0 所在的页
+    pub(crate) fn first_block_page(page_size: usize) -> usize {
+        (2 * SLOT_SIZE).div_ceil(page_size)
+    }
+
+    pub(crate) fn encoded_len(&self) -> usize {
+        FIXED_LEN + 4 + self.user_metadata.len() + 4 + Self::catalog_len(&self.trees) + 4 + Self::catalog_len(&self.snapshots) + 4
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        buf.extend_from_slice(&(self.page_size as u32).to_le_bytes());
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        let (way, root, len) = self.meta.map_or((0, NONE, 0), |meta| (meta.way as u64, meta.root as u64, meta.len as u64));
+        let free_head = self.free_head.map_or(NONE, |id| id as u64);
+        let flags = if self.meta.is_some_and(|meta| meta.persistent) { PERSISTENT } else { 0 };
+        let order = self.meta.map_or(0, |meta| meta.order);
+        for n in [way, root, len, self.block_count as u64, free_head, self.checkpoint_lsn, flags, order] {
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+        buf.extend_from_slice(&(self.user_metadata.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.user_metadata);
+        encode_catalog(&mut buf, &self.trees);
+        encode_catalog(&mut buf, &self.snapshots);
+        let checksum = crc32fast::hash(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        buf
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> Result<Self> {
+        let fixed = buf.get(..FIXED_LEN + 4).ok_or_else(|| anyhow!("superblock is truncated."))?;
+        if &fixed[0..4] != MAGIC {
+            return Err(anyhow!("not a block file."));
+        }
+        let user_len = u32::from_le_bytes(fixed[FIXED_LEN..].try_into()?) as usize;
+        if user_len > MAX_USER_METADATA {
+            return Err(anyhow!("superblock checksum mismatch."));
+        }
+        let catalog_at = FIXED_LEN + 4 + user_len;
+        let catalog_len = buf.get(catalog_at..catalog_at + 4).ok_or_else(|| anyhow!("superblock is truncated."))?;
+        let catalog_len = u32::from_le_bytes(catalog_len.try_into()?) as usize;
+        let snapshots_at = catalog_at + 4 + catalog_len;
+        let snapshots_len = buf.get(snapshots_at..snapshots_at + 4).ok_or_else(|| anyhow!("superblock is truncated."))?;
+        let snapshots_len = u32::from_le_bytes(snapshots_len.try_into()?) as usize;
+        if catalog_len + snapshots_len > MAX_CATALOG {
+            return Err(anyhow!("superblock checksum mismatch."));
+        }
+        let len = snapshots_at + 4 + snapshots_len + 4;
+        let buf = buf.get(..len).ok_or_else(|| anyhow!("superblock is truncated."))?;
+        let (body, checksum) = buf.split_at(len - 4);
+        if crc32fast::hash(body).to_le_bytes() != checksum {
+            return Err(anyhow!("superblock checksum mismatch."));
+        }
+        let format = u32::from_le_bytes(buf[4..8].try_into()?);
+        if format != FORMAT_VERSION {
+            return Err(anyhow!("unsupported block file format: {}.", format));
+        }
+        let n = |i: usize| u64::from_le_bytes(buf[12 + i * 8..20 + i * 8].try_into().unwrap());
+        let meta = (n(2) != NONE).then(|| TreeMeta {
+            way: n(1) as usize,
+            root: n(2) as BlockId,
+            len: n(3) as usize,
+            persistent: n(7) & PERSISTENT != 0,
+            order: n(8),
+        });
+        Ok(Superblock {
+            page_size: u32::from_le_bytes(buf[8..12].try_into()?) as usize,
+            seq: n(0),
+            meta,
+            block_count: n(4) as usize,
+            free_head: (n(5) != NONE).then_some(n(5) as BlockId),
+            checkpoint_lsn: n(6),
+            user_metadata: body[FIXED_LEN + 4..catalog_at].to_vec(),
+            trees: decode_catalog(&body[catalog_at + 4..snapshots_at])?,
+            snapshots: decode_catalog(&body[snapshots_at + 4..])?,
+        })
+    }
+
+    // 两份里 seq 大的有效的那份, 都坏了时返回第一份的错误
+    pub(crate) fn read(file: &mut File) -> Result<Self> {
+        let mut area = vec![0; 2 * SLOT_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        // 文件可能比两份短, 读到多少算多少, 没读到的按坏了算
+        let mut read = 0;
+        while read < area.len() {
+            match file.read(&mut area[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        let mut best: Option<Superblock> = None;
+        let mut first_error = None;
+        for slot in area.chunks(SLOT_SIZE) {
+            match Superblock::decode(slot) {
+                Result::Ok(superblock) if best.as_ref().is_none_or(|best| superblock.seq > best.seq) => best = Some(superblock),
+                Result::Ok(_) => {}
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        best.ok_or_else(|| first_error.unwrap_or_else(|| anyhow!("not a block file.")))
+    }
+
+    // 写到 seq 对应的那一份上, 调用的人负责 fsync
+    pub(crate) fn write(&self, file: &mut File) -> Result<()> {
+        file.seek(SeekFrom::Start((self.seq % 2) * SLOT_SIZE as u64))?;
+        file.write_all(&self.encode())?;
+        Ok(())
+    }
+}
+
+fn encode_catalog(buf: &mut Vec<u8>, trees: &BTreeMap<String, TreeMeta>) {
+    buf.extend_from_slice(&(Superblock::catalog_len(trees) as u32).to_le_bytes());
+    for (name, meta) in trees {
+        buf.push(name.len() as u8);
+        buf.extend_from_slice(name.as_bytes());
+        let flags = if meta.persistent { PERSISTENT } else { 0 };
+        for n in [meta.root as u64, meta.way as u64, meta.len as u64, flags, meta.order] {
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+fn decode_catalog(mut buf: &[u8]) -> Result<BTreeMap<String, TreeMeta>> {
+    let mut trees = BTreeMap::new();
+    while let Some((&name_len, rest)) = buf.split_first() {
+        let entry = rest.get(..name_len as usize + CATALOG_ENTRY_LEN - 1).ok_or_else(|| anyhow!("catalog is truncated."))?;
+        let (name, numbers) = entry.split_at(name_len as usize);
+        let n = |i: usize| u64::from_le_bytes(numbers[i * 8..i * 8 + 8].try_into().unwrap());
+        let meta = TreeMeta {
+            root: n(0) as BlockId,
+            way: n(1) as usize,
+            len: n(2) as usize,
+            persistent: n(3) & PERSISTENT != 0,
+            order: n(4),
+        };
+        trees.insert(String::from_utf8(name.to_vec())?, meta);
+        buf = &rest[entry.len()..];
+    }
+    Ok(trees)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use crate::{
+        block::BlockEngine,
+        file::{FileBlockEngine, FileOptions},
+        tree::{BPlusTree, BPlusTreeNode},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_superblock() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-superblock-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, ..FileOptions::default() };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap();
+        let mut tree = BPlusTree::new(5, engine).unwrap();
+        for i in 0..100 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.flush().unwrap();
+        // 只换 root 不改 block 时, 上一份 superblock 里的树还是完整的
+        let old = tree.meta();
+        tree.flush().unwrap();
+        drop(tree);
+
+        // 不用给 way, 条目数也不用重新数
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        let tree = BPlusTree::open(engine).unwrap();
+        assert_eq!((tree.way, tree.len()), (5, 100));
+        tree.verify().unwrap();
+        drop(tree);
+
+        // 新的那份写坏了, 用旧的那份
+        let mut file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let newest = Superblock::read(&mut file).unwrap();
+        file.seek(SeekFrom::Start((newest.seq % 2) * SLOT_SIZE as u64 + 30)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        let fallback = Superblock::read(&mut file).unwrap();
+        assert_eq!((fallback.seq, fallback.meta), (newest.seq - 1, Some(old)));
+        drop(file);
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        assert_eq!(engine.load_meta(), Some(old));
+        std::fs::remove_file(&path).unwrap();
+
+        // 两份都是坏的时不是一个 block 文件
+        std::fs::write(&path, [0; 100]).unwrap();
+        assert!(FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_user_metadata() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-user-metadata-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, ..FileOptions::default() };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap();
+        let mut tree = BPlusTree::new(5, engine).unwrap();
+        tree.set_user_metadata(b"schema=1").unwrap();
+        tree.insert(1, 1).unwrap();
+        tree.flush().unwrap();
+        assert!(tree.set_user_metadata(&[0; MAX_USER_METADATA + 1]).is_err());
+        // 没 flush 时读到的是新的, 文件里还是旧的, 和旧的 root 在一起
+        tree.set_user_metadata(b"schema=2").unwrap();
+        tree.insert(2, 2).unwrap();
+        assert_eq!(tree.user_metadata().unwrap(), b"schema=2");
+        drop(tree);
+
+        let mut tree = BPlusTree::<u32, u32, _>::open(FileBlockEngine::open(&path, options).unwrap()).unwrap();
+        assert_eq!(tree.user_metadata().unwrap(), b"schema=1");
+        assert_eq!(tree.len(), 1);
+        tree.set_user_metadata(b"schema=2").unwrap();
+        tree.flush().unwrap();
+        drop(tree);
+        let tree = BPlusTree::<u32, u32, _>::open(FileBlockEngine::open(&path, options).unwrap()).unwrap();
+        assert_eq!(tree.user_metadata().unwrap(), b"schema=2");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
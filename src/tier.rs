@@ -0,0 +1,330 @@
+use anyhow::{Ok, Result};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex, RwLock, TryLockError,
+    },
+};
+
+use crate::{
+    block::{Block, BlockEngine, BlockEngineStats, BlockId, BlockReadGuard, BlockWriteGuard, TreeMeta},
+    error::Error,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TierOptions {
+    // 内存层最多放多少个 block
+    pub hot_capacity: usize,
+    // 一个 block 的访问次数至少这么多才会被提到内存层
+    pub promote_after: u32,
+    // 每访问这么多次重新挑一次内存层的 block
+    pub interval: usize,
+}
+
+impl Default for TierOptions {
+    fn default() -> Self {
+        TierOptions { hot_capacity: 1024, promote_after: 2, interval: 4096 }
+    }
+}
+
+// 访问多的 block 复制一份放在内存层, 其余的留在下面的 engine (磁盘或者对象存储) 里, 对树是透明的
+// 每个 block 记着访问次数, 每隔 interval 次访问按次数重新挑一次, 挑完次数减半, 最近访问得多的才算热
+// 读写, 分配和回收都只有 &self, 挑选要等到下一次 flush 时才做, 只读的时候靠定期 flush 调整
+// 回收的 block 在内存层里留着一个无效的位置, 下次挑选时才拿掉
+// 内存层里改过的 block 在被换出或者 flush 时才写回下面的 engine
+pub struct TieredBlockEngine<E, B> {
+    cold: E,
+    hot: HashMap<BlockId, RwLock<Block<B>>>,
+    // 内存层里改过, 还没写回 cold 的
+    dirty: Mutex<HashSet<BlockId>>,
+    counts: Mutex<HashMap<BlockId, u32>>,
+    accesses: AtomicUsize,
+    // 在内存层里找到的次数
+    hot_hits: AtomicU64,
+    options: TierOptions,
+}
+
+impl<E, B> TieredBlockEngine<E, B>
+where
+    E: BlockEngine<Item = B>,
+    B: Clone,
+{
+    pub fn new(cold: E, options: TierOptions) -> Self {
+        TieredBlockEngine {
+            cold,
+            hot: HashMap::new(),
+            dirty: Mutex::new(HashSet::new()),
+            counts: Mutex::new(HashMap::new()),
+            accesses: AtomicUsize::new(0),
+            hot_hits: AtomicU64::new(0),
+            options,
+        }
+    }
+
+    // 内存层里现在有几个 block
+    pub fn hot_len(&self) -> usize {
+        self.hot.len()
+    }
+
+    pub fn into_inner(mut self) -> Result<E> {
+        self.write_dirty()?;
+        Ok(self.cold)
+    }
+
+    // 按访问次数重新挑内存层的 block, 换出去的改过的要先写回
+    pub fn rebalance(&mut self) -> Result<()> {
+        let counts = self.counts.get_mut().map_err(|_| Error::LockPoisoned)?;
+        let promote_after = self.options.promote_after;
+        let mut ranked: Vec<_> =
+            counts.iter().filter(|(_, &count)| count >= promote_after).map(|(&id, &count)| (count, id)).collect();
+        ranked.sort_unstable_by(|a, b| b.cmp(a));
+        let wanted: HashSet<_> = ranked.into_iter().take(self.options.hot_capacity).map(|(_, id)| id).collect();
+        counts.retain(|_, count| {
+            *count /= 2;
+            *count > 0
+        });
+        self.accesses.store(0, Ordering::Relaxed);
+
+        let demoted: Vec<_> = self.hot.keys().copied().filter(|id| !wanted.contains(id)).collect();
+        for block_id in demoted {
+            let block = self.hot.remove(&block_id).unwrap().into_inner().map_err(|_| Error::LockPoisoned)?;
+            if self.dirty.get_mut().map_err(|_| Error::LockPoisoned)?.remove(&block_id) {
+                let mut cold = self.cold.fetch_write(block_id)?;
+                cold.valid = block.valid;
+                cold.content = block.content;
+            }
+        }
+        for block_id in wanted {
+            if self.hot.contains_key(&block_id) {
+                continue;
+            }
+            let cold = self.cold.fetch_read(block_id)?;
+            let block = Block { valid: cold.valid, id: block_id, content: cold.content.clone() };
+            drop(cold);
+            self.hot.insert(block_id, RwLock::new(block));
+        }
+        Ok(())
+    }
+
+    fn touch(&self, block_id: BlockId) -> Result<()> {
+        *self.counts.lock().map_err(|_| Error::LockPoisoned)?.entry(block_id).or_default() += 1;
+        self.accesses.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn rebalance_due(&mut self) -> Result<()> {
+        if self.accesses.load(Ordering::Relaxed) >= self.options.interval {
+            self.rebalance()?;
+        }
+        Ok(())
+    }
+
+    // 内存层留着, 只把改过的复制一份写回 cold
+    fn write_dirty(&mut self) -> Result<()> {
+        let dirty = std::mem::take(self.dirty.get_mut().map_err(|_| Error::LockPoisoned)?);
+        for block_id in dirty {
+            let hot = self.hot[&block_id].read().map_err(|_| Error::LockPoisoned)?;
+            let mut cold = self.cold.fetch_write(block_id)?;
+            cold.valid = hot.valid;
+            cold.content = hot.content.clone();
+        }
+        Ok(())
+    }
+}
+
+impl<E, B> BlockEngine for TieredBlockEngine<E, B>
+where
+    E: BlockEngine<Item = B>,
+    B: Clone,
+{
+    type Item = B;
+
+    // 新的 block 先放在 cold, 访问多了再提上来; 复用的 block 在内存层里还有位置的话接着用它
+    fn alloc_block(&self) -> Result<BlockId> {
+        let block_id = self.cold.alloc_block()?;
+        if let Some(block) = self.hot.get(&block_id) {
+            *block.write().map_err(|_| Error::LockPoisoned)? = Block { valid: true, id: block_id, content: None };
+            self.dirty.lock().map_err(|_| Error::LockPoisoned)?.insert(block_id);
+        }
+        Ok(block_id)
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, B>> {
+        self.touch(block_id)?;
+        match self.hot.get(&block_id) {
+            Some(block) => {
+                self.hot_hits.fetch_add(1, Ordering::Relaxed);
+                Ok(BlockReadGuard { rwlock_guard: block.read().map_err(|_| Error::LockPoisoned)? })
+            }
+            None => self.cold.fetch_read(block_id),
+        }
+    }
+
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, B>> {
+        self.touch(block_id)?;
+        match self.hot.get(&block_id) {
+            Some(block) => {
+                self.hot_hits.fetch_add(1, Ordering::Relaxed);
+                self.dirty.lock().map_err(|_| Error::LockPoisoned)?.insert(block_id);
+                let rwlock_guard = block.write().map_err(|_| Error::LockPoisoned)?;
+                Ok(BlockWriteGuard { rwlock_guard, write_back: |_, _| {} })
+            }
+            None => self.cold.fetch_write(block_id),
+        }
+    }
+
+    fn try_fetch_write(&self, block_id: BlockId) -> Result<Option<BlockWriteGuard<'_, B>>> {
+        self.touch(block_id)?;
+        let Some(block) = self.hot.get(&block_id) else {
+            return self.cold.try_fetch_write(block_id);
+        };
+        let rwlock_guard = match block.try_write() {
+            std::result::Result::Ok(rwlock_guard) => rwlock_guard,
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Poisoned(_)) => return Err(Error::LockPoisoned.into()),
+        };
+        self.hot_hits.fetch_add(1, Ordering::Relaxed);
+        self.dirty.lock().map_err(|_| Error::LockPoisoned)?.insert(block_id);
+        Ok(Some(BlockWriteGuard { rwlock_guard, write_back: |_, _| {} }))
+    }
+
+    fn delete(&self, block_id: BlockId) -> Result<Option<B>> {
+        self.counts.lock().map_err(|_| Error::LockPoisoned)?.remove(&block_id);
+        self.dirty.lock().map_err(|_| Error::LockPoisoned)?.remove(&block_id);
+        let cold = self.cold.delete(block_id)?;
+        match self.hot.get(&block_id) {
+            Some(block) => {
+                let mut block = block.write().map_err(|_| Error::LockPoisoned)?;
+                block.valid = false;
+                Ok(block.content.take())
+            }
+            None => Ok(cold),
+        }
+    }
+
+    fn write_back(block_id: BlockId, block: &Block<B>) {
+        E::write_back(block_id, block)
+    }
+
+    fn load_meta(&self) -> Option<TreeMeta> {
+        self.cold.load_meta()
+    }
+
+    fn set_user_metadata(&mut self, bytes: &[u8]) -> Result<()> {
+        self.cold.set_user_metadata(bytes)
+    }
+
+    fn user_metadata(&self) -> Result<Vec<u8>> {
+        self.cold.user_metadata()
+    }
+
+    fn stage_snapshots(&self, snapshots: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        self.cold.stage_snapshots(snapshots)
+    }
+
+    fn load_snapshots(&self) -> Result<BTreeMap<String, TreeMeta>> {
+        self.cold.load_snapshots()
+    }
+
+    fn flush(&mut self, meta: TreeMeta) -> Result<()> {
+        self.rebalance_due()?;
+        self.write_dirty()?;
+        self.cold.flush(meta)
+    }
+
+    fn checkpoint(&mut self, meta: TreeMeta) -> Result<()> {
+        self.rebalance_due()?;
+        self.write_dirty()?;
+        self.cold.checkpoint(meta)
+    }
+
+    // 截掉的只会是空闲的 block, 内存层里没有
+    fn vacuum(&mut self, meta: TreeMeta) -> Result<usize> {
+        self.write_dirty()?;
+        self.cold.vacuum(meta)
+    }
+
+    // 内存层里已经有的不用预读
+    fn prefetch(&self, block_ids: &[BlockId]) -> Result<usize> {
+        let cold: Vec<_> = block_ids.iter().copied().filter(|block_id| !self.hot.contains_key(block_id)).collect();
+        self.cold.prefetch(&cold)
+    }
+
+    // 内存层里的总是在, 只记 cold 缓存里的
+    fn resident(&self) -> Result<Vec<BlockId>> {
+        self.cold.resident()
+    }
+
+    // 内存层只在 &mut 的时候换出, 只要 pin 住 cold 里的
+    fn pin(&self, block_id: BlockId) -> Result<()> {
+        self.cold.pin(block_id)
+    }
+
+    fn unpin(&self, block_id: BlockId) -> Result<()> {
+        self.cold.unpin(block_id)
+    }
+
+    fn enter_epoch(&self) -> Result<u64> {
+        self.cold.enter_epoch()
+    }
+
+    fn leave_epoch(&self, epoch: u64) {
+        self.cold.leave_epoch(epoch)
+    }
+
+    // 内存层命中也算命中, 改过还没写回 cold 的也算 dirty
+    fn stats(&self) -> BlockEngineStats {
+        let cold = self.cold.stats();
+        BlockEngineStats {
+            hits: cold.hits + self.hot_hits.load(Ordering::Relaxed),
+            dirty: cold.dirty + self.dirty.lock().map_or(0, |dirty| dirty.len()),
+            ..cold
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::{
+        block::MemoryBlockEngine,
+        tree::{BPlusTree, BPlusTreeNode},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_tiered_engine() {
+        let options = TierOptions { hot_capacity: 8, promote_after: 2, interval: 64 };
+        let engine = TieredBlockEngine::new(MemoryBlockEngine::<BPlusTreeNode<u32, u32>>::new(), options);
+        let mut tree = BPlusTree::new(4, engine).unwrap();
+        let mut expected = BTreeMap::new();
+        for i in 0..1000 {
+            tree.insert(i, i).unwrap();
+            expected.insert(i, i);
+        }
+        // 热点一直在变, 改过的 block 要在换出时写回
+        for round in 0..10 {
+            for _ in 0..20 {
+                for key in round * 100..round * 100 + 10 {
+                    assert_eq!(tree.search(&key).unwrap(), Some(key));
+                }
+            }
+            for key in round * 100..round * 100 + 10 {
+                tree.insert(key, key + 1).unwrap();
+                expected.insert(key, key + 1);
+            }
+            tree.delete(&(round * 100 + 50)).unwrap();
+            expected.remove(&(round * 100 + 50));
+            // 只在 flush 时重新挑
+            tree.flush().unwrap();
+            assert!(tree.engine.hot_len() <= 8);
+        }
+        tree.flush().unwrap();
+        assert!(tree.engine.hot_len() > 0);
+        tree.verify().unwrap();
+        assert!(expected.iter().all(|(key, value)| tree.search(key).unwrap() == Some(*value)));
+    }
+}
@@ -1,67 +1,196 @@
-use anyhow::{Ok, Result};
-use std::{cell::Cell, fmt::Debug, marker::PhantomData};
+use anyhow::{anyhow, Ok, Result};
+use std::{cmp::Ordering, collections::{BTreeMap, HashMap, HashSet}, marker::PhantomData, sync::Arc};
 
-use crate::block::{BlockEngine, BlockId};
+use crate::{
+    amplification::{record_written, EntrySizeFn},
+    block::{BlockEngine, BlockEngineStats, BlockId, BlockWriteGuard, TreeMeta},
+    capacity::NodeCapacity,
+    error::Error,
+    order::KeyOrder,
+    refcount::RefCounts,
+    shadow::Shadow,
+    snapshot::History,
+};
 
 pub struct BPlusTree<K, V, E>
 where
     E: BlockEngine<Item = BPlusTreeNode<K, V>>,
     K: Ord,
 {
-    way: usize,
-    engine: E,
-    root: BlockId,
+    pub(crate) way: usize,
+    pub(crate) engine: E,
+    pub(crate) root: BlockId,
+    pub(crate) order: KeyOrder<K>,
+    pub(crate) capacity: NodeCapacity<K, V>,
+    // 当前 root 下的条目数, 增删时维护
+    pub(crate) len: usize,
+    // freeze 之后进入持久化模式: 被旧版本共享的结点不能原地修改
+    pub(crate) persistent: bool,
+    // 当前 root 独占的 block (上一次 freeze 之后新分配的, 或者只剩当前 root 引用的), 可以原地修改
+    pub(crate) owned: HashSet<BlockId>,
+    // 冻结了的 block 被引用了几次, 见 RefCounts
+    pub(crate) refs: RefCounts,
+    // 有名字的 snapshot, flush 时和 root 一起交给 engine, 见 stage_meta
+    pub(crate) snapshots: HashMap<String, Version>,
+    pub(crate) history: History,
+    // 开了 shadow paging 时上一次提交的版本, 见 enable_shadow_paging
+    pub(crate) shadow: Option<Shadow>,
+    // 有的话内部结点的 maxes 记着每个子树里按它最大的 key, 见 IntervalMap; 只在内存里, open 之后要重新设
+    pub(crate) augment: Option<Augment<K>>,
+    // 有的话 insert 时按它记 logical_bytes, 见 set_entry_size
+    pub(crate) entry_size: Option<Arc<EntrySizeFn<K, V>>>,
+    // 写操作开始前预先分配好的空 block, 见 reserve
+    reserved: Vec<BlockId>,
+    pub(crate) stats: TreeStats,
     _marker1: PhantomData<K>,
     _marker2: PhantomData<V>,
 }
 
+// 给子树做汇总用的另一种顺序, 比如区间按 end 比较
+pub(crate) type Augment<K> = fn(&K, &K) -> Ordering;
+
+// 某一次 freeze 时的 root, 拿着一个引用, drop_version 之前一直有效
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Version {
+    pub(crate) root: BlockId,
+    pub(crate) len: usize,
+}
+
+// 树上写操作的计数, 从创建或者打开起算
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TreeStats {
+    pub inserts: u64,
+    pub deletes: u64,
+    // 叶子和内部结点都算
+    pub splits: u64,
+    pub merges: u64,
+    // 写进来的 key 和 value 的字节数, 原地修改的也算, 没有 set_entry_size 时是 0
+    pub logical_bytes: u64,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "file", derive(serde::Serialize, serde::Deserialize))]
 pub struct BPlusTreeNode<K: Ord, V> {
-    parent: Cell<Option<BlockId>>,
-    way: usize,
-    is_leaf: bool,
+    pub(crate) way: usize,
+    pub(crate) is_leaf: bool,
     // sorted
-    keys: Vec<K>,
+    pub(crate) keys: Vec<K>,
     // leaf only
-    values: Vec<V>,
-    prev: Option<BlockId>,
-    next: Option<BlockId>,
+    pub(crate) values: Vec<V>,
+    // 持久化模式下复制出来的叶子的 prev / next 可能指向旧版本, 不可信
+    pub(crate) prev: Option<BlockId>,
+    pub(crate) next: Option<BlockId>,
+    // B-link 的 high key, 结点里的 key 都比它小, 每层最右边的结点没有; 内部结点的 next 是同一层右边的结点
+    // 只有 ConcurrentBPlusTree 维护这两项, 普通的树不看也不改, into_concurrent 时重新算
+    pub(crate) high_key: Option<K>,
 
     // inner only
-    pointers: Vec<BlockId>,
+    pub(crate) pointers: Vec<BlockId>,
+    // counts[i] 是 pointers[i] 子树里的条目数
+    pub(crate) counts: Vec<usize>,
+    // maxes[i] 是 pointers[i] 子树里按树的 augment 最大的 key, 和 counts 一一对应; 没有 augment 的树里都是 None
+    pub(crate) maxes: Vec<Option<K>>,
 }
 
 impl<K: Ord, V> BPlusTreeNode<K, V> {
-    fn is_leaf(&self) -> bool {
+    pub(crate) fn is_leaf(&self) -> bool {
         self.is_leaf
     }
 
-    fn new_leaf(way: usize, parent: Option<usize>) -> BPlusTreeNode<K, V> {
+    pub(crate) fn new_leaf(way: usize) -> BPlusTreeNode<K, V> {
         BPlusTreeNode {
-            parent: Cell::new(parent),
             way,
             is_leaf: true,
             keys: vec![],
             values: vec![],
             prev: None,
             next: None,
+            high_key: None,
             pointers: vec![],
+            counts: vec![],
+            maxes: vec![],
         }
     }
 
-    fn new_inner(way: usize) -> BPlusTreeNode<K, V> {
+    pub(crate) fn new_inner(way: usize) -> BPlusTreeNode<K, V> {
         BPlusTreeNode {
-            parent: Cell::new(None),
             way,
             is_leaf: false,
             keys: vec![],
             values: vec![],
             prev: None,
             next: None,
+            high_key: None,
             pointers: vec![],
+            counts: vec![],
+            maxes: vec![],
+        }
+    }
+
+    // keys[i] 是 pointers[i + 1] 子树中最小的 key
+    pub(crate) fn child_index(&self, key: &K, order: &KeyOrder<K>) -> usize {
+        match order.search(&self.keys, key) {
+            Result::Ok(pos) => pos + 1,
+            Err(pos) => pos,
+        }
+    }
+
+    // 内部结点在 mid_index 处分裂, 返回 (上移的分隔 key, 右半边)
+    pub(crate) fn split_inner(&mut self, mid_index: usize) -> (K, BPlusTreeNode<K, V>) {
+        let mut right = BPlusTreeNode::new_inner(self.way);
+        right.keys = self.keys.split_off(mid_index);
+        right.pointers = self.pointers.split_off(mid_index + 1);
+        right.counts = self.counts.split_off(mid_index + 1);
+        right.maxes = self.maxes.split_off(mid_index + 1);
+        (right.keys.remove(0), right)
+    }
+
+    // 子树里的条目数
+    pub(crate) fn entry_count(&self) -> usize {
+        if self.is_leaf {
+            self.keys.len()
+        } else {
+            self.counts.iter().sum()
+        }
+    }
+
+    // 子树里按 augment 最大的 key
+    pub(crate) fn max_key(&self, augment: Option<Augment<K>>) -> Option<K>
+    where
+        K: Clone,
+    {
+        let augment = augment?;
+        match self.is_leaf {
+            true => self.keys.iter().max_by(|a, b| augment(a, b)).cloned(),
+            false => self.maxes.iter().flatten().max_by(|a, b| augment(a, b)).cloned(),
+        }
+    }
+
+    pub(crate) fn is_overflow(&self) -> bool {
+        self.keys.len() > self.way
+    }
+
+    // 分裂出来的结点至少有这么多 key, 删除后少于这个数就要借用或者合并
+    pub(crate) fn min_keys(&self) -> usize {
+        if self.is_leaf {
+            self.way.div_ceil(2)
+        } else {
+            self.way / 2
         }
     }
 }
 
+// 结点分裂出来的 (分隔 key, 右结点)
+pub(crate) type Split<K> = Option<(K, BlockId)>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum WriteKind {
+    Insert,
+    Delete,
+    // 只改叶子里的 value, 结构不变
+    Update,
+}
+
 impl<K, V, E> BPlusTree<K, V, E>
 where
     E: BlockEngine<Item = BPlusTreeNode<K, V>>,
@@ -69,216 +198,888 @@ where
     V: Clone,
 {
 
-    pub fn new(way: usize, mut engine: E) -> BPlusTree<K, V, E> {
-        let root = engine.alloc_write(BPlusTreeNode::new_leaf(way, None)).unwrap();
+    pub fn new(way: usize, engine: E) -> Result<BPlusTree<K, V, E>> {
+        Self::with_order(way, engine, KeyOrder::default())
+    }
+
+    // 用 compare 而不是 K 的 Ord 决定 key 的顺序, compare 认为相等的 key 算同一个 key
+    pub fn with_comparator<F>(way: usize, engine: E, compare: F) -> Result<BPlusTree<K, V, E>>
+    where
+        F: Fn(&K, &K) -> Ordering + Send + Sync + 'static,
+    {
+        Self::with_order(way, engine, KeyOrder::new(compare))
+    }
+
+    pub fn with_order(way: usize, engine: E, order: KeyOrder<K>) -> Result<BPlusTree<K, V, E>> {
+        let root = engine.alloc_write(BPlusTreeNode::new_leaf(way))?;
+        Ok(Self::from_root(way, engine, root, 0, order))
+    }
+
+    // 结点按 capacity 的字节数而不只是 way 决定什么时候分裂和合并
+    pub fn with_node_capacity(way: usize, engine: E, capacity: NodeCapacity<K, V>) -> Result<BPlusTree<K, V, E>> {
+        let mut tree = Self::new(way, engine)?;
+        tree.capacity = capacity;
+        Ok(tree)
+    }
+
+    // 接着用 engine 里上次 flush 时的树, way 和条目数都是 flush 时记下的
+    pub fn open(engine: E) -> Result<BPlusTree<K, V, E>> {
+        Self::open_with_order(engine, KeyOrder::default(), NodeCapacity::default())
+    }
+
+    // capacity 要和建树时的一样
+    pub fn open_with_node_capacity(engine: E, capacity: NodeCapacity<K, V>) -> Result<BPlusTree<K, V, E>> {
+        Self::open_with_order(engine, KeyOrder::default(), capacity)
+    }
+
+    // 用 with_comparator 建的树要这样打开
+    pub fn open_with_comparator<F>(engine: E, compare: F) -> Result<BPlusTree<K, V, E>>
+    where
+        F: Fn(&K, &K) -> Ordering + Send + Sync + 'static,
+    {
+        Self::open_with_order(engine, KeyOrder::new(compare), NodeCapacity::default())
+    }
+
+    // order 和 capacity 都要和建树时的一样, order 的 id 和 flush 时记下的不同时返回错误
+    pub fn open_with_order(engine: E, order: KeyOrder<K>, capacity: NodeCapacity<K, V>) -> Result<BPlusTree<K, V, E>> {
+        let Some(meta) = engine.load_meta() else {
+            return Err(anyhow!("engine has no flushed tree."));
+        };
+        if meta.order != order.id() {
+            return Err(anyhow!("tree was written with key order {} but opened with {}.", meta.order, order.id()));
+        }
+        let snapshots = engine.load_snapshots()?;
+        let mut tree = Self::from_root(meta.way, engine, meta.root, meta.len, order);
+        tree.capacity = capacity;
+        tree.persistent = meta.persistent;
+        if !snapshots.is_empty() {
+            tree.snapshots = snapshots
+                .into_iter()
+                .map(|(name, meta)| (name, Version { root: meta.root, len: meta.len }))
+                .collect();
+            tree.recount()?;
+        }
+        Ok(tree)
+    }
+
+    // 把当前的 root 和修改过的 block 写到 engine 的持久存储上, 之后 open 能看到这个版本
+    pub fn flush(&mut self) -> Result<()> {
+        let meta = self.stage_meta()?;
+        self.engine.flush(meta)
+    }
+
+    // flush 之后让 engine 把日志合并进存储, 日志就可以清空了
+    pub fn checkpoint(&mut self) -> Result<()> {
+        let meta = self.stage_meta()?;
+        self.engine.checkpoint(meta)
+    }
+
+    // flush 之后让 engine 截掉存储末尾空闲的 block, 返回截掉了多少个
+    pub fn vacuum(&mut self) -> Result<usize> {
+        let meta = self.stage_meta()?;
+        self.engine.vacuum(meta)
+    }
+
+    // 有名字的 snapshot 先交给 engine, 和返回的 meta 在同一次提交里写进去
+    // 没有名字的版本和历史版本不保存, 重新打开之后它们独占的 block 不会再回收
+    fn stage_meta(&self) -> Result<TreeMeta> {
+        let meta = self.meta();
+        self.engine.stage_snapshots(&self.snapshot_metas())?;
+        Ok(meta)
+    }
+
+    pub(crate) fn snapshot_metas(&self) -> BTreeMap<String, TreeMeta> {
+        let meta = self.meta();
+        self.snapshots
+            .iter()
+            .map(|(name, version)| (name.clone(), TreeMeta { root: version.root, len: version.len, ..meta }))
+            .collect()
+    }
+
+    pub(crate) fn meta(&self) -> TreeMeta {
+        TreeMeta { root: self.root, way: self.way, len: self.len, persistent: self.persistent, order: self.order.id() }
+    }
+
+    pub(crate) fn from_root(way: usize, engine: E, root: BlockId, len: usize, order: KeyOrder<K>) -> BPlusTree<K, V, E> {
         BPlusTree {
             way,
             engine,
             root,
+            order,
+            capacity: NodeCapacity::default(),
+            len,
+            persistent: false,
+            owned: HashSet::new(),
+            refs: RefCounts::default(),
+            snapshots: HashMap::new(),
+            history: History::default(),
+            shadow: None,
+            augment: None,
+            entry_size: None,
+            reserved: vec![],
+            stats: TreeStats::default(),
             _marker1: PhantomData,
             _marker2: PhantomData,
         }
     }
 
-    pub fn search(&self, key: &K) -> Option<V> {
+    pub fn stats(&self) -> TreeStats {
+        self.stats
+    }
+
+    pub fn engine_stats(&self) -> BlockEngineStats {
+        self.engine.stats()
+    }
+
+    // 见 BlockEngine::set_user_metadata, 和之后的修改一起提交
+    pub fn set_user_metadata(&mut self, bytes: &[u8]) -> Result<()> {
+        self.engine.set_user_metadata(bytes)
+    }
+
+    pub fn user_metadata(&self) -> Result<Vec<u8>> {
+        self.engine.user_metadata()
+    }
+
+    // 读 block 出错时返回错误, 不会当成 key 不存在
+    pub fn search(&self, key: &K) -> Result<Option<V>> {
         self.search_helper(self.root, key)
     }
 
-    fn search_helper(&self, block_id: BlockId, key: &K) -> Option<V> {
-        let read = self.engine.fetch_read(block_id).unwrap();
-        if read.is_none() {
-            return None;
-        }
-        let BPlusTreeNode {
-            parent: _,
-            way: _,
-            is_leaf,
-            keys,
-            values,
-            prev: _,
-            next: _,
-            pointers,
-        } = read.as_ref().unwrap();
-
-        if !*is_leaf {
-            let pos = keys
-                    .binary_search(key)
-                    .unwrap_or_else(|e| e);
-            self.search_helper(pointers[if pos < keys.len() && *key == keys[pos] { pos + 1 } else { pos }], key)
+    pub fn search_at(&self, version: Version, key: &K) -> Result<Option<V>> {
+        self.search_helper(version.root, key)
+    }
+
+    // 只看 key 在不在, 不 clone value
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        self.leaf_lookup(self.root, key, |_, pos| pos.is_some())
+    }
+
+    pub fn first_key_value(&self) -> Result<Option<(K, V)>> {
+        self.edge_entry(self.root, false)
+    }
+
+    pub fn last_key_value(&self) -> Result<Option<(K, V)>> {
+        self.edge_entry(self.root, true)
+    }
+
+    pub fn pop_first(&mut self) -> Result<Option<(K, V)>> {
+        let Some((key, _)) = self.first_key_value()? else {
+            return Ok(None);
+        };
+        Ok(self.delete(&key)?.map(|value| (key, value)))
+    }
+
+    pub fn pop_last(&mut self) -> Result<Option<(K, V)>> {
+        let Some((key, _)) = self.last_key_value()? else {
+            return Ok(None);
+        };
+        Ok(self.delete(&key)?.map(|value| (key, value)))
+    }
+
+    // 沿着最左 / 最右的指针走到底, 只有整棵树为空时叶子才是空的
+    pub(crate) fn edge_entry(&self, mut block_id: BlockId, last: bool) -> Result<Option<(K, V)>> {
+        loop {
+            let read = self.engine.fetch_read(block_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                let pos = if last { node.keys.len().checked_sub(1) } else { (!node.keys.is_empty()).then_some(0) };
+                return Ok(pos.map(|pos| (node.keys[pos].clone(), node.values[pos].clone())));
+            }
+            block_id = if last { node.pointers[node.pointers.len() - 1] } else { node.pointers[0] };
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn search_helper(&self, root: BlockId, key: &K) -> Result<Option<V>> {
+        self.leaf_lookup(root, key, |node, pos| pos.map(|index| node.values[index].clone()))
+    }
+
+    // 走到 key 所在的叶子, 在叶子的读锁下用 key 的下标 (不存在时是 None) 调用 f
+    fn leaf_lookup<T, F>(&self, mut block_id: BlockId, key: &K, f: F) -> Result<T>
+    where
+        F: FnOnce(&BPlusTreeNode<K, V>, Option<usize>) -> T,
+    {
+        loop {
+            let read = self.engine.fetch_read(block_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                return Ok(f(node, self.order.search(&node.keys, key).ok()));
+            }
+            block_id = node.pointers[node.child_index(key, &self.order)];
+        }
+    }
+
+    // 找到 key 所在的叶子
+    pub(crate) fn find_leaf(&self, mut block_id: BlockId, key: &K) -> Result<BlockId> {
+        loop {
+            let read = self.engine.fetch_read(block_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                return Ok(block_id);
+            }
+            block_id = node.pointers[node.child_index(key, &self.order)];
+        }
+    }
+
+    pub(crate) fn subtree_count(&self, block_id: BlockId) -> Result<usize> {
+        let read = self.engine.fetch_read(block_id)?;
+        Ok(read.as_ref().ok_or(Error::EmptyBlock(block_id))?.entry_count())
+    }
+
+    // 子树里按 augment 最大的 key, 没有 augment 时不读结点
+    pub(crate) fn subtree_max(&self, block_id: BlockId) -> Result<Option<K>> {
+        if self.augment.is_none() {
+            return Ok(None);
+        }
+        let read = self.engine.fetch_read(block_id)?;
+        Ok(read.as_ref().ok_or(Error::EmptyBlock(block_id))?.max_key(self.augment))
+    }
+
+    // 换了 augment 之后整棵树的 maxes 重新算一遍
+    pub(crate) fn set_augment(&mut self, augment: Augment<K>) -> Result<()> {
+        self.augment = Some(augment);
+        self.refresh_maxes(self.root)?;
+        Ok(())
+    }
+
+    // 自底向上重新算子树里每个内部结点的 maxes, 返回子树的 max
+    // maxes 只由子树里的 key 决定, 持久化模式下和旧版本共享的结点原地改也不影响旧版本
+    pub(crate) fn refresh_maxes(&mut self, block_id: BlockId) -> Result<Option<K>> {
+        let pointers = {
+            let read = self.engine.fetch_read(block_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if node.is_leaf() {
+                return Ok(node.max_key(self.augment));
+            }
+            node.pointers.clone()
+        };
+        let maxes = pointers.iter().map(|&child| self.refresh_maxes(child)).collect::<Result<Vec<_>>>()?;
+        let mut write = self.engine.fetch_write(block_id)?;
+        let node = write.as_mut().ok_or(Error::EmptyBlock(block_id))?;
+        node.maxes = maxes;
+        Ok(node.max_key(self.augment))
+    }
+
+    // 冻结当前版本, 之后的修改都不会影响返回的 Version, 不用了要 drop_version
+    pub fn freeze(&mut self) -> Version {
+        self.persistent = true;
+        self.owned.clear();
+        self.refs.retain(self.root);
+        self.version()
+    }
+
+    // 当前的 root, 不冻结
+    pub(crate) fn version(&self) -> Version {
+        Version { root: self.root, len: self.len }
+    }
+
+    // 以 base 为基础做一组修改, 产生一个新的版本, base 本身保持不变, 之后当前 root 就是新的版本
+    // 出错时当前 root 回退到调用前的状态
+    pub fn modify<F>(&mut self, base: Version, f: F) -> Result<Version>
+    where
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        // 冻结当前版本但不拿新的引用, 当前 root 原来的那个引用先留着, 出错时还要回来
+        self.persistent = true;
+        self.owned.clear();
+        let prev = self.version();
+        self.refs.retain(base.root);
+        self.root = base.root;
+        self.len = base.len;
+        if let Err(e) = f(self) {
+            let root = std::mem::replace(&mut self.root, prev.root);
+            self.len = prev.len;
+            self.release(root)?;
+            return Err(e);
+        }
+        self.release(prev.root)?;
+        Ok(self.freeze())
+    }
+
+    // 写结点前调用, 持久化模式下共享的结点先复制到新 block 上, 返回实际可写的 block id
+    // 从上往下走, 走到这里的那个引用是当前 root 的; 它是唯一的引用时不用复制, 直接归当前 root 所有
+    fn node_mut(&mut self, block_id: BlockId) -> Result<(BlockId, BlockWriteGuard<'_, BPlusTreeNode<K, V>>)> {
+        let block_id = if self.persistent && !self.owned.contains(&block_id) && self.refs.get(block_id) > 1 {
+            let copy = self
+                .engine
+                .fetch_read(block_id)?
+                .as_ref()
+                .cloned()
+                .ok_or(Error::EmptyBlock(block_id))?;
+            let copy = self.alloc_node(copy)?;
+            self.free_node(block_id)?;
+            copy
         } else {
-            keys.binary_search(key).ok().map(|index| values[index].clone())
+            if self.persistent {
+                self.owned.insert(block_id);
+            }
+            block_id
+        };
+        Ok((block_id, self.engine.fetch_write(block_id)?))
+    }
+
+    pub(crate) fn alloc_node(&mut self, node: BPlusTreeNode<K, V>) -> Result<BlockId> {
+        let block_id = match self.reserved.pop() {
+            Some(block_id) => {
+                **self.engine.fetch_write(block_id)? = Some(node);
+                block_id
+            }
+            None => self.engine.alloc_write(node)?,
+        };
+        if self.persistent {
+            self.owned.insert(block_id);
+        }
+        Ok(block_id)
+    }
+
+    // 写操作中途分配失败的话树会停在一半的状态, 所以先把这次最多要用的 block 都分配好
+    // 这一步失败时还什么都没改, 直接返回错误
+    pub(crate) fn reserve(&mut self, n: usize) -> Result<()> {
+        while self.reserved.len() < n {
+            match self.engine.alloc_block() {
+                Result::Ok(block_id) => self.reserved.push(block_id),
+                Err(e) => {
+                    self.release_reserved();
+                    return Err(e);
+                }
+            }
         }
+        Ok(())
     }
 
-    pub fn insert(&mut self, key: K, value: V) -> Result<()> {
-        
-        let parent = Cell::new(None);
-        // 找到正确的子结点
-        Self::insert_helper(&mut self.engine, &parent, self.root, key, value)?;
-        if parent.get().is_some() {
-            self.root = parent.get().unwrap()
+    // 归还没用上的预留 block
+    pub(crate) fn release_reserved(&mut self) {
+        for block_id in std::mem::take(&mut self.reserved) {
+            let _ = self.engine.delete(block_id);
         }
+    }
 
+    // 对 key 做一次写操作最多要分配几个 block:
+    // 持久化模式下路径上共享的结点都要复制, 插入时叶子往上连续满的结点会分裂, 全满时还要一个新 root
+    // 删除时每一层都可能要复制一个用来借用 / 合并的兄弟
+    fn blocks_needed(&self, key: &K, kind: WriteKind) -> Result<usize> {
+        let mut block_id = self.root;
+        let (mut copies, mut splits, mut depth) = (0, 0, 0);
+        loop {
+            let read = self.engine.fetch_read(block_id)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+            if self.persistent && !self.owned.contains(&block_id) {
+                copies += 1;
+            }
+            splits = if self.capacity.is_nearly_full(node) { splits + 1 } else { 0 };
+            depth += 1;
+            if node.is_leaf() {
+                // 插入已有的 key 只是替换 value, 不会分裂, 按字节算时 value 变大还是可能分裂
+                if kind == WriteKind::Insert && !self.capacity.is_bytes() && self.order.search(&node.keys, key).is_ok() {
+                    return Ok(copies);
+                }
+                break;
+            }
+            block_id = node.pointers[node.child_index(key, &self.order)];
+        }
+        Ok(match kind {
+            WriteKind::Insert => copies + splits + usize::from(splits == depth),
+            WriteKind::Delete if self.persistent => copies + depth - 1 + self.delete_splits(depth),
+            WriteKind::Delete => copies + self.delete_splits(depth),
+            _ => copies,
+        })
+    }
+
+    // 按字节算时删除中换掉的分隔 key 可能更长, 每个内部结点和 root 都可能分裂
+    fn delete_splits(&self, depth: usize) -> usize {
+        if self.capacity.is_bytes() {
+            depth
+        } else {
+            0
+        }
+    }
+
+    // key 已经存在时替换 value, 返回旧的
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>> {
+        let old = self.insert_unrecorded(key, value)?;
+        self.record_history();
+        Ok(old)
+    }
+
+    // insert 去掉 record_history, 给一次写好几条、最后只记一个历史版本的地方用
+    pub(crate) fn insert_unrecorded(&mut self, key: K, value: V) -> Result<Option<V>> {
+        self.capacity.check_entry(&key, &value)?;
+        let needed = self.blocks_needed(&key, WriteKind::Insert)?;
+        self.reserve(needed)?;
+        let ret = self.insert_root(key, value);
+        self.release_reserved();
+        let old = ret?;
+        if old.is_none() {
+            self.len += 1;
+        }
+        self.stats.inserts += 1;
+        Ok(old)
+    }
+
+    // key 已经存在时返回 Error::KeyExists, 树不变
+    pub fn insert_unique(&mut self, key: K, value: V) -> Result<()> {
+        let leaf = self.find_leaf(self.root, &key)?;
+        let exists = self
+            .engine
+            .fetch_read(leaf)?
+            .as_ref()
+            .is_some_and(|node| self.order.search(&node.keys, &key).is_ok());
+        if exists {
+            return Err(Error::KeyExists.into());
+        }
+        self.insert(key, value)?;
         Ok(())
     }
 
+    fn insert_root(&mut self, key: K, value: V) -> Result<Option<V>> {
+        let (root, split, old) = self.insert_helper(self.root, key, value)?;
+        self.grow_root(root, split)?;
+        Ok(old)
+    }
+
+    // root 分裂时在上面加一层
+    fn grow_root(&mut self, root: BlockId, split: Split<K>) -> Result<()> {
+        self.root = root;
+        if let Some((mid, right)) = split {
+            let mut node = BPlusTreeNode::new_inner(self.way);
+            node.keys = vec![mid];
+            node.pointers = vec![root, right];
+            node.counts = vec![self.subtree_count(root)?, self.subtree_count(right)?];
+            node.maxes = vec![self.subtree_max(root)?, self.subtree_max(right)?];
+            self.root = self.alloc_node(node)?;
+        }
+        Ok(())
+    }
+
+    // 返回写入后结点的 block id (持久化模式下可能变了), 分裂出来的 (分隔 key, 右结点), 以及被替换掉的旧 value
     fn insert_helper(
-        engine: *mut E,
-        parent: &Cell<Option<BlockId>>,
+        &mut self,
         block_id: BlockId,
         key: K,
         value: V,
-    ) -> Result<()> {
-        let mut guard = unsafe { engine.as_mut().unwrap() }.fetch_write(block_id).unwrap(); 
-        if guard.is_none() {
-            return Ok(());
-        }
-        let node = guard.as_mut().unwrap();
+    ) -> Result<(BlockId, Split<K>, Option<V>)> {
+        let block_id = self.own(block_id)?;
+        let mut guard = self.engine.fetch_write(block_id)?;
+        let node = guard.as_mut().ok_or(Error::EmptyBlock(block_id))?;
         if node.is_leaf {
-            let pos = node.keys.binary_search(&key).unwrap_or_else(|e| e);
-            node.keys.insert(pos, key);
-            node.values.insert(pos, value);
-        } else {
-            let pos = node.keys
-                .binary_search(&key)
-                .unwrap_or_else(|e| e);
-            let child = node.pointers[pos];
-            Self::insert_helper(engine, &node.parent, child, key, value)?;
-        }
-
-        if node.keys.len() > node.way {
-            if node.is_leaf {
-                let right_keys = node.keys.split_off(node.keys.len() / 2);
-                let right_values = node.values.split_off(node.values.len() / 2);
-                let mid = right_keys[0].clone();
-                let way = node.way;
-                if parent.get().is_none() {
-                    let mut node = BPlusTreeNode::new_inner(way);
-                    node.pointers =  vec![block_id];
-                    parent.set(unsafe { engine.as_mut().unwrap() }.alloc_write(node).ok());
-                    assert_ne!(parent.get(), None, "alloc write failed.")
+            record_written(&mut self.stats, &self.entry_size, &key, &value);
+            let old = match self.order.search(&node.keys, &key) {
+                Result::Ok(pos) => Some(std::mem::replace(&mut node.values[pos], value)),
+                Err(pos) => {
+                    node.keys.insert(pos, key);
+                    node.values.insert(pos, value);
+                    None
                 }
-                let mut parent_block = unsafe { engine.as_mut().unwrap() }
-                    .fetch_write(parent.get().unwrap())?;
-                let parent_block_ref = parent_block.as_mut().unwrap();
-                let pos = parent_block_ref
-                    .keys
-                    .binary_search(&mid)
-                    .unwrap_or_else(|e| e);
-                
-                let right_block_id = unsafe { engine.as_mut().unwrap() }.alloc_write(
-                    BPlusTreeNode { 
-                        parent: parent.clone(), 
-                        way, 
-                        is_leaf: true,
-                        keys: right_keys,
-                        values: right_values,
-                        prev: Some(block_id),
-                        next: node.next,
-                        pointers: vec![]
-                    }
-                )?;
-                parent_block_ref.keys.insert(pos, mid);
-                parent_block_ref.pointers.insert(pos + 1, right_block_id);
+            };
+            if !self.capacity.is_overflow(node) {
+                return Ok((block_id, None, old));
+            }
+
+            let mid = self.capacity.split_point(node);
+            let right_keys = node.keys.split_off(mid);
+            let right_values = node.values.split_off(mid);
+            let mid = right_keys[0].clone();
+            let mut right = BPlusTreeNode::new_leaf(node.way);
+            right.keys = right_keys;
+            right.values = right_values;
+            right.prev = Some(block_id);
+            right.next = node.next;
+            let next = node.next;
+            drop(guard);
+
+            let right_block_id = self.alloc_node(right)?;
+            self.stats.splits += 1;
+            if let Some(node) = self.engine.fetch_write(block_id)?.as_mut() {
                 node.next = Some(right_block_id);
-            } else {
-                let mut right_keys = node.keys.split_off(node.keys.len() / 2);
-                let right_pointers = node.pointers.split_off(node.keys.len() / 2);
-                let mid = right_keys.remove(0);
-                if parent.get().is_none() {
-                    parent.set(unsafe { engine.as_mut().unwrap() }.alloc_write(BPlusTreeNode::new_inner(node.way)).ok());
-                    assert_ne!(parent.get(), None, "alloc write failed.")
+            }
+            // 持久化模式下右边的兄弟可能被旧版本共享, 不去动它
+            if let (Some(next), false) = (next, self.persistent) {
+                if let Some(node) = self.engine.fetch_write(next)?.as_mut() {
+                    node.prev = Some(right_block_id);
                 }
-                let mut parent_block = unsafe { engine.as_mut().unwrap() }
-                    .fetch_write(parent.get().unwrap())?;
-                let parent_block_ref = parent_block.as_mut().unwrap();
-                let pos = parent_block_ref
-                    .keys
-                    .binary_search(&mid)
-                    .unwrap_or_else(|e| e);
-                let right_block_id = unsafe { engine.as_mut().unwrap() }.alloc_write(
-                    BPlusTreeNode { 
-                        parent: parent.clone(), 
-                        way: node.way, 
-                        is_leaf: false,
-                        keys: right_keys,
-                        values: vec![],
-                        prev: Some(block_id),
-                        next: node.next,
-                        pointers: right_pointers
-                    }
-                )?;
-                parent_block_ref.keys.insert(pos, mid);
-                parent_block_ref.pointers.insert(pos + 1, right_block_id);
             }
-        }
+            Ok((block_id, Some((mid, right_block_id)), old))
+        } else {
+            let pos = node.child_index(&key, &self.order);
+            let child = node.pointers[pos];
+            drop(guard);
 
-        Ok(())
+            let (new_child, split, old) = self.insert_helper(child, key, value)?;
+            if new_child == child && split.is_none() && old.is_some() {
+                return Ok((block_id, None, old));
+            }
+            let (right_count, right_max) = match &split {
+                Some((_, right_child)) => (self.subtree_count(*right_child)?, self.subtree_max(*right_child)?),
+                None => (0, None),
+            };
+            let child_max = self.subtree_max(new_child)?;
+            let mut guard = self.engine.fetch_write(block_id)?;
+            let node = guard.as_mut().ok_or(Error::EmptyBlock(block_id))?;
+            node.pointers[pos] = new_child;
+            node.maxes[pos] = child_max;
+            if old.is_none() {
+                node.counts[pos] += 1;
+            }
+            let Some((mid, right_child)) = split else {
+                return Ok((block_id, None, old));
+            };
+            node.counts[pos] -= right_count;
+            node.keys.insert(pos, mid);
+            node.pointers.insert(pos + 1, right_child);
+            node.counts.insert(pos + 1, right_count);
+            node.maxes.insert(pos + 1, right_max);
+            if !self.capacity.is_overflow(node) {
+                return Ok((block_id, None, old));
+            }
+
+            let (mid, right) = node.split_inner(self.capacity.split_point(node));
+            drop(guard);
+
+            let right_block_id = self.alloc_node(right)?;
+            self.stats.splits += 1;
+            Ok((block_id, Some((mid, right_block_id)), old))
+        }
     }
 
-    // todo: delete 
-    // 懒得实现了
     pub fn delete(&mut self, key: &K) -> Result<Option<V>> {
-        let parent = Cell::new(None);
-        // 找到正确的子结点
-        let ret = Self::delete_helper(&mut self.engine, &parent, self.root, key)?;
-        if parent.get().is_some() {
-            self.root = parent.get().unwrap()
+        // 先只读地确认 key 存在, 避免持久化模式下白白复制一条路径
+        let leaf = self.find_leaf(self.root, key)?;
+        let exists = self
+            .engine
+            .fetch_read(leaf)?
+            .as_ref()
+            .is_some_and(|node| self.order.search(&node.keys, key).is_ok());
+        if !exists {
+            return Ok(None);
         }
+        let needed = self.blocks_needed(key, WriteKind::Delete)?;
+        self.reserve(needed)?;
+        let ret = self.delete_root(key);
+        self.release_reserved();
+        let ret = ret?;
+        if ret.is_some() {
+            self.len -= 1;
+            self.stats.deletes += 1;
+        }
+        self.record_history();
         Ok(ret)
     }
 
-    fn delete_helper(engine: *mut E, parent: &Cell<Option<BlockId>>, block_id: BlockId, key: &K) -> Result<Option<V>> {
-        let mut guard = unsafe { engine.as_mut().unwrap() }.fetch_write(block_id).unwrap();
-        let mut ret: Option<V> = None;
-        if guard.is_none() {
-            return Ok(None);
+    fn delete_root(&mut self, key: &K) -> Result<Option<V>> {
+        let (root, split, ret) = self.delete_helper(self.root, key)?;
+        self.grow_root(root, split)?;
+        let root = self.root;
+        // root 只剩一个孩子时树变矮一层
+        let only_child = {
+            let read = self.engine.fetch_read(root)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(root))?;
+            (!node.is_leaf() && node.keys.is_empty()).then(|| node.pointers[0])
+        };
+        if let Some(child) = only_child {
+            self.root = child;
+            self.free_node(root)?;
         }
-        let node = guard.as_mut().unwrap();
+        Ok(ret)
+    }
+
+    // 和 insert_helper 一样返回分裂出来的右结点: 按字节算时借用会换掉分隔 key, 结点可能因此溢出
+    fn delete_helper(&mut self, block_id: BlockId, key: &K) -> Result<(BlockId, Split<K>, Option<V>)> {
+        let block_id = self.own(block_id)?;
+        let mut guard = self.engine.fetch_write(block_id)?;
+        let node = guard.as_mut().ok_or(Error::EmptyBlock(block_id))?;
         if node.is_leaf {
-            let Result::Ok(pos) = node.keys.binary_search(key) else {
-                return Ok(None)
+            let Result::Ok(pos) = self.order.search(&node.keys, key) else {
+                return Ok((block_id, None, None));
             };
             node.keys.remove(pos);
-            ret = Some(node.values.remove(pos));
+            Ok((block_id, None, Some(node.values.remove(pos))))
         } else {
-            let Result::Ok(pos) = node.keys.binary_search(key) else {
-                return Ok(None)
-            };
+            let pos = node.child_index(key, &self.order);
             let child = node.pointers[pos];
-            ret = Self::delete_helper(engine, &node.parent, child, key)?;
+            drop(guard);
+
+            let (new_child, split, ret) = self.delete_helper(child, key)?;
+            if new_child != child || ret.is_some() {
+                let child_max = self.subtree_max(new_child)?;
+                if let Some(node) = self.engine.fetch_write(block_id)?.as_mut() {
+                    node.pointers[pos] = new_child;
+                    node.counts[pos] -= usize::from(ret.is_some());
+                    node.maxes[pos] = child_max;
+                }
+            }
+            let Some((mid, right_child)) = split else {
+                if ret.is_none() {
+                    return Ok((block_id, None, ret));
+                }
+                let split = self.rebalance_child(block_id, pos)?;
+                return Ok((block_id, split, ret));
+            };
+            let (right_count, right_max) = (self.subtree_count(right_child)?, self.subtree_max(right_child)?);
+            let child_max = self.subtree_max(new_child)?;
+            let mut guard = self.engine.fetch_write(block_id)?;
+            let node = guard.as_mut().ok_or(Error::EmptyBlock(block_id))?;
+            node.counts[pos] -= right_count;
+            node.maxes[pos] = child_max;
+            node.keys.insert(pos, mid);
+            node.pointers.insert(pos + 1, right_child);
+            node.counts.insert(pos + 1, right_count);
+            node.maxes.insert(pos + 1, right_max);
+            if !self.capacity.is_overflow(node) {
+                return Ok((block_id, None, ret));
+            }
+            let (mid, right) = node.split_inner(self.capacity.split_point(node));
+            drop(guard);
+
+            let right_block_id = self.alloc_node(right)?;
+            self.stats.splits += 1;
+            Ok((block_id, Some((mid, right_block_id)), ret))
         }
+    }
 
-        // if node.is_leaf && node.keys.is_empty() {
-            
-        // }
+    // parent 已经是可写的, pointers[pos] 的孩子太空时向兄弟借一个, 借不到就合并
+    // 优先和左边的兄弟配对, 最左边的孩子和右边的兄弟配对
+    // 按字节算时 parent 换了分隔 key 之后可能溢出, 这时分裂 parent, 返回右半边
+    fn rebalance_child(&mut self, parent_id: BlockId, pos: usize) -> Result<Split<K>> {
+        let (left_id, right_id, borrow) = {
+            let read = self.engine.fetch_read(parent_id)?;
+            let parent = read.as_ref().ok_or(Error::EmptyBlock(parent_id))?;
+            if parent.pointers.len() < 2 {
+                return Ok(None);
+            }
+            let li = if pos > 0 { pos - 1 } else { pos };
+            let (left_id, right_id) = (parent.pointers[li], parent.pointers[li + 1]);
+            let left_read = self.engine.fetch_read(left_id)?;
+            let left = left_read.as_ref().ok_or(Error::EmptyBlock(left_id))?;
+            let right_read = self.engine.fetch_read(right_id)?;
+            let right = right_read.as_ref().ok_or(Error::EmptyBlock(right_id))?;
+            let (child, sibling) = if pos > 0 { (right, left) } else { (left, right) };
+            if !self.capacity.is_underflow(child) {
+                return Ok(None);
+            }
+            (left_id, right_id, self.capacity.should_borrow(left, right, sibling, &parent.keys[li]))
+        };
+        let li = if pos > 0 { pos - 1 } else { pos };
+        let left_id = self.own(left_id)?;
+        let mut parent = self.take_node(parent_id)?;
+        parent.pointers[li] = left_id;
+        let mut left = self.take_node(left_id)?;
 
+        if borrow {
+            let right_id = self.own(right_id)?;
+            parent.pointers[li + 1] = right_id;
+            let mut right = self.take_node(right_id)?;
+            match (left.is_leaf(), pos > 0) {
+                // 从左边借最后一个
+                (true, true) => {
+                    let last = left.keys.len() - 1;
+                    right.keys.insert(0, left.keys.remove(last));
+                    right.values.insert(0, left.values.remove(last));
+                    parent.keys[li] = right.keys[0].clone();
+                }
+                // 从右边借第一个
+                (true, false) => {
+                    left.keys.push(right.keys.remove(0));
+                    left.values.push(right.values.remove(0));
+                    parent.keys[li] = right.keys[0].clone();
+                }
+                // 内部结点借用时分隔 key 经过 parent 转一圈
+                (false, true) => {
+                    let last = left.keys.len() - 1;
+                    let separator = std::mem::replace(&mut parent.keys[li], left.keys.remove(last));
+                    right.keys.insert(0, separator);
+                    right.pointers.insert(0, left.pointers.remove(last + 1));
+                    right.counts.insert(0, left.counts.remove(last + 1));
+                    right.maxes.insert(0, left.maxes.remove(last + 1));
+                }
+                (false, false) => {
+                    let separator = std::mem::replace(&mut parent.keys[li], right.keys.remove(0));
+                    left.keys.push(separator);
+                    left.pointers.push(right.pointers.remove(0));
+                    left.counts.push(right.counts.remove(0));
+                    left.maxes.push(right.maxes.remove(0));
+                }
+            }
+            parent.counts[li + 1] = right.entry_count();
+            parent.maxes[li + 1] = right.max_key(self.augment);
+            self.put_node(right_id, right)?;
+        } else {
+            // 右边整个并进左边
+            let right = self.engine.fetch_read(right_id)?.as_ref().cloned().ok_or(Error::EmptyBlock(right_id))?;
+            let separator = parent.keys.remove(li);
+            parent.pointers.remove(li + 1);
+            parent.counts.remove(li + 1);
+            parent.maxes.remove(li + 1);
+            if left.is_leaf() {
+                left.keys.extend(right.keys);
+                left.values.extend(right.values);
+                left.next = right.next;
+                // 持久化模式下叶子链不可信, 不去动右边的邻居
+                if let (Some(next), false) = (right.next, self.persistent) {
+                    if let Some(node) = self.engine.fetch_write(next)?.as_mut() {
+                        node.prev = Some(left_id);
+                    }
+                }
+            } else {
+                left.keys.push(separator);
+                left.keys.extend(right.keys);
+                left.pointers.extend(right.pointers);
+                left.counts.extend(right.counts);
+                left.maxes.extend(right.maxes);
+            }
+            self.free_node(right_id)?;
+            self.stats.merges += 1;
+        }
+        parent.counts[li] = left.entry_count();
+        parent.maxes[li] = left.max_key(self.augment);
+        self.put_node(left_id, left)?;
+        if !self.capacity.is_overflow(&parent) {
+            self.put_node(parent_id, parent)?;
+            return Ok(None);
+        }
+        let (mid, right) = parent.split_inner(self.capacity.split_point(&parent));
+        self.put_node(parent_id, parent)?;
+        self.stats.splits += 1;
+        Ok(Some((mid, self.alloc_node(right)?)))
+    }
 
+    // 持久化模式下共享的结点先复制一份, 返回可写的 block id
+    pub(crate) fn own(&mut self, block_id: BlockId) -> Result<BlockId> {
+        Ok(self.node_mut(block_id)?.0)
+    }
 
-        Ok(ret)
+    pub(crate) fn take_node(&mut self, block_id: BlockId) -> Result<BPlusTreeNode<K, V>> {
+        self.engine.fetch_write(block_id)?.take().ok_or(Error::EmptyBlock(block_id).into())
     }
 
-    pub fn print_tree(&self) where K : Debug, V : Debug {
-        self.print_tree_helper(self.root, 0);
+    pub(crate) fn put_node(&mut self, block_id: BlockId, node: BPlusTreeNode<K, V>) -> Result<()> {
+        **self.engine.fetch_write(block_id)? = Some(node);
+        Ok(())
     }
 
-    fn print_tree_helper(&self, block_id: BlockId, depth: usize) where K : Debug, V : Debug {
-        if let Some(node) = self.engine.fetch_read(block_id).unwrap().as_ref() {
-            let indent = " ".repeat(depth * 2);
-            if node.is_leaf {
-                println!("{}Leaf: {:?} values: {:?}", indent, node.keys, node.values);
-            } else {
-                println!("{}Inner: {:?} values: {:?}", indent, node.keys, node.values);
-                for &child_id in &node.pointers {
-                    self.print_tree_helper(child_id, depth + 1);
-                }
+    // 在叶子的写锁下原地修改 value, key 不存在时返回 false
+    pub fn update_with<F>(&mut self, key: &K, f: F) -> Result<bool>
+    where
+        F: FnOnce(&mut V),
+    {
+        Ok(self.with_leaf_mut(key, |node, pos| f(&mut node.values[pos]))?.is_some())
+    }
+
+    // 当前 value 等于 expected 时换成 new, 否则 (包括 key 不存在) 把 new 原样还回去
+    pub fn compare_exchange(&mut self, key: &K, expected: &V, new: V) -> Result<std::result::Result<(), V>>
+    where
+        V: PartialEq,
+    {
+        // 不相等时什么都不写, 不复制路径也不算写进来的字节
+        if self.get(key)?.is_none_or(|current| *current != *expected) {
+            return Ok(Err(new));
+        }
+        self.with_leaf_mut(key, |node, pos| node.values[pos] = new)?;
+        Ok(Result::Ok(()))
+    }
+
+    // 在 key 所在叶子的写锁下调用 f(叶子, key 的下标), key 不存在时返回 None
+    // 持久化模式下会先复制 root 到叶子的这条路径
+    pub(crate) fn with_leaf_mut<R, F>(&mut self, key: &K, f: F) -> Result<Option<R>>
+    where
+        F: FnOnce(&mut BPlusTreeNode<K, V>, usize) -> R,
+    {
+        let Some(leaf) = self.own_leaf(key)? else {
+            return Ok(None);
+        };
+        let mut guard = self.engine.fetch_write(leaf)?;
+        let node = guard.as_mut().ok_or(Error::EmptyBlock(leaf))?;
+        let Result::Ok(pos) = self.order.search(&node.keys, key) else {
+            return Ok(None);
+        };
+        let ret = f(node, pos);
+        record_written(&mut self.stats, &self.entry_size, &node.keys[pos], &node.values[pos]);
+        drop(guard);
+        self.record_history();
+        Ok(Some(ret))
+    }
+
+    // 返回 key 所在的叶子, 保证它可以原地修改, key 不存在时返回 None
+    // 持久化模式下会先把 root 到叶子的路径复制成当前 root 独占的
+    pub(crate) fn own_leaf(&mut self, key: &K) -> Result<Option<BlockId>> {
+        let leaf = self.find_leaf(self.root, key)?;
+        let exists = self
+            .engine
+            .fetch_read(leaf)?
+            .as_ref()
+            .is_some_and(|node| self.order.search(&node.keys, key).is_ok());
+        if !exists {
+            return Ok(None);
+        }
+        if !self.persistent || self.owned.contains(&leaf) {
+            return Ok(Some(leaf));
+        }
+        let needed = self.blocks_needed(key, WriteKind::Update)?;
+        self.reserve(needed)?;
+        let ret = self.own_path(self.root, key);
+        self.release_reserved();
+        let (root, leaf) = ret?;
+        self.root = root;
+        Ok(Some(leaf))
+    }
+
+    // 返回 (复制后的 block id, 复制后的叶子)
+    fn own_path(&mut self, block_id: BlockId, key: &K) -> Result<(BlockId, BlockId)> {
+        let block_id = self.own(block_id)?;
+        let guard = self.engine.fetch_write(block_id)?;
+        let node = guard.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+        if node.is_leaf {
+            return Ok((block_id, block_id));
+        }
+        let pos = node.child_index(key, &self.order);
+        let child = node.pointers[pos];
+        drop(guard);
+
+        let (new_child, leaf) = self.own_path(child, key)?;
+        if new_child != child {
+            if let Some(node) = self.engine.fetch_write(block_id)?.as_mut() {
+                node.pointers[pos] = new_child;
             }
         }
+        Ok((block_id, leaf))
+    }
+
+    // 回收整棵子树, 只能用在没有被别的版本共享的子树上
+    pub(crate) fn free_subtree(&mut self, block_id: BlockId) -> Result<()> {
+        let Some(node) = self.engine.delete(block_id)? else {
+            return Ok(());
+        };
+        for child in node.pointers {
+            self.free_subtree(child)?;
+        }
+        Ok(())
+    }
+
+    // 按顺序返回 root 下所有叶子的 block id
+    pub(crate) fn leaf_ids(&self, block_id: BlockId, leaves: &mut Vec<BlockId>) -> Result<()> {
+        let read = self.engine.fetch_read(block_id)?;
+        let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+        if node.is_leaf() {
+            leaves.push(block_id);
+            return Ok(());
+        }
+        for &child in &node.pointers {
+            self.leaf_ids(child, leaves)?;
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
     use crate::block::MemoryBlockEngine;
 
     use super::*;
@@ -287,23 +1088,362 @@ mod tests {
     fn test_insert_and_search() {
         let way = 2;
         let engine = MemoryBlockEngine::new();
-        let mut tree = BPlusTree::new(way, engine);
+        let mut tree = BPlusTree::new(way, engine).unwrap();
 
         // Test insert
         tree.insert(1, "apple".to_string()).unwrap();
         tree.insert(2, "banana".to_string()).unwrap();
         tree.insert(3, "cherry".to_string()).unwrap();
 
-        // Inner: [2]
-        //   Leaf: [1]
-        //   Leaf: [2, 3]
         // 结果可以在 https://www.cs.usfca.edu/~galles/visualization/BPlusTree.html 验证
-        tree.print_tree();
+        let mut out = vec![];
+        tree.dump(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Inner: [2]\n  Leaf: [1] values: [\"apple\"]\n  Leaf: [2, 3] values: [\"banana\", \"cherry\"]\n"
+        );
 
         // Test search
-        assert_eq!(tree.search(&1), Some("apple".into()));
-        assert_eq!(tree.search(&2), Some("banana".into()));
-        assert_eq!(tree.search(&3), Some("cherry".into()));
-        assert_eq!(tree.search(&4), None); // Key not present
+        assert_eq!(tree.search(&1).unwrap(), Some("apple".into()));
+        assert_eq!(tree.search(&2).unwrap(), Some("banana".into()));
+        assert_eq!(tree.search(&3).unwrap(), Some("cherry".into()));
+        assert_eq!(tree.search(&4).unwrap(), None); // Key not present
+    }
+
+    #[test]
+    fn test_freeze_and_modify() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..50 {
+            tree.insert(i, i * 10).unwrap();
+        }
+        let v1 = tree.freeze();
+
+        // freeze 之后的修改不影响 v1
+        for i in 50..100 {
+            tree.insert(i, i * 10).unwrap();
+        }
+        tree.delete(&7).unwrap();
+        let v2 = tree.freeze();
+
+        let v3 = tree.modify(v1, |tree| {
+            tree.delete(&0)?;
+            tree.insert(1000, 1)?;
+            Ok(())
+        }).unwrap();
+
+        for i in 0..100 {
+            assert_eq!(tree.search_at(v1, &i).unwrap(), if i < 50 { Some(i * 10) } else { None });
+            assert_eq!(tree.search_at(v2, &i).unwrap(), if i != 7 { Some(i * 10) } else { None });
+        }
+        assert_eq!(tree.search_at(v1, &1000).unwrap(), None);
+        assert_eq!(tree.search_at(v3, &1000).unwrap(), Some(1));
+        assert_eq!(tree.search_at(v3, &0).unwrap(), None);
+        assert_eq!(tree.search_at(v3, &7).unwrap(), Some(70));
+        assert_eq!(tree.search(&1000).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_user_metadata() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        assert_eq!(tree.user_metadata().unwrap(), b"");
+        tree.set_user_metadata(b"schema=1").unwrap();
+        tree.insert(1, 1).unwrap();
+        assert_eq!(tree.user_metadata().unwrap(), b"schema=1");
+        tree.set_user_metadata(b"schema=2").unwrap();
+        assert_eq!(tree.user_metadata().unwrap(), b"schema=2");
+    }
+
+    // 分配次数有上限的 engine, 用来模拟中途分配失败
+    struct LimitedEngine {
+        inner: MemoryBlockEngine<BPlusTreeNode<i32, i32>>,
+        budget: AtomicUsize,
+    }
+
+    impl BlockEngine for LimitedEngine {
+        type Item = BPlusTreeNode<i32, i32>;
+
+        fn alloc_block(&self) -> Result<BlockId> {
+            if self.budget.fetch_update(Relaxed, Relaxed, |budget| budget.checked_sub(1)).is_err() {
+                return Err(anyhow::anyhow!("out of blocks."));
+            }
+            self.inner.alloc_block()
+        }
+
+        fn fetch_read(&self, block_id: BlockId) -> Result<crate::block::BlockReadGuard<'_, Self::Item>> {
+            self.inner.fetch_read(block_id)
+        }
+
+        fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, Self::Item>> {
+            self.inner.fetch_write(block_id)
+        }
+
+        fn delete(&self, block_id: BlockId) -> Result<Option<Self::Item>> {
+            self.inner.delete(block_id)
+        }
+
+        fn write_back(block_id: BlockId, block: &crate::block::Block<Self::Item>) {
+            MemoryBlockEngine::write_back(block_id, block)
+        }
+    }
+
+    #[test]
+    fn test_alloc_failure_keeps_tree() {
+        let engine = LimitedEngine { inner: MemoryBlockEngine::new(), budget: usize::MAX.into() };
+        let mut tree = BPlusTree::new(2, engine).unwrap();
+        for i in 0..20 {
+            tree.insert(i, i).unwrap();
+        }
+        let version = tree.freeze();
+
+        // 分裂 / 复制路径都需要新 block, 失败时树保持原样
+        tree.engine.budget = 1.into();
+        assert!(tree.insert(20, 20).is_err());
+        assert!(tree.delete(&3).is_err());
+        assert_eq!(tree.root, version.root);
+        for i in 0..20 {
+            assert_eq!(tree.search(&i).unwrap(), Some(i));
+        }
+        assert_eq!(tree.search(&20).unwrap(), None);
+
+        tree.engine.budget = usize::MAX.into();
+        tree.insert(20, 20).unwrap();
+        assert_eq!(tree.search(&20).unwrap(), Some(20));
+        assert_eq!(tree.search_at(version, &20).unwrap(), None);
+        assert_eq!(
+            tree.engine.fetch_read(usize::MAX).err().and_then(|e| e.downcast_ref::<Error>().cloned()),
+            Some(Error::InvalidBlock(usize::MAX))
+        );
+    }
+
+    #[test]
+    fn test_storage_full() {
+        let mut tree = BPlusTree::new(2, MemoryBlockEngine::with_capacity(8)).unwrap();
+        let mut inserted = 0;
+        let err = loop {
+            match tree.insert(inserted, inserted) {
+                Result::Ok(_) => inserted += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::StorageFull));
+        for i in 0..inserted {
+            assert_eq!(tree.search(&i).unwrap(), Some(i));
+        }
+        assert_eq!(tree.search(&inserted).unwrap(), None);
+
+        // 不需要新 block 的写还能继续, 放开限制之后插入恢复
+        assert_eq!(tree.delete(&0).unwrap(), Some(0));
+        tree.engine.set_capacity(None);
+        tree.insert(inserted, inserted).unwrap();
+        assert_eq!(tree.search(&inserted).unwrap(), Some(inserted));
+    }
+
+    // 检查每个非 root 结点都不少于 min_keys, 所有叶子同一深度, 返回深度
+    pub(crate) fn check_shape<E>(tree: &BPlusTree<u32, u32, E>, block_id: BlockId, is_root: bool) -> usize
+    where
+        E: BlockEngine<Item = BPlusTreeNode<u32, u32>>,
+    {
+        let read = tree.engine.fetch_read(block_id).unwrap();
+        let node = read.as_ref().unwrap();
+        assert!(is_root || node.keys.len() >= node.min_keys());
+        if node.is_leaf() {
+            return 1;
+        }
+        assert_eq!(node.keys.len() + 1, node.pointers.len());
+        assert_eq!(node.counts.len(), node.pointers.len());
+        for (&child, &count) in node.pointers.iter().zip(&node.counts) {
+            assert_eq!(tree.subtree_count(child).unwrap(), count);
+        }
+        let depths: Vec<_> = node.pointers.iter().map(|&child| check_shape(tree, child, false)).collect();
+        assert!(depths.iter().all(|&depth| depth == depths[0]));
+        depths[0] + 1
+    }
+
+    #[test]
+    fn test_delete_rebalance() {
+        for way in [2, 3, 4, 7] {
+            for persistent in [false, true] {
+                let mut tree = BPlusTree::new(way, MemoryBlockEngine::new()).unwrap();
+                let mut expected = std::collections::BTreeMap::new();
+                let mut seed = 17u32;
+                for i in 0..600 {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    let key = (seed >> 16) % 200;
+                    if persistent && i % 50 == 0 {
+                        tree.freeze();
+                    }
+                    if i < 300 || seed.is_multiple_of(3) {
+                        assert_eq!(tree.insert(key, i).unwrap(), expected.insert(key, i));
+                    } else {
+                        assert_eq!(tree.delete(&key).unwrap(), expected.remove(&key));
+                    }
+                    check_shape(&tree, tree.root, true);
+                }
+                assert_eq!(tree.range(..).collect::<Vec<_>>(), expected.clone().into_iter().collect::<Vec<_>>());
+
+                let frozen = tree.freeze();
+                let before = expected.clone();
+                for (key, value) in before.iter() {
+                    assert_eq!(tree.delete(key).unwrap(), Some(*value));
+                    check_shape(&tree, tree.root, true);
+                }
+                assert_eq!(tree.range(..).count(), 0);
+                assert!(tree.engine.fetch_read(tree.root).unwrap().as_ref().unwrap().is_leaf());
+                // 删光之前冻结的版本不受影响
+                for (key, value) in before {
+                    assert_eq!(tree.search_at(frozen, &key).unwrap(), Some(value));
+                }
+            }
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_stats() {
+        let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        for i in 0..100 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.insert(0, 1).unwrap();
+        let stats = tree.stats();
+        assert_eq!((stats.inserts, stats.deletes, stats.merges), (101, 0, 0));
+        assert!(stats.splits > 0);
+        // 每次分裂分配一个新结点, 再加上 root 和每次长高时的新 root
+        assert!(tree.engine_stats().allocations > stats.splits);
+
+        for i in 0..100 {
+            tree.delete(&i).unwrap();
+        }
+        assert!(tree.delete(&0).unwrap().is_none());
+        assert_eq!(tree.stats().deletes, 100);
+        assert!(tree.stats().merges > 0);
+    }
+
+    #[test]
+    fn test_insert_replace() {
+        let mut tree = BPlusTree::new(2, MemoryBlockEngine::with_capacity(16)).unwrap();
+        let mut len = 0;
+        while let Result::Ok(old) = tree.insert(len, len) {
+            assert_eq!(old, None);
+            len += 1;
+        }
+        // 满了之后替换已有的 key 不需要新 block
+        assert_eq!(tree.insert(5, 50).unwrap(), Some(5));
+        assert_eq!(tree.search(&5).unwrap(), Some(50));
+        assert_eq!(tree.iter().count(), len as usize);
+
+        let err = tree.insert_unique(5, 0).unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::KeyExists));
+        assert_eq!(tree.search(&5).unwrap(), Some(50));
+        tree.engine.set_capacity(None);
+        tree.insert_unique(100, 0).unwrap();
+        assert_eq!(tree.search(&100).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn test_len_and_contains_key() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        assert!(tree.is_empty());
+        for i in 0..40 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.insert(3, 30).unwrap();
+        tree.delete(&100).unwrap();
+        tree.delete(&4).unwrap();
+        assert_eq!(tree.len(), 39);
+        assert!(tree.contains_key(&3).unwrap() && !tree.contains_key(&4).unwrap());
+
+        // 切换到别的版本时 len 跟着切换, 出错时回到原来的
+        let v1 = tree.freeze();
+        tree.delete(&5).unwrap();
+        let v2 = tree.modify(v1, |tree| {
+            tree.insert(100, 0)?;
+            tree.insert(101, 0)?;
+            Ok(())
+        }).unwrap();
+        assert_eq!(tree.len(), 41);
+        assert!(tree.modify(v1, |_| Err(anyhow::anyhow!("abort."))).is_err());
+        assert_eq!(tree.len(), 41);
+        tree.modify(v2, |tree| tree.delete(&0).map(|_| ())).unwrap();
+        assert_eq!(tree.len(), 40);
+        assert_eq!(tree.len(), tree.iter().count());
+    }
+
+    #[test]
+    fn test_first_last_pop() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        assert_eq!(tree.first_key_value().unwrap(), None);
+        assert_eq!(tree.pop_last().unwrap(), None);
+        for i in [5, 3, 9, 1, 7] {
+            tree.insert(i, i * 10).unwrap();
+        }
+        assert_eq!(tree.first_key_value().unwrap(), Some((1, 10)));
+        assert_eq!(tree.last_key_value().unwrap(), Some((9, 90)));
+
+        let mut popped = vec![];
+        while let Some((key, _)) = tree.pop_first().unwrap() {
+            popped.push(key);
+            if let Some((key, _)) = tree.pop_last().unwrap() {
+                popped.push(key);
+            }
+        }
+        assert_eq!(popped, vec![1, 9, 3, 7, 5]);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_update_with() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for word in "a b a c a b".split(' ') {
+            if !tree.update_with(&word, |n| *n += 1).unwrap() {
+                tree.insert(word, 1).unwrap();
+            }
+        }
+        assert_eq!(tree.iter().collect::<Vec<_>>(), vec![("a", 3), ("b", 2), ("c", 1)]);
+        let version = tree.freeze();
+        assert!(tree.update_with(&"c", |n| *n = 10).unwrap());
+        assert!(!tree.update_with(&"d", |n| *n = 10).unwrap());
+        assert_eq!((tree.search(&"c").unwrap(), tree.search_at(version, &"c").unwrap()), (Some(10), Some(1)));
+    }
+
+    #[test]
+    fn test_compare_exchange() {
+        let mut tree = BPlusTree::new(3, MemoryBlockEngine::new()).unwrap();
+        for i in 0..20 {
+            tree.insert(i, i).unwrap();
+        }
+        assert_eq!(tree.compare_exchange(&5, &5, 50).unwrap(), Result::Ok(()));
+        assert_eq!(tree.compare_exchange(&5, &5, 500).unwrap(), Err(500));
+        assert_eq!(tree.compare_exchange(&30, &0, 1).unwrap(), Err(1));
+        assert_eq!((tree.search(&5).unwrap(), tree.len()), (Some(50), 20));
+    }
+
+    #[test]
+    fn test_custom_comparator() {
+        use std::ops::Bound;
+
+        // 倒序
+        let mut tree = BPlusTree::with_comparator(3, MemoryBlockEngine::new(), |a: &u32, b: &u32| b.cmp(a)).unwrap();
+        for i in 0..200 {
+            tree.insert(i, i).unwrap();
+        }
+        for i in (0..200).step_by(3) {
+            assert_eq!(tree.delete(&i).unwrap(), Some(i));
+        }
+        check_shape(&tree, tree.root, true);
+        assert_eq!(tree.keys().take(3).collect::<Vec<_>>(), vec![199, 197, 196]);
+        assert_eq!(tree.range((Bound::Included(20), Bound::Included(15))).map(|(k, _)| k).collect::<Vec<_>>(), vec![20, 19, 17, 16]);
+        assert_eq!(tree.first_key_value().unwrap(), Some((199, 199)));
+
+        // 大小写不敏感, 只差大小写的 key 算同一个
+        let mut tree = BPlusTree::with_comparator(3, MemoryBlockEngine::new(), |a: &String, b: &String| {
+            a.to_lowercase().cmp(&b.to_lowercase())
+        })
+        .unwrap();
+        for word in ["Banana", "apple", "Cherry", "APPLE"] {
+            tree.insert(word.to_string(), word.len()).unwrap();
+        }
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.keys().collect::<Vec<_>>(), vec!["apple", "Banana", "Cherry"]);
+        assert!(tree.contains_key(&"cherry".to_string()).unwrap());
+    }
+}
@@ -0,0 +1,265 @@
+use anyhow::{anyhow, Ok, Result};
+use io_uring::{opcode, squeue, types, IoUring};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    os::unix::{fs::FileExt, io::AsRawFd},
+    path::Path,
+};
+
+use crate::{
+    block::{Block, BlockEngine, BlockEngineStats, BlockId, BlockReadGuard, BlockWriteGuard, TreeMeta},
+    codec::{BincodeCodec, NodeCodec},
+    file::{FileBlockEngine, FileOptions},
+};
+
+// 一个 io_uring 实例, 一批读写一起提交再一起等, 超过提交队列长度的分几次
+pub(crate) struct Ring {
+    ring: IoUring,
+}
+
+impl Ring {
+    pub(crate) fn new(queue_depth: u32) -> Result<Self> {
+        Ok(Ring { ring: IoUring::new(queue_depth)? })
+    }
+
+    // 内核可能把长度向上取整
+    pub(crate) fn queue_depth(&self) -> usize {
+        self.ring.params().sq_entries() as usize
+    }
+
+    pub(crate) fn read_at(&mut self, file: &File, reads: &mut [(u64, &mut [u8])]) -> Result<()> {
+        let fd = types::Fd(file.as_raw_fd());
+        let depth = self.queue_depth();
+        for batch in reads.chunks_mut(depth) {
+            let entries: Vec<_> = batch
+                .iter_mut()
+                .enumerate()
+                .map(|(i, (offset, buf))| {
+                    let read = opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32).offset(*offset);
+                    read.build().user_data(i as u64)
+                })
+                .collect();
+            // SAFETY: batch 里的缓冲区在 submit 返回, 也就是所有请求都完成之前一直借着
+            let done = unsafe { self.submit(&entries)? };
+            // 只读了一部分的 (比如碰到文件末尾) 剩下的同步读, 读不满时报错
+            for (i, n) in done {
+                let (offset, buf) = &mut batch[i];
+                if n < buf.len() {
+                    file.read_exact_at(&mut buf[n..], *offset + n as u64)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_at(&mut self, file: &File, writes: &[(u64, &[u8])]) -> Result<()> {
+        let fd = types::Fd(file.as_raw_fd());
+        let depth = self.queue_depth();
+        for batch in writes.chunks(depth) {
+            let entries: Vec<_> = batch
+                .iter()
+                .enumerate()
+                .map(|(i, (offset, buf))| {
+                    let write = opcode::Write::new(fd, buf.as_ptr(), buf.len() as u32).offset(*offset);
+                    write.build().user_data(i as u64)
+                })
+                .collect();
+            // SAFETY: 同 read_at
+            let done = unsafe { self.submit(&entries)? };
+            for (i, n) in done {
+                let (offset, buf) = batch[i];
+                if n < buf.len() {
+                    file.write_all_at(&buf[n..], offset + n as u64)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 提交一批请求并等它们全部完成, 返回 (user_data, 完成的字节数)
+    // 有请求失败时也要等别的都完成才返回, 不然内核还在用调用的人的缓冲区
+    // 调用的人保证 entries 指向的缓冲区在返回之前有效
+    unsafe fn submit(&mut self, entries: &[squeue::Entry]) -> Result<Vec<(usize, usize)>> {
+        self.ring.submission().push_multiple(entries).map_err(|_| anyhow!("io_uring submission queue is full."))?;
+        let mut done = Vec::with_capacity(entries.len());
+        let mut failed = None;
+        let mut completed = 0;
+        while completed < entries.len() {
+            self.ring.submit_and_wait(entries.len() - completed)?;
+            for cqe in self.ring.completion() {
+                completed += 1;
+                match cqe.result() {
+                    n if n < 0 => {
+                        failed.get_or_insert(std::io::Error::from_raw_os_error(-n));
+                    }
+                    n => done.push((cqe.user_data() as usize, n as usize)),
+                }
+            }
+        }
+        match failed {
+            Some(e) => Err(e.into()),
+            None => Ok(done),
+        }
+    }
+}
+
+// 用 io_uring 做成批读写的 FileBlockEngine, 文件格式一样, 两个可以轮流打开同一个文件
+// flush 时所有 dirty 的页一批提交, prefetch 把一批页一起读进 buffer pool; 单页的缓存不命中和淘汰还是同步读写
+// 开着 wal 时页都在日志里, 不走 io_uring
+pub struct UringBlockEngine<B, C = BincodeCodec> {
+    inner: FileBlockEngine<B, C>,
+    queue_depth: usize,
+}
+
+impl<B> UringBlockEngine<B>
+where
+    B: Serialize + DeserializeOwned,
+{
+    // queue_depth 是一次最多提交的请求数
+    pub fn create(path: impl AsRef<Path>, options: FileOptions, queue_depth: u32) -> Result<Self> {
+        Self::create_with_codec(path, options, queue_depth, BincodeCodec)
+    }
+
+    pub fn open(path: impl AsRef<Path>, options: FileOptions, queue_depth: u32) -> Result<Self> {
+        Self::open_with_codec(path, options, queue_depth, BincodeCodec)
+    }
+}
+
+impl<B, C> UringBlockEngine<B, C>
+where
+    C: NodeCodec<B>,
+{
+    pub fn create_with_codec(path: impl AsRef<Path>, options: FileOptions, queue_depth: u32, codec: C) -> Result<Self> {
+        Self::with_ring(FileBlockEngine::create_with_codec(path, options, codec)?, queue_depth)
+    }
+
+    pub fn open_with_codec(path: impl AsRef<Path>, options: FileOptions, queue_depth: u32, codec: C) -> Result<Self> {
+        Self::with_ring(FileBlockEngine::open_with_codec(path, options, codec)?, queue_depth)
+    }
+
+    fn with_ring(mut inner: FileBlockEngine<B, C>, queue_depth: u32) -> Result<Self> {
+        let ring = Ring::new(queue_depth)?;
+        let queue_depth = ring.queue_depth();
+        inner.set_ring(ring);
+        Ok(UringBlockEngine { inner, queue_depth })
+    }
+
+    // prefetch 一次给这么多个正好填满提交队列
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth
+    }
+}
+
+impl<B, C> BlockEngine for UringBlockEngine<B, C>
+where
+    C: NodeCodec<B>,
+{
+    type Item = B;
+
+    fn alloc_block(&self) -> Result<BlockId> {
+        self.inner.alloc_block()
+    }
+
+    fn fetch_read(&self, block_id: BlockId) -> Result<BlockReadGuard<'_, B>> {
+        self.inner.fetch_read(block_id)
+    }
+
+    fn fetch_write(&self, block_id: BlockId) -> Result<BlockWriteGuard<'_, B>> {
+        self.inner.fetch_write(block_id)
+    }
+
+    fn delete(&self, block_id: BlockId) -> Result<Option<B>> {
+        self.inner.delete(block_id)
+    }
+
+    fn write_back(block_id: BlockId, block: &Block<B>) {
+        FileBlockEngine::<B, C>::write_back(block_id, block)
+    }
+
+    fn load_meta(&self) -> Option<TreeMeta> {
+        self.inner.load_meta()
+    }
+
+    fn stage_snapshots(&self, snapshots: &BTreeMap<String, TreeMeta>) -> Result<()> {
+        self.inner.stage_snapshots(snapshots)
+    }
+
+    fn load_snapshots(&self) -> Result<BTreeMap<String, TreeMeta>> {
+        self.inner.load_snapshots()
+    }
+
+    fn flush(&mut self, meta: TreeMeta) -> Result<()> {
+        self.inner.flush(meta)
+    }
+
+    fn checkpoint(&mut self, meta: TreeMeta) -> Result<()> {
+        self.inner.checkpoint(meta)
+    }
+
+    fn vacuum(&mut self, meta: TreeMeta) -> Result<usize> {
+        self.inner.vacuum(meta)
+    }
+
+    fn stats(&self) -> BlockEngineStats {
+        self.inner.stats()
+    }
+
+    fn prefetch(&self, block_ids: &[BlockId]) -> Result<usize> {
+        self.inner.prefetch(block_ids)
+    }
+
+    fn resident(&self) -> Result<Vec<BlockId>> {
+        self.inner.resident()
+    }
+
+    fn pin(&self, block_id: BlockId) -> Result<()> {
+        self.inner.pin(block_id)
+    }
+
+    fn unpin(&self, block_id: BlockId) -> Result<()> {
+        self.inner.unpin(block_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        doublewrite::DoubleWrite,
+        tree::{BPlusTree, BPlusTreeNode},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_uring_engine() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-uring-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 512, pool_size: 64, double_write: true, ..FileOptions::default() };
+        // 队列很短, flush 时要分好几批提交
+        let engine = UringBlockEngine::<BPlusTreeNode<u32, String>>::create(&path, options, 4).unwrap();
+        assert_eq!(engine.queue_depth(), 4);
+        let mut tree = BPlusTree::new(8, engine).unwrap();
+        for i in 0..1000 {
+            tree.insert(i, format!("value {}", i)).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+
+        // 和 FileBlockEngine 是同一种文件
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, String>>::open(&path, options).unwrap();
+        BPlusTree::open(engine).unwrap().verify().unwrap();
+
+        // 一次最多读满 pool, 已经读进来的不再读
+        let options = FileOptions { pool_size: 16, ..options };
+        let engine = UringBlockEngine::<BPlusTreeNode<u32, String>>::open(&path, options, 4).unwrap();
+        let ids: Vec<_> = (0..100).collect();
+        assert_eq!(engine.prefetch(&ids).unwrap(), 16);
+        assert_eq!(engine.prefetch(&ids[..16]).unwrap(), 0);
+        let tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert_eq!(tree.search(&999).unwrap(), Some("value 999".to_string()));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(DoubleWrite::path(&path)).unwrap();
+    }
+}
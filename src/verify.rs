@@ -0,0 +1,176 @@
+use anyhow::{Ok, Result};
+
+use crate::{
+    block::{BlockEngine, BlockId},
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// verify 顺便统计的树的形状
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeStats {
+    // 层数, 只有一个叶子时是 1
+    pub height: usize,
+    pub inner_nodes: usize,
+    pub leaves: usize,
+    pub entries: usize,
+    // 叶子的平均填充率, keys / way
+    pub leaf_fill: f64,
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 从 root 开始完整检查一遍, 第一个不满足的地方返回 Error::Corrupted
+    // 结点里没有 parent 指针, 父子之间靠分隔 key 的范围和 counts 对上来检查
+    pub fn verify(&self) -> Result<TreeStats> {
+        let mut stats = TreeStats::default();
+        let mut leaves = vec![];
+        let entries = self.verify_node(self.root, None, None, 1, &mut stats, &mut leaves)?;
+        if entries != self.len {
+            return Err(corrupted(self.root, format!("tree has {} entries but len is {}", entries, self.len)));
+        }
+        // 持久化模式下叶子链不可信, 不检查
+        if !self.persistent {
+            self.verify_links(&leaves)?;
+        }
+        stats.entries = entries;
+        stats.leaves = leaves.len();
+        stats.leaf_fill = entries as f64 / (leaves.len() * self.way) as f64;
+        Ok(stats)
+    }
+
+    // 返回子树里的条目数, 叶子按从左到右的顺序放进 leaves
+    fn verify_node(
+        &self,
+        block_id: BlockId,
+        lower: Option<&K>,
+        upper: Option<&K>,
+        depth: usize,
+        stats: &mut TreeStats,
+        leaves: &mut Vec<BlockId>,
+    ) -> Result<usize> {
+        let read = self.engine.fetch_read(block_id)?;
+        let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+        let is_root = block_id == self.root;
+
+        if node.way != self.way {
+            return Err(corrupted(block_id, format!("way is {} instead of {}", node.way, self.way)));
+        }
+        if node.keys.windows(2).any(|w| !self.order.lt(&w[0], &w[1])) {
+            return Err(corrupted(block_id, "keys are not strictly ascending".to_string()));
+        }
+        let in_range = |key: &K| {
+            lower.is_none_or(|lower| !self.order.lt(key, lower)) && upper.is_none_or(|upper| self.order.lt(key, upper))
+        };
+        if !node.keys.iter().all(in_range) {
+            return Err(corrupted(block_id, "key outside the range of its separators".to_string()));
+        }
+        if node.is_overflow() {
+            return Err(corrupted(block_id, format!("{} keys exceed way {}", node.keys.len(), self.way)));
+        }
+        if self.capacity.is_overflow(node) {
+            return Err(corrupted(block_id, format!("{} bytes of cells exceed the node capacity", self.capacity.used(node))));
+        }
+        let min_keys = self.capacity.min_keys(node);
+        if !is_root && node.keys.len() < min_keys {
+            return Err(corrupted(block_id, format!("{} keys are fewer than {}", node.keys.len(), min_keys)));
+        }
+
+        if node.is_leaf() {
+            if node.keys.len() != node.values.len() || !node.pointers.is_empty() || !node.counts.is_empty() {
+                return Err(corrupted(block_id, "leaf has mismatched keys and values".to_string()));
+            }
+            if stats.height == 0 {
+                stats.height = depth;
+            } else if stats.height != depth {
+                return Err(corrupted(block_id, format!("leaf at depth {} while others are at {}", depth, stats.height)));
+            }
+            leaves.push(block_id);
+            return Ok(node.keys.len());
+        }
+
+        if node.pointers.len() != node.keys.len() + 1
+            || node.counts.len() != node.pointers.len()
+            || node.maxes.len() != node.pointers.len()
+            || !node.values.is_empty()
+        {
+            return Err(corrupted(block_id, "inner node has mismatched keys, pointers, counts and maxes".to_string()));
+        }
+        if node.keys.is_empty() {
+            return Err(corrupted(block_id, "inner node has a single child".to_string()));
+        }
+        stats.inner_nodes += 1;
+        let mut entries = 0;
+        for (i, (&child, &count)) in node.pointers.iter().zip(&node.counts).enumerate() {
+            let lower = if i == 0 { lower } else { Some(&node.keys[i - 1]) };
+            let upper = if i == node.keys.len() { upper } else { Some(&node.keys[i]) };
+            let actual = self.verify_node(child, lower, upper, depth + 1, stats, leaves)?;
+            if actual != count {
+                return Err(corrupted(block_id, format!("count of child {} is {} but it has {} entries", child, count, actual)));
+            }
+            if self.augment.is_some() && node.maxes[i] != self.subtree_max(child)? {
+                return Err(corrupted(block_id, format!("max of child {} does not match its keys", child)));
+            }
+            entries += actual;
+        }
+        Ok(entries)
+    }
+
+    // 叶子链要和从 root 往下看到的顺序一致, prev / next 互相指向
+    fn verify_links(&self, leaves: &[BlockId]) -> Result<()> {
+        for (i, &leaf) in leaves.iter().enumerate() {
+            let read = self.engine.fetch_read(leaf)?;
+            let node = read.as_ref().ok_or(Error::EmptyBlock(leaf))?;
+            let prev = i.checked_sub(1).map(|i| leaves[i]);
+            let next = leaves.get(i + 1).copied();
+            if node.prev != prev || node.next != next {
+                return Err(corrupted(
+                    leaf,
+                    format!("leaf links are {:?} / {:?} instead of {:?} / {:?}", node.prev, node.next, prev, next),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn corrupted(block_id: BlockId, reason: String) -> anyhow::Error {
+    Error::Corrupted(block_id, reason).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        for persistent in [false, true] {
+            let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+            assert_eq!(tree.verify().unwrap(), TreeStats { height: 1, leaves: 1, ..TreeStats::default() });
+            for i in 0..500u32 {
+                if persistent && i % 100 == 0 {
+                    tree.freeze();
+                }
+                tree.insert(i * 7 % 500, i).unwrap();
+                if i % 3 == 0 {
+                    tree.delete(&(i * 5 % 500)).unwrap();
+                }
+            }
+            let stats = tree.verify().unwrap();
+            assert_eq!(stats.entries, tree.len());
+            assert!(stats.height >= 4 && stats.leaf_fill > 0.5 && stats.leaf_fill <= 1.0);
+
+            // 把一个分隔 key 改大, 它左边子树里的 key 就越界了
+            let root = tree.root;
+            tree.engine.fetch_write(root).unwrap().as_mut().unwrap().keys[0] += 1000;
+            let err = tree.verify().unwrap_err();
+            assert!(matches!(err.downcast_ref::<Error>(), Some(Error::Corrupted(..))));
+        }
+    }
+}
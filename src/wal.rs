@@ -0,0 +1,255 @@
+use anyhow::{anyhow, Ok, Result};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+// commit 记录的页号
+const COMMIT: u64 = u64::MAX;
+// lsn, 页号, 长度, crc32
+const RECORD_HEADER_LEN: usize = 8 + 8 + 4 + 4;
+
+// 预写日志, 开着的时候数据文件里的页只在 checkpoint 时改写, 所以文件里永远是上一次提交的样子
+// 要写回的页先整页追加到日志里, 再读这一页时从日志里读最新的一份
+// 提交时追加一条带着 superblock 的 commit 记录并 fsync, checkpoint 时把日志里的页写进数据文件再清空日志
+// 记录的格式: lsn, 页号, 长度, crc32, 内容; crc32 覆盖除了它自己以外的整条记录
+// lsn 在清空日志之后接着往下数, 数据文件的 superblock 里记着 checkpoint 到了哪里, 比它小的记录都已经写进文件了
+// 打开时重放最后一条 commit 之前的记录, 后面的是崩溃时还没有提交的, 直接丢掉
+pub(crate) struct Wal {
+    file: File,
+    // 页号 -> 日志里最新一份内容的位置和长度
+    pages: HashMap<usize, (u64, usize)>,
+    // 下一条记录的 lsn 和位置
+    lsn: u64,
+    end: u64,
+    // 追加到日志里和 checkpoint 时写进数据文件的字节数
+    written: u64,
+}
+
+impl Wal {
+    pub(crate) fn path(data: &Path) -> PathBuf {
+        let mut path = data.as_os_str().to_owned();
+        path.push(".wal");
+        path.into()
+    }
+
+    // 新建数据文件时, 旧的日志已经没有用了
+    pub(crate) fn create(data: &Path) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(Self::path(data))?;
+        Ok(Wal { file, pages: HashMap::new(), lsn: 0, end: 0, written: 0 })
+    }
+
+    // 把日志里 checkpoint_lsn 之后提交过的页重放到数据文件里并 fsync, 返回日志和最后一次提交的 superblock
+    // 调用的人写好 superblock 之后再 truncate
+    pub(crate) fn open(
+        data: &Path,
+        data_file: &mut File,
+        page_size: usize,
+        checkpoint_lsn: u64,
+    ) -> Result<(Self, Option<Vec<u8>>)> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(Self::path(data))?;
+        let mut log = vec![];
+        file.read_to_end(&mut log)?;
+
+        let (mut pending, mut committed) = (HashMap::new(), HashMap::new());
+        let mut superblock = None;
+        let mut rest = &log[..];
+        let mut next = None;
+        let mut committed_lsn = checkpoint_lsn;
+        // 截断了, 校验和不对或者 lsn 不连续的记录是崩溃时写了一半的, 从那里往后都不要
+        while let Some((lsn, page_no, image)) = read_record(&mut rest) {
+            if next.is_some_and(|next| next != lsn) {
+                break;
+            }
+            next = Some(lsn + 1);
+            // checkpoint 之后还没来得及清空日志就崩溃了, 这些已经在文件里了
+            if lsn < checkpoint_lsn {
+                continue;
+            }
+            if page_no == COMMIT {
+                committed.extend(pending.drain());
+                committed_lsn = lsn + 1;
+                superblock = Some(image);
+            } else {
+                pending.insert(page_no as usize, image);
+            }
+        }
+        let end = log.len() as u64;
+        for (page_no, image) in committed {
+            if image.len() != page_size {
+                return Err(anyhow!("page {} in the log has {} bytes, expected {}.", page_no, image.len(), page_size));
+            }
+            write_at(data_file, (page_no * page_size) as u64, &image)?;
+        }
+        data_file.sync_data()?;
+        Ok((Wal { file, pages: HashMap::new(), lsn: committed_lsn, end, written: 0 }, superblock))
+    }
+
+    // 下一条记录的 lsn
+    pub(crate) fn lsn(&self) -> u64 {
+        self.lsn
+    }
+
+    pub(crate) fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub(crate) fn append(&mut self, page_no: usize, image: &[u8]) -> Result<()> {
+        let offset = self.end + RECORD_HEADER_LEN as u64;
+        self.append_record(page_no as u64, image)?;
+        self.pages.insert(page_no, (offset, image.len()));
+        Ok(())
+    }
+
+    // 日志里有这一页时读进 buf, 返回 true
+    pub(crate) fn read(&mut self, page_no: usize, buf: &mut [u8]) -> Result<bool> {
+        let Some(&(offset, len)) = self.pages.get(&page_no) else {
+            return Ok(false);
+        };
+        if len != buf.len() {
+            return Err(anyhow!("page {} in the log has {} bytes, expected {}.", page_no, len, buf.len()));
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)?;
+        Ok(true)
+    }
+
+    // sync 时返回之后前面追加的页都不会丢了, 重放时用 superblock 替换文件里的
+    pub(crate) fn commit(&mut self, superblock: &[u8], sync: bool) -> Result<()> {
+        self.append_record(COMMIT, superblock)?;
+        if sync {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    // 把日志里的页写进数据文件并 fsync, 调用前要先 commit, 之后写好 superblock 再 truncate
+    pub(crate) fn checkpoint(&mut self, data_file: &mut File, page_size: usize) -> Result<()> {
+        let mut pages: Vec<_> = self.pages.iter().map(|(&page_no, &location)| (page_no, location)).collect();
+        pages.sort();
+        let mut image = vec![0; page_size];
+        for (page_no, (offset, len)) in pages {
+            image.resize(len, 0);
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut image)?;
+            write_at(data_file, (page_no * page_size) as u64, &image)?;
+            self.written += len as u64;
+        }
+        data_file.sync_data()?;
+        Ok(())
+    }
+
+    fn append_record(&mut self, page_no: u64, image: &[u8]) -> Result<()> {
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + image.len());
+        record.extend_from_slice(&self.lsn.to_le_bytes());
+        record.extend_from_slice(&page_no.to_le_bytes());
+        record.extend_from_slice(&(image.len() as u32).to_le_bytes());
+        record.extend_from_slice(&[0; 4]);
+        record.extend_from_slice(image);
+        let checksum = record_checksum(&record);
+        record[20..24].copy_from_slice(&checksum.to_le_bytes());
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&record)?;
+        self.lsn += 1;
+        self.end += record.len() as u64;
+        self.written += record.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn truncate(&mut self) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.pages.clear();
+        self.end = 0;
+        Ok(())
+    }
+}
+
+fn read_record(rest: &mut &[u8]) -> Option<(u64, u64, Vec<u8>)> {
+    let header = rest.get(..RECORD_HEADER_LEN)?;
+    let lsn = u64::from_le_bytes(header[0..8].try_into().ok()?);
+    let page_no = u64::from_le_bytes(header[8..16].try_into().ok()?);
+    let len = u32::from_le_bytes(header[16..20].try_into().ok()?) as usize;
+    let stored = u32::from_le_bytes(header[20..24].try_into().ok()?);
+    let record = rest.get(..RECORD_HEADER_LEN + len)?;
+    if record_checksum(record) != stored {
+        return None;
+    }
+    *rest = &rest[RECORD_HEADER_LEN + len..];
+    Some((lsn, page_no, record[RECORD_HEADER_LEN..].to_vec()))
+}
+
+// 跳过存校验和的 20..24
+fn record_checksum(record: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&record[..20]);
+    hasher.update(&record[24..]);
+    hasher.finalize()
+}
+
+fn write_at(file: &mut File, offset: u64, buf: &[u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        file::{FileBlockEngine, FileOptions},
+        tree::{BPlusTree, BPlusTreeNode},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_wal_recovery() {
+        let path = std::env::temp_dir().join(format!("bplus-tree-wal-{}.db", std::process::id()));
+        // pool 很小, 没有 flush 的修改也会被淘汰写回
+        let options = FileOptions { page_size: 512, pool_size: 4, wal: true, ..FileOptions::default() };
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::create(&path, options).unwrap();
+        let mut tree = BPlusTree::new(8, engine).unwrap();
+        for i in 0..500 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.checkpoint().unwrap();
+        assert_eq!(std::fs::metadata(Wal::path(&path)).unwrap().len(), 0);
+
+        // 没有 flush 就崩溃了, 分裂到一半的页只在日志里
+        for i in 500..1000 {
+            tree.insert(i, i).unwrap();
+        }
+        assert!(std::fs::metadata(Wal::path(&path)).unwrap().len() > 0);
+        drop(tree);
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, options).unwrap();
+        let mut tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert!(tree.iter().eq((0..500).map(|i| (i, i))));
+
+        // flush 只提交日志, 文件还是 checkpoint 时的样子; 后面再跟一条没写完的记录
+        for i in 500..1000 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+        let mut log = OpenOptions::new().append(true).open(Wal::path(&path)).unwrap();
+        log.write_all(&[0xbb; 100]).unwrap();
+        drop(log);
+
+        // 不开 wal 打开时也要重放提交了的部分
+        let engine = FileBlockEngine::<BPlusTreeNode<u32, u32>>::open(&path, FileOptions::default()).unwrap();
+        let tree = BPlusTree::open(engine).unwrap();
+        tree.verify().unwrap();
+        assert!(tree.iter().eq((0..1000).map(|i| (i, i))));
+        assert_eq!(std::fs::metadata(Wal::path(&path)).unwrap().len(), 0);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(Wal::path(&path)).unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::{Ok, Result};
+use std::ops::Bound;
+
+use crate::{
+    block::{BlockEngine, BlockId},
+    error::Error,
+    tree::{BPlusTree, BPlusTreeNode},
+};
+
+// warm_up 要读进缓存的部分
+#[derive(Debug, Clone, PartialEq)]
+pub enum WarmUp<K> {
+    // 所有内部结点, 之后每次查找只有叶子要读
+    Inner,
+    // 从 root 开始往下 n 层, 只有 root 是 1
+    Levels(usize),
+    // key 落在范围里的叶子, 以及从 root 到它们的内部结点
+    Range(Bound<K>, Bound<K>),
+    // 关闭前 hot_set 记下来的 block, 不管在树里的哪一层
+    Blocks(Vec<BlockId>),
+}
+
+impl<K, V, E> BPlusTree<K, V, E>
+where
+    E: BlockEngine<Item = BPlusTreeNode<K, V>>,
+    K: Ord + Clone,
+    V: Clone,
+{
+    // 打开之后一层一层地把结点读进 engine 的缓存, 每层一次 prefetch, 返回读进来的 block 个数
+    // 上面的层先读, 缓存装不下时后读的会把先读的挤出去, 要读的量最好别超过缓存的大小
+    // 没有缓存的 engine 上只是把结点走一遍
+    pub fn warm_up(&self, mode: WarmUp<K>) -> Result<usize> {
+        let (levels, start, end) = match &mode {
+            WarmUp::Inner => (self.height(self.root)?, Bound::Unbounded, Bound::Unbounded),
+            WarmUp::Levels(n) => (*n, Bound::Unbounded, Bound::Unbounded),
+            // range 反向定位时会走到 end 所在的叶子, end 不包含在内时这个叶子也读上
+            WarmUp::Range(start, Bound::Excluded(end)) => (usize::MAX, start.as_ref(), Bound::Included(end)),
+            WarmUp::Range(start, end) => (usize::MAX, start.as_ref(), end.as_ref()),
+            WarmUp::Blocks(block_ids) => return self.engine.prefetch(block_ids),
+        };
+        let mut level = vec![self.root];
+        let mut loaded = 0;
+        for _ in 0..levels {
+            if level.is_empty() {
+                break;
+            }
+            loaded += self.engine.prefetch(&level)?;
+            let mut next: Vec<BlockId> = vec![];
+            for block_id in level {
+                let read = self.engine.fetch_read(block_id)?;
+                let node = read.as_ref().ok_or(Error::EmptyBlock(block_id))?;
+                if node.is_leaf() {
+                    continue;
+                }
+                // 子树 i 里的 key 在 [keys[i - 1], keys[i]) 里
+                for (i, &child) in node.pointers.iter().enumerate() {
+                    let below_start = i < node.keys.len() && !self.order.after_start(start, &node.keys[i]);
+                    let above_end = i > 0 && !self.order.before_end(end, &node.keys[i - 1]);
+                    if !below_start && !above_end {
+                        next.push(child);
+                    }
+                }
+            }
+            level = next;
+        }
+        Ok(loaded)
+    }
+
+    // 现在在缓存里的 block, 关闭前存到别处, 下次打开后用 WarmUp::Blocks 读回来
+    // 树在这之后又改过的话, 已经回收的 block 会被跳过
+    pub fn hot_set(&self) -> Result<Vec<BlockId>> {
+        self.engine.resident()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::MemoryBlockEngine;
+
+    use super::*;
+
+    #[test]
+    fn test_warm_up_without_cache() {
+        let mut tree = BPlusTree::new(4, MemoryBlockEngine::new()).unwrap();
+        for i in 0..100 {
+            tree.insert(i, i).unwrap();
+        }
+        assert_eq!(tree.warm_up(WarmUp::Inner).unwrap(), 0);
+        assert_eq!(tree.warm_up(WarmUp::Range(Bound::Included(10), Bound::Excluded(20))).unwrap(), 0);
+        assert!(tree.hot_set().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "file")]
+    #[test]
+    fn test_warm_up() {
+        use crate::file::{FileBlockEngine, FileOptions};
+
+        let path = std::env::temp_dir().join(format!("bplus-tree-warm-up-{}.db", std::process::id()));
+        let options = FileOptions { page_size: 1024, pool_size: 256, ..FileOptions::default() };
+        let mut tree = BPlusTree::new(8, FileBlockEngine::create(&path, options).unwrap()).unwrap();
+        for i in 0..2000u32 {
+            tree.insert(i, i).unwrap();
+        }
+        tree.flush().unwrap();
+        drop(tree);
+        let open = || BPlusTree::<u32, u32, _>::open(FileBlockEngine::open(&path, options).unwrap()).unwrap();
+
+        // 内部结点都在缓存里, 查一个 key 只有叶子不命中
+        let tree = open();
+        let height = tree.height(tree.root).unwrap();
+        assert!(height >= 2);
+        let loaded = tree.warm_up(WarmUp::Inner).unwrap();
+        assert!(loaded > 1);
+        let before = tree.engine_stats();
+        assert_eq!(tree.search(&1234).unwrap(), Some(1234));
+        assert_eq!(tree.engine_stats().misses - before.misses, 1);
+        // 已经在缓存里的不再读
+        assert_eq!(tree.warm_up(WarmUp::Levels(height)).unwrap(), 0);
+
+        let tree = open();
+        assert_eq!(tree.warm_up(WarmUp::Levels(1)).unwrap(), 1);
+
+        // 范围里的叶子和路过的内部结点都读进来了
+        let tree = open();
+        let loaded = tree.warm_up(WarmUp::Range(Bound::Included(100), Bound::Excluded(300))).unwrap();
+        assert!(loaded > height && loaded < 100);
+        let before = tree.engine_stats();
+        assert_eq!(tree.range(100..300).count(), 200);
+        assert_eq!(tree.engine_stats().misses, before.misses);
+
+        // 关闭前记下的 hot set, 重新打开后读回来, 之前读过的都不再不命中
+        let mut hot_set = tree.hot_set().unwrap();
+        hot_set.sort();
+        drop(tree);
+        let tree = open();
+        assert_eq!(tree.warm_up(WarmUp::Blocks(hot_set.clone())).unwrap(), hot_set.len());
+        let mut resident = tree.hot_set().unwrap();
+        resident.sort();
+        assert_eq!(resident, hot_set);
+        let before = tree.engine_stats();
+        assert_eq!(tree.range(100..300).count(), 200);
+        assert_eq!(tree.engine_stats().misses, before.misses);
+        std::fs::remove_file(&path).unwrap();
+    }
+}